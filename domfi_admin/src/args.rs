@@ -0,0 +1,9 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+/// Parses a unix timestamp in seconds, rejecting those out of range instead of panicking
+pub fn parse_timestamp(s: &str) -> Result<DateTime<Utc>, String> {
+    let ts = s.parse::<i64>().map_err(|e| e.to_string())?;
+    NaiveDateTime::from_timestamp_opt(ts, 0)
+        .map(|x| DateTime::from_utc(x, Utc))
+        .ok_or_else(|| format!("timestamp {} is out of range", ts))
+}
@@ -0,0 +1,20 @@
+use domfi_util::{ConfigError, ConfigContext};
+
+pub struct Config {
+    pub postgres_url: String,
+}
+
+/// With `maintenance`, connects as the role allowed to delete from the archive instead
+pub fn config_with_prefix(prefix: &str, maintenance: bool) -> Result<Config, ConfigError> {
+    let config = ConfigContext::new(prefix);
+
+    let postgres_url = if maintenance {
+        config.var("MAINTENANCE_DATABASE_URL")?
+    } else {
+        config.var("DATABASE_URL")?
+    };
+
+    Ok(Config {
+        postgres_url,
+    })
+}
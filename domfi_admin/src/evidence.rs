@@ -0,0 +1,55 @@
+use std::error::Error;
+use std::io::Write;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use log::info;
+use sqlx::PgPool;
+use structopt::StructOpt;
+
+use domfi_data::{evidence, serving};
+use domfi_domain::models::financial_assets::get_canonical_default_asset;
+use crate::args::parse_timestamp;
+
+#[derive(StructOpt, Debug)]
+pub struct EvidenceArgs {
+    /// Ticker id of the asset, e.g. `btcdom`
+    asset: String,
+
+    /// Agent whose snapshots the API serves, as set in `DOMFI_API_AGENT` or
+    /// `DOMFI_API_AGENT_<ASSET>`. Defaults to the agent serving the asset when neither is set.
+    #[structopt(long)]
+    agent: Option<String>,
+
+    /// Unix timestamp in seconds of the price. Defaults to the latest price.
+    #[structopt(long, parse(try_from_str = parse_timestamp))]
+    timestamp: Option<DateTime<Utc>>,
+
+    /// Path of the archive to write, or `-` for stdout. Defaults to the bundle name
+    /// in the current directory.
+    #[structopt(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+}
+
+pub async fn run(args: EvidenceArgs, pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    let asset_meta = get_canonical_default_asset(&args.asset)
+        .ok_or_else(|| format!("Unknown asset: '{}'", args.asset))?;
+
+    let agent = args.agent.as_deref()
+        .unwrap_or_else(|| serving::default_agent_for(asset_meta.asset()));
+
+    let bundle = evidence::collect(asset_meta, agent, args.timestamp, pool).await?;
+    let archive = bundle.to_tar()?;
+
+    let output = args.output
+        .unwrap_or_else(|| PathBuf::from(format!("{}.tar", bundle.name())));
+
+    if output.as_os_str() == "-" {
+        std::io::stdout().write_all(&archive)?;
+    } else {
+        std::fs::write(&output, &archive)?;
+        info!("Wrote evidence for provenance {} to '{}'", bundle.provenance.uuid, output.display());
+    }
+
+    Ok(())
+}
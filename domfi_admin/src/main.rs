@@ -0,0 +1,64 @@
+mod args;
+mod config;
+mod evidence;
+mod merkle;
+mod reconcile;
+mod replay;
+mod retention;
+mod verify;
+
+use std::error::Error;
+use sqlx::PgPool;
+use structopt::StructOpt;
+use log::error;
+
+use domfi_util::init_logging;
+use crate::config::config_with_prefix;
+
+const DEFAULT_LOG_FILTERS: &str = "info,domfi_admin=debug,sqlx=warn";
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "domfi_admin", about = "Maintenance and dispute tooling for the coin dominance archive")]
+enum Command {
+    /// Exports the evidence bundle of a price as a tar archive
+    Evidence(evidence::EvidenceArgs),
+
+    /// Commits the provenances of each completed UTC day to a Merkle root
+    Merkle(merkle::MerkleArgs),
+
+    /// Compares the historical chart with the live snapshots per coin at matching timestamps
+    Reconcile(reconcile::ReconcileArgs),
+
+    /// Re-derives coin dominance rows from the archived blobs
+    Replay(replay::ReplayArgs),
+
+    /// Downsamples old coin dominance rows and deletes the provenances and blobs left without rows
+    Retention(retention::RetentionArgs),
+
+    /// Rechecks every archived blob against its hash and the coin dominance rows loaded from it
+    Verify,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let env_result = dotenv::dotenv();
+    init_logging(DEFAULT_LOG_FILTERS);
+
+    if let Err(err) = env_result {
+        error!("Failed to load .env file: {}", err);
+    }
+
+    let command = Command::from_args();
+
+    let config = config_with_prefix("DOMFI_ADMIN", matches!(command, Command::Retention(_)))?;
+    let db_pool = PgPool::connect(&config.postgres_url).await?;
+
+    match command {
+        Command::Evidence(args) => evidence::run(args, &db_pool).await,
+        Command::Merkle(args) => merkle::run(args, &db_pool).await,
+        Command::Reconcile(args) => reconcile::run(args, &db_pool).await,
+        Command::Replay(args) => replay::run(args, &db_pool).await,
+        Command::Retention(args) => retention::run(args, &db_pool).await,
+        Command::Verify => verify::run(&db_pool).await,
+    }
+}
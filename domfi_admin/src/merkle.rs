@@ -0,0 +1,40 @@
+use std::error::Error;
+
+use chrono::{NaiveDate, Utc};
+use log::{info, warn};
+use sqlx::PgPool;
+use structopt::StructOpt;
+
+use domfi_data::pg::ops::merkle_commitment;
+
+#[derive(StructOpt, Debug)]
+pub struct MerkleArgs {
+    /// UTC day to commit, e.g. `2021-01-05`. Defaults to every completed day without a commitment.
+    #[structopt(long)]
+    day: Option<NaiveDate>,
+}
+
+pub async fn run(args: MerkleArgs, pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    let today = Utc::today().naive_utc();
+
+    let days = match args.day {
+        Some(day) if day >= today => {
+            return Err(format!("Day {} is not over yet and cannot be committed", day).into());
+        },
+        Some(day) => vec![day],
+        None => merkle_commitment::uncommitted_days(today, pool).await?,
+    };
+
+    if days.is_empty() {
+        info!("No uncommitted days before {}", today);
+    }
+
+    for day in days {
+        match merkle_commitment::commit_day(day, pool).await? {
+            None => warn!("No provenances found for {}", day),
+            Some(x) => println!("{}\t{}\t{}", x.day, x.leaf_count, hex::encode(x.root)),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use bigdecimal::BigDecimal;
+use chrono::{DateTime, Duration, Utc};
+use log::info;
+use sqlx::PgPool;
+use structopt::StructOpt;
+
+use domfi_data::reconcile::{self, ReconcileFilter, CSV_HEADER};
+use crate::args::parse_timestamp;
+
+#[derive(StructOpt, Debug)]
+pub struct ReconcileArgs {
+    /// Agent that loaded the historical chart
+    #[structopt(long, default_value = "loader_historical")]
+    historical_agent: String,
+
+    /// Agent that loaded the live snapshots
+    #[structopt(long, default_value = "loader_rust")]
+    live_agent: String,
+
+    /// Only compare chart points at or after this unix timestamp in seconds
+    #[structopt(long, parse(try_from_str = parse_timestamp))]
+    from: Option<DateTime<Utc>>,
+
+    /// Only compare chart points before this unix timestamp in seconds
+    #[structopt(long, parse(try_from_str = parse_timestamp))]
+    to: Option<DateTime<Utc>>,
+
+    /// Maximum seconds between a chart point and the live snapshot it is compared with, at
+    /// most a day
+    #[structopt(long, default_value = "300", parse(try_from_str = parse_tolerance))]
+    tolerance: Duration,
+
+    /// Absolute deviation in percentage points above which a pair is reported as an outlier
+    #[structopt(long, default_value = "0.5")]
+    outlier_threshold: BigDecimal,
+
+    /// Writes every compared pair to this CSV file
+    #[structopt(long)]
+    csv: Option<PathBuf>,
+}
+
+/// Upper bound of the tolerance, beyond which pairs are no longer close in time
+const MAX_TOLERANCE_SECONDS: i64 = 24 * 60 * 60;
+
+fn parse_tolerance(s: &str) -> Result<Duration, String> {
+    let seconds = s.parse::<i64>().map_err(|e| e.to_string())?;
+    if !(0..=MAX_TOLERANCE_SECONDS).contains(&seconds) {
+        return Err(format!("expected between 0 and {} seconds but got {}", MAX_TOLERANCE_SECONDS, seconds));
+    }
+    Ok(Duration::seconds(seconds))
+}
+
+pub async fn run(args: ReconcileArgs, pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    let filter = ReconcileFilter {
+        historical_agent: args.historical_agent,
+        live_agent: args.live_agent,
+        from: args.from,
+        to: args.to,
+        tolerance: args.tolerance,
+    };
+
+    let mut csv = match &args.csv {
+        None => None,
+        Some(path) => {
+            let mut w = BufWriter::new(File::create(path)?);
+            writeln!(w, "{}", CSV_HEADER)?;
+            Some(w)
+        },
+    };
+
+    let stats = reconcile::reconcile(&filter, &args.outlier_threshold, pool, |pair, deviation, outlier| {
+        if outlier {
+            println!("{} @ {}: historical {} live {} at {} deviates by {}",
+                pair.coin_id,
+                pair.historical_timestamp.timestamp(),
+                pair.historical,
+                pair.live,
+                pair.live_timestamp.timestamp(),
+                deviation);
+        }
+        match csv.as_mut() {
+            None => Ok(()),
+            Some(w) => writeln!(w, "{}", reconcile::csv_row(pair, deviation, outlier)),
+        }
+    }).await?;
+
+    if let Some(mut w) = csv {
+        w.flush()?;
+    }
+
+    println!("coin_id\tpairs\tmean\tmean_abs\tmax_abs\toutliers");
+    for (coin_id, x) in &stats {
+        println!("{}\t{}\t{}\t{}\t{}\t{}", coin_id, x.pairs, x.mean(), x.mean_abs(), x.max_abs, x.outliers);
+    }
+
+    let pairs: usize = stats.values().map(|x| x.pairs).sum();
+    let outliers: usize = stats.values().map(|x| x.outliers).sum();
+    info!("Compared {} pairs of {} coins between '{}' and '{}', {} outliers",
+        pairs, stats.len(), filter.historical_agent, filter.live_agent, outliers);
+    if let Some(path) = &args.csv {
+        info!("Wrote comparison to '{}'", path.display());
+    }
+
+    Ok(())
+}
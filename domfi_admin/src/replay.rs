@@ -0,0 +1,52 @@
+use std::error::Error;
+
+use chrono::{DateTime, Utc};
+use log::info;
+use sqlx::PgPool;
+use structopt::StructOpt;
+use uuid::Uuid;
+
+use domfi_data::replay::{self, ReplayFilter};
+use crate::args::parse_timestamp;
+
+#[derive(StructOpt, Debug)]
+pub struct ReplayArgs {
+    /// Only print the changes instead of writing them
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// Only replay this provenance
+    #[structopt(long)]
+    provenance: Option<Uuid>,
+
+    /// Only replay provenances at or after this unix timestamp in seconds
+    #[structopt(long, parse(try_from_str = parse_timestamp))]
+    from: Option<DateTime<Utc>>,
+
+    /// Only replay provenances before this unix timestamp in seconds
+    #[structopt(long, parse(try_from_str = parse_timestamp))]
+    to: Option<DateTime<Utc>>,
+}
+
+pub async fn run(args: ReplayArgs, pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    let filter = ReplayFilter {
+        provenance_uuid: args.provenance,
+        from: args.from,
+        to: args.to,
+    };
+
+    let summary = replay::replay(&filter, args.dry_run, pool, |uuid, change| {
+        println!("[{}] {}", uuid, change);
+    }).await?;
+
+    info!("{} {} changes in {} of {} provenances ({} unparsable, {} points deleted by retention, {} chart points not loaded by the backfill)",
+        if args.dry_run { "Found" } else { "Applied" },
+        summary.changes,
+        summary.changed,
+        summary.provenances,
+        summary.unparsable,
+        summary.downsampled,
+        summary.unloaded);
+
+    Ok(())
+}
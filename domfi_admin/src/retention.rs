@@ -0,0 +1,48 @@
+use std::error::Error;
+use std::time::Duration;
+
+use chrono::Utc;
+use log::info;
+use sqlx::PgPool;
+use structopt::StructOpt;
+
+use domfi_data::retention::{self, Resolution, RetentionPolicy};
+
+#[derive(StructOpt, Debug)]
+pub struct RetentionArgs {
+    /// Only print what would be deleted instead of deleting it
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// Age beyond which rows are downsampled, e.g. `30 days`
+    #[structopt(long, parse(try_from_str = parse_duration::parse))]
+    older_than: Duration,
+
+    /// Keep one snapshot per agent per `minute` or `hour`
+    #[structopt(long, default_value = "minute")]
+    keep_per: Resolution,
+
+    /// Also prune provenances of days not yet committed with `domfi_admin merkle`
+    #[structopt(long)]
+    allow_uncommitted: bool,
+}
+
+pub async fn run(args: RetentionArgs, pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    let policy = RetentionPolicy {
+        cutoff: Utc::now() - chrono::Duration::from_std(args.older_than)?,
+        resolution: args.keep_per,
+        allow_uncommitted: args.allow_uncommitted,
+    };
+
+    let summary = retention::apply(&policy, args.dry_run, pool).await?;
+
+    info!("{} {} rows, {} provenances, {} observations and {} blobs ({} bytes)",
+        if args.dry_run { "Would delete" } else { "Deleted" },
+        summary.rows,
+        summary.provenances,
+        summary.observations,
+        summary.blobs,
+        summary.blob_bytes);
+
+    Ok(())
+}
@@ -0,0 +1,18 @@
+use std::error::Error;
+
+use log::{info, warn};
+use sqlx::PgPool;
+
+use domfi_data::verify;
+
+pub async fn run(pool: &PgPool) -> Result<(), Box<dyn Error>> {
+    let summary = verify::verify_all(pool, |finding| println!("{}", finding)).await?;
+
+    info!("Checked {} blobs and {} coin dominance rows", summary.blobs, summary.rows);
+    if summary.findings > 0 {
+        warn!("Found {} integrity issues", summary.findings);
+        return Err(format!("Verification failed with {} issues", summary.findings).into());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::str::FromStr;
+use std::time::Duration;
+
+use bigdecimal::BigDecimal;
+use log::{info, warn};
+use reqwest::Url;
+
+use domfi_domain::models::financial_assets::get_canonical_default_asset;
+use domfi_util::ConfigContext;
+use crate::rules::{Condition, Rule};
+
+pub const DEFAULT_THRESHOLD_PERCENT: &str = "2";
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(60);
+pub const DEFAULT_COOLDOWN: Duration = Duration::from_secs(15 * 60);
+
+pub struct Config {
+    pub database_url: String,
+    pub rules: Vec<Rule>,
+    /// Webhooks to post the alerts of each rule to, by rule name
+    pub webhooks: HashMap<String, Vec<Url>>,
+}
+
+/// Parses a duration such as `90 s`, warning and falling back to the default if it is invalid
+fn duration_or(config: &ConfigContext, key: &str, default: Duration) -> Duration {
+    match config.var(key) {
+        Err(_) => default,
+        Ok(s) => match parse_duration::parse(&s) {
+            Ok(x) => x,
+            Err(err) => {
+                warn!("Failed to parse '{}'. Using default value instead of '{:#?}'. Cause: {}",
+                      config.name_of(key), default, err);
+                default
+            }
+        }
+    }
+}
+
+fn webhooks(config: &ConfigContext) -> Result<Option<Vec<Url>>, Box<dyn Error>> {
+    let raw = match config.var("WEBHOOK_URLS") {
+        Err(_) => return Ok(None),
+        Ok(x) => x,
+    };
+
+    let mut urls = Vec::new();
+    for x in raw.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        let url = Url::parse(x)
+            .map_err(|e| format!("{} has invalid URL format: {}", config.name_of("WEBHOOK_URLS"), e))?;
+        urls.push(url);
+    }
+    Ok(Some(urls))
+}
+
+/// Reads `DOMFI_ALERTS_DATABASE_URL`, the comma separated `DOMFI_ALERTS_WEBHOOK_URLS` and
+/// `DOMFI_ALERTS_RULES`, a comma separated list of rule names. Each rule is configured with
+/// `DOMFI_ALERTS_<NAME>_KIND`, either `price_move` or `stale`, and optionally `_AGENT`,
+/// `_COOLDOWN` and `_WEBHOOK_URLS`, which replace the default webhooks for the rule.
+/// `price_move` rules read `_ASSET`, `_THRESHOLD_PERCENT` and `_WINDOW`, `stale` rules `_MAX_AGE`.
+pub fn config_from_env() -> Result<Config, Box<dyn Error>> {
+    let config = ConfigContext::new("DOMFI_ALERTS");
+    let database_url = config.var("DATABASE_URL")?;
+    let default_webhooks = webhooks(&config)?.unwrap_or_default();
+
+    let names = config.var("RULES")
+        .map_err(|_| format!("{} does not list any rule", config.name_of("RULES")))?;
+
+    let mut rules = Vec::new();
+    let mut webhooks_by_rule = HashMap::new();
+    for name in names.split(',').map(|x| x.trim().to_lowercase()).filter(|x| !x.is_empty()) {
+        if webhooks_by_rule.contains_key(&name) {
+            return Err(format!("Rule '{}' is listed more than once in {}", name, config.name_of("RULES")).into());
+        }
+
+        let rule_config = ConfigContext::new(format!("DOMFI_ALERTS_{}", name.to_uppercase()));
+        let kind = rule_config.var("KIND")
+            .map_err(|_| format!("{} is not set", rule_config.name_of("KIND")))?;
+
+        let condition = match kind.trim() {
+            "price_move" => {
+                let asset_id = rule_config.var("ASSET")
+                    .map_err(|_| format!("{} is not set", rule_config.name_of("ASSET")))?
+                    .trim()
+                    .to_ascii_lowercase();
+                let asset = get_canonical_default_asset(&asset_id)
+                    .ok_or_else(|| format!("{} is not a known asset: '{}'", rule_config.name_of("ASSET"), asset_id))?;
+
+                let threshold_percent = match rule_config.var("THRESHOLD_PERCENT") {
+                    Err(_) => BigDecimal::from_str(DEFAULT_THRESHOLD_PERCENT).unwrap(),
+                    Ok(x) => BigDecimal::from_str(x.trim()).ok()
+                        .filter(|x| *x > BigDecimal::from(0))
+                        .ok_or_else(|| format!("{} is not a positive percentage", rule_config.name_of("THRESHOLD_PERCENT")))?,
+                };
+
+                let window = duration_or(&rule_config, "WINDOW", DEFAULT_WINDOW);
+                Condition::PriceMove {
+                    asset_id,
+                    asset,
+                    threshold_percent,
+                    window: chrono::Duration::from_std(window)?,
+                }
+            },
+            "stale" => {
+                let max_age = duration_or(&rule_config, "MAX_AGE", DEFAULT_MAX_AGE);
+                Condition::Stale {
+                    max_age: chrono::Duration::from_std(max_age)?,
+                }
+            },
+            x => return Err(format!("{} has unknown kind '{}'", rule_config.name_of("KIND"), x).into()),
+        };
+
+        let agent = rule_config.var("AGENT").ok()
+            .map(|x| x.trim().to_owned())
+            .filter(|x| !x.is_empty());
+        let cooldown = duration_or(&rule_config, "COOLDOWN", DEFAULT_COOLDOWN);
+
+        let webhooks = webhooks(&rule_config)?.unwrap_or_else(|| default_webhooks.clone());
+        if webhooks.is_empty() {
+            return Err(format!("Rule '{}' has no webhook, set {} or {}",
+                name, config.name_of("WEBHOOK_URLS"), rule_config.name_of("WEBHOOK_URLS")).into());
+        }
+
+        info!("Rule '{}': {} for agent {:?}, cooldown {:#?}, {} webhook(s)",
+            name, kind.trim(), agent, cooldown, webhooks.len());
+
+        webhooks_by_rule.insert(name.clone(), webhooks);
+        rules.push(Rule {
+            name,
+            condition,
+            agent,
+            cooldown: chrono::Duration::from_std(cooldown)?,
+        });
+    }
+
+    if rules.is_empty() {
+        return Err(format!("{} does not list any rule", config.name_of("RULES")).into());
+    }
+
+    Ok(Config {
+        database_url,
+        rules,
+        webhooks: webhooks_by_rule,
+    })
+}
@@ -0,0 +1,127 @@
+mod config;
+mod rules;
+
+use std::error::Error;
+
+use log::{info, warn, error};
+use reqwest::Url;
+use sqlx::PgPool;
+use tokio::sync::mpsc;
+use tokio::time::Duration;
+use chrono::Utc;
+
+use domfi_util::init_logging;
+use domfi_data::pg::notify::{listen_snapshots, listen_observations, CoinDominanceSnapshot, SnapshotObservationNotifyRow};
+use crate::config::config_from_env;
+use crate::rules::{Alert, AlertEngine};
+
+const DEFAULT_LOG_FILTERS: &str = "info,domfi_alerts=debug,sqlx=warn";
+
+/// How often staleness rules are evaluated
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let env_result = dotenv::dotenv();
+    init_logging(DEFAULT_LOG_FILTERS);
+
+    if let Err(err) = env_result {
+        error!("Failed to load .env file: {}", err);
+    }
+
+    let config = config_from_env()?;
+    let db_pool = PgPool::connect(&config.database_url).await?;
+
+    let http = reqwest::ClientBuilder::new()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()
+        .expect("Failed to build HTTP client");
+
+    let (snapshots_tx, mut snapshots) = mpsc::channel(64);
+    let (observations_tx, mut observations) = mpsc::channel(64);
+    tokio::spawn(forward_snapshots(db_pool.clone(), snapshots_tx));
+    tokio::spawn(forward_observations(db_pool, observations_tx));
+
+    let mut engine = AlertEngine::new(config.rules, Utc::now());
+    info!("Evaluating {} rule(s)", engine.rules().count());
+
+    let mut ticks = tokio::time::interval(CHECK_INTERVAL);
+    let mut shutdown = Box::pin(tokio::signal::ctrl_c());
+    loop {
+        let alerts = tokio::select! {
+            Some(snapshot) = snapshots.recv() => engine.on_snapshot(&snapshot, Utc::now()),
+            Some(observation) = observations.recv() => engine.on_observation(&observation.agent, Utc::now()),
+            _ = ticks.tick() => engine.on_tick(Utc::now()),
+            _ = &mut shutdown => break,
+        };
+
+        for alert in alerts {
+            warn!("[{}] {}", alert.rule, alert.text);
+            for url in config.webhooks.get(&alert.rule).into_iter().flatten() {
+                tokio::spawn(post_alert(http.clone(), url.clone(), alert.clone()));
+            }
+        }
+    }
+
+    info!("Quitting");
+    Ok(())
+}
+
+/// Relays snapshots from the `db_notify` channel, reconnecting whenever the listener stops
+async fn forward_snapshots(pool: PgPool, mut tx: mpsc::Sender<CoinDominanceSnapshot>) {
+    loop {
+        let mut rx = match listen_snapshots(&pool, None, Duration::from_millis(250), 64).await {
+            Err(e) => {
+                error!("Failed to subscribe to snapshots. Retrying in 5s. Cause: {}", e);
+                tokio::time::delay_for(Duration::from_secs(5)).await;
+                continue;
+            },
+            Ok(x) => x,
+        };
+
+        while let Some(snapshot) = rx.recv().await {
+            if tx.send(snapshot).await.is_err() {
+                return;
+            }
+        }
+
+        warn!("Snapshot listener stopped. Reconnecting...");
+    }
+}
+
+/// Relays unchanged snapshots observed by the loaders, reconnecting whenever the listener stops
+async fn forward_observations(pool: PgPool, mut tx: mpsc::Sender<SnapshotObservationNotifyRow>) {
+    loop {
+        let mut rx = match listen_observations(&pool, 64).await {
+            Err(e) => {
+                error!("Failed to subscribe to snapshot observations. Retrying in 5s. Cause: {}", e);
+                tokio::time::delay_for(Duration::from_secs(5)).await;
+                continue;
+            },
+            Ok(x) => x,
+        };
+
+        while let Some(observation) = rx.recv().await {
+            if tx.send(observation).await.is_err() {
+                return;
+            }
+        }
+
+        warn!("Snapshot observation listener stopped. Reconnecting...");
+    }
+}
+
+async fn post_alert(http: reqwest::Client, url: Url, alert: Alert) {
+    let result = http.post(url.clone())
+        .json(&alert)
+        .send()
+        .await
+        .and_then(|x| x.error_for_status());
+
+    if let Err(e) = result {
+        // Webhook URLs often embed a secret token, so only the host is logged
+        error!("[{}] Failed to post alert to webhook on '{}': {}", alert.rule, url.host_str().unwrap_or_default(), e);
+    }
+}
@@ -0,0 +1,432 @@
+//! Alert rules and the state deciding when a triggered rule is notified
+
+use std::collections::{HashMap, VecDeque};
+
+use bigdecimal::BigDecimal;
+use chrono::{DateTime, Duration, Utc};
+use chrono::serde::{ts_seconds, ts_seconds_option};
+use serde::Serialize;
+use uuid::Uuid;
+
+use domfi_data::pg::notify::CoinDominanceSnapshot;
+use domfi_domain::aggregate::AVERAGE_SCALE;
+use domfi_domain::models::{FinancialAssetWithMetadata, FinancialAssetRawValueOf, FinancialDominanceAsset};
+
+pub enum Condition {
+    /// The unrounded value of the asset moved by at least `threshold_percent` percent
+    /// of the earlier value within `window`
+    PriceMove {
+        asset_id: String,
+        asset: &'static FinancialAssetWithMetadata<FinancialDominanceAsset>,
+        threshold_percent: BigDecimal,
+        window: Duration,
+    },
+
+    /// Neither a snapshot nor an observation of an unchanged one arrived for `max_age`
+    Stale {
+        max_age: Duration,
+    },
+}
+
+pub struct Rule {
+    pub name: String,
+    pub condition: Condition,
+    /// Only snapshots of this agent are considered, those of all agents if `None`
+    pub agent: Option<String>,
+    /// Least time between two notifications of the rule
+    pub cooldown: Duration,
+}
+
+#[derive(Serialize, Clone, Eq, PartialEq, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AlertDetails {
+    PriceMove {
+        asset: String,
+        agent: String,
+        provenance_uuid: Uuid,
+        #[serde(with = "ts_seconds")]
+        from_timestamp: DateTime<Utc>,
+        from: BigDecimal,
+        #[serde(with = "ts_seconds")]
+        to_timestamp: DateTime<Utc>,
+        to: BigDecimal,
+        change_percent: BigDecimal,
+        window_seconds: i64,
+    },
+
+    Stale {
+        agent: Option<String>,
+        #[serde(with = "ts_seconds_option")]
+        last_snapshot: Option<DateTime<Utc>>,
+        max_age_seconds: i64,
+    },
+}
+
+/// Payload posted to the webhooks
+#[derive(Serialize, Clone, Eq, PartialEq, Debug)]
+pub struct Alert {
+    pub rule: String,
+    /// Human readable summary, named `text` so chat webhooks (e.g. Slack) display it as is
+    pub text: String,
+    #[serde(with = "ts_seconds")]
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub details: AlertDetails,
+}
+
+struct PricePoint {
+    timestamp: DateTime<Utc>,
+    value: BigDecimal,
+    provenance_uuid: Uuid,
+}
+
+struct RuleState {
+    rule: Rule,
+    /// Whether the rule has been notified since it last triggered. A rule is notified once
+    /// per episode; it needs to stop triggering before it is notified again.
+    firing: bool,
+    last_notified: Option<DateTime<Utc>>,
+    /// Recent values of the asset per agent, oldest first
+    history: HashMap<String, VecDeque<PricePoint>>,
+    /// When the last matching snapshot or observation was received, or when evaluation started
+    last_seen: DateTime<Utc>,
+    last_snapshot: Option<DateTime<Utc>>,
+}
+
+impl RuleState {
+    fn matches(&self, agent: &str) -> bool {
+        self.rule.agent.as_ref().is_none_or(|x| x == agent)
+    }
+
+    /// Returns the alert if it is due, i.e. the rule was not already notified for the
+    /// current episode and its cooldown has passed
+    fn update(&mut self, alert: Option<Alert>, now: DateTime<Utc>) -> Option<Alert> {
+        let alert = match alert {
+            None => {
+                self.firing = false;
+                return None;
+            },
+            Some(x) => x,
+        };
+
+        if self.firing {
+            return None;
+        }
+
+        if let Some(last) = self.last_notified {
+            if now - last < self.rule.cooldown {
+                return None;
+            }
+        }
+
+        self.firing = true;
+        self.last_notified = Some(now);
+        Some(alert)
+    }
+
+    fn evaluate_price_move(&mut self, snapshot: &CoinDominanceSnapshot) -> Option<Option<Alert>> {
+        let (asset_id, asset, threshold_percent, window) = match &self.rule.condition {
+            Condition::PriceMove { asset_id, asset, threshold_percent, window } =>
+                (asset_id, asset, threshold_percent, *window),
+            Condition::Stale { .. } => return None,
+        };
+
+        let row = snapshot.find_by_coin_id(asset.asset().underlying().symbol().id())?;
+        let value = asset.raw_value_of(&row.market_dominance_percentage).into_owned();
+
+        let history = self.history.entry(snapshot.agent.clone()).or_default();
+
+        // The same snapshot may be delivered again after the listener reconnects
+        if history.iter().any(|x| x.provenance_uuid == snapshot.provenance_uuid) {
+            return None;
+        }
+
+        while history.front().iter().any(|x| snapshot.timestamp - x.timestamp > window) {
+            history.pop_front();
+        }
+
+        let zero = BigDecimal::from(0);
+        let largest = history.iter()
+            .filter(|x| x.value != zero && x.timestamp <= snapshot.timestamp)
+            .map(|x| {
+                let change = ((&value - &x.value).abs() * BigDecimal::from(100) / x.value.abs())
+                    .with_scale(AVERAGE_SCALE);
+                (x, change)
+            })
+            .max_by(|(_, a), (_, b)| a.cmp(b));
+
+        let alert = match largest {
+            Some((from, change_percent)) if change_percent >= *threshold_percent => Some(Alert {
+                rule: self.rule.name.clone(),
+                text: format!("{} moved {}% from {} to {} within {}s (agent '{}')",
+                    asset_id.to_uppercase(),
+                    change_percent.round(2),
+                    from.value.round(4),
+                    value.round(4),
+                    (snapshot.timestamp - from.timestamp).num_seconds(),
+                    snapshot.agent),
+                timestamp: snapshot.timestamp,
+                details: AlertDetails::PriceMove {
+                    asset: asset_id.clone(),
+                    agent: snapshot.agent.clone(),
+                    provenance_uuid: snapshot.provenance_uuid,
+                    from_timestamp: from.timestamp,
+                    from: from.value.clone(),
+                    to_timestamp: snapshot.timestamp,
+                    to: value.clone(),
+                    change_percent,
+                    window_seconds: window.num_seconds(),
+                },
+            }),
+            _ => None,
+        };
+
+        history.push_back(PricePoint {
+            timestamp: snapshot.timestamp,
+            value,
+            provenance_uuid: snapshot.provenance_uuid,
+        });
+
+        Some(alert)
+    }
+
+    fn evaluate_stale(&self, now: DateTime<Utc>) -> Option<Option<Alert>> {
+        let max_age = match &self.rule.condition {
+            Condition::Stale { max_age } => *max_age,
+            Condition::PriceMove { .. } => return None,
+        };
+
+        if now - self.last_seen < max_age {
+            return Some(None);
+        }
+
+        let source = match &self.rule.agent {
+            Some(agent) => format!("agent '{}'", agent),
+            None => "any agent".to_owned(),
+        };
+
+        Some(Some(Alert {
+            rule: self.rule.name.clone(),
+            text: format!("No snapshot from {} for {}s", source, (now - self.last_seen).num_seconds()),
+            timestamp: now,
+            details: AlertDetails::Stale {
+                agent: self.rule.agent.clone(),
+                last_snapshot: self.last_snapshot,
+                max_age_seconds: max_age.num_seconds(),
+            },
+        }))
+    }
+}
+
+/// Evaluates the rules against every snapshot and periodically for staleness
+pub struct AlertEngine {
+    rules: Vec<RuleState>,
+}
+
+impl AlertEngine {
+    /// Staleness is measured from `now` until the first snapshot arrives
+    pub fn new(rules: Vec<Rule>, now: DateTime<Utc>) -> AlertEngine {
+        AlertEngine {
+            rules: rules.into_iter()
+                .map(|rule| RuleState {
+                    rule,
+                    firing: false,
+                    last_notified: None,
+                    history: HashMap::new(),
+                    last_seen: now,
+                    last_snapshot: None,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn rules(&self) -> impl Iterator<Item = &Rule> {
+        self.rules.iter().map(|x| &x.rule)
+    }
+
+    /// Evaluates a snapshot received at `now`, returning the alerts that are due
+    pub fn on_snapshot(&mut self, snapshot: &CoinDominanceSnapshot, now: DateTime<Utc>) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        for state in self.rules.iter_mut().filter(|x| x.matches(&snapshot.agent)) {
+            state.last_seen = now;
+            state.last_snapshot = Some(snapshot.timestamp);
+
+            let evaluated = state.evaluate_price_move(snapshot)
+                .or_else(|| state.evaluate_stale(now));
+
+            if let Some(alert) = evaluated {
+                alerts.extend(state.update(alert, now));
+            }
+        }
+        alerts
+    }
+
+    /// Records that `agent` polled an unchanged snapshot at `now`, which keeps its staleness
+    /// rules from triggering while the source serves the same data. Returns the alerts that are due.
+    pub fn on_observation(&mut self, agent: &str, now: DateTime<Utc>) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        for state in self.rules.iter_mut().filter(|x| x.matches(agent)) {
+            state.last_seen = now;
+            if let Some(alert) = state.evaluate_stale(now) {
+                alerts.extend(state.update(alert, now));
+            }
+        }
+        alerts
+    }
+
+    /// Evaluates the staleness rules at `now`, returning the alerts that are due
+    pub fn on_tick(&mut self, now: DateTime<Utc>) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        for state in &mut self.rules {
+            if let Some(alert) = state.evaluate_stale(now) {
+                alerts.extend(state.update(alert, now));
+            }
+        }
+        alerts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use bigdecimal::BigDecimal;
+    use chrono::{Duration, NaiveDateTime, Utc, TimeZone};
+    use uuid::Uuid;
+    use domfi_data::pg::notify::{CoinDominanceNotifyRow, CoinDominanceSnapshot};
+    use domfi_domain::models::financial_assets::get_canonical_default_asset;
+    use crate::rules::{AlertDetails, AlertEngine, Condition, Rule};
+
+    fn decimal(x: &str) -> BigDecimal {
+        BigDecimal::from_str(x).unwrap()
+    }
+
+    fn snapshot(agent: &str, ts: i64, bitcoin: &str) -> CoinDominanceSnapshot {
+        let provenance_uuid = Uuid::new_v4();
+        let timestamp = NaiveDateTime::from_timestamp(ts, 0);
+        CoinDominanceSnapshot {
+            provenance_uuid,
+            object_id: 1,
+            agent: agent.to_owned(),
+            timestamp: Utc.timestamp(ts, 0),
+            rows: vec![CoinDominanceNotifyRow {
+                id: 1,
+                provenance_uuid,
+                object_id: 1,
+                timestamp_utc: timestamp,
+                imported_at_utc: timestamp,
+                agent: agent.to_owned(),
+                coin_id: "bitcoin".to_owned(),
+                coin_name: "BTC".to_owned(),
+                market_cap_usd: decimal("600"),
+                market_dominance_percentage: decimal(bitcoin),
+            }],
+        }
+    }
+
+    fn price_move(cooldown: i64) -> Rule {
+        Rule {
+            name: "btcdom_move".to_owned(),
+            condition: Condition::PriceMove {
+                asset_id: "btcdom".to_owned(),
+                asset: get_canonical_default_asset("btcdom").unwrap(),
+                threshold_percent: decimal("2"),
+                window: Duration::seconds(60),
+            },
+            agent: None,
+            cooldown: Duration::seconds(cooldown),
+        }
+    }
+
+    #[test]
+    fn price_move_should_compare_against_every_value_in_window() {
+        let mut engine = AlertEngine::new(vec![price_move(0)], Utc.timestamp(0, 0));
+
+        assert!(engine.on_snapshot(&snapshot("a", 0, "50"), Utc.timestamp(0, 0)).is_empty());
+        assert!(engine.on_snapshot(&snapshot("a", 30, "50.5"), Utc.timestamp(30, 0)).is_empty());
+
+        let alerts = engine.on_snapshot(&snapshot("a", 60, "51"), Utc.timestamp(60, 0));
+        assert_eq!(alerts.len(), 1);
+        match &alerts[0].details {
+            AlertDetails::PriceMove { from, to, change_percent, from_timestamp, .. } => {
+                assert_eq!(from, &decimal("50"));
+                assert_eq!(to, &decimal("51"));
+                assert_eq!(change_percent, &decimal("2"));
+                assert_eq!(from_timestamp, &Utc.timestamp(0, 0));
+            },
+            x => panic!("Unexpected alert {:?}", x),
+        }
+
+        // 50 fell out of the window, 50.5 -> 51 is below the threshold
+        assert!(engine.on_snapshot(&snapshot("b", 90, "51"), Utc.timestamp(90, 0)).is_empty());
+        assert!(engine.on_snapshot(&snapshot("a", 61, "51"), Utc.timestamp(91, 0)).is_empty());
+    }
+
+    #[test]
+    fn rule_should_notify_once_per_episode_and_respect_cooldown() {
+        let mut engine = AlertEngine::new(vec![price_move(240)], Utc.timestamp(0, 0));
+        let mut count = |ts, value: &str| engine.on_snapshot(&snapshot("a", ts, value), Utc.timestamp(ts, 0)).len();
+
+        assert_eq!(count(0, "50"), 0);
+        assert_eq!(count(10, "52"), 1);
+        // Still moved, but already notified
+        assert_eq!(count(20, "52"), 0);
+        // Stopped triggering, then triggers again within the cooldown
+        assert_eq!(count(200, "52"), 0);
+        assert_eq!(count(210, "54"), 0);
+        // Cooldown passed while the episode is still going on
+        assert_eq!(count(255, "56"), 1);
+    }
+
+    #[test]
+    fn stale_rule_should_fire_until_snapshot_of_agent_arrives() {
+        let rule = Rule {
+            name: "loader_stale".to_owned(),
+            condition: Condition::Stale { max_age: Duration::seconds(60) },
+            agent: Some("loader_rust".to_owned()),
+            cooldown: Duration::seconds(0),
+        };
+        let mut engine = AlertEngine::new(vec![rule], Utc.timestamp(0, 0));
+
+        assert!(engine.on_tick(Utc.timestamp(59, 0)).is_empty());
+        assert_eq!(engine.on_tick(Utc.timestamp(60, 0)).len(), 1);
+        assert!(engine.on_tick(Utc.timestamp(61, 0)).is_empty());
+
+        // Snapshots of other agents do not count
+        assert!(engine.on_snapshot(&snapshot("other", 62, "50"), Utc.timestamp(62, 0)).is_empty());
+        assert!(engine.on_tick(Utc.timestamp(63, 0)).is_empty());
+
+        assert!(engine.on_snapshot(&snapshot("loader_rust", 70, "50"), Utc.timestamp(70, 0)).is_empty());
+        assert!(engine.on_tick(Utc.timestamp(129, 0)).is_empty());
+
+        let alerts = engine.on_tick(Utc.timestamp(130, 0));
+        assert_eq!(alerts[0].details, AlertDetails::Stale {
+            agent: Some("loader_rust".to_owned()),
+            last_snapshot: Some(Utc.timestamp(70, 0)),
+            max_age_seconds: 60,
+        });
+    }
+
+    #[test]
+    fn stale_rule_should_not_fire_while_unchanged_snapshots_are_observed() {
+        let rule = Rule {
+            name: "loader_stale".to_owned(),
+            condition: Condition::Stale { max_age: Duration::seconds(60) },
+            agent: Some("loader_rust".to_owned()),
+            cooldown: Duration::seconds(0),
+        };
+        let mut engine = AlertEngine::new(vec![rule], Utc.timestamp(0, 0));
+
+        assert!(engine.on_snapshot(&snapshot("loader_rust", 10, "50"), Utc.timestamp(10, 0)).is_empty());
+        assert!(engine.on_observation("loader_rust", Utc.timestamp(50, 0)).is_empty());
+        assert!(engine.on_observation("other", Utc.timestamp(100, 0)).is_empty());
+        assert!(engine.on_tick(Utc.timestamp(109, 0)).is_empty());
+
+        // The last snapshot is still the one that was archived
+        let alerts = engine.on_tick(Utc.timestamp(110, 0));
+        assert_eq!(alerts[0].details, AlertDetails::Stale {
+            agent: Some("loader_rust".to_owned()),
+            last_snapshot: Some(Utc.timestamp(10, 0)),
+            max_age_seconds: 60,
+        });
+    }
+}
@@ -2,10 +2,16 @@ use actix_web::{Responder, HttpResponse};
 use actix_web::body::Body;
 use log::{error};
 use crate::repo::RepositoryError;
-use crate::api::models::ErrorResponse;
+use crate::api::models::{ErrorResponse, ConsensusErrorResponse, ResponseStatus};
 use crate::historical;
 use crate::historical::ClientFindByIdHistoryError;
 use crate::api::routes::QueryFlagError;
+use crate::settlement::ClientSettlementError;
+use crate::twap::ClientTwapError;
+use crate::consensus::ClientConsensusError;
+use domfi_domain::aggregate::ConsensusError;
+use domfi_data::evidence::EvidenceError;
+use domfi_data::pg::ops::merkle_commitment::MerkleCommitmentError;
 
 pub trait ToResponse {
     type Output : Responder;
@@ -43,10 +49,118 @@ impl ToResponse for ClientFindByIdHistoryError {
             ClientFindByIdHistoryError::FailedToLocateService => {
                 HttpResponse::InternalServerError().json(ErrorResponse::new(reason))
             }
+            ClientFindByIdHistoryError::InvalidRange
+            | ClientFindByIdHistoryError::InvalidTimestamp
+            | ClientFindByIdHistoryError::TooManyRows { .. } => {
+                HttpResponse::BadRequest().json(ErrorResponse::new(reason))
+            }
+        }
+    }
+}
+
+impl ToResponse for ClientSettlementError {
+    type Output = HttpResponse<Body>;
+    fn to_response(&self) -> Self::Output {
+        let reason = format!("{}", self);
+        match self {
+            ClientSettlementError::CoinUnknownOrNotAllowed
+            | ClientSettlementError::InvalidTimestamp
+            | ClientSettlementError::InvalidWindow
+            | ClientSettlementError::WindowTooLarge { .. } => {
+                HttpResponse::BadRequest().json(ErrorResponse::new(reason))
+            }
+            ClientSettlementError::EmptyWindow => {
+                HttpResponse::NotFound().json(ErrorResponse::new(reason))
+            }
+            ClientSettlementError::DbError => {
+                HttpResponse::InternalServerError().json(ErrorResponse::new(reason))
+            }
+        }
+    }
+}
+
+impl ToResponse for ClientTwapError {
+    type Output = HttpResponse<Body>;
+    fn to_response(&self) -> Self::Output {
+        let reason = format!("{}", self);
+        match self {
+            ClientTwapError::CoinUnknownOrNotAllowed
+            | ClientTwapError::InvalidRange
+            | ClientTwapError::RangeTooLarge { .. }
+            | ClientTwapError::InvalidMaxGap => {
+                HttpResponse::BadRequest().json(ErrorResponse::new(reason))
+            }
+            ClientTwapError::NoData => {
+                HttpResponse::NotFound().json(ErrorResponse::new(reason))
+            }
+            ClientTwapError::DbError => {
+                HttpResponse::InternalServerError().json(ErrorResponse::new(reason))
+            }
+        }
+    }
+}
+
+impl ToResponse for ClientConsensusError {
+    type Output = HttpResponse<Body>;
+    fn to_response(&self) -> Self::Output {
+        let reason = format!("{}", self);
+        match self {
+            ClientConsensusError::CoinUnknownOrNotAllowed
+            | ClientConsensusError::InvalidTimestamp => {
+                HttpResponse::BadRequest().json(ErrorResponse::new(reason))
+            }
+            ClientConsensusError::NotEnabled => {
+                HttpResponse::NotImplemented().json(ErrorResponse::new(reason))
+            }
+            ClientConsensusError::DbError => {
+                HttpResponse::InternalServerError().json(ErrorResponse::new(reason))
+            }
+            ClientConsensusError::NoConsensus { source, dataset } => {
+                let response = ConsensusErrorResponse {
+                    status: ResponseStatus::Error,
+                    reason,
+                    data: dataset,
+                };
+                match source {
+                    ConsensusError::NotEnoughSources { .. } => HttpResponse::ServiceUnavailable().json(response),
+                    ConsensusError::Disagreement { .. } => HttpResponse::Conflict().json(response),
+                }
+            }
         }
     }
 }
 
+impl ToResponse for EvidenceError {
+    type Output = HttpResponse<Body>;
+    fn to_response(&self) -> Self::Output {
+        match self {
+            EvidenceError::SnapshotNotFound => {
+                HttpResponse::NotFound().json(ErrorResponse::new(format!("{}", self)))
+            }
+            EvidenceError::DbError { source } => {
+                error!("Database error: {}", source);
+                HttpResponse::InternalServerError().json(
+                    ErrorResponse::new("Invalid database connection error".into()))
+            }
+            EvidenceError::SerializeError { .. }
+            | EvidenceError::ArchiveError { .. } => {
+                error!("Failed to build evidence bundle: {}", self);
+                HttpResponse::InternalServerError().json(
+                    ErrorResponse::new("Failed to build evidence bundle".into()))
+            }
+        }
+    }
+}
+
+impl ToResponse for MerkleCommitmentError {
+    type Output = HttpResponse<Body>;
+    fn to_response(&self) -> Self::Output {
+        error!("Failed to build inclusion proof: {}", self);
+        HttpResponse::InternalServerError().json(
+            ErrorResponse::new("Failed to build inclusion proof".into()))
+    }
+}
+
 impl ToResponse for QueryFlagError {
     type Output = HttpResponse<Body>;
     fn to_response(&self) -> Self::Output {
@@ -1,5 +1,7 @@
 pub mod models;
 pub mod routes;
+pub mod ws;
+pub mod sse;
 mod convert;
 
 use actix_web::{web, Scope};
@@ -7,10 +9,20 @@ use actix_web::{web, Scope};
 pub fn services() -> Scope {
     web::scope("/")
         .service(routes::ping)
+        .service(routes::get_public_key)
         .service(routes::get_blob)
         .service(routes::get_coingecko_coin_dominance)
         .service(routes::get_data_origin)
+        .service(routes::get_data_origin_proof)
         .service(routes::get_prices)
+        // Must be registered before `/price/{id}` to take precedence
+        .service(routes::get_price_ws)
+        .service(routes::get_price_stream)
         .service(routes::get_price_by_id)
         .service(routes::get_price_historical_by_id)
+        .service(routes::get_price_candles_by_id)
+        .service(routes::get_price_settlement_by_id)
+        .service(routes::get_price_twap_by_id)
+        .service(routes::get_price_consensus_by_id)
+        .service(routes::get_price_evidence_by_id)
 }
@@ -1,7 +1,7 @@
 use std::fmt::{Display, Formatter};
 use std::fmt;
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Utc, NaiveDate};
 use bigdecimal::BigDecimal;
 use uuid::Uuid;
 
@@ -11,10 +11,15 @@ use serde_with::DisplayFromStr;
 use chrono::serde::{ts_milliseconds, ts_seconds};
 use domfi_ext_serde::ToStringVerbatim;
 use std::sync::Arc;
-use crate::historical::{ClientFindByIdHistoryDataset, ClientFindByIdHistoryDatasetSlim};
+use crate::historical::{ClientFindByIdHistoryDataset, ClientFindByIdHistoryDatasetSlim, ClientCandleDataset};
+use crate::settlement::ClientSettlementDataset;
+use crate::twap::ClientTwapDataset;
+use crate::consensus::ClientConsensusDataset;
+use domfi_data::merkle::Hash;
+use domfi_data::pg::ops::merkle_commitment::InclusionProof;
 
-#[serde(rename_all = "lowercase")]
 #[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ResponseStatus {
     Success,
     Error,
@@ -142,6 +147,9 @@ pub struct PricesResponse<'a> {
     pub timestamp: DateTime<Utc>,
 
     pub meta: PricesMeta,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub signatures: Vec<PriceSignature>,
 }
 
 #[serde_as]
@@ -160,6 +168,29 @@ pub struct PriceByIdResponse<'a> {
     pub timestamp: DateTime<Utc>,
 
     pub meta: PricesMeta,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<PriceSignature>,
+}
+
+/// Detached ed25519 signature over the canonical attestation of `ticker_id`, `price`,
+/// the response timestamp and the provenance UUID. The signed price is the asset's, e.g.
+/// ALTDOM, which is not among the coin prices of `/price`.
+#[derive(Serialize)]
+pub struct PriceSignature {
+    pub ticker_id: String,
+    pub price: BigDecimal,
+    pub signature: String,
+}
+
+#[serde_as]
+#[derive(Serialize)]
+pub struct PublicKeyResponse {
+    #[serde_as(as = "DisplayFromStr")]
+    pub status: ResponseStatus,
+    pub algorithm: &'static str,
+    pub format: &'static str,
+    pub public_key: String,
 }
 
 #[serde_as]
@@ -196,3 +227,83 @@ pub struct HistoryResponseSlim<'a> {
     pub status: ResponseStatus,
     pub data: ClientFindByIdHistoryDatasetSlim<'a>,
 }
+
+#[serde_as]
+#[derive(Serialize)]
+pub struct CandlesResponse {
+    #[serde_as(as = "DisplayFromStr")]
+    pub status: ResponseStatus,
+    pub data: ClientCandleDataset,
+}
+
+#[serde_as]
+#[derive(Serialize)]
+pub struct SettlementResponse {
+    #[serde_as(as = "DisplayFromStr")]
+    pub status: ResponseStatus,
+    pub data: ClientSettlementDataset,
+}
+
+#[serde_as]
+#[derive(Serialize)]
+pub struct TwapResponse {
+    #[serde_as(as = "DisplayFromStr")]
+    pub status: ResponseStatus,
+    pub data: ClientTwapDataset,
+}
+
+#[serde_as]
+#[derive(Serialize)]
+pub struct ConsensusResponse {
+    #[serde_as(as = "DisplayFromStr")]
+    pub status: ResponseStatus,
+    pub data: ClientConsensusDataset,
+}
+
+/// Error response which still carries the inputs, so clients can see which sources disagree
+#[serde_as]
+#[derive(Serialize)]
+pub struct ConsensusErrorResponse<'a> {
+    #[serde_as(as = "DisplayFromStr")]
+    pub status: ResponseStatus,
+    pub reason: String,
+    pub data: &'a ClientConsensusDataset,
+}
+
+#[serde_as]
+#[derive(Serialize)]
+pub struct ProvenanceProofResponse {
+    #[serde_as(as = "DisplayFromStr")]
+    pub status: ResponseStatus,
+
+    pub algorithm: &'static str,
+    pub day: NaiveDate,
+    pub provenance_uuid: Uuid,
+    pub leaf_index: usize,
+    pub leaf_count: usize,
+
+    #[serde_as(as = "serde_with::hex::Hex")]
+    pub leaf: Vec<u8>,
+
+    #[serde_as(as = "serde_with::hex::Hex")]
+    pub root: Hash,
+
+    #[serde_as(as = "Vec<serde_with::hex::Hex>")]
+    pub proof: Vec<Hash>,
+}
+
+impl From<InclusionProof> for ProvenanceProofResponse {
+    fn from(x: InclusionProof) -> Self {
+        ProvenanceProofResponse {
+            status: ResponseStatus::Success,
+            algorithm: "rfc6962-sha256",
+            day: x.day,
+            provenance_uuid: x.provenance_uuid,
+            leaf_index: x.leaf_index,
+            leaf_count: x.leaf_count,
+            leaf: x.leaf,
+            root: x.root,
+            proof: x.proof,
+        }
+    }
+}
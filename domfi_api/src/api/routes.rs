@@ -1,6 +1,11 @@
 use std::ops::Deref;
+use std::convert::TryFrom;
 
 use actix_web::{Responder, HttpResponse, web, get};
+use actix_web::dev::BodyEncoding;
+use actix_web::http::ContentEncoding;
+use actix_web::http::header::{CACHE_CONTROL, CONTENT_DISPOSITION};
+use actix_web_actors::ws;
 use sqlx::PgPool;
 use log::error;
 use snafu::Snafu;
@@ -14,8 +19,20 @@ use qstring::QString;
 use domfi_domain::round_price_identifier;
 use crate::repo;
 use crate::api::convert::ToResponse;
-use crate::api::models::{ResponseStatus, PingResponse, ErrorResponse, ProvenanceResponse, TimestampQuery, CoinDominanceResponse, PricesResponse, PriceByIdResponse, HistoryResponse, HistoryResponseSlim};
-use crate::historical::{HistoricalCacheServiceRef, HistoryFetchRequest, ClientFindByIdHistoryError};
+use crate::api::models::{ResponseStatus, PingResponse, ErrorResponse, ProvenanceResponse, TimestampQuery, CoinDominanceResponse, PricesResponse, PriceByIdResponse, HistoryResponse, HistoryResponseSlim, CandlesResponse, SettlementResponse, TwapResponse, ConsensusResponse, PublicKeyResponse, ProvenanceProofResponse};
+use crate::api::ws::PriceStreamSession;
+use crate::api::sse::price_event_stream;
+use crate::live::LiveSnapshotServiceRef;
+use crate::serving::ServingAgent;
+use crate::settlement::{SettlementConfig, ClientSettlementError, fetch_settlement};
+use crate::consensus::{ConsensusConfigRef, ClientConsensusError, fetch_consensus};
+use crate::twap::{ClientTwapError, fetch_twap, DEFAULT_TWAP_STALE_GAP_MODE, DEFAULT_TWAP_MAX_GAP_SECONDS, MAX_TWAP_MAX_GAP_SECONDS};
+use domfi_domain::aggregate::{TwapCalculator, StaleGapMode};
+use domfi_data::evidence;
+use domfi_data::pg::ops::merkle_commitment;
+use domfi_domain::attestation::ATTESTATION_FORMAT;
+use crate::signing::{PriceSigner, PriceSignerRef};
+use crate::historical::{HistoricalCacheServiceRef, HistoryFetchRequest, ClientFindByIdHistoryError, HistoryInterval, HistoryRangeQuery, DEFAULT_HISTORY_INTERVAL, fetch_candles};
 use domfi_domain::models::FinancialAssetValueOf;
 use domfi_domain::models::FinancialAssetRawValueOf;
 use domfi_domain::models::financial_assets::get_canonical_default_asset;
@@ -45,6 +62,21 @@ pub async fn get_data_origin(id: web::Path<Uuid>, db: web::Data<PgPool>) -> impl
     HttpResponse::Ok().json(ProvenanceResponse::from(data))
 }
 
+#[get("/provenance/{id}/proof")]
+pub async fn get_data_origin_proof(id: web::Path<Uuid>, db: web::Data<PgPool>) -> impl Responder {
+
+    let result =
+        merkle_commitment::find_proof(*id, db.get_ref())
+            .await;
+
+    match result {
+        Err(e) => e.to_response(),
+        Ok(None) => HttpResponse::NotFound().json(
+            ErrorResponse::new("Provenance is unknown or its day has not been committed yet".into())),
+        Ok(Some(proof)) => HttpResponse::Ok().json(ProvenanceProofResponse::from(proof)),
+    }
+}
+
 #[get("/blob/{hash}")]
 pub async fn get_blob(hash: web::Path<String>, db: web::Data<PgPool>) -> impl Responder {
 
@@ -75,12 +107,16 @@ pub async fn get_blob(hash: web::Path<String>, db: web::Data<PgPool>) -> impl Re
 }
 
 #[get("/coingecko/coin_dominance")]
-pub async fn get_coingecko_coin_dominance(query: web::Query<TimestampQuery>, db: web::Data<PgPool>) -> impl Responder {
+pub async fn get_coingecko_coin_dominance(
+    query: web::Query<TimestampQuery>,
+    db: web::Data<PgPool>,
+    agent: web::Data<ServingAgent>
+) -> impl Responder {
     let ts = query.timestamp
         .map(|x| DateTime::from_utc(NaiveDateTime::from_timestamp(x as i64, 0), Utc));
 
     let result =
-        repo::CoinDominanceRepo::find_by_timestamp_rounded(ts, db.get_ref())
+        repo::CoinDominanceRepo::find_by_timestamp_rounded(agent.name(), ts, db.get_ref())
             .await;
 
     let data = match result {
@@ -100,13 +136,51 @@ pub async fn get_coingecko_coin_dominance(query: web::Query<TimestampQuery>, db:
     HttpResponse::Ok().json(response)
 }
 
+#[get("/pubkey")]
+pub async fn get_public_key(signer: web::Data<PriceSignerRef>) -> impl Responder {
+    match signer.get_ref() {
+        None => HttpResponse::NotFound().json(
+            ErrorResponse::new("Price signing is not enabled on this server".into())),
+        Some(signer) => HttpResponse::Ok().json(PublicKeyResponse {
+            status: ResponseStatus::Success,
+            algorithm: "ed25519",
+            format: ATTESTATION_FORMAT,
+            public_key: signer.public_key_hex(),
+        }),
+    }
+}
+
+/// Returns the signer if the client asked for signatures with `?sign=true`
+fn requested_signer<'a>(req: &web::HttpRequest, signer: &'a PriceSignerRef) -> Result<Option<&'a PriceSigner>, HttpResponse> {
+    let sign = is_query_flag_set(req.query_string(), "sign")
+        .map_err(|e| e.to_response())?;
+
+    match (sign, signer) {
+        (false, _) => Ok(None),
+        (true, Some(signer)) => Ok(Some(signer)),
+        (true, None) => Err(HttpResponse::NotImplemented().json(
+            ErrorResponse::new("Price signing is not enabled on this server".into()))),
+    }
+}
+
 #[get("/price")]
-pub async fn get_prices(query: web::Query<TimestampQuery>, db: web::Data<PgPool>) -> impl Responder {
+pub async fn get_prices(
+    query: web::Query<TimestampQuery>,
+    req: web::HttpRequest,
+    db: web::Data<PgPool>,
+    agent: web::Data<ServingAgent>,
+    signer: web::Data<PriceSignerRef>
+) -> impl Responder {
     let ts = query.timestamp
         .map(|x| DateTime::from_utc(NaiveDateTime::from_timestamp(x as i64, 0), Utc));
 
+    let signer = match requested_signer(&req, signer.get_ref()) {
+        Err(e) => return e,
+        Ok(x) => x,
+    };
+
     let result =
-        repo::CoinDominanceRepo::find_by_timestamp_rounded(ts, db.get_ref())
+        repo::CoinDominanceRepo::find_by_timestamp_rounded(agent.name(), ts, db.get_ref())
             .await;
 
     let data = match result {
@@ -121,28 +195,108 @@ pub async fn get_prices(query: web::Query<TimestampQuery>, db: web::Data<PgPool>
         prices.push((id, round_price_identifier(&x.dominance_percentage)));
     }
 
+    let timestamp = data.meta.actual_timestamp_utc;
+    let provenance_uuid = data.meta.provenance_uuid;
+
+    let signatures = match signer {
+        None => Vec::new(),
+        Some(signer) => {
+            let percentages: Vec<_> = data.elements.iter()
+                .map(|x| (x.id.as_str(), &x.dominance_percentage))
+                .collect();
+            signer.sign_prices(&percentages, timestamp, provenance_uuid)
+        },
+    };
+
     let response = PricesResponse {
         status: ResponseStatus::Success,
         data: prices,
-        timestamp: data.meta.actual_timestamp_utc,
+        timestamp,
         meta: data.meta.into(),
+        signatures,
     };
 
     HttpResponse::Ok().json(response)
 }
 
+#[get("/price/ws")]
+pub async fn get_price_ws(
+    req: web::HttpRequest,
+    stream: web::Payload,
+    live: web::Data<LiveSnapshotServiceRef>
+) -> Result<HttpResponse, actix_web::Error> {
+    ws::start(PriceStreamSession::new(live.get_ref().clone()), &req, stream)
+}
+
+/// Maximum number of missed snapshots replayed to a reconnecting event stream client
+const MAX_STREAM_CATCH_UP_SNAPSHOTS: i64 = 500;
+
+#[get("/price/stream")]
+pub async fn get_price_stream(
+    req: web::HttpRequest,
+    db: web::Data<PgPool>,
+    agent: web::Data<ServingAgent>,
+    live: web::Data<LiveSnapshotServiceRef>
+) -> impl Responder {
+
+    let last_event_id = match req.headers().get("Last-Event-ID") {
+        None => None,
+        Some(value) => match value.to_str().ok().and_then(|x| Uuid::parse_str(x.trim()).ok()) {
+            None => return HttpResponse::BadRequest().json(
+                ErrorResponse::new("Invalid 'Last-Event-ID' header: Expected a provenance UUID".into())),
+            Some(x) => Some(x),
+        },
+    };
+
+    // Subscribe before catching up so no snapshot falls in between
+    let rx = live.subscribe();
+
+    let missed = match last_event_id {
+        None => Vec::new(),
+        Some(uuid) => {
+            let result =
+                repo::CoinDominanceRepo::find_snapshots_after_provenance(uuid, agent.name(), MAX_STREAM_CATCH_UP_SNAPSHOTS, db.get_ref())
+                    .await;
+
+            match result {
+                Ok(x) => x,
+                Err(e) => return e.to_response(),
+            }
+        },
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        // Compressing would buffer the events
+        .encoding(ContentEncoding::Identity)
+        .streaming(price_event_stream(missed, rx))
+}
+
 #[get("/price/{id}")]
-pub async fn get_price_by_id(id: web::Path<String>, query: web::Query<TimestampQuery>, db: web::Data<PgPool>) -> impl Responder {
+pub async fn get_price_by_id(
+    id: web::Path<String>,
+    query: web::Query<TimestampQuery>,
+    req: web::HttpRequest,
+    db: web::Data<PgPool>,
+    agent: web::Data<ServingAgent>,
+    signer: web::Data<PriceSignerRef>
+) -> impl Responder {
     let ts = query.timestamp
         .map(|x| DateTime::from_utc(NaiveDateTime::from_timestamp(x as i64, 0), Utc));
 
+    let signer = match requested_signer(&req, signer.get_ref()) {
+        Err(e) => return e,
+        Ok(x) => x,
+    };
+
     let asset_meta = match get_canonical_default_asset(id.as_str()) {
         None => return ClientFindByIdHistoryError::CoinUnknownOrNotAllowed.to_response(),
         Some(x) => x
     };
 
     let result =
-        repo::CoinDominanceRepo::find_by_id_at_timestamp_rounded(asset_meta.asset(), ts, db.get_ref())
+        repo::CoinDominanceRepo::find_by_id_at_timestamp_rounded(asset_meta.asset(), agent.for_asset(asset_meta.asset()), ts, db.get_ref())
             .await;
 
     let data = match result {
@@ -150,39 +304,62 @@ pub async fn get_price_by_id(id: web::Path<String>, query: web::Query<TimestampQ
         Err(e) => return e.to_response(),
     };
 
+    let price = asset_meta.value_of(&data.percentage);
+    let timestamp = data.meta.actual_timestamp_utc;
+
+    let signature = signer.map(|signer|
+        signer.sign_price(asset_meta, &data.percentage, timestamp, data.meta.provenance_uuid));
+
     let response = PriceByIdResponse {
         status: ResponseStatus::Success,
         coin_id: &data.coin_id,
         coin_symbol: &data.coin_symbol,
-        price: &asset_meta.value_of(&data.percentage),
+        price: &price,
         price_original: &asset_meta.raw_value_of(&data.percentage),
-        timestamp: data.meta.actual_timestamp_utc,
+        timestamp,
         meta: data.meta.into(),
+        signature,
     };
 
     HttpResponse::Ok().json(response)
 }
 
-fn default_as_false() -> bool {
-    false
-}
-
 #[derive(Deserialize, Debug)]
 pub struct GetPriceHistoryByIdQuery {
-    #[serde(default="default_as_false")]
-    full: bool
+    from: Option<u64>,
+    to: Option<u64>,
+    interval: Option<HistoryInterval>,
+}
+
+impl GetPriceHistoryByIdQuery {
+    pub fn to_range(&self) -> Result<HistoryRangeQuery, ClientFindByIdHistoryError> {
+        let from = self.from
+            .map(|x| utc_from_timestamp(x).ok_or(ClientFindByIdHistoryError::InvalidTimestamp))
+            .transpose()?;
+        let to = self.to
+            .map(|x| utc_from_timestamp(x).ok_or(ClientFindByIdHistoryError::InvalidTimestamp))
+            .transpose()?;
+
+        HistoryRangeQuery::new(from, to, self.interval.unwrap_or(DEFAULT_HISTORY_INTERVAL))
+    }
 }
 
 #[get("/price/{id}/history")]
 pub async fn get_price_historical_by_id(
     id: web::Path<String>,
+    query: web::Query<GetPriceHistoryByIdQuery>,
     req: web::HttpRequest,
     history_service: web::Data<HistoricalCacheServiceRef>
 ) -> impl Responder {
 
+    let range = match query.to_range() {
+        Err(e) => return e.to_response(),
+        Ok(x) => x,
+    };
+
     let history_rx = history_service.get_ref();
 
-    let (msg, rx) = HistoryFetchRequest::new_with_receiver(id.deref().to_owned());
+    let (msg, rx) = HistoryFetchRequest::new_with_receiver(id.deref().to_owned(), range);
     if let Err(e) = history_rx.clone().send(msg).await {
         error!("Failed to send message to history fetch service: {}", e);
         return e.to_response()
@@ -222,6 +399,209 @@ pub async fn get_price_historical_by_id(
     };
 }
 
+#[get("/price/{id}/candles")]
+pub async fn get_price_candles_by_id(
+    id: web::Path<String>,
+    query: web::Query<GetPriceHistoryByIdQuery>,
+    db: web::Data<PgPool>,
+    agent: web::Data<ServingAgent>
+) -> impl Responder {
+
+    let range = match query.to_range() {
+        Err(e) => return e.to_response(),
+        Ok(x) => x,
+    };
+
+    let asset_meta = match get_canonical_default_asset(id.as_str()) {
+        None => return ClientFindByIdHistoryError::CoinUnknownOrNotAllowed.to_response(),
+        Some(x) => x
+    };
+
+    let dataset = match fetch_candles(db.get_ref(), asset_meta, agent.for_asset(asset_meta.asset()), &range).await {
+        Err(e) => return e.to_response(),
+        Ok(x) => x,
+    };
+
+    HttpResponse::Ok().json(CandlesResponse {
+        status: ResponseStatus::Success,
+        data: dataset,
+    })
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GetPriceSettlementByIdQuery {
+    timestamp: u64,
+    window: Option<u64>,
+}
+
+#[get("/price/{id}/settlement")]
+pub async fn get_price_settlement_by_id(
+    id: web::Path<String>,
+    query: web::Query<GetPriceSettlementByIdQuery>,
+    req: web::HttpRequest,
+    config: web::Data<SettlementConfig>,
+    db: web::Data<PgPool>,
+    agent: web::Data<ServingAgent>
+) -> impl Responder {
+
+    let ts = match utc_from_timestamp(query.timestamp) {
+        None => return ClientSettlementError::InvalidTimestamp.to_response(),
+        Some(x) => x,
+    };
+
+    let window = match config.window(query.window) {
+        Err(e) => return e.to_response(),
+        Ok(x) => x,
+    };
+
+    let with_twap = match is_query_flag_set(req.query_string(), "twap") {
+        Err(e) => return e.to_response(),
+        Ok(x) => x,
+    };
+
+    let asset_meta = match get_canonical_default_asset(id.as_str()) {
+        None => return ClientSettlementError::CoinUnknownOrNotAllowed.to_response(),
+        Some(x) => x
+    };
+
+    let dataset = match fetch_settlement(db.get_ref(), asset_meta, agent.for_asset(asset_meta.asset()), ts, window, with_twap).await {
+        Err(e) => return e.to_response(),
+        Ok(x) => x,
+    };
+
+    HttpResponse::Ok().json(SettlementResponse {
+        status: ResponseStatus::Success,
+        data: dataset,
+    })
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GetPriceTwapByIdQuery {
+    from: u64,
+    to: Option<u64>,
+    stale_gap: Option<StaleGapMode>,
+    max_gap: Option<u64>,
+}
+
+#[get("/price/{id}/twap")]
+pub async fn get_price_twap_by_id(
+    id: web::Path<String>,
+    query: web::Query<GetPriceTwapByIdQuery>,
+    db: web::Data<PgPool>,
+    agent: web::Data<ServingAgent>
+) -> impl Responder {
+
+    let from = match utc_from_timestamp(query.from) {
+        None => return ClientTwapError::InvalidRange.to_response(),
+        Some(x) => x,
+    };
+    let to = match query.to.map(utc_from_timestamp) {
+        None => Utc::now(),
+        Some(None) => return ClientTwapError::InvalidRange.to_response(),
+        Some(Some(x)) => x,
+    };
+
+    let max_gap = match query.max_gap {
+        None => DEFAULT_TWAP_MAX_GAP_SECONDS,
+        Some(x) if (1..=MAX_TWAP_MAX_GAP_SECONDS as u64).contains(&x) => x as i64,
+        Some(_) => return ClientTwapError::InvalidMaxGap.to_response(),
+    };
+
+    let calculator = TwapCalculator::new(
+        query.stale_gap.unwrap_or(DEFAULT_TWAP_STALE_GAP_MODE),
+        chrono::Duration::seconds(max_gap));
+
+    let asset_meta = match get_canonical_default_asset(id.as_str()) {
+        None => return ClientTwapError::CoinUnknownOrNotAllowed.to_response(),
+        Some(x) => x
+    };
+
+    let dataset = match fetch_twap(db.get_ref(), asset_meta, agent.for_asset(asset_meta.asset()), from, to, calculator).await {
+        Err(e) => return e.to_response(),
+        Ok(x) => x,
+    };
+
+    HttpResponse::Ok().json(TwapResponse {
+        status: ResponseStatus::Success,
+        data: dataset,
+    })
+}
+
+#[get("/price/{id}/consensus")]
+pub async fn get_price_consensus_by_id(
+    id: web::Path<String>,
+    query: web::Query<TimestampQuery>,
+    config: web::Data<ConsensusConfigRef>,
+    db: web::Data<PgPool>
+) -> impl Responder {
+    let ts = match query.timestamp.map(utc_from_timestamp) {
+        None => Utc::now(),
+        Some(None) => return ClientConsensusError::InvalidTimestamp.to_response(),
+        Some(Some(x)) => x,
+    };
+
+    let config = match config.get_ref() {
+        None => return ClientConsensusError::NotEnabled.to_response(),
+        Some(x) => x,
+    };
+
+    let asset_meta = match get_canonical_default_asset(id.as_str()) {
+        None => return ClientConsensusError::CoinUnknownOrNotAllowed.to_response(),
+        Some(x) => x
+    };
+
+    let dataset = match fetch_consensus(db.get_ref(), config, asset_meta, ts).await {
+        Err(e) => return e.to_response(),
+        Ok(x) => x,
+    };
+
+    HttpResponse::Ok().json(ConsensusResponse {
+        status: ResponseStatus::Success,
+        data: dataset,
+    })
+}
+
+#[get("/price/{id}/evidence")]
+pub async fn get_price_evidence_by_id(
+    id: web::Path<String>,
+    query: web::Query<TimestampQuery>,
+    db: web::Data<PgPool>,
+    agent: web::Data<ServingAgent>
+) -> impl Responder {
+    let ts = match query.timestamp.map(utc_from_timestamp) {
+        None => None,
+        Some(None) => return ClientFindByIdHistoryError::InvalidTimestamp.to_response(),
+        Some(Some(x)) => Some(x),
+    };
+
+    let asset_meta = match get_canonical_default_asset(id.as_str()) {
+        None => return ClientFindByIdHistoryError::CoinUnknownOrNotAllowed.to_response(),
+        Some(x) => x
+    };
+
+    let bundle = match evidence::collect(asset_meta, agent.for_asset(asset_meta.asset()), ts, db.get_ref()).await {
+        Err(e) => return e.to_response(),
+        Ok(x) => x,
+    };
+
+    let archive = match bundle.to_tar() {
+        Err(e) => return e.to_response(),
+        Ok(x) => x,
+    };
+
+    HttpResponse::Ok()
+        .content_type("application/x-tar")
+        .header(CONTENT_DISPOSITION, format!("attachment; filename=\"{}.tar\"", bundle.name()))
+        .body(archive)
+}
+
+/// Converts seconds since the Unix epoch, or `None` if the timestamp is out of range
+fn utc_from_timestamp(secs: u64) -> Option<DateTime<Utc>> {
+    i64::try_from(secs).ok()
+        .and_then(|x| NaiveDateTime::from_timestamp_opt(x, 0))
+        .map(|x| DateTime::from_utc(x, Utc))
+}
+
 #[derive(Snafu, Debug)]
 pub enum QueryFlagError {
     #[snafu(display("Unrecognized input for query parameter '{}'. Expected boolean. Got '{}'", param, input))]
@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use actix_web::web::Bytes;
+use bigdecimal::BigDecimal;
+use futures::prelude::*;
+use futures::stream::{self, LocalBoxStream};
+
+use domfi_data::pg::notify::CoinDominanceSnapshot;
+use domfi_domain::round_price_identifier;
+use crate::api::models::{PricesResponse, PricesMeta, ResponseStatus};
+use crate::live::LiveSnapshot;
+
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Same shape as `GET /price`, built from a live snapshot
+pub fn to_prices_response(snapshot: &CoinDominanceSnapshot) -> PricesResponse<'_> {
+    let mut rows: Vec<_> = snapshot.rows.iter().collect();
+
+    // Same order as the database query: "others" last, then by market cap descending
+    rows.sort_by(|a, b| {
+        a.coin_id.is_empty().cmp(&b.coin_id.is_empty())
+            .then_with(|| b.market_cap_usd.cmp(&a.market_cap_usd))
+    });
+
+    let data: Vec<(&str, BigDecimal)> = rows.into_iter()
+        .map(|x| {
+            let id = if x.coin_id.is_empty() { "others" } else { x.coin_id.as_str() };
+            (id, round_price_identifier(&x.market_dominance_percentage))
+        })
+        .collect();
+
+    PricesResponse {
+        status: ResponseStatus::Success,
+        data,
+        timestamp: snapshot.timestamp,
+        meta: PricesMeta {
+            provenance_uuid: snapshot.provenance_uuid,
+            requested_timestamp: snapshot.timestamp,
+            actual_timestamp: snapshot.timestamp,
+        },
+        signatures: Vec::new(),
+    }
+}
+
+fn to_event(snapshot: &CoinDominanceSnapshot) -> Option<Bytes> {
+    let json = match serde_json::to_string(&to_prices_response(snapshot)) {
+        Err(e) => {
+            error!("[{}] Failed to serialize snapshot event: {}", snapshot.provenance_uuid, e);
+            return None;
+        },
+        Ok(x) => x,
+    };
+
+    Some(Bytes::from(format!("id: {}\nevent: prices\ndata: {}\n\n", snapshot.provenance_uuid, json)))
+}
+
+/// Emits the missed snapshots first, followed by every snapshot received from
+/// the live service that is newer than the last one emitted. `live` must be
+/// subscribed before `missed` is fetched so that nothing committed in between
+/// is lost. Both are expected to be of the serving agent only.
+pub fn price_event_stream(
+    missed: Vec<CoinDominanceSnapshot>,
+    live: tokio::sync::broadcast::Receiver<LiveSnapshot>,
+) -> LocalBoxStream<'static, Result<Bytes, actix_web::Error>> {
+
+    let mut latest = missed.last().map(|x| x.timestamp);
+
+    let catch_up = stream::iter(missed.into_iter()
+        .filter_map(|x| to_event(&x)));
+
+    let live = live.into_stream()
+        .filter_map(move |msg| {
+            let event = match msg {
+                Err(e) => {
+                    warn!("Price event stream lagged behind: {}", e);
+                    None
+                },
+                Ok(x) if latest.is_some_and(|ts| x.timestamp <= ts) => None,
+                Ok(x) => {
+                    latest = Some(x.timestamp);
+                    to_event(&x)
+                },
+            };
+            future::ready(event)
+        });
+
+    let keep_alive = tokio::time::interval(KEEP_ALIVE_INTERVAL)
+        .map(|_| Bytes::from_static(b": keep-alive\n\n"));
+
+    catch_up
+        .chain(stream::select(live, keep_alive))
+        .map(Ok)
+        .boxed_local()
+}
@@ -0,0 +1,179 @@
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
+use actix_web_actors::ws;
+use chrono::{DateTime, Utc};
+use chrono::serde::ts_seconds;
+use uuid::Uuid;
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast::RecvError;
+
+use domfi_domain::models::financial_assets::get_canonical_default_asset;
+use crate::live::{LiveSnapshot, LiveSnapshotServiceRef, ClientLivePrice, price_of};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PriceStreamClientMessage {
+    Subscribe { assets: Vec<String> },
+    Unsubscribe { assets: Vec<String> },
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum PriceStreamServerMessage<'a> {
+    Subscribed {
+        assets: &'a BTreeSet<String>,
+    },
+    Snapshot {
+        provenance_uuid: Uuid,
+        agent: &'a str,
+        #[serde(with = "ts_seconds")]
+        timestamp: DateTime<Utc>,
+        #[serde(with = "tuple_vec_map")]
+        prices: Vec<(&'a str, ClientLivePrice)>,
+    },
+    Error {
+        reason: String,
+    },
+}
+
+/// WebSocket session pushing the prices of the subscribed assets for every new snapshot
+pub struct PriceStreamSession {
+    live: LiveSnapshotServiceRef,
+    subscriptions: BTreeSet<String>,
+    heartbeat: Instant,
+}
+
+impl PriceStreamSession {
+    pub fn new(live: LiveSnapshotServiceRef) -> PriceStreamSession {
+        PriceStreamSession {
+            live,
+            subscriptions: BTreeSet::new(),
+            heartbeat: Instant::now(),
+        }
+    }
+
+    fn send(&self, msg: &PriceStreamServerMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        match serde_json::to_string(msg) {
+            Err(e) => error!("Failed to serialize price stream message: {}", e),
+            Ok(json) => ctx.text(json),
+        }
+    }
+
+    fn handle_client_message(&mut self, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let msg = match serde_json::from_str::<PriceStreamClientMessage>(text) {
+            Err(e) => {
+                self.send(&PriceStreamServerMessage::Error { reason: format!("Invalid message: {}", e) }, ctx);
+                return;
+            },
+            Ok(x) => x,
+        };
+
+        match msg {
+            PriceStreamClientMessage::Subscribe { assets } => {
+                for id in assets {
+                    let id = id.to_ascii_lowercase();
+                    if get_canonical_default_asset(&id).is_none() {
+                        let reason = format!("Unknown instrument or not allowed: '{}'", id);
+                        self.send(&PriceStreamServerMessage::Error { reason }, ctx);
+                        return;
+                    }
+                    self.subscriptions.insert(id);
+                }
+            },
+            PriceStreamClientMessage::Unsubscribe { assets } => {
+                for id in assets {
+                    self.subscriptions.remove(&id.to_ascii_lowercase());
+                }
+            },
+        }
+
+        self.send(&PriceStreamServerMessage::Subscribed { assets: &self.subscriptions }, ctx);
+    }
+}
+
+impl Actor for PriceStreamSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.add_stream(self.live.subscribe().into_stream());
+
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            if Instant::now().duration_since(act.heartbeat) > CLIENT_TIMEOUT {
+                debug!("Price stream client timed out");
+                ctx.stop();
+                return;
+            }
+            ctx.ping(b"");
+        });
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for PriceStreamSession {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => {
+                self.heartbeat = Instant::now();
+                ctx.pong(&msg);
+            },
+            Ok(ws::Message::Pong(_)) => {
+                self.heartbeat = Instant::now();
+            },
+            Ok(ws::Message::Text(text)) => {
+                self.heartbeat = Instant::now();
+                self.handle_client_message(&text, ctx);
+            },
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            },
+            Err(e) => {
+                debug!("Price stream protocol error: {}", e);
+                ctx.stop();
+            },
+            _ => {},
+        }
+    }
+}
+
+impl StreamHandler<Result<LiveSnapshot, RecvError>> for PriceStreamSession {
+    fn handle(&mut self, msg: Result<LiveSnapshot, RecvError>, ctx: &mut Self::Context) {
+        let snapshot = match msg {
+            Err(RecvError::Lagged(n)) => {
+                warn!("Price stream client lagged behind by {} snapshots", n);
+                return;
+            },
+            Err(RecvError::Closed) => {
+                ctx.stop();
+                return;
+            },
+            Ok(x) => x,
+        };
+
+        if self.subscriptions.is_empty() {
+            return;
+        }
+
+        let prices = self.subscriptions.iter()
+            .filter_map(|id| {
+                let asset_meta = get_canonical_default_asset(id)?;
+                price_of(&snapshot, asset_meta).map(|p| (id.as_str(), p))
+            })
+            .collect();
+
+        self.send(&PriceStreamServerMessage::Snapshot {
+            provenance_uuid: snapshot.provenance_uuid,
+            agent: &snapshot.agent,
+            timestamp: snapshot.timestamp,
+            prices,
+        }, ctx);
+    }
+
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        ctx.stop();
+    }
+}
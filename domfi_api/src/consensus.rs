@@ -0,0 +1,210 @@
+use std::str::FromStr;
+
+use sqlx::PgPool;
+use chrono::{DateTime, Utc};
+use chrono::serde::ts_seconds;
+use bigdecimal::BigDecimal;
+use uuid::Uuid;
+use log::{error, warn};
+use snafu::Snafu;
+use serde::Serialize;
+
+use domfi_domain::aggregate::{ConsensusRules, ConsensusError};
+use domfi_domain::models::{FinancialAssetWithMetadata, FinancialAssetWithMetadataOfAny, FinancialAssetRawValueOf, FinancialDominanceAsset};
+use domfi_util::ConfigContext;
+use crate::repo::{RepositoryError, CoinDominanceRepo};
+
+/// Snapshots older than this do not contribute to the consensus
+pub const DEFAULT_CONSENSUS_MAX_STALENESS_SECONDS: i64 = 5 * 60;
+
+/// Largest accepted staleness, one day
+pub const MAX_CONSENSUS_MAX_STALENESS_SECONDS: i64 = 24 * 60 * 60;
+
+/// Largest deviation from the median, in percent of the median, before sources disagree
+pub const DEFAULT_CONSENSUS_MAX_DEVIATION_PERCENT: &str = "0.5";
+
+#[derive(Clone, Debug)]
+pub struct ConsensusConfig {
+    pub agents: Vec<String>,
+    pub rules: ConsensusRules,
+}
+
+pub type ConsensusConfigRef = Option<ConsensusConfig>;
+
+impl ConsensusConfig {
+    /// Reads the comma separated `<prefix>_CONSENSUS_AGENTS`, `<prefix>_CONSENSUS_MAX_STALENESS_SECONDS`,
+    /// `<prefix>_CONSENSUS_MAX_DEVIATION_PERCENT` and `<prefix>_CONSENSUS_MIN_AGENTS`, falling back to
+    /// the defaults when unset or invalid. At least a majority of the agents must contribute by default.
+    /// Returns `None` if no agents are configured.
+    pub fn from_env(prefix: &str) -> ConsensusConfigRef {
+        let config = ConfigContext::new(prefix);
+
+        let mut agents: Vec<String> = config.var("CONSENSUS_AGENTS").ok()?
+            .split(',')
+            .map(|x| x.trim().to_owned())
+            .filter(|x| !x.is_empty())
+            .collect();
+        agents.sort();
+        agents.dedup();
+
+        if agents.is_empty() {
+            return None;
+        }
+
+        fn parse_or<T: FromStr>(config: &ConfigContext, name: &str, default: T, is_valid: impl Fn(&T) -> bool) -> T {
+            match config.var(name) {
+                Err(_) => default,
+                Ok(x) => match x.parse::<T>() {
+                    Ok(x) if is_valid(&x) => x,
+                    _ => {
+                        warn!("Invalid '{}'. Using default instead.", config.name_of(name));
+                        default
+                    }
+                },
+            }
+        }
+
+        let max_staleness = parse_or(&config, "CONSENSUS_MAX_STALENESS_SECONDS", DEFAULT_CONSENSUS_MAX_STALENESS_SECONDS,
+            |x| (1..=MAX_CONSENSUS_MAX_STALENESS_SECONDS).contains(x));
+        let max_deviation_percent = parse_or(
+            &config,
+            "CONSENSUS_MAX_DEVIATION_PERCENT",
+            BigDecimal::from_str(DEFAULT_CONSENSUS_MAX_DEVIATION_PERCENT).unwrap(),
+            |x| *x >= BigDecimal::from(0));
+        let min_sources = parse_or(&config, "CONSENSUS_MIN_AGENTS", agents.len() / 2 + 1, |x| *x >= 1)
+            .min(agents.len());
+
+        Some(ConsensusConfig {
+            agents,
+            rules: ConsensusRules {
+                max_staleness: chrono::Duration::seconds(max_staleness),
+                max_deviation_percent,
+                min_sources,
+            },
+        })
+    }
+}
+
+#[derive(Snafu, Debug)]
+pub enum ClientConsensusError {
+    #[snafu(display("Unknown instrument or not allowed."))]
+    CoinUnknownOrNotAllowed,
+
+    #[snafu(display("Consensus pricing is not enabled on this server"))]
+    NotEnabled,
+
+    #[snafu(display("Invalid timestamp. Expected seconds since the Unix epoch."))]
+    InvalidTimestamp,
+
+    #[snafu(display("Failed to fetch coin dominance for consensus."))]
+    DbError,
+
+    #[snafu(display("No consensus: {}.", source))]
+    NoConsensus {
+        source: ConsensusError,
+        dataset: Box<ClientConsensusDataset>,
+    },
+}
+
+#[derive(Serialize, Debug)]
+pub struct ClientConsensusInput {
+    pub agent: String,
+    pub provenance_uuid: Uuid,
+    #[serde(with = "ts_seconds")]
+    pub timestamp: DateTime<Utc>,
+    /// Unrounded value of the asset, i.e. after inversion for ALTDOM
+    pub value: BigDecimal,
+    /// Deviation from the median in percent of the median. Absent for stale inputs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deviation_percent: Option<BigDecimal>,
+    pub contributed: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ClientConsensusDataset {
+    pub asset: FinancialAssetWithMetadataOfAny,
+    #[serde(with = "ts_seconds")]
+    pub timestamp: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub price: Option<BigDecimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub price_original: Option<BigDecimal>,
+    pub max_staleness_seconds: i64,
+    pub max_deviation_percent: BigDecimal,
+    pub min_agents: usize,
+    pub inputs: Vec<ClientConsensusInput>,
+    /// Configured agents without any snapshot of the coin at or before the timestamp
+    pub missing_agents: Vec<String>,
+}
+
+/// Takes the median of the latest unrounded value of each configured agent at `timestamp`,
+/// then applies the asset's rounding to the result. Fails with the inputs when too few agents
+/// are fresh or they disagree.
+pub async fn fetch_consensus(
+    pool: &PgPool,
+    config: &ConsensusConfig,
+    asset_meta: &FinancialAssetWithMetadata<FinancialDominanceAsset>,
+    timestamp: DateTime<Utc>,
+) -> Result<ClientConsensusDataset, ClientConsensusError> {
+
+    let db_result = CoinDominanceRepo::find_by_id_latest_per_agent(
+        asset_meta.asset(),
+        &config.agents,
+        timestamp,
+        pool)
+        .await;
+
+    let db_rows = match db_result {
+        Err(e @ RepositoryError::SqlError { .. }) => {
+            error!("Failed to fetch consensus inputs for coin `{:?}`: {}", asset_meta.asset(), e);
+            return Err(ClientConsensusError::DbError);
+        }
+        Ok(x) => x
+    };
+
+    let missing_agents = config.agents.iter()
+        .filter(|agent| !db_rows.iter().any(|r| &r.agent == *agent))
+        .cloned()
+        .collect();
+
+    let values: Vec<(DateTime<Utc>, BigDecimal)> = db_rows.iter()
+        .map(|r| (r.timestamp_utc, asset_meta.raw_value_of(&r.dominance_percentage).into_owned()))
+        .collect();
+
+    let consensus = config.rules.evaluate(&values, timestamp);
+    let result = config.rules.check(&consensus);
+
+    let inputs = db_rows.into_iter()
+        .zip(values)
+        .zip(consensus.deviations)
+        .map(|((r, (_, value)), deviation_percent)| ClientConsensusInput {
+            agent: r.agent,
+            provenance_uuid: r.provenance_uuid,
+            timestamp: r.timestamp_utc,
+            value,
+            contributed: deviation_percent.is_some(),
+            deviation_percent,
+        })
+        .collect();
+
+    let mut dataset = ClientConsensusDataset {
+        asset: asset_meta.clone().into_any(),
+        timestamp,
+        price: None,
+        price_original: None,
+        max_staleness_seconds: config.rules.max_staleness.num_seconds(),
+        max_deviation_percent: config.rules.max_deviation_percent.clone(),
+        min_agents: config.rules.min_sources,
+        inputs,
+        missing_agents,
+    };
+
+    match result {
+        Err(source) => Err(ClientConsensusError::NoConsensus { source, dataset: Box::new(dataset) }),
+        Ok(median) => {
+            dataset.price = Some(asset_meta.metadata().round(&median));
+            dataset.price_original = Some(median);
+            Ok(dataset)
+        }
+    }
+}
@@ -1,4 +1,6 @@
 use std::sync::Arc;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
 
 use tokio::sync::{oneshot, mpsc};
 use tokio::time::Duration;
@@ -6,30 +8,188 @@ use tokio::sync::mpsc::Sender;
 use tokio::select;
 
 use sqlx::PgPool;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Utc, TimeZone};
 use chrono::serde::ts_seconds;
 use bigdecimal::BigDecimal;
 use uuid::Uuid;
 use log::{error};
 use snafu::{Snafu};
-use serde::{Serialize};
+use serde::{Serialize, Serializer, Deserialize, Deserializer, de};
 use ttl_cache::TtlCache;
 
-use domfi_domain::models::{FinancialAssetWithMetadata, FinancialAssetWithMetadataOfAny, FinancialAssetValueOf, FinancialDominanceAsset, FinancialAssetRawValueOf};
+use domfi_domain::models::{FinancialAssetWithMetadata, FinancialAssetWithMetadataOfAny, FinancialAssetValueOf, FinancialDominanceAsset, FinancialAssetRawValueOf, Ohlc};
 use crate::repo::{RepositoryError, CoinDominanceRepo};
+use crate::serving::ServingAgent;
 use domfi_domain::models::financial_assets::get_canonical_default_asset;
 
+/// Number of buckets returned when the client does not specify `from`. For the
+/// default one-minute interval this is the original 72 hour window.
+pub const DEFAULT_HISTORY_ROWS: i64 = 72 * 60;
+
+/// Upper bound on the number of buckets a single history request may span.
+pub const MAX_HISTORY_ROWS: i64 = 7 * 24 * 60;
+
+pub const DEFAULT_HISTORY_INTERVAL: HistoryInterval = HistoryInterval::OneMinute;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum HistoryInterval {
+    OneMinute,
+    FiveMinutes,
+    FifteenMinutes,
+    OneHour,
+    OneDay,
+}
+
+impl HistoryInterval {
+    pub fn as_seconds(&self) -> i64 {
+        match self {
+            HistoryInterval::OneMinute => 60,
+            HistoryInterval::FiveMinutes => 5 * 60,
+            HistoryInterval::FifteenMinutes => 15 * 60,
+            HistoryInterval::OneHour => 60 * 60,
+            HistoryInterval::OneDay => 24 * 60 * 60,
+        }
+    }
+
+    pub fn duration(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.as_seconds())
+    }
+
+    /// Rounds the timestamp down to the start of the bucket that contains it
+    pub fn truncate(&self, ts: DateTime<Utc>) -> DateTime<Utc> {
+        let secs = ts.timestamp();
+        Utc.timestamp(secs - secs.rem_euclid(self.as_seconds()), 0)
+    }
+}
+
+impl Display for HistoryInterval {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryInterval::OneMinute => f.write_str("1m"),
+            HistoryInterval::FiveMinutes => f.write_str("5m"),
+            HistoryInterval::FifteenMinutes => f.write_str("15m"),
+            HistoryInterval::OneHour => f.write_str("1h"),
+            HistoryInterval::OneDay => f.write_str("1d"),
+        }
+    }
+}
+
+#[derive(Snafu, Debug)]
+pub enum HistoryIntervalParseError {
+    #[snafu(display("Invalid interval specified: '{}'. Expected one of: 1m, 5m, 15m, 1h, 1d", input))]
+    InvalidFormat {
+        input: String,
+    }
+}
+
+impl FromStr for HistoryInterval {
+    type Err = HistoryIntervalParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "1m" => Ok(HistoryInterval::OneMinute),
+            "5m" => Ok(HistoryInterval::FiveMinutes),
+            "15m" => Ok(HistoryInterval::FifteenMinutes),
+            "1h" => Ok(HistoryInterval::OneHour),
+            "1d" => Ok(HistoryInterval::OneDay),
+            _ => InvalidFormat { input: s.to_owned() }.fail()
+        }
+    }
+}
+
+impl Serialize for HistoryInterval {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for HistoryInterval {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de>
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
+/// Time range requested by the client. Bounds are aligned to the interval so
+/// that requests for the same buckets share a cache entry. A missing `to` means
+/// the range trails the current time and is refreshed in the background.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct HistoryRangeQuery {
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    interval: HistoryInterval,
+}
+
+/// Concrete `[from, to)` range resolved from a `HistoryRangeQuery`
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct HistoryRange {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub interval: HistoryInterval,
+}
+
+impl HistoryRange {
+    pub fn row_count(&self) -> i64 {
+        (self.to - self.from).num_seconds() / self.interval.as_seconds()
+    }
+}
+
+impl HistoryRangeQuery {
+    pub fn new(
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+        interval: HistoryInterval
+    ) -> Result<HistoryRangeQuery, ClientFindByIdHistoryError> {
+        let query = HistoryRangeQuery {
+            from: from.map(|x| interval.truncate(x)),
+            to: to.map(|x| interval.truncate(x)),
+            interval,
+        };
+
+        // Validate eagerly so that bad requests never reach the cache service
+        query.resolve(Utc::now())?;
+        Ok(query)
+    }
+
+    pub fn is_trailing(&self) -> bool {
+        self.to.is_none()
+    }
+
+    pub fn resolve(&self, now: DateTime<Utc>) -> Result<HistoryRange, ClientFindByIdHistoryError> {
+        let interval = self.interval;
+        let to = self.to.unwrap_or_else(|| interval.truncate(now));
+        let from = self.from.unwrap_or_else(|| to - interval.duration() * DEFAULT_HISTORY_ROWS as i32);
+
+        if from >= to {
+            return Err(ClientFindByIdHistoryError::InvalidRange);
+        }
+
+        let range = HistoryRange { from, to, interval };
+        let requested = range.row_count();
+        if requested > MAX_HISTORY_ROWS {
+            return Err(ClientFindByIdHistoryError::TooManyRows { requested, max: MAX_HISTORY_ROWS });
+        }
+
+        Ok(range)
+    }
+}
+
 #[derive(Debug)]
 pub struct HistoryFetchRequest {
     pub coin_id: String,
+    pub range: HistoryRangeQuery,
     pub sender: oneshot::Sender<HistoryFetchResponse>,
 }
 
 impl HistoryFetchRequest {
-    pub fn new_with_receiver(asset_id: String) -> (HistoryFetchRequest, oneshot::Receiver<HistoryFetchResponse>) {
+    pub fn new_with_receiver(asset_id: String, range: HistoryRangeQuery) -> (HistoryFetchRequest, oneshot::Receiver<HistoryFetchResponse>) {
         let (tx, rx) = oneshot::channel();
         let msg = HistoryFetchRequest {
             coin_id: asset_id,
+            range,
             sender: tx
         };
         (msg, rx)
@@ -48,6 +208,18 @@ pub enum ClientFindByIdHistoryError {
 
     #[snafu(display("Failed to locate the service from the server context."))]
     FailedToLocateService,
+
+    #[snafu(display("Invalid time range. Expected 'from' to be before 'to'."))]
+    InvalidRange,
+
+    #[snafu(display("Invalid timestamp. Expected seconds since the Unix epoch."))]
+    InvalidTimestamp,
+
+    #[snafu(display("Time range too large. Requested {} rows, but at most {} are allowed.", requested, max))]
+    TooManyRows {
+        requested: i64,
+        max: i64,
+    },
 }
 
 #[derive(Serialize, Debug)]
@@ -77,17 +249,31 @@ impl<'a> From<&'a ClientFindByIdHistoryEntry> for ClientFindByIdHistoryEntrySlim
     }
 }
 
-type AssetId = FinancialDominanceAsset;
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+struct HistoryKey {
+    asset: FinancialDominanceAsset,
+    range: HistoryRangeQuery,
+}
 
 #[derive(Serialize, Clone, Debug)]
 pub struct ClientFindByIdHistoryDataset {
     pub asset: FinancialAssetWithMetadataOfAny,
+    pub interval: HistoryInterval,
+    #[serde(with = "ts_seconds")]
+    pub from: DateTime<Utc>,
+    #[serde(with = "ts_seconds")]
+    pub to: DateTime<Utc>,
     pub rows: Arc<Vec<ClientFindByIdHistoryEntry>>,
 }
 
 #[derive(Serialize, Debug)]
 pub struct ClientFindByIdHistoryDatasetSlim<'a> {
     pub asset: &'a FinancialAssetWithMetadataOfAny,
+    pub interval: HistoryInterval,
+    #[serde(with = "ts_seconds")]
+    pub from: DateTime<Utc>,
+    #[serde(with = "ts_seconds")]
+    pub to: DateTime<Utc>,
     pub rows: Vec<ClientFindByIdHistoryEntrySlim<'a>>,
 }
 
@@ -95,29 +281,55 @@ impl<'a> From<&'a ClientFindByIdHistoryDataset> for ClientFindByIdHistoryDataset
     fn from(x: &'a ClientFindByIdHistoryDataset) -> Self {
         ClientFindByIdHistoryDatasetSlim {
             asset: &x.asset,
+            interval: x.interval,
+            from: x.from,
+            to: x.to,
             rows: x.rows.iter().map(|r| r.into()).collect(),
         }
     }
 }
 
+#[derive(Serialize, Debug)]
+pub struct ClientCandleEntry {
+    #[serde(with = "ts_seconds")]
+    pub tick: DateTime<Utc>,
+    #[serde(flatten)]
+    pub price: Ohlc,
+    pub samples: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ClientCandleDataset {
+    pub asset: FinancialAssetWithMetadataOfAny,
+    pub interval: HistoryInterval,
+    #[serde(with = "ts_seconds")]
+    pub from: DateTime<Utc>,
+    #[serde(with = "ts_seconds")]
+    pub to: DateTime<Utc>,
+    pub rows: Vec<ClientCandleEntry>,
+}
+
 pub struct HistoricalCacheService {
     rx: mpsc::Receiver<HistoryFetchRequest>,
     pool: PgPool,
-    cache: TtlCache<AssetId, Arc<ClientFindByIdHistoryDataset>>,
+    agent: ServingAgent,
+    cache: TtlCache<HistoryKey, Arc<ClientFindByIdHistoryDataset>>,
     default_ttl: Duration,
     update_interval: Duration
 }
 
 #[derive(Debug)]
 enum HistoricalCoinMonitorMessage {
-    ShouldUpdate(AssetId, oneshot::Sender<bool>),
-    UpdatedDataset(AssetId, Arc<ClientFindByIdHistoryDataset>),
+    ShouldUpdate(HistoryKey, oneshot::Sender<bool>),
+    UpdatedDataset(HistoryKey, Arc<ClientFindByIdHistoryDataset>),
 }
 
 struct HistoricalCoinMonitor {
     asset_meta: FinancialAssetWithMetadata<FinancialDominanceAsset>,
+    range: HistoryRangeQuery,
     update_interval: Duration,
     pool: PgPool,
+    agent: ServingAgent,
     parent: mpsc::Sender<HistoricalCoinMonitorMessage>,
 }
 
@@ -126,8 +338,15 @@ impl HistoricalCoinMonitor {
         mpsc::channel(buffer_size)
     }
 
+    fn key(&self) -> HistoryKey {
+        HistoryKey {
+            asset: self.asset_meta.asset().clone(),
+            range: self.range,
+        }
+    }
+
     pub async fn into_run(mut self) {
-        info!("Started monitor for coin '{:?}' (update interval = {:?})", self.asset_meta.asset(), self.update_interval);
+        info!("Started monitor for coin '{:?}' (range = {:?}, update interval = {:?})", self.asset_meta.asset(), self.range, self.update_interval);
         let mut interval = tokio::time::interval(self.update_interval);
         loop {
             interval.tick().await;
@@ -135,7 +354,7 @@ impl HistoricalCoinMonitor {
             let (should_fetch_tx, should_fetch_rx) = oneshot::channel();
             if let Err(e) =
                 self.parent.send(HistoricalCoinMonitorMessage::ShouldUpdate(
-                    self.asset_meta.asset().clone(),
+                    self.key(),
                     should_fetch_tx)).await {
 
                 warn!("Failed to determine coin historical liveliness for '{:?}': {}", self.asset_meta.asset(), e);
@@ -154,7 +373,7 @@ impl HistoricalCoinMonitor {
                 _ => {}
             };
 
-            let result = fetch(&self.pool, &self.asset_meta).await;
+            let result = fetch(&self.pool, &self.asset_meta, self.agent.for_asset(self.asset_meta.asset()), &self.range).await;
             let dataset = match result {
                 Err(e) => {
                     warn!("Failed to fetch coin historical data for '{:?}': {}", self.asset_meta.asset(), e);
@@ -164,7 +383,7 @@ impl HistoricalCoinMonitor {
             };
 
             if let Err(e) = self.parent.send(HistoricalCoinMonitorMessage::UpdatedDataset(
-                self.asset_meta.asset().clone(),
+                self.key(),
                 dataset)).await
             {
                 warn!("Failed to send coin historical data for '{:?}': {}", self.asset_meta.asset(), e);
@@ -177,11 +396,12 @@ impl HistoricalCoinMonitor {
 pub type HistoricalCacheServiceRef = mpsc::Sender<HistoryFetchRequest>;
 
 impl HistoricalCacheService {
-    pub fn new(pool: PgPool, buffer_size: usize, default_ttl: Duration, update_interval: Duration) -> (HistoricalCacheService, HistoricalCacheServiceRef) {
+    pub fn new(pool: PgPool, agent: ServingAgent, buffer_size: usize, default_ttl: Duration, update_interval: Duration) -> (HistoricalCacheService, HistoricalCacheServiceRef) {
         let (tx, rx) = mpsc::channel(buffer_size);
         let ctx = HistoricalCacheService {
             rx,
             pool,
+            agent,
             cache: TtlCache::new(4096),
             default_ttl,
             update_interval,
@@ -202,11 +422,11 @@ impl HistoricalCacheService {
                 Some(msg) = monitors_rx.recv() => {
                     // debug!("recv: {:?}", msg);
                     match msg {
-                        HistoricalCoinMonitorMessage::ShouldUpdate(coin_id, child) => {
-                            child.send(self.cache.contains_key(&coin_id)).ok();
+                        HistoricalCoinMonitorMessage::ShouldUpdate(key, child) => {
+                            child.send(self.cache.contains_key(&key)).ok();
                         },
-                        HistoricalCoinMonitorMessage::UpdatedDataset(coin_id, dataset) => {
-                            match self.cache.entry(coin_id) {
+                        HistoricalCoinMonitorMessage::UpdatedDataset(key, dataset) => {
+                            match self.cache.entry(key) {
                                 ttl_cache::Entry::Occupied(mut entry) => {
                                     // Avoid updating the TTL
                                     *entry.get_mut() = dataset;
@@ -234,29 +454,39 @@ impl HistoricalCacheService {
         };
 
         // Determine if we already have this in the cache
-        let asset_id = asset_meta.asset();
-        if let ttl_cache::Entry::Occupied(entry) = self.cache.entry(asset_id.clone()) {
+        let key = HistoryKey {
+            asset: asset_meta.asset().clone(),
+            range: msg.range,
+        };
+
+        if let ttl_cache::Entry::Occupied(entry) = self.cache.entry(key.clone()) {
             let dataset = entry.get();
             msg.sender.send(Ok(dataset.clone())).ok();
 
             // Refresh the entry TTL
-            self.cache.remove(asset_id).map(|x|
-                self.cache.insert(asset_id.clone(), x, self.default_ttl));
+            self.cache.remove(&key).map(|x|
+                self.cache.insert(key.clone(), x, self.default_ttl));
 
             return;
         }
 
-        let result = fetch(&self.pool, asset_meta).await;
+        let result = fetch(&self.pool, asset_meta, self.agent.for_asset(asset_meta.asset()), &msg.range).await;
         if let Ok(dataset) = result.as_ref() {
-            let monitor_ctx = HistoricalCoinMonitor {
-                asset_meta: asset_meta.clone(),
-                update_interval: self.update_interval.clone(),
-                pool: self.pool.clone(),
-                parent: ctx.clone(),
-            };
-
-            self.cache.insert(asset_id.clone(), dataset.clone(), self.default_ttl.clone());
-            tokio::spawn(monitor_ctx.into_run());
+            self.cache.insert(key, dataset.clone(), self.default_ttl.clone());
+
+            // Only ranges that trail the current time can change
+            if msg.range.is_trailing() {
+                let monitor_ctx = HistoricalCoinMonitor {
+                    asset_meta: asset_meta.clone(),
+                    range: msg.range,
+                    update_interval: self.update_interval.clone(),
+                    pool: self.pool.clone(),
+                    agent: self.agent.clone(),
+                    parent: ctx.clone(),
+                };
+
+                tokio::spawn(monitor_ctx.into_run());
+            }
         }
         msg.sender.send(result).ok();
     }
@@ -265,12 +495,21 @@ impl HistoricalCacheService {
 
 async fn fetch(
     pool: &PgPool,
-    asset_meta: &FinancialAssetWithMetadata<FinancialDominanceAsset>
+    asset_meta: &FinancialAssetWithMetadata<FinancialDominanceAsset>,
+    agent: &str,
+    range_query: &HistoryRangeQuery,
 ) -> Result<Arc<ClientFindByIdHistoryDataset>, ClientFindByIdHistoryError> {
 
+    let range = range_query.resolve(Utc::now())?;
+
     // Manually perform a slow fetch
     let db_result = CoinDominanceRepo::find_by_id_history(
         &asset_meta.asset(),
+        agent,
+        range.from,
+        range.to,
+        range.interval.as_seconds(),
+        MAX_HISTORY_ROWS,
         pool)
         .await;
 
@@ -286,7 +525,7 @@ async fn fetch(
         .map(|r| {
             let value = r.dominance_percentage;
             ClientFindByIdHistoryEntry {
-                tick: r.timestamp_utc_minutely,
+                tick: r.timestamp_utc_bucket,
                 timestamp_original: r.timestamp_utc_exact,
                 provenance_uuid: r.provenance_uuid,
                 price: asset_meta.value_of(&value),
@@ -297,8 +536,109 @@ async fn fetch(
 
     let dataset = ClientFindByIdHistoryDataset {
         asset: asset_meta.clone().into_any(),
+        interval: range.interval,
+        from: range.from,
+        to: range.to,
         rows: Arc::new(rows),
     };
 
     Ok(Arc::new(dataset))
-}
\ No newline at end of file
+}
+pub async fn fetch_candles(
+    pool: &PgPool,
+    asset_meta: &FinancialAssetWithMetadata<FinancialDominanceAsset>,
+    agent: &str,
+    range_query: &HistoryRangeQuery,
+) -> Result<ClientCandleDataset, ClientFindByIdHistoryError> {
+
+    let range = range_query.resolve(Utc::now())?;
+
+    let db_result = CoinDominanceRepo::find_by_id_candles(
+        asset_meta.asset(),
+        agent,
+        range.from,
+        range.to,
+        range.interval.as_seconds(),
+        MAX_HISTORY_ROWS,
+        pool)
+        .await;
+
+    let db_rows = match db_result {
+        Err(e @ RepositoryError::SqlError { .. }) => {
+            error!("Failed to fetch candles for coin `{:?}`: {}", asset_meta.asset(), e);
+            return Err(ClientFindByIdHistoryError::DbError);
+        }
+        Ok(x) => x
+    };
+
+    // Candles are built from the stored percentages, so the asset's inversion
+    // (e.g. ALTDOM) and rounding are applied per price afterwards.
+    let rows = db_rows.into_iter()
+        .map(|r| {
+            let stored = Ohlc::new(r.open, r.high, r.low, r.close);
+            ClientCandleEntry {
+                tick: r.timestamp_utc_bucket,
+                price: stored.map_values(|x| asset_meta.value_of(x)),
+                samples: r.samples,
+            }
+        })
+        .collect();
+
+    Ok(ClientCandleDataset {
+        asset: asset_meta.clone().into_any(),
+        interval: range.interval,
+        from: range.from,
+        to: range.to,
+        rows,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp(secs, 0)
+    }
+
+    #[test]
+    fn should_parse_intervals() {
+        assert_eq!(HistoryInterval::from_str("1m").unwrap(), HistoryInterval::OneMinute);
+        assert_eq!(HistoryInterval::from_str("15M").unwrap(), HistoryInterval::FifteenMinutes);
+        assert_eq!(HistoryInterval::from_str("1d").unwrap(), HistoryInterval::OneDay);
+        assert!(HistoryInterval::from_str("2m").is_err());
+
+        let x: HistoryInterval = serde_json::from_str(r#""1h""#).unwrap();
+        assert_eq!(x, HistoryInterval::OneHour);
+        assert_eq!(serde_json::to_string(&x).unwrap(), r#""1h""#);
+    }
+
+    #[test]
+    fn should_truncate_to_bucket_start() {
+        assert_eq!(HistoryInterval::FiveMinutes.truncate(ts(1609339955)), ts(1609339800));
+        assert_eq!(HistoryInterval::OneDay.truncate(ts(1609339955)), ts(1609286400));
+    }
+
+    #[test]
+    fn default_range_should_trail_72_hours_of_minutes() {
+        let now = ts(1609339955);
+        let query = HistoryRangeQuery::new(None, None, DEFAULT_HISTORY_INTERVAL).unwrap();
+        let range = query.resolve(now).unwrap();
+        assert_eq!(range.to, ts(1609339920));
+        assert_eq!(range.to - range.from, chrono::Duration::hours(72));
+        assert_eq!(range.row_count(), DEFAULT_HISTORY_ROWS);
+    }
+
+    #[test]
+    fn should_reject_invalid_or_oversized_ranges() {
+        let result = HistoryRangeQuery::new(Some(ts(1609339955)), Some(ts(1609300000)), HistoryInterval::OneMinute);
+        assert!(matches!(result, Err(ClientFindByIdHistoryError::InvalidRange)));
+
+        let month = 30 * 24 * 60 * 60;
+        let result = HistoryRangeQuery::new(Some(ts(1609339955 - month)), Some(ts(1609339955)), HistoryInterval::OneMinute);
+        assert!(matches!(result, Err(ClientFindByIdHistoryError::TooManyRows { .. })));
+
+        let result = HistoryRangeQuery::new(Some(ts(1609339955 - month)), Some(ts(1609339955)), HistoryInterval::FiveMinutes);
+        assert!(result.is_ok());
+    }
+}
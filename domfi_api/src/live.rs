@@ -0,0 +1,83 @@
+use std::sync::Arc;
+
+use tokio::sync::broadcast;
+use tokio::time::Duration;
+
+use sqlx::PgPool;
+use bigdecimal::BigDecimal;
+use serde::Serialize;
+
+use domfi_data::pg::notify::{listen_snapshots, CoinDominanceSnapshot, SnapshotFilter};
+use domfi_domain::models::{FinancialAssetWithMetadata, FinancialDominanceAsset, FinancialAssetValueOf, FinancialAssetRawValueOf};
+
+pub type LiveSnapshot = Arc<CoinDominanceSnapshot>;
+
+/// Subscribe to receive every new snapshot committed by the serving agent
+pub type LiveSnapshotServiceRef = broadcast::Sender<LiveSnapshot>;
+
+/// Relays snapshots of the serving agent from the `db_notify` channel to all subscribers.
+/// Snapshots not newer than the last one relayed, e.g. from a replay, are dropped.
+pub struct LiveSnapshotService {
+    pool: PgPool,
+    tx: broadcast::Sender<LiveSnapshot>,
+    agent: String,
+    quiet_period: Duration,
+}
+
+impl LiveSnapshotService {
+    pub fn new(pool: PgPool, agent: String, buffer_size: usize, quiet_period: Duration) -> (LiveSnapshotService, LiveSnapshotServiceRef) {
+        let (tx, _) = broadcast::channel(buffer_size);
+        let ctx = LiveSnapshotService {
+            pool,
+            tx: tx.clone(),
+            agent,
+            quiet_period,
+        };
+
+        (ctx, tx)
+    }
+
+    pub async fn into_run(self) {
+        info!("Starting live snapshot service for agent '{}'", self.agent);
+        let mut latest = None;
+        loop {
+            let filter = SnapshotFilter::new(self.agent.clone(), latest);
+            let mut rx = match listen_snapshots(&self.pool, Some(filter), self.quiet_period, 1024).await {
+                Err(e) => {
+                    error!("Failed to subscribe to snapshots. Retrying in 5s. Cause: {}", e);
+                    tokio::time::delay_for(Duration::from_secs(5)).await;
+                    continue;
+                },
+                Ok(x) => x,
+            };
+
+            while let Some(snapshot) = rx.recv().await {
+                debug!("[{}] Received snapshot at {}", snapshot.provenance_uuid, snapshot.timestamp);
+                latest = Some(snapshot.timestamp);
+
+                // Sending only fails if nobody is subscribed right now
+                self.tx.send(Arc::new(snapshot)).ok();
+            }
+
+            warn!("Snapshot listener stopped. Reconnecting...");
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ClientLivePrice {
+    pub price: BigDecimal,
+    pub price_original: BigDecimal,
+}
+
+pub fn price_of(
+    snapshot: &CoinDominanceSnapshot,
+    asset_meta: &FinancialAssetWithMetadata<FinancialDominanceAsset>
+) -> Option<ClientLivePrice> {
+    let row = snapshot.find_by_coin_id(asset_meta.asset().underlying().symbol().id())?;
+    let value = &row.market_dominance_percentage;
+    Some(ClientLivePrice {
+        price: asset_meta.value_of(value),
+        price_original: asset_meta.raw_value_of(value).into_owned(),
+    })
+}
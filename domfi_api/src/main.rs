@@ -3,6 +3,12 @@ mod repo;
 mod base64;
 mod convert;
 mod historical;
+mod live;
+mod settlement;
+mod twap;
+mod consensus;
+mod signing;
+mod serving;
 
 #[macro_use]
 extern crate log;
@@ -21,6 +27,11 @@ use listenfd::ListenFd;
 
 use domfi_util::init_logging;
 use crate::historical::HistoricalCacheService;
+use crate::live::LiveSnapshotService;
+use crate::settlement::SettlementConfig;
+use crate::consensus::ConsensusConfig;
+use crate::signing::PriceSigner;
+use crate::serving::ServingAgent;
 
 const DEFAULT_LOG_FILTERS: &'static str =
     "actix_server=info,actix_web=info,sqlx=warn,coingecko_cache_api=info,warn";
@@ -39,21 +50,42 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let database_url = env::var("DOMFI_API_DATABASE_URL").expect("DATABASE_URL is not set in .env file");
     let db_pool = PgPool::connect(&database_url).await?;
 
+    let serving_agent = ServingAgent::from_env("DOMFI_API");
+
     let (historical_cache_service, history_rx) =
         HistoricalCacheService::new(
             db_pool.clone(),
+            serving_agent.clone(),
             1024,
             std::time::Duration::from_secs(5 * 60),
             std::time::Duration::from_secs(45));
 
     tokio::spawn(historical_cache_service.into_run());
 
+    let (live_snapshot_service, live_rx) =
+        LiveSnapshotService::new(
+            db_pool.clone(),
+            serving_agent.name().to_owned(),
+            64,
+            std::time::Duration::from_millis(250));
+
+    tokio::spawn(live_snapshot_service.into_run());
+
+    let settlement_config = SettlementConfig::from_env("DOMFI_API");
+    let consensus_config = ConsensusConfig::from_env("DOMFI_API");
+    let price_signer = PriceSigner::from_env("DOMFI_API")?;
+
     // HTTP Server
     let mut server = HttpServer::new(move || {
         App::new()
             .wrap(Logger::new(r#"%{r}a [%a] "%r" %s %b "%{Referer}i" "%{User-Agent}i" %Dms"#))
             .wrap(middleware::Compress::default())
             .data(history_rx.clone())
+            .data(live_rx.clone())
+            .data(serving_agent.clone())
+            .data(settlement_config.clone())
+            .data(consensus_config.clone())
+            .data(price_signer.clone())
             .data(db_pool.clone())
             .service(web::scope("/api/v0/")
                 .service(api::services()))
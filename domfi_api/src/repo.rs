@@ -1,11 +1,12 @@
-use std::ops::Add;
 use futures::prelude::*;
 use sqlx::PgPool;
 use sqlx::types::Uuid;
 use sqlx::types::BigDecimal;
-use chrono::{DateTime, Utc, SubsecRound, Timelike, Duration, TimeZone};
+use chrono::{DateTime, Utc, TimeZone};
 use snafu::{Snafu, ResultExt};
 use domfi_domain::models::FinancialDominanceAsset;
+use domfi_data::pg::ops::coin_dominance_entry;
+use domfi_data::pg::notify::{CoinDominanceSnapshot, CoinDominanceNotifyRow, SnapshotAssembler, SnapshotFilter};
 
 pub struct OriginMetadata {
     pub requested_timestamp_utc: DateTime<Utc>,
@@ -70,7 +71,7 @@ pub struct StorageBlob {
 }
 
 pub struct FindByIdHistoryRow {
-    pub timestamp_utc_minutely: DateTime<Utc>,
+    pub timestamp_utc_bucket: DateTime<Utc>,
     pub timestamp_utc_exact: DateTime<Utc>,
     pub provenance_uuid: Uuid,
     pub dominance_percentage: BigDecimal,
@@ -81,6 +82,22 @@ pub struct FindByIdHistoryDataset {
     pub rows: Vec<FindByIdHistoryRow>,
 }
 
+pub struct FindByIdCandleRow {
+    pub timestamp_utc_bucket: DateTime<Utc>,
+    pub open: BigDecimal,
+    pub high: BigDecimal,
+    pub low: BigDecimal,
+    pub close: BigDecimal,
+    pub samples: i64,
+}
+
+pub struct FindByIdWindowRow {
+    pub timestamp_utc: DateTime<Utc>,
+    pub provenance_uuid: Uuid,
+    pub agent: String,
+    pub dominance_percentage: BigDecimal,
+}
+
 impl ObjectStorageRepo {
     pub async fn get_by_sha256(hash: &[u8], pool: &PgPool) -> Result<StorageBlob, RepositoryError> {
 
@@ -148,77 +165,24 @@ impl DataOriginRepo {
 
 pub struct CoinDominanceRepo { }
 
-pub struct TimestampAgent {
-    timestamp: DateTime<Utc>,
-    agent: String,
-}
-
 impl CoinDominanceRepo {
 
-    pub fn round_timestamp(ts: DateTime<Utc>) -> DateTime<Utc> {
-        ts.trunc_subsecs(0).with_second(0).unwrap()
-    }
-
-    pub async fn latest_timestamp_agent(pool: &PgPool) -> Result<TimestampAgent, RepositoryError> {
-
-        let row = sqlx::query!(r#"
-            select
-                timestamp_utc, agent
-            from
-                coin_dominance
-            where
-                timestamp_utc = (
-                    select max(timestamp_utc) from coin_dominance
-                )
-            limit 1
-        "#)
-            .fetch_one(pool)
-            .await
-            .context(SqlError)?;
-
-        Ok(TimestampAgent {
-            timestamp: Utc.from_utc_datetime(&row.timestamp_utc),
-            agent: row.agent,
-        })
-    }
-
-    pub async fn timestamp_from_range(ts: DateTime<Utc>, pool: &PgPool) -> Result<TimestampAgent, RepositoryError> {
-
-        let actual_ts = Self::round_timestamp(ts);
-        let actual_ts_plus_1 = actual_ts.add(Duration::minutes(1));
-
-        let row = sqlx::query!(r#"
-            select
-                timestamp_utc, agent
-            from
-                coin_dominance
-            where
-                timestamp_utc between $1 and $2
-            order by timestamp_utc asc
-            limit 1
-        "#,
-            actual_ts.naive_utc(),
-            actual_ts_plus_1.naive_utc(),
-            )
-            .fetch_one(pool)
-            .await
-            .context(SqlError)?;
-
-        Ok(TimestampAgent {
-            timestamp: Utc.from_utc_datetime(&row.timestamp_utc),
-            agent: row.agent,
-        })
+    /// Resolves the timestamp of the agent's snapshot served for `ts`, shared with the evidence
+    /// export so that both agree
+    async fn served_timestamp(agent: &str, ts: Option<DateTime<Utc>>, pool: &PgPool) -> Result<DateTime<Utc>, RepositoryError> {
+        coin_dominance_entry::find_served_timestamp(agent, ts, pool).await
+            .context(SqlError)?
+            .ok_or(sqlx::Error::RowNotFound)
+            .context(SqlError)
     }
 
     pub async fn find_by_timestamp_rounded(
+        agent: &str,
         ts: Option<DateTime<Utc>>,
         pool: &PgPool
     ) -> Result<FindByTimestampResult, RepositoryError> {
 
-        let timestamp_agent = match ts {
-            Some(ts) => Self::timestamp_from_range(ts, pool).await?,
-            None => Self::latest_timestamp_agent(pool).await?,
-        };
+        let timestamp = Self::served_timestamp(agent, ts, pool).await?;
 
         let mut cursor =
             sqlx::query!(r#"
@@ -247,8 +211,8 @@ impl CoinDominanceRepo {
                     -- then sort by market cap descending
                     data.market_cap_usd desc
                 "#,
-                timestamp_agent.timestamp.naive_utc(),
-                timestamp_agent.agent)
+                timestamp.naive_utc(),
+                agent)
                 .fetch(pool);
 
         let mut elements = Vec::new();
@@ -293,14 +257,12 @@ impl CoinDominanceRepo {
 
     pub async fn find_by_id_at_timestamp_rounded(
         asset: &FinancialDominanceAsset,
+        agent: &str,
         ts: Option<DateTime<Utc>>,
         pool: &PgPool
     ) -> Result<PricingResult, RepositoryError> {
 
-        let timestamp_agent = match ts {
-            Some(ts) => Self::timestamp_from_range(ts, pool).await?,
-            None => Self::latest_timestamp_agent(pool).await?,
-        };
+        let timestamp = Self::served_timestamp(agent, ts, pool).await?;
 
         let x =
             sqlx::query!(r#"
@@ -318,8 +280,8 @@ impl CoinDominanceRepo {
                     and data.coin_id = $3
                 limit 1
                 "#,
-                timestamp_agent.timestamp.naive_utc(),
-                timestamp_agent.agent,
+                timestamp.naive_utc(),
+                agent,
                 asset.underlying().symbol().id())
                 .fetch_one(pool)
                 .await
@@ -340,35 +302,56 @@ impl CoinDominanceRepo {
         })
     }
 
+    /// Returns the agent's first snapshot of every `bucket_seconds` wide bucket within `[from, to)`
     pub async fn find_by_id_history(
         asset: &FinancialDominanceAsset,
+        agent: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        bucket_seconds: i64,
+        max_rows: i64,
         pool: &PgPool
     ) -> Result<FindByIdHistoryDataset, RepositoryError> {
 
         let rows =
             sqlx::query!(r#"
-                select distinct on (date_trunc('minute', timestamp_utc))
-                    date_trunc('minute', timestamp_utc) as timestamp_utc_minutely,
+                select distinct on (bucket)
+                    bucket as "timestamp_utc_bucket!",
                     timestamp_utc,
                     provenance_uuid,
                     market_dominance_percentage
-                from
-                    coin_dominance
-                where
-                    coin_id = $1
-                    and timestamp_utc >= now() at time zone 'utc' - '72 hours'::interval
-                    and timestamp_utc < date_trunc('minute', now() at time zone 'utc')
+                from (
+                    select
+                        to_timestamp(floor(extract(epoch from timestamp_utc))::bigint / $4::bigint * $4::bigint)
+                            at time zone 'utc' as bucket,
+                        timestamp_utc,
+                        provenance_uuid,
+                        market_dominance_percentage
+                    from
+                        coin_dominance
+                    where
+                        coin_id = $1
+                        and agent = $6
+                        and timestamp_utc >= $2
+                        and timestamp_utc < $3
+                ) as data
                 order by
-                    date_trunc('minute', timestamp_utc),
+                    bucket,
                     timestamp_utc asc
+                limit $5
                 "#,
-                asset.underlying().symbol().id())
+                asset.underlying().symbol().id(),
+                from.naive_utc(),
+                to.naive_utc(),
+                bucket_seconds,
+                max_rows,
+                agent)
                 .fetch_all(pool)
                 .await
                 .context(SqlError)?
                 .into_iter()
                 .map(|r| FindByIdHistoryRow {
-                    timestamp_utc_minutely: Utc.from_utc_datetime(&r.timestamp_utc_minutely.unwrap()),
+                    timestamp_utc_bucket: Utc.from_utc_datetime(&r.timestamp_utc_bucket),
                     timestamp_utc_exact: Utc.from_utc_datetime(&r.timestamp_utc),
                     provenance_uuid: r.provenance_uuid,
                     dominance_percentage: r.market_dominance_percentage,
@@ -380,4 +363,305 @@ impl CoinDominanceRepo {
             rows,
         })
     }
-}
\ No newline at end of file
+
+    /// Aggregates all snapshots of the agent of every `bucket_seconds` wide bucket within
+    /// `[from, to)` into open/high/low/close of the stored dominance percentage
+    pub async fn find_by_id_candles(
+        asset: &FinancialDominanceAsset,
+        agent: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        bucket_seconds: i64,
+        max_rows: i64,
+        pool: &PgPool
+    ) -> Result<Vec<FindByIdCandleRow>, RepositoryError> {
+
+        let rows =
+            sqlx::query!(r#"
+                select
+                    bucket as "timestamp_utc_bucket!",
+                    (array_agg(market_dominance_percentage order by timestamp_utc asc))[1] as "open!",
+                    max(market_dominance_percentage) as "high!",
+                    min(market_dominance_percentage) as "low!",
+                    (array_agg(market_dominance_percentage order by timestamp_utc desc))[1] as "close!",
+                    count(*) as "samples!"
+                from (
+                    select
+                        to_timestamp(floor(extract(epoch from timestamp_utc))::bigint / $4::bigint * $4::bigint)
+                            at time zone 'utc' as bucket,
+                        timestamp_utc,
+                        market_dominance_percentage
+                    from
+                        coin_dominance
+                    where
+                        coin_id = $1
+                        and agent = $6
+                        and timestamp_utc >= $2
+                        and timestamp_utc < $3
+                ) as data
+                group by
+                    bucket
+                order by
+                    bucket
+                limit $5
+                "#,
+                asset.underlying().symbol().id(),
+                from.naive_utc(),
+                to.naive_utc(),
+                bucket_seconds,
+                max_rows,
+                agent)
+                .fetch_all(pool)
+                .await
+                .context(SqlError)?
+                .into_iter()
+                .map(|r| FindByIdCandleRow {
+                    timestamp_utc_bucket: Utc.from_utc_datetime(&r.timestamp_utc_bucket),
+                    open: r.open,
+                    high: r.high,
+                    low: r.low,
+                    close: r.close,
+                    samples: r.samples,
+                })
+                .collect();
+
+        Ok(rows)
+    }
+
+    /// Returns every snapshot of the agent within `[from, to]`, oldest first
+    pub async fn find_by_id_window(
+        asset: &FinancialDominanceAsset,
+        agent: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        pool: &PgPool
+    ) -> Result<Vec<FindByIdWindowRow>, RepositoryError> {
+
+        let rows =
+            sqlx::query!(r#"
+                select
+                    timestamp_utc,
+                    provenance_uuid,
+                    agent,
+                    market_dominance_percentage
+                from
+                    coin_dominance
+                where
+                    coin_id = $1
+                    and timestamp_utc >= $2
+                    and timestamp_utc <= $3
+                    and agent = $4
+                order by
+                    timestamp_utc asc
+                "#,
+                asset.underlying().symbol().id(),
+                from.naive_utc(),
+                to.naive_utc(),
+                agent)
+                .fetch_all(pool)
+                .await
+                .context(SqlError)?
+                .into_iter()
+                .map(|r| FindByIdWindowRow {
+                    timestamp_utc: Utc.from_utc_datetime(&r.timestamp_utc),
+                    provenance_uuid: r.provenance_uuid,
+                    agent: r.agent,
+                    dominance_percentage: r.market_dominance_percentage,
+                })
+                .collect();
+
+        Ok(rows)
+    }
+
+    /// Returns every snapshot of the agent within `(from, to)`, oldest first, preceded by its latest
+    /// snapshot at or before `from` as it is the one in effect at the start of the range
+    pub async fn find_by_id_series(
+        asset: &FinancialDominanceAsset,
+        agent: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        pool: &PgPool
+    ) -> Result<Vec<FindByIdWindowRow>, RepositoryError> {
+
+        let rows =
+            sqlx::query!(r#"
+                select
+                    timestamp_utc as "timestamp_utc!",
+                    provenance_uuid as "provenance_uuid!",
+                    agent as "agent!",
+                    market_dominance_percentage as "market_dominance_percentage!"
+                from (
+                    (
+                        select
+                            timestamp_utc,
+                            provenance_uuid,
+                            agent,
+                            market_dominance_percentage
+                        from
+                            coin_dominance
+                        where
+                            coin_id = $1
+                            and timestamp_utc <= $2
+                            and agent = $4
+                        order by
+                            timestamp_utc desc
+                        limit 1
+                    )
+                    union all
+                    (
+                        select
+                            timestamp_utc,
+                            provenance_uuid,
+                            agent,
+                            market_dominance_percentage
+                        from
+                            coin_dominance
+                        where
+                            coin_id = $1
+                            and timestamp_utc > $2
+                            and timestamp_utc < $3
+                            and agent = $4
+                    )
+                ) as data
+                order by
+                    timestamp_utc asc
+                "#,
+                asset.underlying().symbol().id(),
+                from.naive_utc(),
+                to.naive_utc(),
+                agent)
+                .fetch_all(pool)
+                .await
+                .context(SqlError)?
+                .into_iter()
+                .map(|r| FindByIdWindowRow {
+                    timestamp_utc: Utc.from_utc_datetime(&r.timestamp_utc),
+                    provenance_uuid: r.provenance_uuid,
+                    agent: r.agent,
+                    dominance_percentage: r.market_dominance_percentage,
+                })
+                .collect();
+
+        Ok(rows)
+    }
+
+    /// Returns the latest snapshot at or before `at` of each of the agents, ordered by agent.
+    /// Agents without any such snapshot are left out.
+    pub async fn find_by_id_latest_per_agent(
+        asset: &FinancialDominanceAsset,
+        agents: &[String],
+        at: DateTime<Utc>,
+        pool: &PgPool
+    ) -> Result<Vec<FindByIdWindowRow>, RepositoryError> {
+
+        let rows =
+            sqlx::query!(r#"
+                select distinct on (agent)
+                    timestamp_utc,
+                    provenance_uuid,
+                    agent,
+                    market_dominance_percentage
+                from
+                    coin_dominance
+                where
+                    coin_id = $1
+                    and agent = any($2)
+                    and timestamp_utc <= $3
+                order by
+                    agent,
+                    timestamp_utc desc
+                "#,
+                asset.underlying().symbol().id(),
+                agents,
+                at.naive_utc())
+                .fetch_all(pool)
+                .await
+                .context(SqlError)?
+                .into_iter()
+                .map(|r| FindByIdWindowRow {
+                    timestamp_utc: Utc.from_utc_datetime(&r.timestamp_utc),
+                    provenance_uuid: r.provenance_uuid,
+                    agent: r.agent,
+                    dominance_percentage: r.market_dominance_percentage,
+                })
+                .collect();
+
+        Ok(rows)
+    }
+
+    /// Returns up to `max_snapshots` snapshots of the agent inserted after the given provenance
+    /// and taken after it, in insertion order
+    pub async fn find_snapshots_after_provenance(
+        provenance_uuid: Uuid,
+        agent: &str,
+        max_snapshots: i64,
+        pool: &PgPool
+    ) -> Result<Vec<CoinDominanceSnapshot>, RepositoryError> {
+
+        let rows =
+            sqlx::query!(r#"
+                select
+                    data.id,
+                    data.provenance_uuid,
+                    data.object_id,
+                    data.timestamp_utc,
+                    data.imported_at_utc,
+                    data.agent,
+                    data.coin_id,
+                    data.coin_name,
+                    data.market_cap_usd,
+                    data.market_dominance_percentage
+                from
+                    coin_dominance as data
+                where
+                    data.agent = $2
+                    and data.provenance_uuid in (
+                        select
+                            provenance_uuid
+                        from
+                            coin_dominance
+                        where
+                            agent = $2
+                            and id > (select max(id) from coin_dominance where provenance_uuid = $1)
+                            and timestamp_utc > (select max(timestamp_utc) from coin_dominance where provenance_uuid = $1)
+                        group by
+                            provenance_uuid
+                        order by
+                            min(id)
+                        limit $3
+                    )
+                order by
+                    data.id
+                "#,
+                provenance_uuid,
+                agent,
+                max_snapshots)
+                .fetch_all(pool)
+                .await
+                .context(SqlError)?;
+
+        let mut assembler = SnapshotAssembler::with_filter(SnapshotFilter::new(agent, None));
+        let mut snapshots = Vec::new();
+        for r in rows {
+            let row = CoinDominanceNotifyRow {
+                id: r.id,
+                provenance_uuid: r.provenance_uuid,
+                object_id: r.object_id,
+                timestamp_utc: r.timestamp_utc,
+                imported_at_utc: r.imported_at_utc,
+                agent: r.agent,
+                coin_id: r.coin_id,
+                coin_name: r.coin_name,
+                market_cap_usd: r.market_cap_usd,
+                market_dominance_percentage: r.market_dominance_percentage,
+            };
+
+            if let Some(snapshot) = assembler.push(row) {
+                snapshots.push(snapshot);
+            }
+        }
+        snapshots.extend(assembler.flush());
+
+        Ok(snapshots)
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+
+use log::info;
+
+use domfi_data::serving::{default_agent_for, DEFAULT_SERVING_AGENT};
+use domfi_domain::models::FinancialDominanceAsset;
+use domfi_domain::models::financial_assets::CANONICAL_DEFAULT_ASSETS;
+use domfi_util::ConfigContext;
+
+/// Loader agents whose snapshots the price routes serve. `/price` and the streams serve the
+/// snapshots of one agent, while the routes of a single asset, from its latest price to history,
+/// candles, settlements and TWAPs, serve the agent resolved for that asset. Only consensus
+/// compares several agents.
+#[derive(Clone, Debug)]
+pub struct ServingAgent {
+    default: String,
+    assets: HashMap<FinancialDominanceAsset, String>,
+}
+
+impl ServingAgent {
+    /// Reads `<prefix>_AGENT`, falling back to the default when unset or empty, and the agent of
+    /// each asset from `<prefix>_AGENT_<ASSET>`, e.g. `DOMFI_API_AGENT_SOLDOM`. Unset, an asset is
+    /// served from `<prefix>_AGENT`, unless [`default_agent_for`] serves it from another agent
+    pub fn from_env(prefix: &str) -> ServingAgent {
+        let config = ConfigContext::new(prefix);
+        let var = |key: &str| config.var(key).ok()
+            .map(|x| x.trim().to_owned())
+            .filter(|x| !x.is_empty());
+
+        let default = var("AGENT").unwrap_or_else(|| DEFAULT_SERVING_AGENT.to_owned());
+        info!("Serving prices of agent '{}'", default);
+
+        let mut assets = HashMap::new();
+        for (id, asset_meta) in CANONICAL_DEFAULT_ASSETS.iter() {
+            let agent = var(&format!("AGENT_{}", id.to_uppercase())).or_else(|| {
+                let fallback = default_agent_for(asset_meta.asset());
+                if fallback != DEFAULT_SERVING_AGENT { Some(fallback.to_owned()) } else { None }
+            });
+
+            if let Some(agent) = agent.filter(|x| x != &default) {
+                info!("Serving prices of {} from agent '{}'", id, agent);
+                assets.insert(asset_meta.asset().clone(), agent);
+            }
+        }
+
+        ServingAgent { default, assets }
+    }
+
+    /// Agent of `/price` and the streams
+    pub fn name(&self) -> &str {
+        &self.default
+    }
+
+    /// Agent of the routes of a single asset
+    pub fn for_asset(&self, asset: &FinancialDominanceAsset) -> &str {
+        self.assets.get(asset).unwrap_or(&self.default)
+    }
+}
@@ -0,0 +1,202 @@
+use std::borrow::Borrow;
+
+use sqlx::PgPool;
+use chrono::{DateTime, Utc};
+use chrono::serde::ts_seconds;
+use bigdecimal::BigDecimal;
+use uuid::Uuid;
+use log::{error, warn};
+use snafu::Snafu;
+use serde::Serialize;
+
+use domfi_domain::aggregate::{median, TwapCalculator};
+use domfi_domain::models::{FinancialAssetWithMetadata, FinancialAssetWithMetadataOfAny, FinancialAssetRawValueOf, FinancialDominanceAsset};
+use domfi_util::ConfigContext;
+use crate::repo::{RepositoryError, CoinDominanceRepo};
+
+/// Window used when the client does not specify one
+pub const DEFAULT_SETTLEMENT_WINDOW_SECONDS: i64 = 5 * 60;
+
+/// Upper bound on the window a client may request
+pub const DEFAULT_SETTLEMENT_MAX_WINDOW_SECONDS: i64 = 60 * 60;
+
+#[derive(Clone, Debug)]
+pub struct SettlementConfig {
+    pub default_window: chrono::Duration,
+    pub max_window: chrono::Duration,
+}
+
+impl SettlementConfig {
+    /// Reads `<prefix>_SETTLEMENT_WINDOW_SECONDS` and `<prefix>_SETTLEMENT_MAX_WINDOW_SECONDS`,
+    /// falling back to the defaults when unset or invalid.
+    pub fn from_env(prefix: &str) -> SettlementConfig {
+        let config = ConfigContext::new(prefix);
+
+        let seconds_of = |name: &str, default: i64| -> i64 {
+            match config.var(name) {
+                Err(_) => default,
+                Ok(x) => match x.parse::<i64>() {
+                    Ok(x) if x > 0 => x,
+                    _ => {
+                        warn!("Invalid '{}': Expected a positive number of seconds. Using default instead: {}", config.name_of(name), default);
+                        default
+                    }
+                },
+            }
+        };
+
+        let default_window = seconds_of("SETTLEMENT_WINDOW_SECONDS", DEFAULT_SETTLEMENT_WINDOW_SECONDS);
+        let max_window = seconds_of("SETTLEMENT_MAX_WINDOW_SECONDS", DEFAULT_SETTLEMENT_MAX_WINDOW_SECONDS);
+
+        SettlementConfig {
+            default_window: chrono::Duration::seconds(default_window.min(max_window)),
+            max_window: chrono::Duration::seconds(max_window),
+        }
+    }
+
+    /// Resolves the window requested by the client against the configured bounds
+    pub fn window(&self, requested_seconds: Option<u64>) -> Result<chrono::Duration, ClientSettlementError> {
+        let requested = match requested_seconds {
+            None => return Ok(self.default_window),
+            Some(x) => x.min(i64::MAX as u64) as i64,
+        };
+
+        if requested < 1 {
+            return Err(ClientSettlementError::InvalidWindow);
+        }
+
+        if requested > self.max_window.num_seconds() {
+            return Err(ClientSettlementError::WindowTooLarge {
+                requested,
+                max: self.max_window.num_seconds(),
+            });
+        }
+
+        Ok(chrono::Duration::seconds(requested))
+    }
+}
+
+#[derive(Snafu, Debug)]
+pub enum ClientSettlementError {
+    #[snafu(display("Unknown instrument or not allowed."))]
+    CoinUnknownOrNotAllowed,
+
+    #[snafu(display("Failed to fetch coin dominance for settlement."))]
+    DbError,
+
+    #[snafu(display("Invalid timestamp. Expected seconds since the Unix epoch."))]
+    InvalidTimestamp,
+
+    #[snafu(display("Invalid window. Expected at least 1 second."))]
+    InvalidWindow,
+
+    #[snafu(display("Window too large. Requested {} seconds, but at most {} are allowed.", requested, max))]
+    WindowTooLarge {
+        requested: i64,
+        max: i64,
+    },
+
+    #[snafu(display("No snapshots found within the settlement window."))]
+    EmptyWindow,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ClientSettlementInput {
+    pub provenance_uuid: Uuid,
+    #[serde(with = "ts_seconds")]
+    pub timestamp: DateTime<Utc>,
+    /// Unrounded value of the asset, i.e. after inversion for ALTDOM
+    pub value: BigDecimal,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ClientSettlementPrice {
+    pub price: BigDecimal,
+    pub price_original: BigDecimal,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ClientSettlementDataset {
+    pub asset: FinancialAssetWithMetadataOfAny,
+    pub agent: String,
+    #[serde(with = "ts_seconds")]
+    pub timestamp: DateTime<Utc>,
+    pub window_seconds: i64,
+    pub median: ClientSettlementPrice,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub twap: Option<ClientSettlementPrice>,
+    pub inputs: Vec<ClientSettlementInput>,
+}
+
+/// Aggregates every snapshot of the agent within `[timestamp - window, timestamp]`, so a snapshot
+/// taken at the settlement time counts towards the median. The TWAP weights each snapshot by the time
+/// it was in effect until `timestamp`, which is none for such a snapshot. Aggregation happens
+/// on the unrounded values; the asset's rounding is applied to the result only.
+pub async fn fetch_settlement(
+    pool: &PgPool,
+    asset_meta: &FinancialAssetWithMetadata<FinancialDominanceAsset>,
+    agent: &str,
+    timestamp: DateTime<Utc>,
+    window: chrono::Duration,
+    with_twap: bool,
+) -> Result<ClientSettlementDataset, ClientSettlementError> {
+
+    let db_result = CoinDominanceRepo::find_by_id_window(
+        asset_meta.asset(),
+        agent,
+        timestamp - window,
+        timestamp,
+        pool)
+        .await;
+
+    let db_rows = match db_result {
+        Err(e @ RepositoryError::SqlError { .. }) => {
+            error!("Failed to fetch settlement window for coin `{:?}`: {}", asset_meta.asset(), e);
+            return Err(ClientSettlementError::DbError);
+        }
+        Ok(x) => x
+    };
+
+    if db_rows.is_empty() {
+        return Err(ClientSettlementError::EmptyWindow);
+    }
+
+    let inputs: Vec<ClientSettlementInput> = db_rows.into_iter()
+        .map(|r| ClientSettlementInput {
+            provenance_uuid: r.provenance_uuid,
+            timestamp: r.timestamp_utc,
+            value: asset_meta.raw_value_of(&r.dominance_percentage).into_owned(),
+        })
+        .collect();
+
+    let rounded = |x: BigDecimal| ClientSettlementPrice {
+        price: asset_meta.metadata().round(x.borrow()),
+        price_original: x,
+    };
+
+    let values: Vec<BigDecimal> = inputs.iter().map(|x| x.value.clone()).collect();
+    let median = median(&values)
+        .map(rounded)
+        .ok_or(ClientSettlementError::EmptyWindow)?;
+
+    let twap = if with_twap {
+        let samples: Vec<(DateTime<Utc>, BigDecimal)> = inputs.iter()
+            .map(|x| (x.timestamp, x.value.clone()))
+            .collect();
+        TwapCalculator::carry()
+            .calculate(&samples, timestamp - window, timestamp)
+            .map(|x| rounded(x.value))
+    } else {
+        None
+    };
+
+    Ok(ClientSettlementDataset {
+        asset: asset_meta.clone().into_any(),
+        agent: agent.to_owned(),
+        timestamp,
+        window_seconds: window.num_seconds(),
+        median,
+        twap,
+        inputs,
+    })
+}
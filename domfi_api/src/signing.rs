@@ -0,0 +1,166 @@
+use std::sync::Arc;
+
+use bigdecimal::BigDecimal;
+use chrono::{DateTime, Utc};
+use ed25519_dalek::Keypair;
+use log::info;
+use uuid::Uuid;
+
+use domfi_domain::attestation::{PriceAttestation, AttestationError, keypair_from_hex};
+use domfi_domain::models::{FinancialAssetWithMetadata, FinancialAssetValueOf, FinancialDominanceAsset, TickerDisplay};
+use domfi_domain::models::financial_assets::CANONICAL_DEFAULT_ASSETS;
+use domfi_util::ConfigContext;
+use crate::api::models::PriceSignature;
+
+/// Signs price attestations with the key configured in `<prefix>_SIGNING_KEY`
+#[derive(Clone)]
+pub struct PriceSigner {
+    keypair: Arc<Keypair>,
+}
+
+/// Signing is optional; `None` when no key is configured
+pub type PriceSignerRef = Option<PriceSigner>;
+
+impl PriceSigner {
+    pub fn from_env(prefix: &str) -> Result<PriceSignerRef, AttestationError> {
+        let config = ConfigContext::new(prefix);
+
+        let secret_hex = match config.var("SIGNING_KEY") {
+            Err(_) => return Ok(None),
+            Ok(x) => x,
+        };
+
+        let signer = PriceSigner {
+            keypair: Arc::new(keypair_from_hex(&secret_hex)?),
+        };
+
+        info!("Signing prices with public key {}", signer.public_key_hex());
+        Ok(Some(signer))
+    }
+
+    pub fn public_key_hex(&self) -> String {
+        hex::encode(self.keypair.public.as_bytes())
+    }
+
+    pub fn sign_hex(&self, attestation: &PriceAttestation) -> String {
+        hex::encode(attestation.sign(&self.keypair).to_bytes().as_ref())
+    }
+
+    /// Signs the price of the asset from the stored dominance percentage. Every route signs
+    /// through here, so a snapshot is attested under the same ticker id and price everywhere.
+    pub fn sign_price(
+        &self,
+        asset_meta: &FinancialAssetWithMetadata<FinancialDominanceAsset>,
+        percentage: &BigDecimal,
+        timestamp: DateTime<Utc>,
+        provenance_uuid: Uuid,
+    ) -> PriceSignature {
+        let ticker_id = asset_meta.asset().to_ticker_id();
+        let price = asset_meta.value_of(percentage);
+        let attestation = PriceAttestation::new(&ticker_id, &price, timestamp, provenance_uuid);
+
+        PriceSignature {
+            signature: self.sign_hex(&attestation),
+            ticker_id,
+            price,
+        }
+    }
+
+    /// Signs the price of every canonical asset whose coin is part of the snapshot, given as
+    /// coin ids with their stored dominance percentage. Ordered by ticker id.
+    pub fn sign_prices(
+        &self,
+        percentages: &[(&str, &BigDecimal)],
+        timestamp: DateTime<Utc>,
+        provenance_uuid: Uuid,
+    ) -> Vec<PriceSignature> {
+        let mut assets: Vec<_> = CANONICAL_DEFAULT_ASSETS.values().collect();
+        assets.sort_by_cached_key(|x| x.asset().to_ticker_id());
+
+        assets.into_iter()
+            .filter_map(|asset_meta| {
+                let coin_id = asset_meta.asset().underlying().symbol().id();
+                let (_, percentage) = percentages.iter().find(|(id, _)| *id == coin_id)?;
+                Some(self.sign_price(asset_meta, percentage, timestamp, provenance_uuid))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use bigdecimal::BigDecimal;
+    use chrono::{Utc, TimeZone};
+    use uuid::Uuid;
+    use domfi_domain::attestation::{keypair_from_hex, PriceAttestation};
+    use domfi_domain::models::financial_assets::get_canonical_default_asset;
+    use crate::api::models::{PricesMeta, PricesResponse, ResponseStatus};
+    use crate::signing::PriceSigner;
+
+    const SECRET: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+
+    #[test]
+    fn prices_and_price_by_id_should_sign_alike() {
+        let signer = PriceSigner {
+            keypair: Arc::new(keypair_from_hex(SECRET).unwrap()),
+        };
+        let timestamp = Utc.timestamp(1608195600, 0);
+        let uuid = Uuid::from_str("d6c651dd-cd97-183b-2e40-bc464231c962").unwrap();
+        let bitcoin = BigDecimal::from_str("60.2554").unwrap();
+        let ethereum = BigDecimal::from_str("12.3456").unwrap();
+        let others = BigDecimal::from_str("27.399").unwrap();
+
+        let all = signer.sign_prices(&[("bitcoin", &bitcoin), ("ethereum", &ethereum), ("", &others)], timestamp, uuid);
+
+        let tickers: Vec<_> = all.iter().map(|x| x.ticker_id.as_str()).collect();
+        assert_eq!(tickers, vec!["bitcoin^altdom", "bitcoin^dom", "ethereum^dom"]);
+
+        for (id, percentage) in &[("btcdom", &bitcoin), ("altdom", &bitcoin), ("ethdom", &ethereum)] {
+            let asset_meta = get_canonical_default_asset(id).unwrap();
+            let one = signer.sign_price(asset_meta, percentage, timestamp, uuid);
+            let same = all.iter().find(|x| x.ticker_id == one.ticker_id).unwrap();
+            assert_eq!(same.signature, one.signature);
+        }
+    }
+
+    #[test]
+    fn prices_signatures_should_verify_from_the_response_body() {
+        let signer = PriceSigner {
+            keypair: Arc::new(keypair_from_hex(SECRET).unwrap()),
+        };
+        let timestamp = Utc.timestamp(1608195600, 0);
+        let uuid = Uuid::from_str("d6c651dd-cd97-183b-2e40-bc464231c962").unwrap();
+        let bitcoin = BigDecimal::from_str("60.2554").unwrap();
+        let others = BigDecimal::from_str("39.7446").unwrap();
+
+        let response = PricesResponse {
+            status: ResponseStatus::Success,
+            data: vec![("bitcoin", BigDecimal::from_str("60.26").unwrap()), ("others", BigDecimal::from_str("39.74").unwrap())],
+            timestamp,
+            meta: PricesMeta {
+                provenance_uuid: uuid,
+                requested_timestamp: timestamp,
+                actual_timestamp: timestamp,
+            },
+            signatures: signer.sign_prices(&[("bitcoin", &bitcoin), ("", &others)], timestamp, uuid),
+        };
+        let body = serde_json::to_value(&response).unwrap();
+
+        let timestamp = Utc.timestamp(body["timestamp"].as_i64().unwrap(), 0);
+        let uuid = Uuid::from_str(body["meta"]["provenance_uuid"].as_str().unwrap()).unwrap();
+        let signatures = body["signatures"].as_array().unwrap();
+        assert_eq!(signatures.len(), 2);
+
+        for x in signatures {
+            let ticker_id = x["ticker_id"].as_str().unwrap();
+            let price = BigDecimal::from_str(x["price"].as_str().unwrap()).unwrap();
+            PriceAttestation::new(ticker_id, &price, timestamp, uuid)
+                .verify_hex(&signer.public_key_hex(), x["signature"].as_str().unwrap())
+                .unwrap();
+        }
+        assert_eq!(signatures[0]["ticker_id"], "bitcoin^altdom");
+        assert_eq!(signatures[0]["price"], "39.74");
+    }
+}
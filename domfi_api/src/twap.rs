@@ -0,0 +1,121 @@
+use sqlx::PgPool;
+use chrono::{DateTime, Utc};
+use chrono::serde::ts_seconds;
+use bigdecimal::BigDecimal;
+use log::error;
+use snafu::Snafu;
+use serde::Serialize;
+
+use domfi_domain::aggregate::{TwapCalculator, StaleGapMode};
+use domfi_domain::models::{FinancialAssetWithMetadata, FinancialAssetWithMetadataOfAny, FinancialAssetRawValueOf, FinancialDominanceAsset};
+use crate::repo::{RepositoryError, CoinDominanceRepo};
+
+/// Upper bound on the time range a single TWAP request may span
+pub const MAX_TWAP_RANGE_SECONDS: i64 = 24 * 60 * 60;
+
+pub const DEFAULT_TWAP_STALE_GAP_MODE: StaleGapMode = StaleGapMode::Cap;
+
+/// Longest time between two snapshots before the earlier one is considered stale
+pub const DEFAULT_TWAP_MAX_GAP_SECONDS: i64 = 5 * 60;
+
+/// Upper bound on the maximum gap a request may set
+pub const MAX_TWAP_MAX_GAP_SECONDS: i64 = 365 * 24 * 60 * 60;
+
+#[derive(Snafu, Debug)]
+pub enum ClientTwapError {
+    #[snafu(display("Unknown instrument or not allowed."))]
+    CoinUnknownOrNotAllowed,
+
+    #[snafu(display("Failed to fetch coin dominance for TWAP."))]
+    DbError,
+
+    #[snafu(display("Invalid time range. Expected 'from' to be before 'to'."))]
+    InvalidRange,
+
+    #[snafu(display("Time range too large. Requested {} seconds, but at most {} are allowed.", requested, max))]
+    RangeTooLarge {
+        requested: i64,
+        max: i64,
+    },
+
+    #[snafu(display("Invalid maximum gap. Expected between 1 second and 1 year."))]
+    InvalidMaxGap,
+
+    #[snafu(display("No snapshots found to compute the TWAP over."))]
+    NoData,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ClientTwapDataset {
+    pub asset: FinancialAssetWithMetadataOfAny,
+    pub agent: String,
+    #[serde(with = "ts_seconds")]
+    pub from: DateTime<Utc>,
+    #[serde(with = "ts_seconds")]
+    pub to: DateTime<Utc>,
+    pub stale_gap: StaleGapMode,
+    pub max_gap_seconds: i64,
+    pub price: BigDecimal,
+    pub price_original: BigDecimal,
+    pub covered_seconds: i64,
+    pub samples: usize,
+}
+
+/// Computes the TWAP of the agent's snapshots over `[from, to)` on the unrounded values of
+/// the asset, then applies the asset's rounding to the result.
+pub async fn fetch_twap(
+    pool: &PgPool,
+    asset_meta: &FinancialAssetWithMetadata<FinancialDominanceAsset>,
+    agent: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    calculator: TwapCalculator,
+) -> Result<ClientTwapDataset, ClientTwapError> {
+
+    if from >= to {
+        return Err(ClientTwapError::InvalidRange);
+    }
+
+    let requested = (to - from).num_seconds();
+    if requested > MAX_TWAP_RANGE_SECONDS {
+        return Err(ClientTwapError::RangeTooLarge {
+            requested,
+            max: MAX_TWAP_RANGE_SECONDS,
+        });
+    }
+
+    if calculator.max_gap() < chrono::Duration::seconds(1)
+        || calculator.max_gap() > chrono::Duration::seconds(MAX_TWAP_MAX_GAP_SECONDS) {
+        return Err(ClientTwapError::InvalidMaxGap);
+    }
+
+    let db_result = CoinDominanceRepo::find_by_id_series(asset_meta.asset(), agent, from, to, pool).await;
+
+    let db_rows = match db_result {
+        Err(e @ RepositoryError::SqlError { .. }) => {
+            error!("Failed to fetch TWAP series for coin `{:?}`: {}", asset_meta.asset(), e);
+            return Err(ClientTwapError::DbError);
+        }
+        Ok(x) => x
+    };
+
+    let samples: Vec<(DateTime<Utc>, BigDecimal)> = db_rows.into_iter()
+        .map(|r| (r.timestamp_utc, asset_meta.raw_value_of(&r.dominance_percentage).into_owned()))
+        .collect();
+
+    let twap = calculator.calculate(&samples, from, to)
+        .ok_or(ClientTwapError::NoData)?;
+
+    Ok(ClientTwapDataset {
+        asset: asset_meta.clone().into_any(),
+        agent: agent.to_owned(),
+        from,
+        to,
+        stale_gap: calculator.stale_gap_mode(),
+        max_gap_seconds: calculator.max_gap().num_seconds(),
+        price: asset_meta.metadata().rounding().round(&twap.value),
+        price_original: twap.value,
+        covered_seconds: twap.covered.num_seconds(),
+        samples: twap.samples,
+    })
+}
@@ -0,0 +1,570 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
+
+use chrono::serde::{ts_seconds, ts_milliseconds};
+use bigdecimal::{BigDecimal, Zero};
+use serde::Deserialize;
+use serde_with::serde_as;
+use domfi_ext_serde::BigDecimalExact;
+
+use crate::pg::models::CoinDominanceEntry;
+
+/// Decimal places of self-computed dominance percentages. Quotients of market caps
+/// rarely terminate, and this is already more than CoinGecko itself reports.
+pub const COMPUTED_DOMINANCE_SCALE: i64 = 18;
+
+/// Id of the "others" bucket in the historical chart
+pub const HISTORICAL_OTHERS_ID: &str = "others-coingecko-global";
+
+/// Id of the "others" bucket in `/global/coin_dominance`, which has no coin id
+pub const LIVE_OTHERS_ID: &str = "";
+
+lazy_static! {
+    static ref KNOWN_COINS_BY_SYMBOL: HashMap<&'static str, &'static str> = {
+        let mut m = HashMap::new();
+        m.insert("Others", HISTORICAL_OTHERS_ID);
+        m.insert("XLM"   , "stellar");
+        m.insert("XMR"   , "monero");
+        m.insert("NEO"   , "neo");
+        m.insert("EOS"   , "eos");
+
+        m.insert("BSV"   , "bitcoin-cash-sv");
+        m.insert("LINK"  , "chainlink");
+        m.insert("BNB"   , "binancecoin");
+        m.insert("BCH"   , "bitcoin-cash");
+        m.insert("DOT"   , "polkadot");
+        m.insert("LTC"   , "litecoin");
+        m.insert("XRP"   , "ripple");
+        m.insert("USDT"  , "tether");
+        m.insert("ETH"   , "ethereum");
+        m.insert("BTC"   , "bitcoin");
+        m
+    };
+}
+
+/// Response of `/api/v3/global/coin_dominance`, as polled by `domfi_loader`
+#[derive(Deserialize, Debug)]
+pub struct CoinDominanceResponse {
+    pub data: Vec<CoinDominance>,
+
+    #[serde(with = "ts_seconds")]
+    pub timestamp: DateTime<Utc>,
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct CoinDominance {
+    pub name: String,
+    pub id: String,
+    #[serde_as(as = "BigDecimalExact")]
+    pub market_cap_usd: BigDecimal,
+    #[serde_as(as = "BigDecimalExact")]
+    pub dominance_percentage: BigDecimal,
+}
+
+impl CoinDominanceResponse {
+    pub fn into_entries(self) -> Vec<CoinDominanceEntry<'static>> {
+        let timestamp = self.timestamp;
+        self.data.into_iter()
+            .map(|r| CoinDominanceEntry {
+                name: Cow::Owned(r.name),
+                id: Cow::Owned(r.id),
+                market_cap_usd: Cow::Owned(r.market_cap_usd),
+                dominance_percentage: Cow::Owned(r.dominance_percentage),
+                timestamp: Cow::Owned(timestamp),
+            })
+            .collect()
+    }
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct Series {
+    pub name: String,
+    pub data: Vec<SeriesEntry>,
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct SeriesEntry(
+    #[serde(with = "ts_milliseconds")]
+    pub DateTime<Utc>,
+    #[serde_as(as = "Option<BigDecimalExact>")]
+    pub Option<BigDecimal>,
+);
+
+/// Coin ids of the series in `/global_charts/market_dominance_data`, which are only named by
+/// symbol. Defaults to the coins known when the historical data was first loaded.
+#[derive(Clone, Debug)]
+pub struct CoinMap {
+    ids_by_symbol: HashMap<String, String>,
+}
+
+impl Default for CoinMap {
+    fn default() -> Self {
+        CoinMap {
+            ids_by_symbol: KNOWN_COINS_BY_SYMBOL.iter()
+                .map(|(symbol, id)| (symbol.to_string(), id.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl CoinMap {
+    /// Parses a JSON object of coin ids by series name, e.g. `{"BTC": "bitcoin"}`
+    pub fn from_json(data: &[u8]) -> serde_json::Result<CoinMap> {
+        Ok(CoinMap {
+            ids_by_symbol: serde_json::from_slice(data)?,
+        })
+    }
+
+    pub fn get(&self, symbol: &str) -> Option<&str> {
+        self.ids_by_symbol.get(symbol).map(|x| x.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids_by_symbol.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids_by_symbol.is_empty()
+    }
+
+    /// The map a historical blob was loaded with, given the blobs stored alongside it: the
+    /// custom coin map if there is one, otherwise the default, extended by the coins lists
+    /// that unmapped series were auto-mapped through
+    pub fn from_children(market: &MarketDominanceData, children: &[Vec<u8>]) -> serde_json::Result<CoinMap> {
+        let mut coins = CoinMap::default();
+        let mut lists = Vec::new();
+        for child in children {
+            match serde_json::from_slice::<Vec<CoinListEntry>>(child) {
+                Ok(list) => lists.push(list),
+                Err(_) => coins = CoinMap::from_json(child)?,
+            }
+        }
+
+        for list in &lists {
+            coins.auto_map(&market.unmapped_series(&coins), list);
+        }
+        Ok(coins)
+    }
+
+    /// Maps each of `symbols` to the coin of `coins` with the same symbol, ignoring case.
+    /// Symbols shared by several coins are left unmapped. Returns the symbols that were mapped.
+    pub fn auto_map(&mut self, symbols: &[String], coins: &[CoinListEntry]) -> Vec<String> {
+        let mut mapped = Vec::new();
+        for symbol in symbols {
+            let mut matches = coins.iter()
+                .filter(|c| c.symbol.eq_ignore_ascii_case(symbol));
+
+            if let (Some(coin), None) = (matches.next(), matches.next()) {
+                self.ids_by_symbol.insert(symbol.clone(), coin.id.clone());
+                mapped.push(symbol.clone());
+            }
+        }
+        mapped
+    }
+}
+
+/// Element of `/api/v3/coins/list`
+#[derive(Deserialize, Debug)]
+pub struct CoinListEntry {
+    pub id: String,
+    pub symbol: String,
+    pub name: String,
+}
+
+/// Response of `/global_charts/market_dominance_data`, as fetched by `domfi_loader_historical`
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct MarketDominanceData {
+    pub series_data_array: Vec<Series>,
+}
+
+impl MarketDominanceData {
+    /// Entries of every coin of the default `CoinMap` with a value. Market caps are not
+    /// part of the historical data and are stored as zero.
+    pub fn into_entries(self) -> Vec<CoinDominanceEntry<'static>> {
+        self.into_entries_with(&CoinMap::default())
+    }
+
+    /// Names of the series without a coin in `coins`, in order of appearance
+    pub fn unmapped_series(&self, coins: &CoinMap) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for series in &self.series_data_array {
+            if coins.get(&series.name).is_none() && !names.contains(&series.name) {
+                names.push(series.name.clone());
+            }
+        }
+        names
+    }
+
+    /// Entries of every series mapped by `coins` with a value, skipping the other series
+    pub fn into_entries_with(self, coins: &CoinMap) -> Vec<CoinDominanceEntry<'static>> {
+        self.series_data_array.into_iter()
+            .filter_map(|x| {
+                let coin_id = coins.get(x.name.as_str()).map(|id| id.to_owned());
+                coin_id.map(|id| (id, x))
+            })
+            .flat_map(|(coin_id, series)| {
+                let coin_name = series.name;
+                series.data.into_iter()
+                    .filter_map(move |row| {
+                        let timestamp = row.0;
+                        row.1.map(|dom_perc| CoinDominanceEntry {
+                            name: Cow::Owned(coin_name.clone()),
+                            id: Cow::Owned(coin_id.clone()),
+                            market_cap_usd: Cow::Owned(BigDecimal::zero()),
+                            dominance_percentage: Cow::Owned(dom_perc),
+                            timestamp: Cow::Owned(timestamp),
+                        })
+                    })
+            })
+            .collect()
+    }
+}
+
+/// Response of `/api/v3/global`
+#[derive(Deserialize, Debug)]
+pub struct GlobalResponse {
+    pub data: GlobalData,
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct GlobalData {
+    #[serde_as(as = "HashMap<_, BigDecimalExact>")]
+    pub total_market_cap: HashMap<String, BigDecimal>,
+
+    #[serde(with = "ts_seconds")]
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Element of the response of `/api/v3/coins/markets`
+#[serde_as]
+#[derive(Deserialize, Debug)]
+pub struct CoinMarket {
+    pub id: String,
+    pub symbol: String,
+    pub name: String,
+    #[serde_as(as = "Option<BigDecimalExact>")]
+    pub market_cap: Option<BigDecimal>,
+}
+
+/// Dominance of every coin with a market cap, relative to the total USD market cap
+/// of `/global`. Coins listed on several pages are only counted once. Coins without a
+/// positive market cap are skipped, and nothing is computed without a positive total.
+pub fn compute_dominance(global: &GlobalResponse, markets: &[CoinMarket]) -> Vec<CoinDominanceEntry<'static>> {
+    let zero = BigDecimal::zero();
+    let hundred = BigDecimal::from(100);
+    let total = match global.data.total_market_cap.get("usd") {
+        Some(x) if *x > zero => x,
+        _ => return Vec::new(),
+    };
+
+    let mut seen = HashSet::new();
+    markets.iter()
+        .filter(|x| seen.insert(x.id.as_str()))
+        .filter_map(|x| {
+            let market_cap = x.market_cap.as_ref().filter(|m| **m > zero)?;
+            let dominance = (market_cap * &hundred / total).with_scale(COMPUTED_DOMINANCE_SCALE);
+
+            Some(CoinDominanceEntry {
+                name: Cow::Owned(x.symbol.to_uppercase()),
+                id: Cow::Owned(x.id.clone()),
+                market_cap_usd: Cow::Owned(market_cap.clone()),
+                dominance_percentage: Cow::Owned(dominance),
+                timestamp: Cow::Owned(global.data.updated_at),
+            })
+        })
+        .collect()
+}
+
+/// The CoinGecko endpoints whose responses end up in `object_storage`
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SourceSchema {
+    CoinDominance,
+    MarketDominance,
+    Global,
+    CoinMarkets,
+}
+
+impl SourceSchema {
+    pub const ALL: [SourceSchema; 4] = [
+        SourceSchema::CoinDominance,
+        SourceSchema::MarketDominance,
+        SourceSchema::Global,
+        SourceSchema::CoinMarkets,
+    ];
+
+    pub fn from_url(url: &str) -> Option<SourceSchema> {
+        let path = url.split('?').next().unwrap_or(url).trim_end_matches('/');
+        if path.ends_with("/global/coin_dominance") {
+            Some(SourceSchema::CoinDominance)
+        } else if path.ends_with("/global_charts/market_dominance_data") {
+            Some(SourceSchema::MarketDominance)
+        } else if path.ends_with("/global") {
+            Some(SourceSchema::Global)
+        } else if path.ends_with("/coins/markets") {
+            Some(SourceSchema::CoinMarkets)
+        } else {
+            None
+        }
+    }
+
+    /// Entries derived from a blob of this schema. `children` are the blobs fetched
+    /// alongside it, i.e. the `/coins/markets` pages of a `/global` blob, or the coin map and
+    /// coins lists of a historical blob, see [`CoinMap::from_children`]. Markets pages
+    /// themselves yield no entries, as they are only meaningful with their parent.
+    pub fn parse(self, data: &[u8], children: &[Vec<u8>]) -> Result<Vec<CoinDominanceEntry<'static>>, serde_json::Error> {
+        match self {
+            SourceSchema::CoinDominance =>
+                serde_json::from_slice::<CoinDominanceResponse>(data).map(|x| x.into_entries()),
+            SourceSchema::MarketDominance => {
+                let market = serde_json::from_slice::<MarketDominanceData>(data)?;
+                let coins = CoinMap::from_children(&market, children)?;
+                Ok(market.into_entries_with(&coins))
+            },
+            SourceSchema::Global => {
+                let global = serde_json::from_slice::<GlobalResponse>(data)?;
+                let mut markets = Vec::new();
+                for child in children {
+                    markets.extend(serde_json::from_slice::<Vec<CoinMarket>>(child)?);
+                }
+                Ok(compute_dominance(&global, &markets))
+            },
+            SourceSchema::CoinMarkets =>
+                serde_json::from_slice::<Vec<CoinMarket>>(data).map(|_| Vec::new()),
+        }
+    }
+
+    /// Parses the blob with the schema of the requested URL. Blobs without request
+    /// metadata are tried against every schema in turn.
+    pub fn detect_and_parse(url: Option<&str>, data: &[u8], children: &[Vec<u8>])
+        -> Result<(SourceSchema, Vec<CoinDominanceEntry<'static>>), String>
+    {
+        if let Some(schema) = url.and_then(SourceSchema::from_url) {
+            return schema.parse(data, children)
+                .map(|x| (schema, x))
+                .map_err(|e| format!("{:?}: {}", schema, e));
+        }
+
+        let mut errors = Vec::new();
+        for schema in SourceSchema::ALL.iter().copied() {
+            match schema.parse(data, children) {
+                Ok(x) => return Ok((schema, x)),
+                Err(e) => errors.push(format!("{:?}: {}", schema, e)),
+            }
+        }
+
+        Err(errors.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use chrono::TimeZone;
+
+    #[test]
+    fn coin_dominance_should_parse_decimals_exactly() {
+        let test = r#"{
+                "name":"LTC",
+                "id":"litecoin",
+                "market_cap_usd":8364520669.848436,
+                "dominance_percentage":1.1188873746973713
+            }"#;
+
+        let buf = test.as_bytes();
+        let json = serde_json::from_slice::<CoinDominance>(buf).unwrap();
+
+        assert_eq!(json.market_cap_usd, BigDecimal::from_str("8364520669.848436").unwrap());
+        assert_eq!(json.dominance_percentage, BigDecimal::from_str("1.1188873746973713").unwrap());
+    }
+
+    #[test]
+    fn series_entry_should_parse_decimals_exactly() {
+        let n = "1.1234679123479120374890123740981237498";
+        let test = format!("[1609339955000, {}]", n);
+
+        let dt = Utc
+            .ymd(2020, 12, 30)
+            .and_hms(14, 52, 35);
+
+        let buf = test.as_bytes();
+        let json = serde_json::from_slice::<SeriesEntry>(buf).unwrap();
+
+        assert_eq!(json.0, dt);
+        assert_eq!(json.1, Some(BigDecimal::from_str(n).unwrap()));
+    }
+
+    #[test]
+    fn market_dominance_should_skip_unknown_coins_and_missing_values() {
+        let test = r#"{"series_data_array":[
+                {"name":"BTC","data":[[1609339955000,70.5],[1609339956000,null]]},
+                {"name":"DOGE","data":[[1609339955000,0.3]]}
+            ]}"#;
+
+        let json = serde_json::from_str::<MarketDominanceData>(test).unwrap();
+        let entries = json.into_entries();
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, "bitcoin");
+        assert_eq!(*entries[0].dominance_percentage, BigDecimal::from_str("70.5").unwrap());
+    }
+
+    #[test]
+    fn market_dominance_should_use_the_configured_coin_map() {
+        let test = r#"{"series_data_array":[
+                {"name":"BTC","data":[[1609339955000,70.5]]},
+                {"name":"DOGE","data":[[1609339955000,0.3]]},
+                {"name":"UNI","data":[[1609339955000,0.2]]},
+                {"name":"DOGE","data":[[1609339956000,0.4]]}
+            ]}"#;
+
+        let coins = r#"[
+                {"id":"dogecoin","symbol":"doge","name":"Dogecoin"},
+                {"id":"uniswap","symbol":"uni","name":"Uniswap"},
+                {"id":"universe-token","symbol":"uni","name":"Universe"}
+            ]"#;
+
+        let mut map = CoinMap::from_json(br#"{"BTC":"bitcoin"}"#).unwrap();
+        let json = serde_json::from_str::<MarketDominanceData>(test).unwrap();
+        assert_eq!(json.unmapped_series(&map), vec!["DOGE", "UNI"]);
+
+        // UNI is ambiguous and stays unmapped
+        let coins = serde_json::from_str::<Vec<CoinListEntry>>(coins).unwrap();
+        assert_eq!(map.auto_map(&json.unmapped_series(&map), &coins), vec!["DOGE"]);
+        assert_eq!(json.unmapped_series(&map), vec!["UNI"]);
+
+        let entries = json.into_entries_with(&map);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].id, "dogecoin");
+        assert_eq!(entries[2].name, "DOGE");
+    }
+
+    #[test]
+    fn market_dominance_should_be_replayed_with_its_coin_map_and_coins_lists() {
+        let data = br#"{"series_data_array":[
+                {"name":"BTC","data":[[1609339955000,70.5]]},
+                {"name":"DOGE","data":[[1609339955000,0.3]]}
+            ]}"#;
+        let children = vec![
+            br#"[{"id":"dogecoin","symbol":"doge","name":"Dogecoin"}]"#.to_vec(),
+            br#"{"BTC":"wrapped-bitcoin"}"#.to_vec(),
+        ];
+
+        assert_eq!(SourceSchema::MarketDominance.parse(data, &[]).unwrap().len(), 1);
+
+        let entries = SourceSchema::MarketDominance.parse(data, &children[..1]).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].id, "bitcoin");
+        assert_eq!(entries[1].id, "dogecoin");
+
+        // A custom map replaces the default one regardless of the order it was stored in
+        let entries = SourceSchema::MarketDominance.parse(data, &children).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].id, "wrapped-bitcoin");
+        assert_eq!(entries[1].id, "dogecoin");
+    }
+
+    #[test]
+    fn computed_dominance_should_use_global_total_and_skip_missing_caps() {
+        let global = r#"{"data":{"total_market_cap":{"btc":1,"usd":800},"updated_at":1608195600}}"#;
+        let markets = r#"[
+                {"id":"bitcoin","symbol":"btc","name":"Bitcoin","market_cap":500},
+                {"id":"solana","symbol":"sol","name":"Solana","market_cap":3},
+                {"id":"new-coin","symbol":"new","name":"New","market_cap":null},
+                {"id":"bitcoin","symbol":"btc","name":"Bitcoin","market_cap":1}
+            ]"#;
+
+        let global = serde_json::from_str::<GlobalResponse>(global).unwrap();
+        let markets = serde_json::from_str::<Vec<CoinMarket>>(markets).unwrap();
+        let entries = compute_dominance(&global, &markets);
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].id, "bitcoin");
+        assert_eq!(entries[0].name, "BTC");
+        assert_eq!(*entries[0].dominance_percentage, BigDecimal::from_str("62.5").unwrap());
+        assert_eq!(entries[1].id, "solana");
+        assert_eq!(entries[1].dominance_percentage.to_string(), "0.375000000000000000");
+        assert_eq!(*entries[1].timestamp, Utc.timestamp(1608195600, 0));
+    }
+
+    #[test]
+    fn computed_dominance_should_truncate_non_terminating_quotients() {
+        let global = r#"{"data":{"total_market_cap":{"usd":3},"updated_at":1608195600}}"#;
+        let markets = r#"[{"id":"cardano","symbol":"ada","name":"Cardano","market_cap":1}]"#;
+
+        let global = serde_json::from_str::<GlobalResponse>(global).unwrap();
+        let markets = serde_json::from_str::<Vec<CoinMarket>>(markets).unwrap();
+        let entries = compute_dominance(&global, &markets);
+
+        assert_eq!(entries[0].dominance_percentage.to_string(), "33.333333333333333333");
+    }
+
+    #[test]
+    fn computed_dominance_should_reject_negative_market_caps() {
+        let markets = r#"[
+                {"id":"bitcoin","symbol":"btc","name":"Bitcoin","market_cap":500},
+                {"id":"broken","symbol":"brk","name":"Broken","market_cap":-20}
+            ]"#;
+        let markets = serde_json::from_str::<Vec<CoinMarket>>(markets).unwrap();
+
+        let global = r#"{"data":{"total_market_cap":{"usd":800},"updated_at":1608195600}}"#;
+        let global = serde_json::from_str::<GlobalResponse>(global).unwrap();
+        let entries = compute_dominance(&global, &markets);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, "bitcoin");
+
+        let global = r#"{"data":{"total_market_cap":{"usd":-800},"updated_at":1608195600}}"#;
+        let global = serde_json::from_str::<GlobalResponse>(global).unwrap();
+        assert!(compute_dominance(&global, &markets).is_empty());
+    }
+
+    #[test]
+    fn schema_should_be_detected_from_url() {
+        assert_eq!(
+            SourceSchema::from_url("https://api.coingecko.com/api/v3/global/coin_dominance?_=123"),
+            Some(SourceSchema::CoinDominance));
+        assert_eq!(
+            SourceSchema::from_url("https://www.coingecko.com/global_charts/market_dominance_data?locale=en"),
+            Some(SourceSchema::MarketDominance));
+        assert_eq!(
+            SourceSchema::from_url("https://api.coingecko.com/api/v3/global"),
+            Some(SourceSchema::Global));
+        assert_eq!(
+            SourceSchema::from_url("https://api.coingecko.com/api/v3/coins/markets?vs_currency=usd&page=2"),
+            Some(SourceSchema::CoinMarkets));
+        assert_eq!(SourceSchema::from_url("https://example.com/"), None);
+    }
+
+    #[test]
+    fn schema_should_be_sniffed_without_url() {
+        let live = br#"{"data":[{"name":"BTC","id":"bitcoin","market_cap_usd":1,"dominance_percentage":2}],"timestamp":1608195600}"#;
+        let historical = br#"{"series_data_array":[{"name":"BTC","data":[[1608195600000,63.1]]}]}"#;
+        let global = br#"{"data":{"total_market_cap":{"usd":4},"updated_at":1608195600}}"#;
+        let markets = br#"[{"id":"solana","symbol":"sol","name":"Solana","market_cap":1}]"#;
+
+        let detect = |data: &[u8], children: &[Vec<u8>]| SourceSchema::detect_and_parse(None, data, children).unwrap();
+
+        assert_eq!(detect(live, &[]).0, SourceSchema::CoinDominance);
+        assert_eq!(detect(historical, &[]).0, SourceSchema::MarketDominance);
+
+        let (schema, entries) = detect(markets, &[]);
+        assert_eq!(schema, SourceSchema::CoinMarkets);
+        assert!(entries.is_empty());
+
+        let (schema, entries) = detect(global, &[markets.to_vec()]);
+        assert_eq!(schema, SourceSchema::Global);
+        assert_eq!(entries[0].id, "solana");
+        assert_eq!(*entries[0].dominance_percentage, BigDecimal::from(25));
+
+        let url = Some("https://www.coingecko.com/global_charts/market_dominance_data");
+        assert!(SourceSchema::detect_and_parse(url, live, &[]).is_err());
+        assert!(SourceSchema::detect_and_parse(None, b"1", &[]).is_err());
+    }
+}
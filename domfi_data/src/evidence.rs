@@ -0,0 +1,413 @@
+//! Evidence bundles for disputed prices.
+//!
+//! A bundle is a tar archive with everything needed to independently reproduce a
+//! price: the raw upstream response, the HTTP metadata of the request, the rows
+//! derived from it, the price computation and a manifest of SHA-256 hashes.
+
+use std::borrow::Borrow;
+
+use bigdecimal::BigDecimal;
+use chrono::{DateTime, Utc, TimeZone};
+use serde::Serialize;
+use sha2::{Sha256, Digest};
+use snafu::{Snafu, ResultExt};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use domfi_domain::ext::bigdecimal::RoundingMode;
+use domfi_domain::models::{
+    FinancialAssetWithMetadata,
+    FinancialAssetWithMetadataOfAny,
+    FinancialAssetRawValueOf,
+    FinancialDominanceAsset,
+    FinancialDominanceMode,
+    TickerDisplay,
+};
+use crate::pg::ops::coin_dominance_entry;
+
+pub const MANIFEST_FORMAT_VERSION: u32 = 1;
+
+#[derive(Snafu, Debug)]
+pub enum EvidenceError {
+    #[snafu(display("No snapshot found for the requested asset and timestamp"))]
+    SnapshotNotFound,
+
+    #[snafu(display("Failed to access database: {}", source))]
+    DbError {
+        source: sqlx::Error,
+    },
+
+    #[snafu(display("Failed to serialize evidence: {}", source))]
+    SerializeError {
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Failed to write evidence archive: {}", source))]
+    ArchiveError {
+        source: std::io::Error,
+    },
+}
+
+#[derive(Serialize, Debug)]
+pub struct EvidenceProvenance {
+    pub uuid: Uuid,
+    pub agent: String,
+    pub timestamp_utc: DateTime<Utc>,
+    pub object_id: i64,
+    pub request_metadata: Option<serde_json::Value>,
+    pub response_metadata: Option<serde_json::Value>,
+}
+
+#[derive(Debug)]
+pub struct EvidenceBlob {
+    pub sha256: Vec<u8>,
+    pub data: Vec<u8>,
+    pub mime: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct EvidenceCoinDominanceRow {
+    pub id: i64,
+    pub timestamp_utc: DateTime<Utc>,
+    pub imported_at_utc: DateTime<Utc>,
+    pub agent: String,
+    pub coin_id: String,
+    pub coin_name: String,
+    pub market_cap_usd: BigDecimal,
+    pub market_dominance_percentage: BigDecimal,
+}
+
+/// A single step from the stored dominance percentage to the served price
+#[derive(Serialize, Debug)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum EvidencePriceStep {
+    /// Dominance percentage as stored in `coin_dominance`
+    Stored {
+        value: BigDecimal,
+    },
+    /// Inversion of the percentage for ALTDOM assets
+    Invert {
+        formula: &'static str,
+        value: BigDecimal,
+    },
+    /// Rounding of the asset applied last
+    Round {
+        digits: i64,
+        mode: RoundingMode,
+        value: BigDecimal,
+    },
+}
+
+#[derive(Serialize, Debug)]
+pub struct EvidencePrice {
+    pub ticker: String,
+    pub asset: FinancialAssetWithMetadataOfAny,
+    pub coin_id: String,
+    pub requested_timestamp_utc: Option<DateTime<Utc>>,
+    pub timestamp_utc: DateTime<Utc>,
+    pub steps: Vec<EvidencePriceStep>,
+    pub price: BigDecimal,
+}
+
+impl EvidencePrice {
+    pub fn compute(
+        asset_meta: &FinancialAssetWithMetadata<FinancialDominanceAsset>,
+        coin_id: String,
+        requested_timestamp_utc: Option<DateTime<Utc>>,
+        timestamp_utc: DateTime<Utc>,
+        stored: BigDecimal,
+    ) -> EvidencePrice {
+        let mut steps = Vec::new();
+
+        let raw = asset_meta.raw_value_of(&stored).into_owned();
+        steps.push(EvidencePriceStep::Stored { value: stored });
+
+        if asset_meta.asset().mode() == FinancialDominanceMode::AltDom {
+            steps.push(EvidencePriceStep::Invert {
+                formula: "100 - value",
+                value: raw.clone(),
+            });
+        }
+
+        let rounding = asset_meta.metadata().rounding();
+        let price = rounding.round(raw.borrow());
+        steps.push(EvidencePriceStep::Round {
+            digits: rounding.digits(),
+            mode: rounding.mode(),
+            value: price.clone(),
+        });
+
+        EvidencePrice {
+            ticker: asset_meta.asset().ticker_id().to_string(),
+            asset: asset_meta.clone().into_any(),
+            coin_id,
+            requested_timestamp_utc,
+            timestamp_utc,
+            steps,
+            price,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct EvidenceManifestEntry {
+    pub path: String,
+    pub sha256: String,
+    pub size: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct EvidenceManifest {
+    pub format_version: u32,
+    pub generated_at_utc: DateTime<Utc>,
+    pub ticker: String,
+    pub provenance_uuid: Uuid,
+    /// Hash of the raw response as stored in `object_storage`
+    pub blob_sha256: String,
+    /// Whether the stored hash matches the hash of the raw response recomputed on export
+    pub blob_sha256_verified: bool,
+    pub files: Vec<EvidenceManifestEntry>,
+}
+
+#[derive(Debug)]
+pub struct EvidenceBundle {
+    pub generated_at_utc: DateTime<Utc>,
+    pub provenance: EvidenceProvenance,
+    pub blob: EvidenceBlob,
+    pub rows: Vec<EvidenceCoinDominanceRow>,
+    pub price: EvidencePrice,
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+impl EvidenceBundle {
+    /// Directory every file of the archive is placed in, also usable as the archive name
+    pub fn name(&self) -> String {
+        let ticker = self.price.ticker.to_ascii_lowercase()
+            .replace(|c: char| !c.is_ascii_alphanumeric(), "-");
+
+        format!("evidence-{}-{}", ticker, self.price.timestamp_utc.timestamp())
+    }
+
+    fn blob_file_name(&self) -> &'static str {
+        match self.blob.mime.as_deref() {
+            Some(mime) if mime.starts_with("application/json") => "raw_response.json",
+            _ => "raw_response.bin",
+        }
+    }
+
+    /// Files of the bundle in archive order, excluding the manifest
+    pub fn files(&self) -> Result<Vec<(String, Vec<u8>)>, EvidenceError> {
+        Ok(vec![
+            (self.blob_file_name().to_owned(), self.blob.data.clone()),
+            ("provenance.json".to_owned(), serde_json::to_vec_pretty(&self.provenance).context(SerializeError)?),
+            ("coin_dominance.json".to_owned(), serde_json::to_vec_pretty(&self.rows).context(SerializeError)?),
+            ("price.json".to_owned(), serde_json::to_vec_pretty(&self.price).context(SerializeError)?),
+        ])
+    }
+
+    pub fn manifest(&self, files: &[(String, Vec<u8>)]) -> EvidenceManifest {
+        EvidenceManifest {
+            format_version: MANIFEST_FORMAT_VERSION,
+            generated_at_utc: self.generated_at_utc,
+            ticker: self.price.ticker.clone(),
+            provenance_uuid: self.provenance.uuid,
+            blob_sha256: hex::encode(&self.blob.sha256),
+            blob_sha256_verified: Sha256::digest(&self.blob.data).as_slice() == self.blob.sha256.as_slice(),
+            files: files.iter()
+                .map(|(path, data)| EvidenceManifestEntry {
+                    path: path.clone(),
+                    sha256: sha256_hex(data),
+                    size: data.len(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Writes the bundle as a tar archive. Entries carry the provenance timestamp as
+    /// their modification time so that exporting the same bundle twice only differs
+    /// in the manifest's generation time.
+    pub fn to_tar(&self) -> Result<Vec<u8>, EvidenceError> {
+        let files = self.files()?;
+        let manifest = serde_json::to_vec_pretty(&self.manifest(&files)).context(SerializeError)?;
+
+        let dir = self.name();
+        let mtime = self.provenance.timestamp_utc.timestamp().max(0) as u64;
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let entries = std::iter::once(("manifest.json".to_owned(), manifest)).chain(files);
+        for (path, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(mtime);
+            header.set_cksum();
+
+            builder.append_data(&mut header, format!("{}/{}", dir, path), data.as_slice())
+                .context(ArchiveError)?;
+        }
+
+        builder.into_inner().context(ArchiveError)
+    }
+}
+
+/// Resolves the snapshot served for the asset at the timestamp with the same lookup as the
+/// price endpoints
+async fn find_snapshot(
+    asset: &FinancialDominanceAsset,
+    agent: &str,
+    ts: Option<DateTime<Utc>>,
+    pool: &PgPool
+) -> Result<(Uuid, String, DateTime<Utc>, BigDecimal), EvidenceError> {
+
+    let timestamp = coin_dominance_entry::find_served_timestamp(agent, ts, pool).await
+        .context(DbError)?
+        .ok_or(EvidenceError::SnapshotNotFound)?;
+
+    let row = sqlx::query!(r#"
+        select
+            provenance_uuid,
+            coin_id,
+            timestamp_utc,
+            market_dominance_percentage
+        from
+            coin_dominance
+        where
+            timestamp_utc = $1
+            and agent = $2
+            and coin_id = $3
+        limit 1
+        "#,
+        timestamp.naive_utc(),
+        agent,
+        asset.underlying().symbol().id())
+        .fetch_optional(pool)
+        .await
+        .context(DbError)?
+        .ok_or(EvidenceError::SnapshotNotFound)?;
+
+    Ok((row.provenance_uuid, row.coin_id, Utc.from_utc_datetime(&row.timestamp_utc), row.market_dominance_percentage))
+}
+
+/// Collects the evidence for the price of the asset served from the agent's snapshots at the
+/// timestamp, or the latest price
+pub async fn collect(
+    asset_meta: &FinancialAssetWithMetadata<FinancialDominanceAsset>,
+    agent: &str,
+    ts: Option<DateTime<Utc>>,
+    pool: &PgPool
+) -> Result<EvidenceBundle, EvidenceError> {
+
+    let (provenance_uuid, coin_id, timestamp, stored) =
+        find_snapshot(asset_meta.asset(), agent, ts, pool).await?;
+
+    let p = sqlx::query!(r#"
+        select
+            p.uuid,
+            p.agent,
+            p.timestamp_utc,
+            p.object_id,
+            p.request_metadata,
+            p.response_metadata,
+            obj.sha256,
+            obj.data,
+            obj.mime
+        from
+            provenance as p
+            inner join object_storage as obj
+                on obj.id = p.object_id
+        where
+            p.uuid = $1
+        "#,
+        provenance_uuid)
+        .fetch_one(pool)
+        .await
+        .context(DbError)?;
+
+    let rows = sqlx::query!(r#"
+        select
+            id,
+            timestamp_utc,
+            imported_at_utc,
+            agent,
+            coin_id,
+            coin_name,
+            market_cap_usd,
+            market_dominance_percentage
+        from
+            coin_dominance
+        where
+            provenance_uuid = $1
+        order by
+            id
+        "#,
+        provenance_uuid)
+        .fetch_all(pool)
+        .await
+        .context(DbError)?
+        .into_iter()
+        .map(|r| EvidenceCoinDominanceRow {
+            id: r.id,
+            timestamp_utc: Utc.from_utc_datetime(&r.timestamp_utc),
+            imported_at_utc: Utc.from_utc_datetime(&r.imported_at_utc),
+            agent: r.agent,
+            coin_id: r.coin_id,
+            coin_name: r.coin_name,
+            market_cap_usd: r.market_cap_usd,
+            market_dominance_percentage: r.market_dominance_percentage,
+        })
+        .collect();
+
+    Ok(EvidenceBundle {
+        generated_at_utc: Utc::now(),
+        provenance: EvidenceProvenance {
+            uuid: p.uuid,
+            agent: p.agent,
+            timestamp_utc: Utc.from_utc_datetime(&p.timestamp_utc),
+            object_id: p.object_id,
+            request_metadata: p.request_metadata,
+            response_metadata: p.response_metadata,
+        },
+        blob: EvidenceBlob {
+            sha256: p.sha256,
+            data: p.data,
+            mime: p.mime,
+        },
+        rows,
+        price: EvidencePrice::compute(asset_meta, coin_id, ts, timestamp, stored),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use bigdecimal::BigDecimal;
+    use chrono::{Utc, TimeZone};
+    use domfi_domain::models::financial_assets::get_canonical_default_asset;
+    use crate::evidence::{EvidencePrice, EvidencePriceStep};
+
+    fn decimal(x: &str) -> BigDecimal {
+        BigDecimal::from_str(x).unwrap()
+    }
+
+    #[test]
+    fn price_steps_should_invert_altdom_before_rounding() {
+        let asset = get_canonical_default_asset("altdom").unwrap();
+        let price = EvidencePrice::compute(asset, "bitcoin".into(), None, Utc.timestamp(0, 0), decimal("60.2554"));
+
+        assert_eq!(price.steps.len(), 3);
+        assert!(matches!(&price.steps[1], EvidencePriceStep::Invert { value, .. } if *value == decimal("39.7446")));
+        assert_eq!(price.price, decimal("39.74"));
+    }
+
+    #[test]
+    fn price_steps_should_only_round_dom() {
+        let asset = get_canonical_default_asset("btcdom").unwrap();
+        let price = EvidencePrice::compute(asset, "bitcoin".into(), None, Utc.timestamp(0, 0), decimal("60.2554"));
+
+        assert_eq!(price.steps.len(), 2);
+        assert_eq!(price.price, decimal("60.26"));
+    }
+}
@@ -1,2 +1,15 @@
+#[macro_use]
+extern crate lazy_static;
 
-pub mod pg;
\ No newline at end of file
+pub mod pg;
+pub mod evidence;
+pub mod merkle;
+pub mod coingecko;
+pub mod verify;
+pub mod replay;
+#[cfg(feature = "fetch")]
+pub mod source;
+pub mod validate;
+pub mod reconcile;
+pub mod retention;
+pub mod serving;
@@ -0,0 +1,180 @@
+//! Merkle tree hashing as specified by RFC 6962 (Certificate Transparency).
+//!
+//! Leaves and interior nodes are hashed with distinct prefixes so a leaf can never
+//! be passed off as a node, and unbalanced trees split at the largest power of two.
+
+use sha2::{Sha256, Digest};
+
+pub type Hash = [u8; 32];
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+pub fn leaf_hash(data: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+pub fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Largest power of two strictly less than `n`, for `n > 1`
+fn split_point(n: usize) -> usize {
+    let mut k = 1;
+    while k << 1 < n {
+        k <<= 1;
+    }
+    k
+}
+
+fn subtree_root(hashes: &[Hash]) -> Hash {
+    match hashes.len() {
+        0 => Sha256::digest(&[]).into(),
+        1 => hashes[0],
+        n => {
+            let k = split_point(n);
+            node_hash(&subtree_root(&hashes[..k]), &subtree_root(&hashes[k..]))
+        }
+    }
+}
+
+fn subtree_proof(index: usize, hashes: &[Hash], proof: &mut Vec<Hash>) {
+    let n = hashes.len();
+    if n <= 1 {
+        return;
+    }
+
+    let k = split_point(n);
+    if index < k {
+        subtree_proof(index, &hashes[..k], proof);
+        proof.push(subtree_root(&hashes[k..]));
+    } else {
+        subtree_proof(index - k, &hashes[k..], proof);
+        proof.push(subtree_root(&hashes[..k]));
+    }
+}
+
+pub struct MerkleTree {
+    leaf_hashes: Vec<Hash>,
+}
+
+impl MerkleTree {
+    pub fn new<I, T>(leaves: I) -> MerkleTree
+        where I: IntoIterator<Item = T>, T: AsRef<[u8]>
+    {
+        MerkleTree {
+            leaf_hashes: leaves.into_iter().map(|x| leaf_hash(x.as_ref())).collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaf_hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaf_hashes.is_empty()
+    }
+
+    pub fn root(&self) -> Hash {
+        subtree_root(&self.leaf_hashes)
+    }
+
+    /// Audit path of the leaf at `index`, ordered from the leaf towards the root
+    pub fn proof(&self, index: usize) -> Option<Vec<Hash>> {
+        if index >= self.leaf_hashes.len() {
+            return None;
+        }
+
+        let mut proof = Vec::new();
+        subtree_proof(index, &self.leaf_hashes, &mut proof);
+        Some(proof)
+    }
+}
+
+/// Checks that `leaf` is at `index` of a tree of `size` leaves with the given root
+/// (RFC 9162, section 2.1.3.2)
+pub fn verify_proof(leaf: &[u8], index: usize, size: usize, proof: &[Hash], root: &Hash) -> bool {
+    if index >= size {
+        return false;
+    }
+
+    let mut f = index;
+    let mut s = size - 1;
+    let mut r = leaf_hash(leaf);
+
+    for p in proof {
+        if s == 0 {
+            return false;
+        }
+
+        if f & 1 == 1 || f == s {
+            r = node_hash(p, &r);
+            while f & 1 == 0 && f != 0 {
+                f >>= 1;
+                s >>= 1;
+            }
+        } else {
+            r = node_hash(&r, p);
+        }
+
+        f >>= 1;
+        s >>= 1;
+    }
+
+    s == 0 && &r == root
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::merkle::{MerkleTree, verify_proof, leaf_hash, node_hash};
+
+    fn leaves(n: u8) -> Vec<Vec<u8>> {
+        (0..n).map(|i| vec![i; 32]).collect()
+    }
+
+    #[test]
+    fn root_should_match_rfc6962_vectors() {
+        let empty: Vec<Vec<u8>> = Vec::new();
+        assert_eq!(
+            hex::encode(MerkleTree::new(empty).root()),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(
+            hex::encode(MerkleTree::new(vec![b""]).root()),
+            "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d");
+    }
+
+    #[test]
+    fn root_should_split_at_largest_power_of_two() {
+        let xs = leaves(3);
+        let expected = node_hash(
+            &node_hash(&leaf_hash(&xs[0]), &leaf_hash(&xs[1])),
+            &leaf_hash(&xs[2]));
+
+        assert_eq!(MerkleTree::new(&xs).root(), expected);
+    }
+
+    #[test]
+    fn proofs_should_verify_for_every_leaf() {
+        for n in 1..=17 {
+            let xs = leaves(n);
+            let tree = MerkleTree::new(&xs);
+            let root = tree.root();
+
+            for (i, x) in xs.iter().enumerate() {
+                let proof = tree.proof(i).unwrap();
+                assert!(verify_proof(x, i, xs.len(), &proof, &root), "n = {}, i = {}", n, i);
+                assert!(!verify_proof(x, (i + 1) % xs.len(), xs.len(), &proof, &root) || n == 1);
+                assert!(!verify_proof(b"other", i, xs.len(), &proof, &root));
+            }
+
+            assert!(tree.proof(xs.len()).is_none());
+        }
+    }
+}
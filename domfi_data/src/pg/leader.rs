@@ -0,0 +1,140 @@
+//! Leader election between loader replicas with a session-level advisory lock. Postgres
+//! releases the lock when the session of the leader ends, and followers end the session of
+//! a leader that stopped heartbeating in `loader_leader`.
+
+use std::time::Duration;
+
+use sqlx::{Connection, Done, PgConnection};
+
+/// Splits an advisory lock key into the `classid` and `objid` that `pg_locks` reports for it,
+/// i.e. the high and low 32 bits taken as unsigned `oid`s
+pub fn advisory_lock_ids(key: i64) -> (i64, i64) {
+    ((key >> 32) & 0xffff_ffff, key & 0xffff_ffff)
+}
+
+/// A dedicated session competing for the advisory lock `key`
+pub struct LeaderLock {
+    conn: PgConnection,
+    key: i64,
+    instance: String,
+}
+
+impl LeaderLock {
+    pub async fn connect(url: &str, key: i64, instance: impl Into<String>) -> Result<LeaderLock, sqlx::Error> {
+        Ok(LeaderLock {
+            conn: PgConnection::connect(url).await?,
+            key,
+            instance: instance.into(),
+        })
+    }
+
+    /// Takes the lock if it is free and records this instance as the leader
+    pub async fn try_acquire(&mut self) -> Result<bool, sqlx::Error> {
+        let row = sqlx::query!(r#"select pg_try_advisory_lock($1) as "acquired!""#, self.key)
+            .fetch_one(&mut self.conn)
+            .await?;
+
+        if !row.acquired {
+            return Ok(false);
+        }
+
+        sqlx::query!(r#"
+            insert into loader_leader (lock_key, instance, backend_pid, elected_at, heartbeat_at)
+            values ($1, $2, pg_backend_pid(), now() at time zone 'utc', now() at time zone 'utc')
+            on conflict (lock_key) do update
+                set instance = excluded.instance,
+                    backend_pid = excluded.backend_pid,
+                    elected_at = excluded.elected_at,
+                    heartbeat_at = excluded.heartbeat_at
+            "#,
+            self.key,
+            self.instance)
+            .execute(&mut self.conn)
+            .await?;
+
+        Ok(true)
+    }
+
+    /// Refreshes the heartbeat of the leader. Returns false if this session is no longer
+    /// recorded as the leader.
+    pub async fn heartbeat(&mut self) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(r#"
+            update loader_leader
+            set heartbeat_at = now() at time zone 'utc'
+            where lock_key = $1 and backend_pid = pg_backend_pid()
+            "#,
+            self.key)
+            .execute(&mut self.conn)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Ends the session of the leader if its heartbeat is older than `timeout` and it still
+    /// holds the lock, returning the instance name of the terminated leader
+    pub async fn terminate_stale_leader(&mut self, timeout: Duration) -> Result<Option<String>, sqlx::Error> {
+        let (classid, objid) = advisory_lock_ids(self.key);
+        let row = sqlx::query!(r#"
+            select
+                s.instance,
+                pg_terminate_backend(l.pid) as "terminated!"
+            from
+                loader_leader as s
+                inner join pg_locks as l
+                    on l.pid = s.backend_pid
+            where
+                s.lock_key = $1
+                and s.heartbeat_at < (now() at time zone 'utc') - make_interval(secs => $2)
+                and l.locktype = 'advisory'
+                and l.granted
+                and l.classid = $3::bigint::oid
+                and l.objid = $4::bigint::oid
+                and l.objsubid = 1
+            "#,
+            self.key,
+            timeout.as_secs_f64(),
+            classid,
+            objid)
+            .fetch_optional(&mut self.conn)
+            .await?;
+
+        Ok(row.filter(|r| r.terminated).map(|r| r.instance))
+    }
+
+    /// Clears the status and releases the lock
+    pub async fn release(mut self) -> Result<(), sqlx::Error> {
+        sqlx::query!(r#"
+            delete from loader_leader
+            where lock_key = $1 and backend_pid = pg_backend_pid()
+            "#,
+            self.key)
+            .execute(&mut self.conn)
+            .await?;
+
+        sqlx::query!(r#"select pg_advisory_unlock($1) as "released!""#, self.key)
+            .fetch_one(&mut self.conn)
+            .await?;
+
+        self.conn.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_ids_should_split_positive_keys() {
+        assert_eq!(advisory_lock_ids(0), (0, 0));
+        assert_eq!(advisory_lock_ids(42), (0, 42));
+        assert_eq!(advisory_lock_ids((7 << 32) | 42), (7, 42));
+        assert_eq!(advisory_lock_ids(i64::MAX), (0x7fff_ffff, 0xffff_ffff));
+    }
+
+    #[test]
+    fn lock_ids_should_be_unsigned_for_negative_keys() {
+        assert_eq!(advisory_lock_ids(-1), (0xffff_ffff, 0xffff_ffff));
+        assert_eq!(advisory_lock_ids(i64::MIN), (0x8000_0000, 0));
+        assert_eq!(advisory_lock_ids(-(1 << 32)), (0xffff_ffff, 0));
+    }
+}
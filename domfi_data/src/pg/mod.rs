@@ -1,3 +1,6 @@
 pub mod models;
 pub mod ops;
+#[cfg(feature = "fetch")]
 pub mod convert;
+pub mod notify;
+pub mod leader;
@@ -0,0 +1,442 @@
+use std::time::Duration;
+
+use log::{info, warn, error};
+use snafu::{Snafu, ResultExt};
+use sqlx::PgPool;
+use sqlx::postgres::PgListener;
+use tokio::sync::mpsc;
+
+use bigdecimal::BigDecimal;
+use chrono::{DateTime, NaiveDateTime, Utc, TimeZone};
+use uuid::Uuid;
+
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_with::serde_as;
+use domfi_ext_serde::BigDecimalExact;
+
+/// Channel used by `notify_trigger()` (see migrations)
+pub const DB_NOTIFY_CHANNEL: &str = "db_notify";
+
+#[derive(Deserialize, Debug)]
+pub struct DbNotification<T> {
+    pub timestamp: NaiveDateTime,
+    pub operation: String,
+    pub schema: String,
+    pub table: String,
+    pub data: T,
+}
+
+#[serde_as]
+#[derive(Deserialize, Clone, Debug)]
+pub struct CoinDominanceNotifyRow {
+    pub id: i64,
+    pub provenance_uuid: Uuid,
+    pub object_id: i64,
+    pub timestamp_utc: NaiveDateTime,
+    pub imported_at_utc: NaiveDateTime,
+    pub agent: String,
+    pub coin_id: String,
+    pub coin_name: String,
+    #[serde_as(as = "BigDecimalExact")]
+    pub market_cap_usd: BigDecimal,
+    #[serde_as(as = "BigDecimalExact")]
+    pub market_dominance_percentage: BigDecimal,
+}
+
+/// A `snapshot_observation` row, i.e. a poll whose snapshot was unchanged and not archived again
+#[derive(Deserialize, Clone, Debug)]
+pub struct SnapshotObservationNotifyRow {
+    pub id: i64,
+    pub timestamp_utc: NaiveDateTime,
+    pub agent: String,
+    pub provenance_uuid: Uuid,
+    pub reason: String,
+}
+
+/// All `coin_dominance` rows inserted for a single provenance
+#[derive(Clone, Debug)]
+pub struct CoinDominanceSnapshot {
+    pub provenance_uuid: Uuid,
+    pub object_id: i64,
+    pub agent: String,
+    pub timestamp: DateTime<Utc>,
+    pub rows: Vec<CoinDominanceNotifyRow>,
+}
+
+impl CoinDominanceSnapshot {
+    fn new(row: CoinDominanceNotifyRow) -> CoinDominanceSnapshot {
+        CoinDominanceSnapshot {
+            provenance_uuid: row.provenance_uuid,
+            object_id: row.object_id,
+            agent: row.agent.clone(),
+            timestamp: Utc.from_utc_datetime(&row.timestamp_utc),
+            rows: vec![row],
+        }
+    }
+
+    pub fn find_by_coin_id(&self, coin_id: &str) -> Option<&CoinDominanceNotifyRow> {
+        self.rows.iter().find(|r| r.coin_id == coin_id)
+    }
+}
+
+/// Restricts snapshots to those of a single agent moving forward in time, so that backfills,
+/// replays and the other agents' snapshots are not taken for the latest prices
+#[derive(Clone, Debug)]
+pub struct SnapshotFilter {
+    pub agent: String,
+    /// Only snapshots taken after this are passed
+    pub since: Option<DateTime<Utc>>,
+}
+
+impl SnapshotFilter {
+    pub fn new(agent: impl Into<String>, since: Option<DateTime<Utc>>) -> SnapshotFilter {
+        SnapshotFilter {
+            agent: agent.into(),
+            since,
+        }
+    }
+}
+
+/// Groups consecutive row notifications of the same provenance into snapshots.
+///
+/// Postgres delivers the notifications of a transaction together once it
+/// commits, so a snapshot is complete once a row of another provenance arrives
+/// or the channel has been quiet for a moment.
+#[derive(Default)]
+pub struct SnapshotAssembler {
+    pending: Option<CoinDominanceSnapshot>,
+    filter: Option<SnapshotFilter>,
+}
+
+impl SnapshotAssembler {
+    pub fn new() -> SnapshotAssembler {
+        SnapshotAssembler { pending: None, filter: None }
+    }
+
+    /// Drops rows not passing the filter as they arrive rather than buffering them. A snapshot
+    /// then only holds rows of a single timestamp.
+    pub fn with_filter(filter: SnapshotFilter) -> SnapshotAssembler {
+        SnapshotAssembler { pending: None, filter: Some(filter) }
+    }
+
+    fn accepts(&self, row: &CoinDominanceNotifyRow) -> bool {
+        let filter = match &self.filter {
+            None => return true,
+            Some(x) => x,
+        };
+
+        let timestamp = Utc.from_utc_datetime(&row.timestamp_utc);
+        match &self.pending {
+            _ if row.agent != filter.agent => false,
+            Some(snapshot) if snapshot.provenance_uuid == row.provenance_uuid => snapshot.timestamp == timestamp,
+            _ => filter.since.is_none_or(|x| timestamp > x),
+        }
+    }
+
+    /// Adds a row, returning the previous snapshot if the row starts a new one
+    pub fn push(&mut self, row: CoinDominanceNotifyRow) -> Option<CoinDominanceSnapshot> {
+        if !self.accepts(&row) {
+            return None;
+        }
+
+        match self.pending.as_mut() {
+            Some(snapshot) if snapshot.provenance_uuid == row.provenance_uuid => {
+                snapshot.rows.push(row);
+                None
+            },
+            _ => {
+                let snapshot = CoinDominanceSnapshot::new(row);
+                if let Some(filter) = self.filter.as_mut() {
+                    filter.since = Some(snapshot.timestamp);
+                }
+                self.pending.replace(snapshot)
+            },
+        }
+    }
+
+    pub fn flush(&mut self) -> Option<CoinDominanceSnapshot> {
+        self.pending.take()
+    }
+}
+
+#[derive(Snafu, Debug)]
+pub enum NotifyError {
+    #[snafu(display("Failed to listen on channel '{}': {}", channel, source))]
+    ListenError {
+        channel: String,
+        source: sqlx::Error,
+    },
+
+    #[snafu(display("Failed to deserialize notification payload: {}", source))]
+    PayloadError {
+        source: serde_json::Error,
+    },
+}
+
+fn parse_inserted_row<T: DeserializeOwned>(payload: &str, table: &str) -> Result<Option<T>, NotifyError> {
+    let msg = serde_json::from_str::<DbNotification<serde_json::Value>>(payload)
+        .context(PayloadError)?;
+
+    if msg.table != table || msg.operation != "INSERT" {
+        return Ok(None);
+    }
+
+    let row = serde_json::from_value::<T>(msg.data)
+        .context(PayloadError)?;
+
+    Ok(Some(row))
+}
+
+fn parse_coin_dominance_row(payload: &str) -> Result<Option<CoinDominanceNotifyRow>, NotifyError> {
+    parse_inserted_row(payload, "coin_dominance")
+}
+
+fn parse_snapshot_observation_row(payload: &str) -> Result<Option<SnapshotObservationNotifyRow>, NotifyError> {
+    parse_inserted_row(payload, "snapshot_observation")
+}
+
+/// Subscribes to `db_notify` and yields a `CoinDominanceSnapshot` for every
+/// committed snapshot passing the filter, if any. The background tasks stop once
+/// the receiver is dropped.
+pub async fn listen_snapshots(
+    pool: &PgPool,
+    filter: Option<SnapshotFilter>,
+    quiet_period: Duration,
+    buffer_size: usize,
+) -> Result<mpsc::Receiver<CoinDominanceSnapshot>, NotifyError> {
+
+    let mut listener = PgListener::connect_with(pool).await
+        .context(ListenError { channel: DB_NOTIFY_CHANNEL })?;
+
+    listener.listen(DB_NOTIFY_CHANNEL).await
+        .context(ListenError { channel: DB_NOTIFY_CHANNEL })?;
+
+    info!("Listening for snapshots on channel '{}'", DB_NOTIFY_CHANNEL);
+
+    let (mut rows_tx, mut rows_rx) = mpsc::channel::<CoinDominanceNotifyRow>(buffer_size);
+    let (mut snapshots_tx, snapshots_rx) = mpsc::channel::<CoinDominanceSnapshot>(buffer_size);
+
+    // Reading from the listener is kept in its own task since dropping a
+    // pending `recv()` (e.g. on timeout) is not safe for the connection.
+    tokio::spawn(async move {
+        loop {
+            let notification = match listener.recv().await {
+                Err(e) => {
+                    error!("Failed to receive notification. Retrying in 5s. Cause: {}", e);
+                    tokio::time::delay_for(Duration::from_secs(5)).await;
+                    continue;
+                },
+                Ok(x) => x,
+            };
+
+            let row = match parse_coin_dominance_row(notification.payload()) {
+                Err(e) => {
+                    warn!("Ignoring notification: {}", e);
+                    continue;
+                },
+                Ok(None) => continue,
+                Ok(Some(x)) => x,
+            };
+
+            if rows_tx.send(row).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut assembler = match filter {
+            None => SnapshotAssembler::new(),
+            Some(x) => SnapshotAssembler::with_filter(x),
+        };
+        loop {
+            let completed = match tokio::time::timeout(quiet_period, rows_rx.recv()).await {
+                Ok(None) => break,
+                Ok(Some(row)) => assembler.push(row),
+                Err(_) => assembler.flush(),
+            };
+
+            if let Some(snapshot) = completed {
+                if snapshots_tx.send(snapshot).await.is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(snapshots_rx)
+}
+
+/// Subscribes to `db_notify` and yields every `snapshot_observation` as it is committed.
+/// The background task stops once the receiver is dropped.
+pub async fn listen_observations(
+    pool: &PgPool,
+    buffer_size: usize,
+) -> Result<mpsc::Receiver<SnapshotObservationNotifyRow>, NotifyError> {
+
+    let mut listener = PgListener::connect_with(pool).await
+        .context(ListenError { channel: DB_NOTIFY_CHANNEL })?;
+
+    listener.listen(DB_NOTIFY_CHANNEL).await
+        .context(ListenError { channel: DB_NOTIFY_CHANNEL })?;
+
+    info!("Listening for snapshot observations on channel '{}'", DB_NOTIFY_CHANNEL);
+
+    let (mut tx, rx) = mpsc::channel::<SnapshotObservationNotifyRow>(buffer_size);
+
+    tokio::spawn(async move {
+        loop {
+            let notification = match listener.recv().await {
+                Err(e) => {
+                    error!("Failed to receive notification. Retrying in 5s. Cause: {}", e);
+                    tokio::time::delay_for(Duration::from_secs(5)).await;
+                    continue;
+                },
+                Ok(x) => x,
+            };
+
+            let row = match parse_snapshot_observation_row(notification.payload()) {
+                Err(e) => {
+                    warn!("Ignoring notification: {}", e);
+                    continue;
+                },
+                Ok(None) => continue,
+                Ok(Some(x)) => x,
+            };
+
+            if tx.send(row).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn payload(id: i64, provenance: &str, coin_id: &str) -> String {
+        payload_of(id, provenance, "loader_rust", "2020-12-30T14:52:35", coin_id)
+    }
+
+    fn payload_of(id: i64, provenance: &str, agent: &str, timestamp: &str, coin_id: &str) -> String {
+        format!(r#"{{
+            "timestamp":"2020-12-30T14:52:36.101",
+            "operation":"INSERT",
+            "schema":"public",
+            "table":"coin_dominance",
+            "data":{{
+                "id":{},
+                "provenance_uuid":"{}",
+                "object_id":12,
+                "timestamp_utc":"{}",
+                "imported_at_utc":"2020-12-30T14:52:36.100213",
+                "agent":"{}",
+                "coin_id":"{}",
+                "coin_name":"BTC",
+                "market_cap_usd":540123456789.123456789,
+                "market_dominance_percentage":69.1234567890123456789
+            }}
+        }}"#, id, provenance, timestamp, agent, coin_id)
+    }
+
+    const A: &str = "0a7ee5fd-65ee-4b8f-a0a3-cbb4dc6e8e3a";
+    const B: &str = "a53ac3d2-7b8e-4d4c-9ce3-df5a8a8e2f6b";
+    const C: &str = "5d0f1e4b-2c8a-4f6e-9b3d-7a1c2e4f6a8b";
+
+    #[test]
+    fn should_parse_coin_dominance_notification_exactly() {
+        let row = parse_coin_dominance_row(&payload(1, A, "bitcoin")).unwrap().unwrap();
+        assert_eq!(row.provenance_uuid, Uuid::from_str(A).unwrap());
+        assert_eq!(row.market_dominance_percentage, BigDecimal::from_str("69.1234567890123456789").unwrap());
+        assert_eq!(row.market_cap_usd, BigDecimal::from_str("540123456789.123456789").unwrap());
+    }
+
+    #[test]
+    fn should_ignore_other_tables() {
+        let x = payload(1, A, "bitcoin").replace(r#""table":"coin_dominance""#, r#""table":"provenance""#);
+        assert!(parse_coin_dominance_row(&x).unwrap().is_none());
+    }
+
+    #[test]
+    fn should_parse_snapshot_observation_notification() {
+        let x = format!(r#"{{
+            "timestamp":"2021-01-14T10:00:01.5",
+            "operation":"INSERT",
+            "schema":"public",
+            "table":"snapshot_observation",
+            "data":{{
+                "id":7,
+                "timestamp_utc":"2021-01-14T10:00:01.25",
+                "agent":"loader_coingecko_markets",
+                "provenance_uuid":"{}",
+                "reason":"hash"
+            }}
+        }}"#, A);
+
+        let row = parse_snapshot_observation_row(&x).unwrap().unwrap();
+        assert_eq!(row.agent, "loader_coingecko_markets");
+        assert_eq!(row.provenance_uuid, Uuid::from_str(A).unwrap());
+
+        // Neither kind of row is mistaken for the other
+        assert!(parse_coin_dominance_row(&x).unwrap().is_none());
+        assert!(parse_snapshot_observation_row(&payload(1, A, "bitcoin")).unwrap().is_none());
+    }
+
+    #[test]
+    fn should_group_rows_by_provenance() {
+        let mut assembler = SnapshotAssembler::new();
+        let row = |id, p| parse_coin_dominance_row(&payload(id, p, "bitcoin")).unwrap().unwrap();
+
+        assert!(assembler.push(row(1, A)).is_none());
+        assert!(assembler.push(row(2, A)).is_none());
+
+        let first = assembler.push(row(3, B)).unwrap();
+        assert_eq!(first.provenance_uuid, Uuid::from_str(A).unwrap());
+        assert_eq!(first.rows.len(), 2);
+
+        let second = assembler.flush().unwrap();
+        assert_eq!(second.provenance_uuid, Uuid::from_str(B).unwrap());
+        assert_eq!(second.rows.len(), 1);
+        assert!(assembler.flush().is_none());
+    }
+
+    #[test]
+    fn filtered_should_not_emit_other_agents() {
+        let filter = SnapshotFilter::new("loader_rust", None);
+        let mut assembler = SnapshotAssembler::with_filter(filter);
+        let row = |id, ts| parse_coin_dominance_row(&payload_of(id, A, "loader_historical", ts, "bitcoin")).unwrap().unwrap();
+
+        assert!(assembler.push(row(1, "2018-01-01T00:00:00")).is_none());
+        assert!(assembler.push(row(2, "2018-01-02T00:00:00")).is_none());
+        assert!(assembler.flush().is_none());
+    }
+
+    #[test]
+    fn filtered_should_only_move_forward_in_time() {
+        let since = Utc.ymd(2020, 12, 30).and_hms(14, 0, 0);
+        let mut assembler = SnapshotAssembler::with_filter(SnapshotFilter::new("loader_rust", Some(since)));
+        let row = |id, p, ts| parse_coin_dominance_row(&payload_of(id, p, "loader_rust", ts, "bitcoin")).unwrap().unwrap();
+
+        // Replayed rows older than the last snapshot passed
+        assert!(assembler.push(row(1, A, "2020-12-30T13:00:00")).is_none());
+        assert!(assembler.flush().is_none());
+
+        assert!(assembler.push(row(2, B, "2020-12-30T14:52:35")).is_none());
+        // A series under a single provenance only contributes its first timestamp
+        assert!(assembler.push(row(3, B, "2020-12-30T14:53:35")).is_none());
+        // Not newer than the pending snapshot
+        assert!(assembler.push(row(4, C, "2020-12-30T14:52:35")).is_none());
+
+        let snapshot = assembler.flush().unwrap();
+        assert_eq!(snapshot.provenance_uuid, Uuid::from_str(B).unwrap());
+        assert_eq!(snapshot.rows.len(), 1);
+
+        assert!(assembler.push(row(5, C, "2020-12-30T14:53:40")).is_none());
+        assert_eq!(assembler.flush().unwrap().provenance_uuid, Uuid::from_str(C).unwrap());
+    }
+}
@@ -1,15 +1,31 @@
 use log::info;
-use sqlx::PgPool;
+use sqlx::{Done, PgPool, Postgres, Transaction};
+use bigdecimal::BigDecimal;
+use std::collections::HashSet;
+use chrono::{DateTime, Duration, NaiveDateTime, SubsecRound, Timelike, Utc};
 use crate::pg::models::{
     ProvenanceId,
     CoinDominanceEntry
 };
 use std::ops::Deref;
 
+/// Entries suggested per [`insert_batch`] statement
+pub const DEFAULT_BATCH_SIZE: usize = 1000;
+
+/// What to do with an entry whose coin already has a row at the same timestamp for the same object
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum OnConflict {
+    /// Keep the existing row, as when the same blob is fetched again
+    Ignore,
+    /// Overwrite the values of the existing row, as when re-deriving rows from a blob
+    Update,
+}
+
 pub async fn insert<'a>(
     agent_name: &str,
     pid: &ProvenanceId,
     entries: &'a [CoinDominanceEntry<'a>],
+    on_conflict: OnConflict,
     pool: &PgPool)
     -> Result<(), sqlx::Error>
 {
@@ -17,7 +33,8 @@ pub async fn insert<'a>(
 
     let mut i = 0usize;
     for coin in entries.iter() {
-        sqlx::query!(r#"
+        match on_conflict {
+            OnConflict::Ignore => sqlx::query!(r#"
         insert into coin_dominance (
             provenance_uuid,
             object_id,
@@ -40,7 +57,36 @@ pub async fn insert<'a>(
         coin.market_cap_usd.deref(),
         coin.dominance_percentage.deref())
             .execute(&mut tx)
-            .await?;
+            .await?,
+
+            OnConflict::Update => sqlx::query!(r#"
+        insert into coin_dominance (
+            provenance_uuid,
+            object_id,
+            agent,
+            timestamp_utc,
+            coin_id,
+            coin_name,
+            market_cap_usd,
+            market_dominance_percentage
+        )
+        values ($1, $2, $3, $4, $5, $6, $7, $8)
+        on conflict (object_id, coin_id, timestamp_utc) do update
+            set coin_name = excluded.coin_name,
+                market_cap_usd = excluded.market_cap_usd,
+                market_dominance_percentage = excluded.market_dominance_percentage
+        "#,
+        pid.uuid,
+        pid.object_id,
+        agent_name,
+        coin.timestamp.naive_utc(),
+        coin.id.deref(),
+        coin.name.deref(),
+        coin.market_cap_usd.deref(),
+        coin.dominance_percentage.deref())
+            .execute(&mut tx)
+            .await?,
+        };
 
         i += 1;
         if i % 1000 == 0 {
@@ -56,3 +102,160 @@ pub async fn insert<'a>(
     Ok(())
 }
 
+/// Inserts `batch` in a single statement as part of `tx`, passing each column as an array to
+/// `unnest`. With [`OnConflict::Update`], a batch must not contain the same coin and timestamp twice.
+pub async fn insert_batch<'a>(
+    agent_name: &str,
+    pid: &ProvenanceId,
+    batch: &'a [CoinDominanceEntry<'a>],
+    on_conflict: OnConflict,
+    tx: &mut Transaction<'_, Postgres>)
+    -> Result<u64, sqlx::Error>
+{
+    let timestamps: Vec<NaiveDateTime> = batch.iter().map(|x| x.timestamp.naive_utc()).collect();
+    let ids: Vec<String> = batch.iter().map(|x| x.id.to_string()).collect();
+    let names: Vec<String> = batch.iter().map(|x| x.name.to_string()).collect();
+    let market_caps: Vec<BigDecimal> = batch.iter().map(|x| x.market_cap_usd.clone().into_owned()).collect();
+    let dominance: Vec<BigDecimal> = batch.iter().map(|x| x.dominance_percentage.clone().into_owned()).collect();
+
+    let done = match on_conflict {
+        OnConflict::Ignore => sqlx::query!(r#"
+            insert into coin_dominance (
+                provenance_uuid,
+                object_id,
+                agent,
+                timestamp_utc,
+                coin_id,
+                coin_name,
+                market_cap_usd,
+                market_dominance_percentage
+            )
+            select $1::uuid, $2::bigint, $3::text, x.*
+            from unnest($4::timestamp[], $5::text[], $6::text[], $7::numeric[], $8::numeric[]) as x
+            on conflict do nothing
+            "#,
+            pid.uuid,
+            pid.object_id,
+            agent_name,
+            &timestamps,
+            &ids,
+            &names,
+            &market_caps,
+            &dominance)
+            .execute(&mut *tx)
+            .await?,
+
+        OnConflict::Update => sqlx::query!(r#"
+            insert into coin_dominance (
+                provenance_uuid,
+                object_id,
+                agent,
+                timestamp_utc,
+                coin_id,
+                coin_name,
+                market_cap_usd,
+                market_dominance_percentage
+            )
+            select $1::uuid, $2::bigint, $3::text, x.*
+            from unnest($4::timestamp[], $5::text[], $6::text[], $7::numeric[], $8::numeric[]) as x
+            on conflict (object_id, coin_id, timestamp_utc) do update
+                set coin_name = excluded.coin_name,
+                    market_cap_usd = excluded.market_cap_usd,
+                    market_dominance_percentage = excluded.market_dominance_percentage
+            "#,
+            pid.uuid,
+            pid.object_id,
+            agent_name,
+            &timestamps,
+            &ids,
+            &names,
+            &market_caps,
+            &dominance)
+            .execute(&mut *tx)
+            .await?,
+    };
+
+    Ok(done.rows_affected())
+}
+
+/// Coins and timestamps of the rows the agent has loaded within `[from, to)`
+pub async fn find_points(
+    agent_name: &str,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    pool: &PgPool)
+    -> Result<HashSet<(String, DateTime<Utc>)>, sqlx::Error>
+{
+    let rows = sqlx::query!(r#"
+        select
+            coin_id,
+            timestamp_utc
+        from
+            coin_dominance
+        where
+            agent = $1
+            and ($2::timestamp is null or timestamp_utc >= $2)
+            and ($3::timestamp is null or timestamp_utc < $3)
+        "#,
+        agent_name,
+        from.map(|x| x.naive_utc()),
+        to.map(|x| x.naive_utc()))
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows.into_iter()
+        .map(|r| (r.coin_id, DateTime::from_utc(r.timestamp_utc, Utc)))
+        .collect())
+}
+
+/// Resolves the timestamp of the agent's snapshot the price endpoints serve for `ts`: the first
+/// snapshot within the minute of `ts`, or the latest snapshot if `ts` is `None`
+pub async fn find_served_timestamp(
+    agent_name: &str,
+    ts: Option<DateTime<Utc>>,
+    pool: &PgPool)
+    -> Result<Option<DateTime<Utc>>, sqlx::Error>
+{
+    let timestamp = match ts {
+        Some(ts) => {
+            let from = ts.trunc_subsecs(0).with_second(0).unwrap();
+            let to = from + Duration::minutes(1);
+
+            sqlx::query!(r#"
+                select
+                    timestamp_utc
+                from
+                    coin_dominance
+                where
+                    agent = $1
+                    and timestamp_utc between $2 and $3
+                order by timestamp_utc asc
+                limit 1
+                "#,
+                agent_name,
+                from.naive_utc(),
+                to.naive_utc())
+                .fetch_optional(pool)
+                .await?
+                .map(|r| r.timestamp_utc)
+        },
+        None => {
+            sqlx::query!(r#"
+                select
+                    timestamp_utc
+                from
+                    coin_dominance
+                where
+                    agent = $1
+                order by timestamp_utc desc
+                limit 1
+                "#,
+                agent_name)
+                .fetch_optional(pool)
+                .await?
+                .map(|r| r.timestamp_utc)
+        },
+    };
+
+    Ok(timestamp.map(|x| DateTime::from_utc(x, Utc)))
+}
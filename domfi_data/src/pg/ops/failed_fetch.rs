@@ -0,0 +1,49 @@
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use sqlx::PgPool;
+
+/// A failed attempt to fetch a snapshot
+#[derive(Debug)]
+pub struct FailedFetch<'a> {
+    pub timestamp: DateTime<Utc>,
+    pub agent: &'a str,
+    pub url: Option<String>,
+    /// 0 for the first attempt of a snapshot, then counting retries
+    pub attempt: u32,
+    pub http_status: Option<u16>,
+    pub retry_after: Option<Duration>,
+    pub error: String,
+    /// Body of the rejected response, if one was received
+    pub data: Option<&'a [u8]>,
+    pub mime: Option<&'a str>,
+    pub request_meta: Option<&'a Value>,
+    pub response_meta: Option<&'a Value>,
+}
+
+pub async fn insert(failure: &FailedFetch<'_>, pool: &PgPool) -> Result<i64, sqlx::Error> {
+    let row = sqlx::query!(r#"
+        insert into failed_fetch (
+            timestamp_utc, agent, url, attempt, http_status, retry_after_ms, error,
+            data, mime, request_metadata, response_metadata)
+        values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+        returning id
+        "#,
+        failure.timestamp.naive_utc(),
+        failure.agent,
+        failure.url,
+        failure.attempt as i32,
+        failure.http_status.map(|x| x as i16),
+        failure.retry_after.map(|x| i64::try_from(x.as_millis()).unwrap_or(i64::MAX)),
+        failure.error,
+        failure.data,
+        failure.mime,
+        failure.request_meta,
+        failure.response_meta)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(row.id)
+}
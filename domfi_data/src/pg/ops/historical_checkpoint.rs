@@ -0,0 +1,223 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use sqlx::{Done, PgPool, Postgres, Transaction};
+
+use crate::pg::models::{CoinDominanceEntry, ProvenanceId};
+
+/// An unfinished backfill of a historical chart
+#[derive(Debug)]
+pub struct HistoricalCheckpoint {
+    pub id: i64,
+    pub provenance: ProvenanceId,
+    /// Blob of the chart being loaded
+    pub data: Vec<u8>,
+    /// Every point before this timestamp has been loaded
+    pub resume_from: Option<DateTime<Utc>>,
+    pub inserted: i64,
+}
+
+pub async fn create(
+    agent: &str,
+    provenance: &ProvenanceId,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    pool: &PgPool,
+) -> Result<i64, sqlx::Error> {
+    let row = sqlx::query!(r#"
+        insert into historical_checkpoint (agent, provenance_uuid, from_utc, to_utc)
+        values ($1, $2, $3, $4)
+        returning id
+        "#,
+        agent,
+        provenance.uuid,
+        from.map(|x| x.naive_utc()),
+        to.map(|x| x.naive_utc()))
+        .fetch_one(pool)
+        .await?;
+
+    Ok(row.id)
+}
+
+/// Latest unfinished backfill of the agent over the same range
+pub async fn find_incomplete(
+    agent: &str,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    pool: &PgPool,
+) -> Result<Option<HistoricalCheckpoint>, sqlx::Error> {
+    let row = sqlx::query!(r#"
+        select
+            c.id,
+            p.uuid,
+            p.object_id,
+            obj.data,
+            c.resume_from_utc,
+            c.inserted
+        from
+            historical_checkpoint as c
+            inner join provenance as p
+                on p.uuid = c.provenance_uuid
+            inner join object_storage as obj
+                on obj.id = p.object_id
+        where
+            c.agent = $1
+            and c.from_utc is not distinct from $2
+            and c.to_utc is not distinct from $3
+            and c.completed_at is null
+            and c.abandoned_at is null
+        order by
+            c.started_at desc
+        limit 1
+        "#,
+        agent,
+        from.map(|x| x.naive_utc()),
+        to.map(|x| x.naive_utc()))
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(row.map(|r| HistoricalCheckpoint {
+        id: r.id,
+        provenance: ProvenanceId { uuid: r.uuid, object_id: r.object_id },
+        data: r.data,
+        resume_from: r.resume_from_utc.map(|x| DateTime::from_utc(x, Utc)),
+        inserted: r.inserted,
+    }))
+}
+
+/// Marks the unfinished backfills of the agent over the same range as abandoned, returning
+/// how many there were
+pub async fn abandon(
+    agent: &str,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    pool: &PgPool,
+) -> Result<u64, sqlx::Error> {
+    let abandoned = sqlx::query!(r#"
+        update historical_checkpoint
+        set abandoned_at = now() at time zone 'utc',
+            updated_at = now() at time zone 'utc'
+        where
+            agent = $1
+            and from_utc is not distinct from $2
+            and to_utc is not distinct from $3
+            and completed_at is null
+            and abandoned_at is null
+        "#,
+        agent,
+        from.map(|x| x.naive_utc()),
+        to.map(|x| x.naive_utc()))
+        .execute(pool)
+        .await?
+        .rows_affected();
+
+    Ok(abandoned)
+}
+
+/// Entries of the chart left to insert, in the order they are inserted: those at or after
+/// `start` and before `to`, except for the points already `loaded` as `(coin_id, timestamp)`.
+/// When resuming, `start` is the checkpoint, whose points may only be partly inserted.
+pub fn pending_entries<'a>(
+    entries: Vec<CoinDominanceEntry<'a>>,
+    start: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    loaded: &HashSet<(String, DateTime<Utc>)>,
+) -> Vec<CoinDominanceEntry<'a>> {
+    let mut x: Vec<_> = entries.into_iter()
+        .filter(|e| start.is_none_or(|start| *e.timestamp >= start))
+        .filter(|e| to.is_none_or(|to| *e.timestamp < to))
+        .filter(|e| !loaded.contains(&(e.id.to_string(), *e.timestamp)))
+        .collect();
+
+    x.sort_by(|a, b| (&a.timestamp, &a.id).cmp(&(&b.timestamp, &b.id)));
+    x
+}
+
+/// Records that every point before `resume_from` has been loaded, as part of the
+/// transaction inserting them
+pub async fn advance(
+    id: i64,
+    resume_from: DateTime<Utc>,
+    inserted: u64,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(r#"
+        update historical_checkpoint
+        set resume_from_utc = $2,
+            inserted = inserted + $3,
+            updated_at = now() at time zone 'utc'
+        where id = $1
+        "#,
+        id,
+        resume_from.naive_utc(),
+        inserted as i64)
+        .execute(&mut *tx)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn complete(id: i64, pool: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query!(r#"
+        update historical_checkpoint
+        set completed_at = now() at time zone 'utc',
+            updated_at = now() at time zone 'utc'
+        where id = $1
+        "#,
+        id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::collections::HashSet;
+    use bigdecimal::BigDecimal;
+    use chrono::{DateTime, Utc, TimeZone};
+    use crate::pg::models::CoinDominanceEntry;
+    use crate::pg::ops::historical_checkpoint::pending_entries;
+
+    fn entry(id: &'static str, secs: i64) -> CoinDominanceEntry<'static> {
+        CoinDominanceEntry {
+            name: Cow::Borrowed(id),
+            id: Cow::Borrowed(id),
+            market_cap_usd: Cow::Owned(BigDecimal::from(0)),
+            dominance_percentage: Cow::Owned(BigDecimal::from(1)),
+            timestamp: Cow::Owned(Utc.timestamp(secs, 0)),
+        }
+    }
+
+    fn chart() -> Vec<CoinDominanceEntry<'static>> {
+        vec![entry("eth", 0), entry("eth", 60), entry("btc", 0), entry("btc", 60), entry("btc", 120)]
+    }
+
+    fn keys(entries: &[CoinDominanceEntry]) -> Vec<(String, i64)> {
+        entries.iter().map(|e| (e.id.to_string(), e.timestamp.timestamp())).collect()
+    }
+
+    #[test]
+    fn pending_entries_should_be_in_range_and_sorted() {
+        let pending = pending_entries(chart(), Some(Utc.timestamp(60, 0)), Some(Utc.timestamp(120, 0)), &HashSet::new());
+        assert_eq!(keys(&pending), vec![("btc".into(), 60), ("eth".into(), 60)]);
+
+        assert_eq!(pending_entries(chart(), None, None, &HashSet::new()).len(), 5);
+    }
+
+    #[test]
+    fn resuming_should_insert_the_rest_of_a_partly_inserted_timestamp() {
+        let mut loaded: HashSet<(String, DateTime<Utc>)> = HashSet::new();
+
+        // A first run inserts a batch ending half way through the points at 60 and stops
+        let pending = pending_entries(chart(), None, None, &loaded);
+        let batch = &pending[..3];
+        let resume_from = Some(*batch[batch.len() - 1].timestamp);
+        loaded.extend(batch.iter().map(|e| (e.id.to_string(), *e.timestamp)));
+        assert_eq!(resume_from, Some(Utc.timestamp(60, 0)));
+
+        let pending = pending_entries(chart(), resume_from, None, &loaded);
+        assert_eq!(keys(&pending), vec![("eth".into(), 60), ("btc".into(), 120)]);
+    }
+}
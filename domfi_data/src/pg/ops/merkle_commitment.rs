@@ -0,0 +1,212 @@
+use chrono::NaiveDate;
+use log::info;
+use snafu::{Snafu, ResultExt};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::merkle::{MerkleTree, Hash};
+
+#[derive(Snafu, Debug)]
+pub enum MerkleCommitmentError {
+    #[snafu(display("Failed to access database: {}", source))]
+    DbError {
+        source: sqlx::Error,
+    },
+
+    #[snafu(display("Day {} is already committed", day))]
+    AlreadyCommitted {
+        day: NaiveDate,
+    },
+
+    #[snafu(display("Stored leaves of day {} no longer match the committed root", day))]
+    RootMismatch {
+        day: NaiveDate,
+    },
+}
+
+#[derive(Debug)]
+pub struct DayCommitment {
+    pub day: NaiveDate,
+    pub leaf_count: usize,
+    pub root: Hash,
+}
+
+#[derive(Debug)]
+pub struct InclusionProof {
+    pub day: NaiveDate,
+    pub provenance_uuid: Uuid,
+    pub leaf_index: usize,
+    pub leaf_count: usize,
+    /// `object_storage.sha256` of the provenance, i.e. the leaf data
+    pub leaf: Vec<u8>,
+    pub root: Hash,
+    pub proof: Vec<Hash>,
+}
+
+fn to_hash(x: &[u8]) -> Hash {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(x);
+    hash
+}
+
+/// Days with provenances before `before` that have no commitment yet, oldest first
+pub async fn uncommitted_days(before: NaiveDate, pool: &PgPool) -> Result<Vec<NaiveDate>, MerkleCommitmentError> {
+    let rows = sqlx::query!(r#"
+        select distinct
+            p.timestamp_utc::date as "day!"
+        from
+            provenance as p
+        where
+            p.timestamp_utc < $1::date
+            and not exists (
+                select 1 from merkle_day_commitment as c where c.day = p.timestamp_utc::date
+            )
+        order by
+            1
+        "#,
+        before)
+        .fetch_all(pool)
+        .await
+        .context(DbError)?;
+
+    Ok(rows.into_iter().map(|r| r.day).collect())
+}
+
+/// Builds the tree over the blob hashes of every provenance of the UTC day, ordered by
+/// timestamp then UUID, and stores its root and leaves. Returns `None` for empty days.
+pub async fn commit_day(day: NaiveDate, pool: &PgPool) -> Result<Option<DayCommitment>, MerkleCommitmentError> {
+    let mut tx = pool.begin().await.context(DbError)?;
+
+    let existing = sqlx::query!(r#"
+        select day from merkle_day_commitment where day = $1 for update
+        "#,
+        day)
+        .fetch_optional(&mut tx)
+        .await
+        .context(DbError)?;
+
+    if existing.is_some() {
+        return AlreadyCommitted { day }.fail();
+    }
+
+    let leaves = sqlx::query!(r#"
+        select
+            p.uuid,
+            obj.sha256
+        from
+            provenance as p
+            inner join object_storage as obj
+                on obj.id = p.object_id
+        where
+            p.timestamp_utc >= $1::date
+            and p.timestamp_utc < $1::date + 1
+        order by
+            p.timestamp_utc,
+            p.uuid
+        "#,
+        day)
+        .fetch_all(&mut tx)
+        .await
+        .context(DbError)?;
+
+    if leaves.is_empty() {
+        return Ok(None);
+    }
+
+    let tree = MerkleTree::new(leaves.iter().map(|x| &x.sha256));
+    let root = tree.root();
+
+    sqlx::query!(r#"
+        insert into merkle_day_commitment (day, leaf_count, root_sha256)
+        values ($1, $2, $3)
+        "#,
+        day,
+        leaves.len() as i32,
+        &root[..])
+        .execute(&mut tx)
+        .await
+        .context(DbError)?;
+
+    for (i, leaf) in leaves.iter().enumerate() {
+        sqlx::query!(r#"
+            insert into merkle_day_leaf (day, leaf_index, provenance_uuid, sha256)
+            values ($1, $2, $3, $4)
+            "#,
+            day,
+            i as i32,
+            leaf.uuid,
+            leaf.sha256)
+            .execute(&mut tx)
+            .await
+            .context(DbError)?;
+    }
+
+    tx.commit().await.context(DbError)?;
+
+    info!("Committed {} provenances of {} with root {}", leaves.len(), day, hex::encode(root));
+    Ok(Some(DayCommitment {
+        day,
+        leaf_count: leaves.len(),
+        root,
+    }))
+}
+
+/// Inclusion proof of the provenance in its day's commitment, or `None` if not committed yet
+pub async fn find_proof(provenance_uuid: Uuid, pool: &PgPool) -> Result<Option<InclusionProof>, MerkleCommitmentError> {
+    let leaf = sqlx::query!(r#"
+        select
+            leaf.day,
+            leaf.leaf_index,
+            c.root_sha256
+        from
+            merkle_day_leaf as leaf
+            inner join merkle_day_commitment as c
+                on c.day = leaf.day
+        where
+            leaf.provenance_uuid = $1
+        "#,
+        provenance_uuid)
+        .fetch_optional(pool)
+        .await
+        .context(DbError)?;
+
+    let leaf = match leaf {
+        None => return Ok(None),
+        Some(x) => x,
+    };
+
+    let leaves = sqlx::query!(r#"
+        select
+            sha256
+        from
+            merkle_day_leaf
+        where
+            day = $1
+        order by
+            leaf_index
+        "#,
+        leaf.day)
+        .fetch_all(pool)
+        .await
+        .context(DbError)?;
+
+    let tree = MerkleTree::new(leaves.iter().map(|x| &x.sha256));
+    let root = to_hash(&leaf.root_sha256);
+    if tree.root() != root {
+        return RootMismatch { day: leaf.day }.fail();
+    }
+
+    let leaf_index = leaf.leaf_index as usize;
+    let proof = tree.proof(leaf_index)
+        .ok_or(MerkleCommitmentError::RootMismatch { day: leaf.day })?;
+
+    Ok(Some(InclusionProof {
+        day: leaf.day,
+        provenance_uuid,
+        leaf_index,
+        leaf_count: tree.len(),
+        leaf: leaves[leaf_index].sha256.clone(),
+        root,
+        proof,
+    }))
+}
@@ -1,2 +1,7 @@
 pub mod provenance;
 pub mod coin_dominance_entry;
+pub mod merkle_commitment;
+pub mod failed_fetch;
+pub mod quarantined_snapshot;
+pub mod snapshot_observation;
+pub mod historical_checkpoint;
@@ -1,24 +1,30 @@
-use std::io::Cursor;
-
 use log::{warn};
 use bytes::Bytes;
 use sha2::{Sha256, Digest};
+#[cfg(feature = "fetch")]
 use snafu::{Snafu, ResultExt};
-use sqlx::PgPool;
+use sqlx::{PgPool, Postgres, Transaction};
 
 use chrono::{DateTime, Utc};
 use uuid::Uuid;
 
 use serde::Serialize;
 use serde_json::Value;
+#[cfg(feature = "fetch")]
 use serde::de::DeserializeOwned;
 
+#[cfg(feature = "fetch")]
 use reqwest::IntoUrl;
+#[cfg(feature = "fetch")]
 use reqwest::header::CONTENT_TYPE;
 
 use crate::pg::models::{ProvenanceId, RequestMetadata, ResponseMetadata};
+#[cfg(feature = "fetch")]
 use crate::pg::convert::ToMetadata;
+#[cfg(feature = "fetch")]
+use crate::pg::ops::failed_fetch::{self, FailedFetch};
 
+#[allow(clippy::too_many_arguments)]
 pub async fn insert(
     timestamp: &DateTime<Utc>,
     agent_name: impl AsRef<str>,
@@ -26,10 +32,28 @@ pub async fn insert(
     mime: Option<String>,
     request_meta: &Option<serde_json::Value>,
     response_meta: &Option<serde_json::Value>,
+    parent_uuid: Option<Uuid>,
     pool: &PgPool,
 ) -> Result<ProvenanceId, sqlx::Error> {
 
     let mut tx = pool.begin().await?;
+    let pid = insert_in_tx(timestamp, agent_name, buffer, mime, request_meta, response_meta, parent_uuid, &mut tx).await?;
+    tx.commit().await?;
+    Ok(pid)
+}
+
+/// Like [`insert`], as part of `tx`
+#[allow(clippy::too_many_arguments)]
+pub async fn insert_in_tx(
+    timestamp: &DateTime<Utc>,
+    agent_name: impl AsRef<str>,
+    buffer: &Bytes,
+    mime: Option<String>,
+    request_meta: &Option<serde_json::Value>,
+    response_meta: &Option<serde_json::Value>,
+    parent_uuid: Option<Uuid>,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<ProvenanceId, sqlx::Error> {
 
     let mut hasher = Sha256::new();
     hasher.update(buffer.as_ref());
@@ -50,7 +74,7 @@ pub async fn insert(
         hash.as_slice(),
         buffer.as_ref(),
         mime)
-        .fetch_one(&mut tx)
+        .fetch_one(&mut *tx)
         .await?;
 
     let object_id_opt: Option<i64> = storage.id;
@@ -64,24 +88,56 @@ pub async fn insert(
             agent,
             timestamp_utc,
             request_metadata,
-            response_metadata
+            response_metadata,
+            parent_uuid
         )
-        values ($1, $2, $3, $4, $5, $6)
+        values ($1, $2, $3, $4, $5, $6, $7)
     "#,
         uuid,
         storage.id,
         agent_name.as_ref(),
         timestamp.naive_utc(),
         *request_meta,
-        *response_meta)
-        .execute(&mut tx)
+        *response_meta,
+        parent_uuid)
+        .execute(&mut *tx)
         .await?;
 
-    tx.commit().await?;
     Ok(ProvenanceId { uuid, object_id })
 }
 
 
+/// Blobs of the provenances fetched alongside `parent_uuid`, in the order they were fetched
+pub async fn find_children_data(parent_uuid: &Uuid, pool: &PgPool) -> Result<Vec<Vec<u8>>, sqlx::Error> {
+    let rows = sqlx::query!(r#"
+        select
+            obj.data
+        from
+            provenance as p
+            inner join object_storage as obj
+                on obj.id = p.object_id
+        where
+            p.parent_uuid = $1
+        order by
+            p.timestamp_utc,
+            p.uuid
+        "#,
+        parent_uuid)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows.into_iter().map(|r| r.data).collect())
+}
+
+fn convert<T: Serialize>(value: T) -> Option<Value> {
+    let json = serde_json::to_value(value);
+    if let Err(ref err) = json {
+        warn!("Failed to serialize snapshot metadata: {}", err);
+    }
+    json.ok()
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn insert_from_http<S: AsRef<str>>(
     timestamp: &DateTime<Utc>,
     agent_name: S,
@@ -89,17 +145,10 @@ pub async fn insert_from_http<S: AsRef<str>>(
     mime: Option<String>,
     request_meta: &RequestMetadata,
     response_meta: &ResponseMetadata,
+    parent_uuid: Option<Uuid>,
     pool: &PgPool
 ) -> Result<ProvenanceId, sqlx::Error> {
 
-    fn convert<T: Serialize>(value: T) -> Option<Value> {
-        let json = serde_json::to_value(value);
-        if let Err(ref err) = json {
-            warn!("Failed to serialize snapshot metadata: {}", err);
-        }
-        json.ok()
-    }
-
     let request_meta = convert(request_meta);
     let response_meta = convert(response_meta);
 
@@ -110,6 +159,7 @@ pub async fn insert_from_http<S: AsRef<str>>(
         mime,
         &request_meta,
         &response_meta,
+        parent_uuid,
         &pool
     ).await?;
 
@@ -122,7 +172,7 @@ pub struct FetchIntoProvenanceOutput<Json> {
     pub json: Json,
 }
 
-
+#[cfg(feature = "fetch")]
 #[derive(Snafu, Debug)]
 pub enum FetchToInsertError {
     #[snafu(display("Failed to complete HTTP request: {}", source))]
@@ -130,6 +180,11 @@ pub enum FetchToInsertError {
         source: reqwest::Error,
     },
 
+    #[snafu(display("Server responded with HTTP {}", status))]
+    HttpStatus {
+        status: u16,
+    },
+
     #[snafu(display("Failed to deserialize response: {}", source))]
     DeserializationError {
         source: serde_json::Error,
@@ -141,6 +196,7 @@ pub enum FetchToInsertError {
     }
 }
 
+#[cfg(feature = "fetch")]
 pub async fn insert_from_json_url_with_client<Json, S, U>(
     agent_name: S,
     url: U,
@@ -158,19 +214,53 @@ where
         agent_name,
         url,
         &http,
+        None,
         pool).await
 }
 
+/// Fetches and deserializes `url`, storing the response as a provenance, fetched alongside
+/// `parent_uuid` if given
+#[cfg(feature = "fetch")]
 pub async fn insert_from_json_url<Json, S, U>(
     agent_name: S,
     url: U,
     http: &reqwest::Client,
+    parent_uuid: Option<Uuid>,
     pool: &PgPool)
     -> Result<FetchIntoProvenanceOutput<Json>, FetchToInsertError>
 where
     Json: DeserializeOwned,
     S: AsRef<str>,
     U: IntoUrl,
+{
+    let fetched = fetch_json_url::<Json, _, U>(&agent_name, url, http, pool).await?;
+    insert_fetched(agent_name, fetched, parent_uuid, pool).await
+}
+
+/// A deserialized response that is not stored yet
+#[cfg(feature = "fetch")]
+pub struct FetchedJson<Json> {
+    pub fetched_at: DateTime<Utc>,
+    pub buffer: Bytes,
+    pub mime: Option<String>,
+    pub request_meta: RequestMetadata,
+    pub response_meta: ResponseMetadata,
+    pub json: Json,
+}
+
+/// Fetches and deserializes `url` without storing it, so that the caller can check the
+/// response first. Rejected responses are kept as a failed fetch.
+#[cfg(feature = "fetch")]
+pub async fn fetch_json_url<Json, S, U>(
+    agent_name: S,
+    url: U,
+    http: &reqwest::Client,
+    pool: &PgPool)
+    -> Result<FetchedJson<Json>, FetchToInsertError>
+where
+    Json: DeserializeOwned,
+    S: AsRef<str>,
+    U: IntoUrl,
 {
     let request_builder = http.get(url);
     let request = request_builder.build().context(HttpError)?;
@@ -179,6 +269,9 @@ where
     let response = http.execute(request).await.context(HttpError)?;
     let now = Utc::now();
 
+    let status = response.status();
+    let url = response.url().to_string();
+
     let mime = response.headers()
         .get(CONTENT_TYPE)
         .and_then(|x| x.to_str().map(|s| s.to_string()).ok());
@@ -186,22 +279,72 @@ where
     let response_meta = response.to_metadata();
     let buffer = response.bytes().await.context(HttpError)?;
 
+    // Only usable responses become a provenance. Rejected ones are kept as a failed fetch.
+    let parsed = if status.is_success() {
+        serde_json::from_slice::<Json>(&buffer).context(DeserializationError)
+    } else {
+        Err(FetchToInsertError::HttpStatus { status: status.as_u16() })
+    };
+
+    let json = match parsed {
+        Ok(x) => x,
+        Err(err) => {
+            let request_meta = convert(&request_meta);
+            let response_meta = convert(&response_meta);
+            let failure = FailedFetch {
+                timestamp: now,
+                agent: agent_name.as_ref(),
+                url: Some(url),
+                attempt: 0,
+                http_status: Some(status.as_u16()),
+                retry_after: None,
+                error: err.to_string(),
+                data: Some(buffer.as_ref()),
+                mime: mime.as_deref(),
+                request_meta: request_meta.as_ref(),
+                response_meta: response_meta.as_ref(),
+            };
+            failed_fetch::insert(&failure, pool).await.context(DbError)?;
+            return Err(err);
+        }
+    };
+
+    Ok(FetchedJson {
+        fetched_at: now,
+        buffer,
+        mime,
+        request_meta,
+        response_meta,
+        json,
+    })
+}
+
+/// Stores a response fetched with [`fetch_json_url`] as a provenance, fetched alongside
+/// `parent_uuid` if given
+#[cfg(feature = "fetch")]
+pub async fn insert_fetched<Json, S>(
+    agent_name: S,
+    fetched: FetchedJson<Json>,
+    parent_uuid: Option<Uuid>,
+    pool: &PgPool)
+    -> Result<FetchIntoProvenanceOutput<Json>, FetchToInsertError>
+where
+    S: AsRef<str>,
+{
     let pid = super::provenance::insert_from_http(
-        &now,
+        &fetched.fetched_at,
         agent_name,
-        &buffer,
-        mime,
-        &request_meta,
-        &response_meta,
-        &pool,
+        &fetched.buffer,
+        fetched.mime,
+        &fetched.request_meta,
+        &fetched.response_meta,
+        parent_uuid,
+        pool,
     ).await.context(DbError)?;
 
-    let cursor = Cursor::new(buffer);
-    let json = serde_json::from_reader::<_, Json>(cursor).context(DeserializationError)?;
-
     Ok(FetchIntoProvenanceOutput {
-        imported_at: now,
+        imported_at: fetched.fetched_at,
         provenance: pid,
-        json,
+        json: fetched.json,
     })
 }
@@ -0,0 +1,40 @@
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use sqlx::PgPool;
+
+/// A snapshot rejected by validation
+#[derive(Debug)]
+pub struct QuarantinedSnapshot<'a> {
+    pub timestamp: DateTime<Utc>,
+    pub agent: &'a str,
+    pub url: &'a str,
+    pub data: &'a [u8],
+    pub mime: Option<&'a str>,
+    pub request_meta: Option<&'a Value>,
+    pub response_meta: Option<&'a Value>,
+    pub children: Vec<Vec<u8>>,
+    pub violations: Vec<String>,
+}
+
+pub async fn insert(snapshot: &QuarantinedSnapshot<'_>, pool: &PgPool) -> Result<i64, sqlx::Error> {
+    let row = sqlx::query!(r#"
+        insert into quarantined_snapshot (
+            timestamp_utc, agent, url, data, mime, request_metadata, response_metadata,
+            children, violations)
+        values ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        returning id
+        "#,
+        snapshot.timestamp.naive_utc(),
+        snapshot.agent,
+        snapshot.url,
+        snapshot.data,
+        snapshot.mime,
+        snapshot.request_meta,
+        snapshot.response_meta,
+        &snapshot.children,
+        &snapshot.violations)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(row.id)
+}
@@ -0,0 +1,78 @@
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// A poll whose snapshot was unchanged from the latest provenance of the agent
+#[derive(Debug)]
+pub struct SnapshotObservation<'a> {
+    pub timestamp: DateTime<Utc>,
+    pub agent: &'a str,
+    pub provenance_uuid: Uuid,
+    pub reason: &'a str,
+    pub sha256: &'a [u8],
+}
+
+/// Latest top-level provenance of an agent
+#[derive(Debug)]
+pub struct LatestProvenance {
+    pub uuid: Uuid,
+    /// Hashes of its blob and those of its children, in the order they were fetched
+    pub hashes: Vec<Vec<u8>>,
+}
+
+pub async fn insert(observation: &SnapshotObservation<'_>, pool: &PgPool) -> Result<i64, sqlx::Error> {
+    let row = sqlx::query!(r#"
+        insert into snapshot_observation (timestamp_utc, agent, provenance_uuid, reason, sha256)
+        values ($1, $2, $3, $4, $5)
+        returning id
+        "#,
+        observation.timestamp.naive_utc(),
+        observation.agent,
+        observation.provenance_uuid,
+        observation.reason,
+        observation.sha256)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(row.id)
+}
+
+pub async fn find_latest_provenance(agent: &str, pool: &PgPool) -> Result<Option<LatestProvenance>, sqlx::Error> {
+    let row = sqlx::query!(r#"
+        select
+            p.uuid,
+            obj.sha256,
+            array(
+                select
+                    child_obj.sha256
+                from
+                    provenance as child
+                    inner join object_storage as child_obj
+                        on child_obj.id = child.object_id
+                where
+                    child.parent_uuid = p.uuid
+                order by
+                    child.timestamp_utc,
+                    child.uuid
+            ) as "children!"
+        from
+            provenance as p
+            inner join object_storage as obj
+                on obj.id = p.object_id
+        where
+            p.agent = $1
+            and p.parent_uuid is null
+        order by
+            p.timestamp_utc desc
+        limit 1
+        "#,
+        agent)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(row.map(|r| {
+        let mut hashes = vec![r.sha256];
+        hashes.extend(r.children);
+        LatestProvenance { uuid: r.uuid, hashes }
+    }))
+}
@@ -0,0 +1,254 @@
+//! Compares the historical chart points of one agent with the live snapshots of another.
+//! The chart only has rounded dominance percentages and no market caps, so the two are
+//! aligned per coin at the nearest timestamp and only their dominance is compared.
+
+use std::collections::BTreeMap;
+use std::io;
+
+use bigdecimal::{BigDecimal, Zero};
+use chrono::{DateTime, Duration, Utc};
+use futures::TryStreamExt;
+use snafu::{Snafu, ResultExt};
+use sqlx::PgPool;
+
+use crate::coingecko::{HISTORICAL_OTHERS_ID, LIVE_OTHERS_ID};
+
+#[derive(Snafu, Debug)]
+pub enum ReconcileError {
+    #[snafu(display("Failed to access database: {}", source))]
+    DbError {
+        source: sqlx::Error,
+    },
+
+    #[snafu(display("Failed to write comparison: {}", source))]
+    OutputError {
+        source: io::Error,
+    },
+}
+
+#[derive(Debug)]
+pub struct ReconcileFilter {
+    pub historical_agent: String,
+    pub live_agent: String,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    /// Maximum distance between a chart point and the live snapshot it is compared with
+    pub tolerance: Duration,
+}
+
+/// A chart point and the nearest live snapshot of the same coin
+#[derive(Debug)]
+pub struct Pair {
+    pub coin_id: String,
+    pub historical_timestamp: DateTime<Utc>,
+    pub live_timestamp: DateTime<Utc>,
+    pub historical: BigDecimal,
+    pub live: BigDecimal,
+}
+
+impl Pair {
+    /// Live minus historical dominance, in percentage points
+    pub fn deviation(&self) -> BigDecimal {
+        &self.live - &self.historical
+    }
+}
+
+pub const CSV_HEADER: &str = "coin_id,historical_timestamp,live_timestamp,historical,live,deviation,outlier";
+
+pub fn csv_row(pair: &Pair, deviation: &BigDecimal, outlier: bool) -> String {
+    format!("{},{},{},{},{},{},{}",
+        csv_field(&pair.coin_id),
+        pair.historical_timestamp.timestamp(),
+        pair.live_timestamp.timestamp(),
+        pair.historical,
+        pair.live,
+        deviation,
+        outlier)
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+/// Deviation statistics of the pairs of a coin, in percentage points
+#[derive(Debug)]
+pub struct CoinStats {
+    pub pairs: usize,
+    pub outliers: usize,
+    sum: BigDecimal,
+    sum_abs: BigDecimal,
+    pub max_abs: BigDecimal,
+}
+
+impl Default for CoinStats {
+    fn default() -> Self {
+        CoinStats {
+            pairs: 0,
+            outliers: 0,
+            sum: BigDecimal::zero(),
+            sum_abs: BigDecimal::zero(),
+            max_abs: BigDecimal::zero(),
+        }
+    }
+}
+
+impl CoinStats {
+    pub fn add(&mut self, deviation: &BigDecimal, outlier: bool) {
+        let abs = deviation.abs();
+
+        self.pairs += 1;
+        self.sum += deviation;
+        if abs > self.max_abs {
+            self.max_abs = abs.clone();
+        }
+        self.sum_abs += abs;
+
+        if outlier {
+            self.outliers += 1;
+        }
+    }
+
+    /// Mean deviation, i.e. how much higher the live snapshots are on average
+    pub fn mean(&self) -> BigDecimal {
+        self.average(&self.sum)
+    }
+
+    pub fn mean_abs(&self) -> BigDecimal {
+        self.average(&self.sum_abs)
+    }
+
+    fn average(&self, sum: &BigDecimal) -> BigDecimal {
+        if self.pairs == 0 {
+            return BigDecimal::zero();
+        }
+        (sum / BigDecimal::from(self.pairs as u64)).with_scale(8)
+    }
+}
+
+/// Aligns every chart point of the historical agent with the nearest live snapshot of the
+/// same coin within the tolerance, passing each pair to `on_pair` along with its deviation
+/// and whether its absolute deviation exceeds `outlier_threshold`. Chart points without a
+/// live snapshot close enough are left out. The "others" bucket, which the two store under
+/// different ids, is reported under the historical one.
+pub async fn reconcile<F>(
+    filter: &ReconcileFilter,
+    outlier_threshold: &BigDecimal,
+    pool: &PgPool,
+    mut on_pair: F,
+) -> Result<BTreeMap<String, CoinStats>, ReconcileError>
+    where F: FnMut(&Pair, &BigDecimal, bool) -> io::Result<()>
+{
+    let mut stats: BTreeMap<String, CoinStats> = BTreeMap::new();
+    let mut rows = sqlx::query!(r#"
+        select
+            h.coin_id,
+            h.timestamp_utc as historical_timestamp,
+            h.market_dominance_percentage as historical,
+            l.timestamp_utc as live_timestamp,
+            l.market_dominance_percentage as live
+        from
+            (
+                select distinct on (coin_id, timestamp_utc)
+                    coin_id, timestamp_utc, market_dominance_percentage
+                from
+                    coin_dominance
+                where
+                    agent = $1
+                    and ($3::timestamp is null or timestamp_utc >= $3)
+                    and ($4::timestamp is null or timestamp_utc < $4)
+                order by
+                    coin_id,
+                    timestamp_utc,
+                    id
+            ) as h
+            cross join lateral (
+                select
+                    timestamp_utc,
+                    market_dominance_percentage
+                from
+                    coin_dominance
+                where
+                    agent = $2
+                    and coin_id = case when h.coin_id = $6 then $7 else h.coin_id end
+                    and timestamp_utc between h.timestamp_utc - make_interval(secs => $5)
+                        and h.timestamp_utc + make_interval(secs => $5)
+                order by
+                    abs(extract(epoch from timestamp_utc - h.timestamp_utc)),
+                    id
+                limit 1
+            ) as l
+        order by
+            h.coin_id,
+            h.timestamp_utc
+        "#,
+        filter.historical_agent,
+        filter.live_agent,
+        filter.from.map(|x| x.naive_utc()),
+        filter.to.map(|x| x.naive_utc()),
+        filter.tolerance.num_milliseconds() as f64 / 1000f64,
+        HISTORICAL_OTHERS_ID,
+        LIVE_OTHERS_ID)
+        .fetch(pool);
+
+    while let Some(r) = rows.try_next().await.context(DbError)? {
+        let pair = Pair {
+            coin_id: r.coin_id,
+            historical_timestamp: DateTime::from_utc(r.historical_timestamp, Utc),
+            live_timestamp: DateTime::from_utc(r.live_timestamp, Utc),
+            historical: r.historical,
+            live: r.live,
+        };
+
+        let deviation = pair.deviation();
+        let outlier = deviation.abs() > *outlier_threshold;
+
+        on_pair(&pair, &deviation, outlier).context(OutputError)?;
+        stats.entry(pair.coin_id.clone()).or_default().add(&deviation, outlier);
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use bigdecimal::BigDecimal;
+    use chrono::{Utc, TimeZone};
+    use crate::reconcile::{csv_row, CoinStats, Pair};
+
+    fn dec(s: &str) -> BigDecimal {
+        BigDecimal::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn stats_should_track_bias_and_absolute_deviation() {
+        let mut stats = CoinStats::default();
+        stats.add(&dec("0.5"), false);
+        stats.add(&dec("-1.5"), true);
+        stats.add(&dec("0.25"), false);
+
+        assert_eq!(stats.pairs, 3);
+        assert_eq!(stats.outliers, 1);
+        assert_eq!(stats.mean(), dec("-0.25"));
+        assert_eq!(stats.mean_abs(), dec("0.75"));
+        assert_eq!(stats.max_abs, dec("1.5"));
+    }
+
+    #[test]
+    fn csv_row_should_keep_exact_values() {
+        let pair = Pair {
+            coin_id: "bitcoin".into(),
+            historical_timestamp: Utc.timestamp(1608195600, 0),
+            live_timestamp: Utc.timestamp(1608195603, 0),
+            historical: dec("63.1"),
+            live: dec("63.05285004474413"),
+        };
+
+        assert_eq!(csv_row(&pair, &pair.deviation(), false),
+            "bitcoin,1608195600,1608195603,63.1,63.05285004474413,-0.04714995525587,false");
+    }
+}
@@ -0,0 +1,365 @@
+//! Re-derives `coin_dominance` rows from the archived blobs, e.g. after fixing a
+//! parsing bug in one of the source schemas. Rows deleted by retention stay deleted, see
+//! [`crate::retention`].
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use log::{info, warn};
+use snafu::{Snafu, ResultExt};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::coingecko::SourceSchema;
+use crate::pg::models::{CoinDominanceEntry, ProvenanceId};
+use crate::pg::ops::coin_dominance_entry::{self, OnConflict};
+use crate::pg::ops::provenance;
+use crate::retention::{self, RetentionError};
+use crate::verify::{self, StoredRow};
+
+#[derive(Snafu, Debug)]
+pub enum ReplayError {
+    #[snafu(display("Failed to access database: {}", source))]
+    DbError {
+        source: sqlx::Error,
+    },
+
+    #[snafu(display("Failed to read stored rows: {}", source))]
+    StoredRowsError {
+        source: verify::VerifyError,
+    },
+
+    #[snafu(display("Failed to read retention cutoff: {}", source))]
+    RetentionCutoffError {
+        source: RetentionError,
+    },
+}
+
+#[derive(Eq, PartialEq, Debug)]
+pub enum RowChange {
+    Insert {
+        coin_id: String,
+        timestamp: DateTime<Utc>,
+    },
+
+    Update {
+        coin_id: String,
+        timestamp: DateTime<Utc>,
+        field: &'static str,
+        old: String,
+        new: String,
+    },
+}
+
+impl Display for RowChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RowChange::Insert { coin_id, timestamp } =>
+                write!(f, "+ {} @ {}", coin_id, timestamp.timestamp()),
+            RowChange::Update { coin_id, timestamp, field, old, new } =>
+                write!(f, "~ {} @ {} {}: {} -> {}", coin_id, timestamp.timestamp(), field, old, new),
+        }
+    }
+}
+
+/// Changes that upserting `entries` would make to the stored rows of the same object.
+/// Rows are unique per object, coin and timestamp.
+pub fn diff_rows(entries: &[CoinDominanceEntry], rows: &[StoredRow]) -> Vec<RowChange> {
+    let rows: HashMap<(&str, DateTime<Utc>), &StoredRow> = rows.iter()
+        .map(|x| ((x.coin_id.as_str(), x.timestamp_utc), x))
+        .collect();
+
+    let mut changes = Vec::new();
+    for entry in entries {
+        let row = match rows.get(&(entry.id.as_ref(), *entry.timestamp)) {
+            None => {
+                changes.push(RowChange::Insert { coin_id: entry.id.to_string(), timestamp: *entry.timestamp });
+                continue;
+            },
+            Some(x) => x,
+        };
+
+        let mut update = |field: &'static str, old: String, new: String| {
+            changes.push(RowChange::Update { coin_id: entry.id.to_string(), timestamp: *entry.timestamp, field, old, new });
+        };
+
+        if row.coin_name != entry.name {
+            update("coin_name", row.coin_name.clone(), entry.name.to_string());
+        }
+        if row.market_cap_usd != *entry.market_cap_usd {
+            update("market_cap_usd", row.market_cap_usd.to_string(), entry.market_cap_usd.to_string());
+        }
+        if row.dominance_percentage != *entry.dominance_percentage {
+            update("dominance_percentage", row.dominance_percentage.to_string(), entry.dominance_percentage.to_string());
+        }
+    }
+
+    changes
+}
+
+/// Leaves out the entries before `cutoff` without a stored row, as retention deleted them.
+/// Those with a row were kept by retention and are still compared.
+pub fn skip_downsampled<'a>(
+    entries: Vec<CoinDominanceEntry<'a>>,
+    rows: &[StoredRow],
+    cutoff: Option<DateTime<Utc>>,
+) -> Vec<CoinDominanceEntry<'a>> {
+    let cutoff = match cutoff {
+        None => return entries,
+        Some(x) => x,
+    };
+
+    let rows: HashSet<(&str, DateTime<Utc>)> = rows.iter()
+        .map(|x| (x.coin_id.as_str(), x.timestamp_utc))
+        .collect();
+
+    entries.into_iter()
+        .filter(|e| *e.timestamp >= cutoff || rows.contains(&(e.id.as_ref(), *e.timestamp)))
+        .collect()
+}
+
+/// Leaves out the chart points without a stored row, which the backfill skipped for being out of
+/// its range or already loaded from another chart. Inserting them would duplicate the agent's
+/// points under a second object, as rows are only unique per object.
+pub fn skip_unloaded<'a>(
+    entries: Vec<CoinDominanceEntry<'a>>,
+    rows: &[StoredRow],
+) -> Vec<CoinDominanceEntry<'a>> {
+    let rows: HashSet<(&str, DateTime<Utc>)> = rows.iter()
+        .map(|x| (x.coin_id.as_str(), x.timestamp_utc))
+        .collect();
+
+    entries.into_iter()
+        .filter(|e| rows.contains(&(e.id.as_ref(), *e.timestamp)))
+        .collect()
+}
+
+#[derive(Default, Debug)]
+pub struct ReplayFilter {
+    pub provenance_uuid: Option<Uuid>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+#[derive(Default, Debug)]
+pub struct ReplaySummary {
+    pub provenances: usize,
+    pub unparsable: usize,
+    pub changed: usize,
+    pub changes: usize,
+    /// Entries left out as retention deleted their rows
+    pub downsampled: usize,
+    /// Chart points left out as the backfill did not load them
+    pub unloaded: usize,
+}
+
+struct ReplayRow {
+    uuid: Uuid,
+    object_id: i64,
+    agent: String,
+    url: Option<String>,
+    data: Vec<u8>,
+}
+
+/// Replays every provenance matching the filter, oldest first, reporting the
+/// changes of each through `on_change`. Nothing is written when `dry_run` is set.
+/// Provenances fetched alongside another are replayed as part of their parent.
+/// Historical charts only update the points the backfill loaded, see [`skip_unloaded`], and
+/// snapshots leave out the points before the latest retention cutoff without a row.
+pub async fn replay<F>(filter: &ReplayFilter, dry_run: bool, pool: &PgPool, mut on_change: F) -> Result<ReplaySummary, ReplayError>
+    where F: FnMut(&Uuid, &RowChange)
+{
+    let mut summary = ReplaySummary::default();
+    let cutoff = retention::latest_cutoff(pool).await.context(RetentionCutoffError)?;
+
+    let mut provenances = sqlx::query_as!(ReplayRow, r#"
+        select
+            p.uuid,
+            p.object_id,
+            p.agent,
+            p.request_metadata->>'url' as url,
+            obj.data
+        from
+            provenance as p
+            inner join object_storage as obj
+                on obj.id = p.object_id
+        where
+            ($1::uuid is null or p.uuid = $1)
+            and ($2::timestamp is null or p.timestamp_utc >= $2)
+            and ($3::timestamp is null or p.timestamp_utc < $3)
+            and p.parent_uuid is null
+        order by
+            p.timestamp_utc,
+            p.uuid
+        "#,
+        filter.provenance_uuid,
+        filter.from.map(|x| x.naive_utc()),
+        filter.to.map(|x| x.naive_utc()))
+        .fetch(pool);
+
+    while let Some(p) = provenances.try_next().await.context(DbError)? {
+        summary.provenances += 1;
+
+        let children = provenance::find_children_data(&p.uuid, pool).await.context(DbError)?;
+        let (schema, entries) = match SourceSchema::detect_and_parse(p.url.as_deref(), &p.data, &children) {
+            Err(e) => {
+                warn!("[{}] Skipping unparsable blob obj #{}: {}", p.uuid, p.object_id, e);
+                summary.unparsable += 1;
+                continue;
+            },
+            Ok(x) => x,
+        };
+
+        let rows = verify::find_rows(p.object_id, pool).await.context(StoredRowsError)?;
+        let parsed = entries.len();
+        let entries = if schema == SourceSchema::MarketDominance {
+            let entries = skip_unloaded(entries, &rows);
+            summary.unloaded += parsed - entries.len();
+            entries
+        } else {
+            let entries = skip_downsampled(entries, &rows, cutoff);
+            summary.downsampled += parsed - entries.len();
+            entries
+        };
+
+        let changes = diff_rows(&entries, &rows);
+        if changes.is_empty() {
+            continue;
+        }
+
+        summary.changed += 1;
+        summary.changes += changes.len();
+        changes.iter().for_each(|x| on_change(&p.uuid, x));
+
+        if !dry_run {
+            let pid = ProvenanceId { uuid: p.uuid, object_id: p.object_id };
+            coin_dominance_entry::insert(&p.agent, &pid, &entries, OnConflict::Update, pool)
+                .await
+                .context(DbError)?;
+            info!("[{}] Replayed {} changes", p.uuid, changes.len());
+        }
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::str::FromStr;
+    use bigdecimal::BigDecimal;
+    use chrono::{Utc, TimeZone};
+    use uuid::Uuid;
+    use crate::coingecko::SourceSchema;
+    use crate::replay::{RowChange, diff_rows, skip_downsampled, skip_unloaded};
+    use crate::verify::StoredRow;
+
+    const LIVE: &[u8] = br#"{"data":[
+        {"name":"BTC","id":"bitcoin","market_cap_usd":432393586286.0436,"dominance_percentage":63.05285004474413}
+        ],"timestamp":1608195600}"#;
+
+    const HISTORICAL: &[u8] = br#"{"series_data_array":[
+        {"name":"BTC","data":[[1608195600000,63.1],[1608195700000,63.2]]}
+        ]}"#;
+
+    #[test]
+    fn diff_should_insert_every_missing_point_of_a_chart() {
+        let (_, entries) = SourceSchema::detect_and_parse(None, HISTORICAL, &[]).unwrap();
+
+        let rows = vec![StoredRow {
+            provenance_uuid: Uuid::nil(),
+            timestamp_utc: Utc.timestamp(1608195600, 0),
+            coin_id: "bitcoin".into(),
+            coin_name: "BTC".into(),
+            market_cap_usd: BigDecimal::from(0),
+            dominance_percentage: BigDecimal::from_str("63.1").unwrap(),
+        }];
+
+        assert_eq!(diff_rows(&entries, &rows), vec![
+            RowChange::Insert { coin_id: "bitcoin".into(), timestamp: Utc.timestamp(1608195700, 0) },
+        ]);
+    }
+
+    #[test]
+    fn diff_should_list_inserts_and_changed_fields() {
+        let (_, entries) = SourceSchema::detect_and_parse(None, LIVE, &[]).unwrap();
+        let mut entries = entries;
+        entries.push(crate::pg::models::CoinDominanceEntry {
+            name: Cow::Borrowed("ETH"),
+            id: Cow::Borrowed("ethereum"),
+            market_cap_usd: Cow::Owned(BigDecimal::from(1)),
+            dominance_percentage: Cow::Owned(BigDecimal::from(2)),
+            timestamp: Cow::Owned(Utc.timestamp(1608195600, 0)),
+        });
+
+        let rows = vec![StoredRow {
+            provenance_uuid: Uuid::nil(),
+            timestamp_utc: Utc.timestamp(1608195600, 0),
+            coin_id: "bitcoin".into(),
+            coin_name: "BTC".into(),
+            market_cap_usd: BigDecimal::from_str("432393586286.0436").unwrap(),
+            dominance_percentage: BigDecimal::from_str("63.052850044744").unwrap(),
+        }];
+
+        assert_eq!(diff_rows(&entries, &rows), vec![
+            RowChange::Update {
+                coin_id: "bitcoin".into(),
+                timestamp: Utc.timestamp(1608195600, 0),
+                field: "dominance_percentage",
+                old: "63.052850044744".into(),
+                new: "63.05285004474413".into(),
+            },
+            RowChange::Insert { coin_id: "ethereum".into(), timestamp: Utc.timestamp(1608195600, 0) },
+        ]);
+    }
+
+    #[test]
+    fn replay_should_skip_points_deleted_by_retention() {
+        let entries = || SourceSchema::detect_and_parse(None, HISTORICAL, &[]).unwrap().1;
+        let rows = vec![StoredRow {
+            provenance_uuid: Uuid::nil(),
+            timestamp_utc: Utc.timestamp(1608195600, 0),
+            coin_id: "bitcoin".into(),
+            coin_name: "BTC".into(),
+            market_cap_usd: BigDecimal::from(0),
+            dominance_percentage: BigDecimal::from_str("63.0").unwrap(),
+        }];
+
+        // Only the kept point before the cutoff is compared
+        let kept = skip_downsampled(entries(), &rows, Some(Utc.timestamp(1608195800, 0)));
+        assert_eq!(kept.len(), 1);
+        assert_eq!(*kept[0].timestamp, Utc.timestamp(1608195600, 0));
+        assert_eq!(diff_rows(&kept, &rows).len(), 1);
+
+        assert_eq!(skip_downsampled(entries(), &rows, Some(Utc.timestamp(1608195700, 0))).len(), 2);
+        assert_eq!(skip_downsampled(entries(), &rows, None).len(), 2);
+    }
+
+    #[test]
+    fn replay_should_only_update_the_loaded_points_of_a_chart() {
+        let (_, entries) = SourceSchema::detect_and_parse(None, HISTORICAL, &[]).unwrap();
+        let rows = vec![StoredRow {
+            provenance_uuid: Uuid::nil(),
+            timestamp_utc: Utc.timestamp(1608195700, 0),
+            coin_id: "bitcoin".into(),
+            coin_name: "BTC".into(),
+            market_cap_usd: BigDecimal::from(0),
+            dominance_percentage: BigDecimal::from_str("63.0").unwrap(),
+        }];
+
+        let loaded = skip_unloaded(entries, &rows);
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(diff_rows(&loaded, &rows), vec![
+            RowChange::Update {
+                coin_id: "bitcoin".into(),
+                timestamp: Utc.timestamp(1608195700, 0),
+                field: "dominance_percentage",
+                old: "63.0".into(),
+                new: "63.2".into(),
+            },
+        ]);
+    }
+}
@@ -0,0 +1,422 @@
+//! Downsamples old `coin_dominance` rows to one snapshot per minute or hour per agent, and
+//! garbage-collects the provenances no longer referenced by any row and the blobs of those
+//! provenances left without one.
+//!
+//! Each run records its cutoff in `retention_run`. Replay skips the points before the latest
+//! cutoff that have no row, as retention deleted them, and only updates the kept ones.
+
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use futures::TryStreamExt;
+use log::info;
+use snafu::{Snafu, ResultExt};
+use sqlx::{Done, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+/// Snapshots whose rows are deleted per statement
+const DELETE_BATCH_SIZE: usize = 1000;
+
+#[derive(Snafu, Debug)]
+pub enum RetentionError {
+    #[snafu(display("Failed to access database: {}", source))]
+    DbError {
+        source: sqlx::Error,
+    },
+}
+
+/// Bucket of which one snapshot per agent is kept
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Resolution {
+    Minute,
+    Hour,
+}
+
+impl Resolution {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Resolution::Minute => "minute",
+            Resolution::Hour => "hour",
+        }
+    }
+
+    /// Start of the bucket `timestamp` falls in
+    pub fn bucket(&self, timestamp: NaiveDateTime) -> NaiveDateTime {
+        let seconds = match self {
+            Resolution::Minute => 60,
+            Resolution::Hour => 60 * 60,
+        };
+        let ts = timestamp.timestamp();
+        NaiveDateTime::from_timestamp(ts - ts.rem_euclid(seconds), 0)
+    }
+}
+
+impl FromStr for Resolution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "minute" => Ok(Resolution::Minute),
+            "hour" => Ok(Resolution::Hour),
+            x => Err(format!("expected 'minute' or 'hour' but got '{}'", x)),
+        }
+    }
+}
+
+impl Display for Resolution {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Decides which snapshots to keep, given them ordered by agent, timestamp and provenance:
+/// the first of each agent per bucket
+#[derive(Debug)]
+pub struct Downsampler {
+    resolution: Resolution,
+    last: Option<(String, NaiveDateTime)>,
+}
+
+impl Downsampler {
+    pub fn new(resolution: Resolution) -> Self {
+        Downsampler { resolution, last: None }
+    }
+
+    pub fn keep(&mut self, agent: &str, timestamp: NaiveDateTime) -> bool {
+        let bucket = self.resolution.bucket(timestamp);
+        match &self.last {
+            Some((last_agent, last_bucket)) if last_agent == agent && *last_bucket == bucket => false,
+            _ => {
+                self.last = Some((agent.to_owned(), bucket));
+                true
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RetentionPolicy {
+    /// Rows before this timestamp are downsampled
+    pub cutoff: DateTime<Utc>,
+    pub resolution: Resolution,
+    /// Also prune provenances of days without a Merkle commitment, whose inclusion
+    /// proofs would be lost with them
+    pub allow_uncommitted: bool,
+}
+
+#[derive(Default, Debug)]
+pub struct RetentionSummary {
+    pub rows: u64,
+    pub observations: u64,
+    pub provenances: u64,
+    pub blobs: u64,
+    pub blob_bytes: u64,
+}
+
+/// Deletes the rows of every snapshot but the first of each agent per bucket before the
+/// cutoff. A snapshot is the rows of a provenance at one timestamp, so a historical chart
+/// also keeps a single point per bucket. Provenances left without rows are then deleted with
+/// their children and observations, unless a historical backfill refers to them, followed by
+/// every blob without provenance.
+///
+/// Everything happens in one transaction. With `dry_run`, the same predicates are only counted
+/// and nothing is deleted.
+pub async fn apply(policy: &RetentionPolicy, dry_run: bool, pool: &PgPool) -> Result<RetentionSummary, RetentionError> {
+    let mut summary = RetentionSummary::default();
+    let mut tx = pool.begin().await.context(DbError)?;
+
+    let cutoff = policy.cutoff.naive_utc();
+
+    let mut downsampler = Downsampler::new(policy.resolution);
+    let mut doomed: Vec<(Uuid, NaiveDateTime)> = Vec::new();
+    let mut kept_provenances: HashSet<Uuid> = HashSet::new();
+
+    let mut snapshots = sqlx::query!(r#"
+        select
+            agent,
+            timestamp_utc,
+            provenance_uuid,
+            count(*) as "rows!"
+        from
+            coin_dominance
+        where
+            timestamp_utc < $1
+        group by
+            agent,
+            timestamp_utc,
+            provenance_uuid
+        order by
+            agent,
+            timestamp_utc,
+            provenance_uuid
+        "#,
+        cutoff)
+        .fetch(&mut tx);
+
+    while let Some(s) = snapshots.try_next().await.context(DbError)? {
+        if downsampler.keep(&s.agent, s.timestamp_utc) {
+            kept_provenances.insert(s.provenance_uuid);
+        } else {
+            doomed.push((s.provenance_uuid, s.timestamp_utc));
+            summary.rows += s.rows as u64;
+        }
+    }
+    drop(snapshots);
+
+    // Provenances whose rows before the cutoff are all deleted
+    let emptied: Vec<Uuid> = doomed.iter()
+        .map(|(uuid, _)| *uuid)
+        .filter(|x| !kept_provenances.contains(x))
+        .collect::<HashSet<Uuid>>()
+        .into_iter()
+        .collect();
+
+    info!("Downsampling rows before {} to one snapshot per {}, {} rows in {} snapshots",
+        policy.cutoff, policy.resolution, summary.rows, doomed.len());
+
+    if !dry_run {
+        for batch in doomed.chunks(DELETE_BATCH_SIZE) {
+            delete_snapshots(batch, &mut tx).await?;
+        }
+    }
+
+    let orphans: Vec<Uuid> = sqlx::query!(r#"
+        select
+            p.uuid
+        from
+            provenance as p
+        where
+            p.parent_uuid is null
+            and p.timestamp_utc < $1
+            and not exists (
+                select 1
+                from coin_dominance as c
+                where
+                    c.provenance_uuid = p.uuid
+                    and not (c.timestamp_utc < $1 and c.provenance_uuid = any($3))
+            )
+            and not exists (select 1 from historical_checkpoint as h where h.provenance_uuid = p.uuid)
+            and ($2 or not exists (
+                select 1
+                from provenance as q
+                where
+                    (q.uuid = p.uuid or q.parent_uuid = p.uuid)
+                    and not exists (select 1 from merkle_day_leaf as l where l.provenance_uuid = q.uuid)
+            ))
+        "#,
+        cutoff,
+        policy.allow_uncommitted,
+        &emptied)
+        .fetch_all(&mut tx)
+        .await
+        .context(DbError)?
+        .into_iter()
+        .map(|r| r.uuid)
+        .collect();
+
+    // Only the blobs of these provenances may be left without one by this run
+    let objects: Vec<i64> = sqlx::query!(r#"
+        select distinct
+            object_id
+        from
+            provenance
+        where
+            uuid = any($1)
+            or parent_uuid = any($1)
+        "#,
+        &orphans)
+        .fetch_all(&mut tx)
+        .await
+        .context(DbError)?
+        .into_iter()
+        .map(|r| r.object_id)
+        .collect();
+
+    if dry_run {
+        let counts = sqlx::query!(r#"
+            select
+                (select count(*) from snapshot_observation where provenance_uuid = any($1)) as "observations!",
+                (select count(*) from provenance where uuid = any($1) or parent_uuid = any($1)) as "provenances!"
+            "#,
+            &orphans)
+            .fetch_one(&mut tx)
+            .await
+            .context(DbError)?;
+
+        summary.observations = counts.observations as u64;
+        summary.provenances = counts.provenances as u64;
+    } else {
+        summary.observations = sqlx::query!(r#"
+            delete from snapshot_observation where provenance_uuid = any($1)
+            "#,
+            &orphans)
+            .execute(&mut tx)
+            .await
+            .context(DbError)?
+            .rows_affected();
+
+        let children = sqlx::query!(r#"
+            delete from provenance where parent_uuid = any($1)
+            "#,
+            &orphans)
+            .execute(&mut tx)
+            .await
+            .context(DbError)?
+            .rows_affected();
+
+        let parents = sqlx::query!(r#"
+            delete from provenance where uuid = any($1)
+            "#,
+            &orphans)
+            .execute(&mut tx)
+            .await
+            .context(DbError)?
+            .rows_affected();
+
+        summary.provenances = children + parents;
+    }
+
+    info!("Deleting {} provenances without rows and {} of their observations",
+        summary.provenances, summary.observations);
+
+    let (blobs, bytes) = if dry_run {
+        let r = sqlx::query!(r#"
+            select
+                count(*) as "blobs!",
+                coalesce(sum(octet_length(obj.data)), 0)::bigint as "bytes!"
+            from
+                object_storage as obj
+            where
+                obj.id = any($2)
+                and not exists (
+                    select 1
+                    from provenance as p
+                    where
+                        p.object_id = obj.id
+                        and not (p.uuid = any($1) or coalesce(p.parent_uuid = any($1), false))
+                )
+            "#,
+            &orphans,
+            &objects)
+            .fetch_one(&mut tx)
+            .await
+            .context(DbError)?;
+        (r.blobs, r.bytes)
+    } else {
+        let r = sqlx::query!(r#"
+            with deleted as (
+                delete from object_storage as obj
+                where
+                    obj.id = any($1)
+                    and not exists (select 1 from provenance as p where p.object_id = obj.id)
+                returning octet_length(obj.data) as size
+            )
+            select
+                count(*) as "blobs!",
+                coalesce(sum(size), 0)::bigint as "bytes!"
+            from
+                deleted
+            "#,
+            &objects)
+            .fetch_one(&mut tx)
+            .await
+            .context(DbError)?;
+        (r.blobs, r.bytes)
+    };
+
+    summary.blobs = blobs as u64;
+    summary.blob_bytes = bytes as u64;
+    info!("Deleting {} blobs left without provenance, {} bytes", summary.blobs, summary.blob_bytes);
+
+    if dry_run {
+        tx.rollback().await.context(DbError)?;
+        return Ok(summary);
+    }
+
+    sqlx::query!(r#"
+        insert into retention_run (cutoff_utc, resolution, rows_deleted)
+        values ($1, $2, $3)
+        "#,
+        cutoff,
+        policy.resolution.as_str(),
+        summary.rows as i64)
+        .execute(&mut tx)
+        .await
+        .context(DbError)?;
+
+    tx.commit().await.context(DbError)?;
+    Ok(summary)
+}
+
+async fn delete_snapshots(snapshots: &[(Uuid, NaiveDateTime)], tx: &mut Transaction<'_, Postgres>) -> Result<u64, RetentionError> {
+    let (uuids, timestamps): (Vec<Uuid>, Vec<NaiveDateTime>) = snapshots.iter().cloned().unzip();
+
+    let deleted = sqlx::query!(r#"
+        delete from coin_dominance as c
+        using unnest($1::uuid[], $2::timestamp[]) as d(provenance_uuid, timestamp_utc)
+        where
+            c.provenance_uuid = d.provenance_uuid
+            and c.timestamp_utc = d.timestamp_utc
+        "#,
+        &uuids,
+        &timestamps)
+        .execute(&mut *tx)
+        .await
+        .context(DbError)?
+        .rows_affected();
+
+    Ok(deleted)
+}
+
+/// Cutoff of the latest retention run, before which every row has been downsampled
+pub async fn latest_cutoff(pool: &PgPool) -> Result<Option<DateTime<Utc>>, RetentionError> {
+    let row = sqlx::query!(r#"
+        select max(cutoff_utc) as cutoff_utc from retention_run
+        "#)
+        .fetch_one(pool)
+        .await
+        .context(DbError)?;
+
+    Ok(row.cutoff_utc.map(|x| DateTime::from_utc(x, Utc)))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+    use crate::retention::{Downsampler, Resolution};
+
+    fn ts(secs: i64) -> NaiveDateTime {
+        NaiveDateTime::from_timestamp(secs, 0)
+    }
+
+    #[test]
+    fn resolution_should_parse_date_trunc_fields() {
+        assert_eq!("Hour".parse::<Resolution>(), Ok(Resolution::Hour));
+        assert_eq!(" minute ".parse::<Resolution>(), Ok(Resolution::Minute));
+        assert!("day".parse::<Resolution>().is_err());
+    }
+
+    #[test]
+    fn resolution_should_truncate_to_bucket() {
+        assert_eq!(Resolution::Minute.bucket(ts(1608195659)), ts(1608195600));
+        assert_eq!(Resolution::Hour.bucket(ts(1608195659)), ts(1608195600));
+        assert_eq!(Resolution::Hour.bucket(ts(1608199199)), ts(1608195600));
+        assert_eq!(Resolution::Minute.bucket(ts(-1)), ts(-60));
+    }
+
+    #[test]
+    fn downsampler_should_keep_first_snapshot_of_each_agent_per_bucket() {
+        let mut downsampler = Downsampler::new(Resolution::Minute);
+        let kept: Vec<bool> = vec![
+            ("a", 0), ("a", 0), ("a", 30), ("a", 60), ("a", 119),
+            ("b", 0), ("b", 59), ("b", 60),
+        ].into_iter()
+            .map(|(agent, secs)| downsampler.keep(agent, ts(secs)))
+            .collect();
+
+        assert_eq!(kept, vec![true, false, false, true, false, true, false, true]);
+    }
+}
@@ -0,0 +1,38 @@
+//! Loader agents whose snapshots the API serves each asset from, shared with the admin tooling
+//! so both resolve the same agent when none is configured.
+
+use domfi_domain::models::FinancialDominanceAsset;
+use domfi_domain::models::financial_assets::{ADADOM, SOLDOM};
+
+/// Agent of the `coingecko` source, i.e. the default agent of `domfi_loader`
+pub const DEFAULT_SERVING_AGENT: &str = "loader_rust";
+
+/// Agent of the `coingecko_markets` source, the only one computing the dominance of coins
+/// outside the CoinGecko top list
+pub const MARKETS_SERVING_AGENT: &str = "loader_coingecko_markets";
+
+/// Agent serving the asset when none is configured for it: [`MARKETS_SERVING_AGENT`] for
+/// SOLDOM and ADADOM, [`DEFAULT_SERVING_AGENT`] for every other asset
+pub fn default_agent_for(asset: &FinancialDominanceAsset) -> &'static str {
+    if asset == SOLDOM.asset() || asset == ADADOM.asset() {
+        MARKETS_SERVING_AGENT
+    } else {
+        DEFAULT_SERVING_AGENT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use domfi_domain::models::financial_assets::get_canonical_default_asset;
+    use super::*;
+
+    #[test]
+    fn default_agent_should_serve_coins_outside_the_top_list_from_the_markets_agent() {
+        let agent_of = |id: &str| default_agent_for(get_canonical_default_asset(id).unwrap().asset());
+
+        assert_eq!(agent_of("soldom"), MARKETS_SERVING_AGENT);
+        assert_eq!(agent_of("adadom"), MARKETS_SERVING_AGENT);
+        assert_eq!(agent_of("btcdom"), DEFAULT_SERVING_AGENT);
+        assert_eq!(agent_of("altdom"), DEFAULT_SERVING_AGENT);
+    }
+}
@@ -0,0 +1,233 @@
+use async_trait::async_trait;
+use reqwest::Url;
+use snafu::ResultExt;
+
+use crate::coingecko::{CoinDominanceResponse, SourceSchema};
+use crate::pg::models::CoinDominanceEntry;
+use crate::source::{DataSource, RawSnapshot, SourceError, DeserializationError};
+
+pub const COINGECKO_COIN_DOMINANCE_URL: &str = "https://api.coingecko.com/api/v3/global/coin_dominance";
+pub const COINGECKO_API_URL: &str = "https://api.coingecko.com/api/v3";
+
+/// Largest page size `/coins/markets` allows
+const MARKETS_PER_PAGE: u32 = 250;
+
+/// URL with a random query parameter, so that no cache between us and the API
+/// serves a stale response
+fn cache_busted(url: &Url) -> Url {
+    let num = rand::random::<u64>();
+
+    url.clone()
+        .query_pairs_mut()
+        .append_pair("_", num.to_string().as_str())
+        .finish()
+        .to_owned()
+}
+
+/// Polls `/api/v3/global/coin_dominance`
+pub struct CoinGeckoDominanceSource {
+    name: String,
+    agent_name: String,
+    url: Url,
+}
+
+impl CoinGeckoDominanceSource {
+    pub fn new(name: impl Into<String>, agent_name: impl Into<String>, url: Url) -> CoinGeckoDominanceSource {
+        CoinGeckoDominanceSource {
+            name: name.into(),
+            agent_name: agent_name.into(),
+            url,
+        }
+    }
+}
+
+#[async_trait]
+impl DataSource for CoinGeckoDominanceSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn agent_name(&self) -> &str {
+        &self.agent_name
+    }
+
+    async fn fetch(&self, http: &reqwest::Client) -> Result<Vec<RawSnapshot>, SourceError> {
+        Ok(vec![RawSnapshot::fetch_http(http, cache_busted(&self.url)).await?])
+    }
+
+    fn normalize(&self, buffer: &[u8], _children: &[Vec<u8>]) -> Result<Vec<CoinDominanceEntry<'static>>, SourceError> {
+        let json = serde_json::from_slice::<CoinDominanceResponse>(buffer).context(DeserializationError)?;
+        Ok(json.into_entries())
+    }
+}
+
+/// Computes dominance of every coin from `/api/v3/coins/markets`, relative to the
+/// total market cap of `/api/v3/global`. Unlike `/global/coin_dominance`, this also
+/// covers coins outside of the top ten.
+pub struct CoinGeckoMarketsSource {
+    name: String,
+    agent_name: String,
+    global_url: Url,
+    markets_url: Url,
+    pages: u32,
+}
+
+impl CoinGeckoMarketsSource {
+    /// `api_url` is the base of the API, e.g. [`COINGECKO_API_URL`]. `pages` of 250 coins
+    /// each are fetched, by descending market cap.
+    pub fn new(name: impl Into<String>, agent_name: impl Into<String>, api_url: &Url, pages: u32) -> Result<CoinGeckoMarketsSource, url::ParseError> {
+        let base = api_url.as_str().trim_end_matches('/');
+        let global_url = Url::parse(&format!("{}/global", base))?;
+        let mut markets_url = Url::parse(&format!("{}/coins/markets", base))?;
+        markets_url.query_pairs_mut()
+            .append_pair("vs_currency", "usd")
+            .append_pair("order", "market_cap_desc")
+            .append_pair("per_page", &MARKETS_PER_PAGE.to_string());
+
+        Ok(CoinGeckoMarketsSource {
+            name: name.into(),
+            agent_name: agent_name.into(),
+            global_url,
+            markets_url,
+            pages: pages.max(1),
+        })
+    }
+
+    fn markets_page_url(&self, page: u32) -> Url {
+        let mut url = self.markets_url.clone();
+        url.query_pairs_mut().append_pair("page", &page.to_string());
+        url
+    }
+}
+
+#[async_trait]
+impl DataSource for CoinGeckoMarketsSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn agent_name(&self) -> &str {
+        &self.agent_name
+    }
+
+    async fn fetch(&self, http: &reqwest::Client) -> Result<Vec<RawSnapshot>, SourceError> {
+        let mut snapshots = vec![RawSnapshot::fetch_http(http, cache_busted(&self.global_url)).await?];
+        for page in 1..=self.pages {
+            let url = cache_busted(&self.markets_page_url(page));
+            match RawSnapshot::fetch_http(http, url).await {
+                Ok(x) => snapshots.push(x),
+                // The responses received so far are kept with the failure
+                Err(e) => return Err(SourceError::IncompleteSnapshot {
+                    source: Box::new(e),
+                    fetched: snapshots,
+                }),
+            }
+        }
+        Ok(snapshots)
+    }
+
+    fn normalize(&self, buffer: &[u8], children: &[Vec<u8>]) -> Result<Vec<CoinDominanceEntry<'static>>, SourceError> {
+        SourceSchema::Global.parse(buffer, children).context(DeserializationError)
+    }
+
+    /// Only the fetched pages of coins are covered
+    fn complete(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use chrono::Utc;
+    use reqwest::Url;
+    use tokio::net::TcpListener;
+    use tokio::prelude::*;
+    use crate::source::{DataSource, SourceError};
+    use crate::source::coingecko::{CoinGeckoDominanceSource, CoinGeckoMarketsSource};
+
+    const GLOBAL: &str = r#"{"data":{"total_market_cap":{"usd":800},"updated_at":1608195600}}"#;
+    const MARKETS: &str = r#"[{"id":"bitcoin","symbol":"btc","name":"Bitcoin","market_cap":500}]"#;
+
+    /// Serves every request with the status and body of the first route contained in its path
+    async fn serve(routes: Vec<(&'static str, u16, &'static str)>) -> Url {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buffer = vec![0; 4096];
+                let n = socket.read(&mut buffer).await.unwrap();
+                let request = String::from_utf8_lossy(&buffer[..n]).into_owned();
+                let path = request.split_whitespace().nth(1).unwrap_or("/");
+                let (status, body) = routes.iter()
+                    .find(|(route, _, _)| path.contains(route))
+                    .map(|(_, status, body)| (*status, *body))
+                    .unwrap_or((404, ""));
+
+                let response = format!(
+                    "HTTP/1.1 {} Test\r\nContent-Type: application/json\r\nContent-Length: {}\r\nRetry-After: 7\r\nConnection: close\r\n\r\n{}",
+                    status, body.len(), body);
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        Url::parse(&format!("http://{}/api/v3", addr)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn non_2xx_response_should_be_rejected_with_its_body() {
+        let url = serve(vec![("/coin_dominance", 404, r#"{"error":"gone"}"#)]).await;
+        let source = CoinGeckoDominanceSource::new("coingecko", "loader_rust", url.join("v3/global/coin_dominance").unwrap());
+
+        let err = source.fetch(&reqwest::Client::new()).await.unwrap_err();
+        assert!(matches!(err, SourceError::HttpStatus { status: 404, .. }));
+        assert!(!err.is_retryable());
+        assert_eq!(err.retry_after(), None);
+
+        let rejected = err.rejected_responses();
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].buffer.as_ref(), br#"{"error":"gone"}"#);
+    }
+
+    #[tokio::test]
+    async fn failed_markets_page_should_keep_the_responses_fetched_before() {
+        let url = serve(vec![
+            ("/global", 200, GLOBAL),
+            ("page=1", 200, MARKETS),
+            ("page=2", 503, "unavailable"),
+        ]).await;
+        let source = CoinGeckoMarketsSource::new("coingecko_markets", "loader_coingecko_markets", &url, 2).unwrap();
+
+        let err = source.fetch(&reqwest::Client::new()).await.unwrap_err();
+        assert!(matches!(err, SourceError::IncompleteSnapshot { .. }));
+        assert!(err.is_retryable());
+        assert_eq!(err.http_status(), Some(503));
+        assert_eq!(err.retry_after(), Some(Duration::from_secs(7)));
+
+        let failures = err.failed_fetches("loader_coingecko_markets", 1, Utc::now());
+        let bodies: Vec<&[u8]> = failures.iter().map(|x| x.data.unwrap()).collect();
+        assert_eq!(bodies, vec![GLOBAL.as_bytes(), MARKETS.as_bytes(), b"unavailable"]);
+        assert!(failures[0].url.as_deref().unwrap().contains("/global"));
+        assert!(failures[2].url.as_deref().unwrap().contains("page=2"));
+        assert!(failures.iter().all(|x| x.http_status == Some(503) && x.attempt == 1));
+    }
+
+    #[tokio::test]
+    async fn markets_should_only_normalize_parsable_responses() {
+        let url = serve(vec![("/global", 200, GLOBAL), ("/markets", 200, MARKETS)]).await;
+        let source = CoinGeckoMarketsSource::new("coingecko_markets", "loader_coingecko_markets", &url, 1).unwrap();
+
+        let snapshots = source.fetch(&reqwest::Client::new()).await.unwrap();
+        assert_eq!(snapshots.len(), 2);
+
+        let children = vec![snapshots[1].buffer.to_vec()];
+        let entries = source.normalize(&snapshots[0].buffer, &children).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, "bitcoin");
+
+        let err = source.normalize(b"<html>", &children);
+        assert!(matches!(err, Err(SourceError::DeserializationError { .. })));
+        assert!(source.normalize(&snapshots[0].buffer, &[b"{}".to_vec()]).is_err());
+    }
+}
@@ -0,0 +1,454 @@
+//! Data sources the loader can poll. A source fetches a raw snapshot, which is normalized
+//! into `coin_dominance` rows and archived as a provenance. Sources which need several
+//! responses per snapshot archive the others as children of the first. Snapshots with
+//! non-2xx or unparsable responses are rejected before anything is archived, and those
+//! failing validation are quarantined. Unchanged snapshots may be recorded as observations
+//! of the previous provenance instead of being archived again.
+
+pub mod coingecko;
+pub mod retry;
+pub mod unchanged;
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use log::warn;
+use reqwest::IntoUrl;
+use reqwest::StatusCode;
+use reqwest::header::{CONTENT_TYPE, RETRY_AFTER};
+use serde::Serialize;
+use serde_json::Value;
+use snafu::{Snafu, ResultExt};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::pg::convert::ToMetadata;
+use crate::pg::models::{CoinDominanceEntry, ProvenanceId};
+use crate::pg::ops::coin_dominance_entry::{self, OnConflict};
+use crate::pg::ops::failed_fetch::FailedFetch;
+use crate::pg::ops::provenance;
+use crate::pg::ops::quarantined_snapshot::{self, QuarantinedSnapshot};
+use crate::pg::ops::snapshot_observation::{self, SnapshotObservation};
+use crate::source::unchanged::{SkipUnchanged, UnchangedReason};
+use crate::validate::{self, PreviousSnapshot, ValidationRules};
+
+#[derive(Snafu, Debug)]
+pub enum SourceError {
+    #[snafu(display("Failed to complete HTTP request: {}", source))]
+    HttpError {
+        source: reqwest::Error,
+    },
+
+    #[snafu(display("Server responded with HTTP {} to {}", status, url))]
+    HttpStatus {
+        url: String,
+        status: u16,
+        retry_after: Option<Duration>,
+        response: Box<RawSnapshot>,
+    },
+
+    #[snafu(display("Malformed response: {}", source))]
+    MalformedResponse {
+        source: Box<SourceError>,
+        responses: Vec<RawSnapshot>,
+    },
+
+    /// A response of a snapshot failed after the earlier ones were received
+    #[snafu(display("{}", source))]
+    IncompleteSnapshot {
+        source: Box<SourceError>,
+        fetched: Vec<RawSnapshot>,
+    },
+
+    #[snafu(display("Failed to deserialize response: {}", source))]
+    DeserializationError {
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Failed to insert into database: {}", source))]
+    DbError {
+        source: sqlx::Error,
+    },
+
+    #[snafu(display("Source returned no response"))]
+    EmptySnapshot,
+
+    #[snafu(display("Snapshot failed validation and was quarantined as #{}: {}", id, violations.join("; ")))]
+    Quarantined {
+        id: i64,
+        violations: Vec<String>,
+    },
+}
+
+impl SourceError {
+    /// Whether trying again may succeed, i.e. on network errors, rate limiting and server errors
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            SourceError::HttpError { source } => !source.is_builder(),
+            SourceError::HttpStatus { status, .. } => *status == 429 || *status >= 500,
+            SourceError::IncompleteSnapshot { source, .. } => source.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Delay the server asked for with `Retry-After`
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            SourceError::HttpStatus { retry_after, .. } => *retry_after,
+            SourceError::IncompleteSnapshot { source, .. } => source.retry_after(),
+            _ => None,
+        }
+    }
+
+    pub fn url(&self) -> Option<String> {
+        match self {
+            SourceError::HttpError { source } => source.url().map(|x| x.to_string()),
+            SourceError::HttpStatus { url, .. } => Some(url.clone()),
+            SourceError::MalformedResponse { responses, .. } => responses.first().map(|x| x.url.clone()),
+            SourceError::IncompleteSnapshot { source, .. } => source.url(),
+            _ => None,
+        }
+    }
+
+    pub fn http_status(&self) -> Option<u16> {
+        match self {
+            SourceError::HttpError { source } => source.status().map(|x| x.as_u16()),
+            SourceError::HttpStatus { status, .. } => Some(*status),
+            SourceError::IncompleteSnapshot { source, .. } => source.http_status(),
+            _ => None,
+        }
+    }
+
+    /// Responses that were received but rejected, in the order they were fetched
+    pub fn rejected_responses(&self) -> Vec<&RawSnapshot> {
+        match self {
+            SourceError::HttpStatus { response, .. } => vec![response.as_ref()],
+            SourceError::MalformedResponse { responses, .. } => responses.iter().collect(),
+            SourceError::IncompleteSnapshot { source, fetched } => fetched.iter()
+                .chain(source.rejected_responses())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Records of the failed attempt, one per rejected response so that each body is kept.
+    /// Nothing is recorded for database errors or quarantined snapshots.
+    pub fn failed_fetches<'a>(&'a self, agent: &'a str, attempt: u32, timestamp: DateTime<Utc>) -> Vec<FailedFetch<'a>> {
+        match self {
+            // Nothing to record into if the database is what failed
+            SourceError::DbError { .. } => return Vec::new(),
+            // Already kept in `quarantined_snapshot`
+            SourceError::Quarantined { .. } => return Vec::new(),
+            _ => (),
+        }
+
+        let error = self.to_string();
+        let responses: Vec<Option<&RawSnapshot>> = match self.rejected_responses() {
+            xs if xs.is_empty() => vec![None],
+            xs => xs.into_iter().map(Some).collect(),
+        };
+
+        responses.into_iter()
+            .map(|raw| FailedFetch {
+                timestamp,
+                agent,
+                url: raw.map(|x| x.url.clone()).or_else(|| self.url()),
+                attempt,
+                http_status: self.http_status(),
+                retry_after: self.retry_after(),
+                error: error.clone(),
+                data: raw.map(|x| x.buffer.as_ref()),
+                mime: raw.and_then(|x| x.mime.as_deref()),
+                request_meta: raw.and_then(|x| x.request_meta.as_ref()),
+                response_meta: raw.and_then(|x| x.response_meta.as_ref()),
+            })
+            .collect()
+    }
+}
+
+/// Raw bytes of a snapshot as fetched, with whatever describes how they were obtained
+#[derive(Debug)]
+pub struct RawSnapshot {
+    pub fetched_at: DateTime<Utc>,
+    pub url: String,
+    pub buffer: Bytes,
+    pub mime: Option<String>,
+    pub request_meta: Option<Value>,
+    pub response_meta: Option<Value>,
+}
+
+impl RawSnapshot {
+    /// Performs a GET request, keeping the request and response metadata. Non-2xx
+    /// responses fail with [`SourceError::HttpStatus`], which still carries the response.
+    pub async fn fetch_http<U: IntoUrl>(http: &reqwest::Client, url: U) -> Result<RawSnapshot, SourceError> {
+        let request = http.get(url).build().context(HttpError)?;
+        let request_meta = request.to_metadata();
+
+        let response = http.execute(request).await.context(HttpError)?;
+        let fetched_at = Utc::now();
+
+        let status = response.status();
+        let url = response.url().to_string();
+
+        let retry_after = match status {
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => response.headers()
+                .get(RETRY_AFTER)
+                .and_then(|x| x.to_str().ok())
+                .and_then(|x| retry::parse_retry_after(x, fetched_at)),
+            _ => None,
+        };
+
+        let mime = response.headers()
+            .get(CONTENT_TYPE)
+            .and_then(|x| x.to_str().map(|s| s.to_string()).ok());
+
+        let response_meta = response.to_metadata();
+        let buffer = response.bytes().await.context(HttpError)?;
+
+        let raw = RawSnapshot {
+            fetched_at,
+            url: url.clone(),
+            buffer,
+            mime,
+            request_meta: to_json(request_meta),
+            response_meta: to_json(response_meta),
+        };
+
+        if !status.is_success() {
+            return Err(SourceError::HttpStatus {
+                url,
+                status: status.as_u16(),
+                retry_after,
+                response: Box::new(raw),
+            });
+        }
+
+        Ok(raw)
+    }
+}
+
+fn to_json<T: Serialize>(value: T) -> Option<Value> {
+    let json = serde_json::to_value(value);
+    if let Err(ref err) = json {
+        warn!("Failed to serialize snapshot metadata: {}", err);
+    }
+    json.ok()
+}
+
+#[async_trait]
+pub trait DataSource: Send + Sync {
+    /// Identifier of the source in configuration and logs, e.g. `coingecko`
+    fn name(&self) -> &str;
+
+    /// Agent recorded on every provenance and row of this source
+    fn agent_name(&self) -> &str;
+
+    /// Responses making up one snapshot. Rows are attached to the first.
+    async fn fetch(&self, http: &reqwest::Client) -> Result<Vec<RawSnapshot>, SourceError>;
+
+    /// Rows of the snapshot, given the first response and the others in order
+    fn normalize(&self, buffer: &[u8], children: &[Vec<u8>]) -> Result<Vec<CoinDominanceEntry<'static>>, SourceError>;
+
+    /// Whether the rows cover the whole market, so that their dominance adds up to 100%
+    fn complete(&self) -> bool {
+        true
+    }
+}
+
+async fn store(
+    source: &dyn DataSource,
+    raw: &RawSnapshot,
+    parent_uuid: Option<Uuid>,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<ProvenanceId, SourceError> {
+    provenance::insert_in_tx(
+        &raw.fetched_at,
+        source.agent_name(),
+        &raw.buffer,
+        raw.mime.clone(),
+        &raw.request_meta,
+        &raw.response_meta,
+        parent_uuid,
+        tx,
+    ).await.context(DbError)
+}
+
+pub struct IngestOutput {
+    pub imported_at: DateTime<Utc>,
+    pub provenance: ProvenanceId,
+    pub rows: usize,
+}
+
+/// A snapshot which was not archived since it is unchanged from the latest provenance
+pub struct UnchangedOutput {
+    pub observed_at: DateTime<Utc>,
+    pub provenance_uuid: Uuid,
+    pub reason: UnchangedReason,
+}
+
+pub enum IngestResult {
+    Loaded(IngestOutput),
+    Unchanged(UnchangedOutput),
+}
+
+/// Fetches one snapshot of the source and loads it. Responses are only archived once
+/// they normalize and pass validation, so that every provenance points at usable data.
+/// The provenances and their rows are committed together.
+/// Rejected responses are returned with the error instead, and invalid snapshots are
+/// quarantined. Snapshots unchanged according to `skip_unchanged` are only recorded in
+/// `snapshot_observation`. `observed` keeps the latest validated snapshot between calls,
+/// see [`validate::validate`].
+pub async fn ingest(
+    source: &dyn DataSource,
+    rules: &ValidationRules,
+    observed: &mut Option<PreviousSnapshot>,
+    skip_unchanged: SkipUnchanged,
+    http: &reqwest::Client,
+    pool: &PgPool,
+) -> Result<IngestResult, SourceError> {
+    let snapshots = source.fetch(http).await?;
+    if snapshots.is_empty() {
+        return Err(SourceError::EmptySnapshot);
+    }
+
+    let children: Vec<Vec<u8>> = snapshots[1..].iter()
+        .map(|x| x.buffer.to_vec())
+        .collect();
+
+    let entries = match source.normalize(&snapshots[0].buffer, &children) {
+        Ok(x) => x,
+        Err(e) => return Err(SourceError::MalformedResponse {
+            source: Box::new(e),
+            responses: snapshots,
+        }),
+    };
+
+    let raw = &snapshots[0];
+    let previous = validate::find_previous(source.agent_name(), pool).await.context(DbError)?;
+
+    if skip_unchanged != SkipUnchanged::Off && !entries.is_empty() {
+        let latest = snapshot_observation::find_latest_provenance(source.agent_name(), pool).await.context(DbError)?;
+        if let Some(latest) = latest {
+            let hashes: Vec<Vec<u8>> = snapshots.iter().map(|x| unchanged::sha256(&x.buffer)).collect();
+            let timestamp = entries.iter().map(|x| *x.timestamp).max().unwrap();
+            let reason = unchanged::check(
+                skip_unchanged,
+                &hashes,
+                &latest.hashes,
+                timestamp,
+                previous.as_ref().map(|x| x.timestamp));
+
+            if let Some(reason) = reason {
+                let observation = SnapshotObservation {
+                    timestamp: raw.fetched_at,
+                    agent: source.agent_name(),
+                    provenance_uuid: latest.uuid,
+                    reason: reason.as_str(),
+                    sha256: &hashes[0],
+                };
+                snapshot_observation::insert(&observation, pool).await.context(DbError)?;
+
+                return Ok(IngestResult::Unchanged(UnchangedOutput {
+                    observed_at: raw.fetched_at,
+                    provenance_uuid: latest.uuid,
+                    reason,
+                }));
+            }
+        }
+    }
+
+    let violations = validate::validate(&entries, source.complete(), previous.as_ref(), observed.as_ref(), rules);
+    *observed = PreviousSnapshot::from_entries(&entries);
+    if !violations.is_empty() {
+        let violations: Vec<String> = violations.iter().map(|x| x.to_string()).collect();
+        let quarantined = QuarantinedSnapshot {
+            timestamp: raw.fetched_at,
+            agent: source.agent_name(),
+            url: &raw.url,
+            data: &raw.buffer,
+            mime: raw.mime.as_deref(),
+            request_meta: raw.request_meta.as_ref(),
+            response_meta: raw.response_meta.as_ref(),
+            children,
+            violations: violations.clone(),
+        };
+
+        let id = quarantined_snapshot::insert(&quarantined, pool).await.context(DbError)?;
+        return Err(SourceError::Quarantined { id, violations });
+    }
+
+    let mut tx = pool.begin().await.context(DbError)?;
+    let pid = store(source, raw, None, &mut tx).await?;
+    for child in &snapshots[1..] {
+        store(source, child, Some(pid.uuid), &mut tx).await?;
+    }
+
+    for batch in entries.chunks(coin_dominance_entry::DEFAULT_BATCH_SIZE) {
+        coin_dominance_entry::insert_batch(
+            source.agent_name(),
+            &pid,
+            batch,
+            OnConflict::Ignore,
+            &mut tx,
+        ).await.context(DbError)?;
+    }
+    tx.commit().await.context(DbError)?;
+
+    Ok(IngestResult::Loaded(IngestOutput {
+        imported_at: raw.fetched_at,
+        provenance: pid,
+        rows: entries.len(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use chrono::{Utc, TimeZone};
+    use crate::source::{RawSnapshot, SourceError};
+
+    fn raw(url: &str, body: &'static [u8]) -> RawSnapshot {
+        RawSnapshot {
+            fetched_at: Utc.timestamp(100, 0),
+            url: url.to_owned(),
+            buffer: Bytes::from_static(body),
+            mime: Some("application/json".to_owned()),
+            request_meta: None,
+            response_meta: None,
+        }
+    }
+
+    #[test]
+    fn failed_fetch_should_be_recorded_per_rejected_response() {
+        let err = SourceError::MalformedResponse {
+            source: Box::new(SourceError::EmptySnapshot),
+            responses: vec![raw("https://example.com/global", b"{}"), raw("https://example.com/markets", b"[")],
+        };
+
+        let failures = err.failed_fetches("loader_rust", 2, Utc.timestamp(200, 0));
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures[0].url.as_deref(), Some("https://example.com/global"));
+        assert_eq!(failures[1].data, Some(&b"["[..]));
+        assert_eq!(failures[1].mime, Some("application/json"));
+        assert!(failures.iter().all(|x| x.attempt == 2 && x.http_status.is_none() && x.error == err.to_string()));
+    }
+
+    #[test]
+    fn failed_fetch_should_be_recorded_without_responses() {
+        let failures = SourceError::EmptySnapshot.failed_fetches("loader_rust", 0, Utc.timestamp(200, 0));
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].data.is_none());
+        assert!(failures[0].url.is_none());
+    }
+
+    #[test]
+    fn failed_fetch_should_not_be_recorded_for_db_errors_or_quarantined_snapshots() {
+        let db = SourceError::DbError { source: sqlx::Error::RowNotFound };
+        assert!(db.failed_fetches("loader_rust", 0, Utc::now()).is_empty());
+
+        let quarantined = SourceError::Quarantined { id: 1, violations: vec!["empty".to_owned()] };
+        assert!(quarantined.failed_fetches("loader_rust", 0, Utc::now()).is_empty());
+    }
+}
@@ -0,0 +1,213 @@
+//! How the loader retries a failed snapshot and slows down while a source is down
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rand::Rng;
+
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+pub const DEFAULT_BACKOFF_BASE: Duration = Duration::from_secs(1);
+pub const DEFAULT_BACKOFF_MAX: Duration = Duration::from_secs(60);
+pub const DEFAULT_RETRY_AFTER_MAX: Duration = Duration::from_secs(60 * 60);
+pub const DEFAULT_BREAKER_THRESHOLD: u32 = 5;
+pub const DEFAULT_BREAKER_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Exponential backoff with jitter. The delay before retry `n` is drawn from
+/// `[d / 2, d]` where `d = min(max, base * 2^n)`, so retries of several loaders
+/// do not line up.
+#[derive(Copy, Clone, Debug)]
+pub struct Backoff {
+    pub base: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    /// Upper bound of the delay before retry `attempt`, counted from 0
+    pub fn ceiling(&self, attempt: u32) -> Duration {
+        let factor = 2u32.checked_pow(attempt).unwrap_or(u32::MAX);
+        self.base.checked_mul(factor)
+            .unwrap_or(self.max)
+            .min(self.max)
+    }
+
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = self.ceiling(attempt);
+        let half = ceiling / 2;
+        half + rand::thread_rng().gen_range(Duration::from_secs(0), ceiling - half + Duration::from_nanos(1))
+    }
+}
+
+/// Opens after `threshold` consecutive failed snapshots. While open, the source is polled
+/// at most once per `interval` instead of at its usual rate. The first success closes it.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    interval: Duration,
+    consecutive_failures: u32,
+    open_until: Option<DateTime<Utc>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, interval: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            threshold: threshold.max(1),
+            interval,
+            consecutive_failures: 0,
+            open_until: None,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open_until.is_some()
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+    }
+
+    /// Time left until the next attempt is allowed
+    pub fn wait_time(&self, now: DateTime<Utc>) -> Duration {
+        self.open_until
+            .and_then(|x| (x - now).to_std().ok())
+            .unwrap_or_default()
+    }
+
+    pub fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.open_until = None;
+    }
+
+    /// Returns true if this failure opened the breaker
+    pub fn record_failure(&mut self, now: DateTime<Utc>) -> bool {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        if self.consecutive_failures < self.threshold {
+            return false;
+        }
+
+        let was_open = self.is_open();
+        self.open_until = chrono::Duration::from_std(self.interval).ok().map(|x| now + x);
+        !was_open
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct RetryPolicy {
+    /// Retries of a snapshot after the first attempt failed
+    pub max_retries: u32,
+    pub backoff: Backoff,
+    /// Longest `Retry-After` honoured, so that a bogus header cannot stall the source for good
+    pub retry_after_max: Duration,
+    pub breaker_threshold: u32,
+    pub breaker_interval: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: DEFAULT_MAX_RETRIES,
+            backoff: Backoff {
+                base: DEFAULT_BACKOFF_BASE,
+                max: DEFAULT_BACKOFF_MAX,
+            },
+            retry_after_max: DEFAULT_RETRY_AFTER_MAX,
+            breaker_threshold: DEFAULT_BREAKER_THRESHOLD,
+            breaker_interval: DEFAULT_BREAKER_INTERVAL,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn circuit_breaker(&self) -> CircuitBreaker {
+        CircuitBreaker::new(self.breaker_threshold, self.breaker_interval)
+    }
+
+    /// Delay the server asked for with `Retry-After`, capped at `retry_after_max`
+    pub fn retry_after(&self, retry_after: Option<Duration>) -> Duration {
+        retry_after.unwrap_or_default().min(self.retry_after_max)
+    }
+
+    /// Delay before retry `attempt`, waiting longer if the server asked to with `Retry-After`
+    pub fn retry_delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        self.backoff.delay(attempt).max(self.retry_after(retry_after))
+    }
+}
+
+/// Parses a `Retry-After` header, which is either a number of seconds or an HTTP date.
+/// Dates in the past yield a zero delay.
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some((date.with_timezone(&Utc) - now).to_std().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use chrono::{Utc, TimeZone};
+    use crate::source::retry::{Backoff, CircuitBreaker, RetryPolicy, parse_retry_after};
+
+    #[test]
+    fn backoff_should_double_up_to_max() {
+        let backoff = Backoff { base: Duration::from_secs(1), max: Duration::from_secs(10) };
+
+        assert_eq!(backoff.ceiling(0), Duration::from_secs(1));
+        assert_eq!(backoff.ceiling(3), Duration::from_secs(8));
+        assert_eq!(backoff.ceiling(4), Duration::from_secs(10));
+        assert_eq!(backoff.ceiling(100), Duration::from_secs(10));
+
+        for attempt in 0..8 {
+            let delay = backoff.delay(attempt);
+            assert!(delay >= backoff.ceiling(attempt) / 2);
+            assert!(delay <= backoff.ceiling(attempt));
+        }
+    }
+
+    #[test]
+    fn breaker_should_open_after_threshold_and_close_on_success() {
+        let now = Utc.timestamp(1000, 0);
+        let mut breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+
+        assert!(!breaker.record_failure(now));
+        assert!(!breaker.is_open());
+        assert!(breaker.record_failure(now));
+        assert_eq!(breaker.wait_time(Utc.timestamp(1020, 0)), Duration::from_secs(40));
+
+        // Failing again while open keeps it open without reporting it as newly opened
+        assert!(!breaker.record_failure(Utc.timestamp(1060, 0)));
+        assert_eq!(breaker.wait_time(Utc.timestamp(1060, 0)), Duration::from_secs(60));
+
+        breaker.record_success();
+        assert!(!breaker.is_open());
+        assert_eq!(breaker.consecutive_failures(), 0);
+        assert_eq!(breaker.wait_time(now), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn retry_delay_should_honour_retry_after_up_to_its_own_max() {
+        let policy = RetryPolicy {
+            backoff: Backoff { base: Duration::from_secs(1), max: Duration::from_secs(10) },
+            retry_after_max: Duration::from_secs(600),
+            ..RetryPolicy::default()
+        };
+
+        assert_eq!(policy.retry_delay(0, Some(Duration::from_secs(5))), Duration::from_secs(5));
+        assert_eq!(policy.retry_delay(0, Some(Duration::from_secs(120))), Duration::from_secs(120));
+        assert_eq!(policy.retry_delay(0, Some(Duration::from_secs(u64::MAX))), Duration::from_secs(600));
+        assert!(policy.retry_delay(0, None) <= Duration::from_secs(1));
+        assert_eq!(policy.retry_after(None), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn retry_after_should_parse_seconds_and_dates() {
+        let now = Utc.ymd(2021, 1, 7).and_hms(12, 0, 0);
+
+        assert_eq!(parse_retry_after(" 120 ", now), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after("Thu, 07 Jan 2021 12:00:30 GMT", now), Some(Duration::from_secs(30)));
+        assert_eq!(parse_retry_after("Thu, 07 Jan 2021 11:00:00 GMT", now), Some(Duration::from_secs(0)));
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+}
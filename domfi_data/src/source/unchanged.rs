@@ -0,0 +1,122 @@
+//! Detecting snapshots unchanged from the previous one, e.g. when CoinGecko serves the same
+//! cached body to several polls. These are only recorded as `snapshot_observation`.
+
+use std::fmt::{Display, Formatter};
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use sha2::{Sha256, Digest};
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SkipUnchanged {
+    /// Archive every snapshot
+    Off,
+    /// Skip snapshots whose responses hash the same as those of the latest provenance
+    Hash,
+    /// Also skip snapshots with the same source timestamp as the latest rows, even if the
+    /// body differs. Only suits sources whose timestamp changes with every update.
+    Timestamp,
+}
+
+/// Every snapshot is archived unless configured otherwise
+pub const DEFAULT_SKIP_UNCHANGED: SkipUnchanged = SkipUnchanged::Off;
+
+impl FromStr for SkipUnchanged {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" | "false" | "no" => Ok(SkipUnchanged::Off),
+            "hash" => Ok(SkipUnchanged::Hash),
+            "timestamp" => Ok(SkipUnchanged::Timestamp),
+            x => Err(format!("expected 'off', 'hash' or 'timestamp' but got '{}'", x)),
+        }
+    }
+}
+
+/// Why a snapshot is considered unchanged, as recorded in `snapshot_observation.reason`
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum UnchangedReason {
+    Hash,
+    Timestamp,
+}
+
+impl UnchangedReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UnchangedReason::Hash => "hash",
+            UnchangedReason::Timestamp => "timestamp",
+        }
+    }
+}
+
+impl Display for UnchangedReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            UnchangedReason::Hash => write!(f, "same responses as the latest provenance"),
+            UnchangedReason::Timestamp => write!(f, "same source timestamp as the latest rows"),
+        }
+    }
+}
+
+pub fn sha256(buffer: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(buffer);
+    hasher.finalize().to_vec()
+}
+
+/// Compares the hashes of a snapshot's responses, in the order they were fetched, and its
+/// timestamp against those of the latest provenance and rows of the agent
+pub fn check(
+    mode: SkipUnchanged,
+    hashes: &[Vec<u8>],
+    previous_hashes: &[Vec<u8>],
+    timestamp: DateTime<Utc>,
+    previous_timestamp: Option<DateTime<Utc>>,
+) -> Option<UnchangedReason> {
+    if mode == SkipUnchanged::Off || previous_hashes.is_empty() {
+        return None;
+    }
+
+    if hashes == previous_hashes {
+        return Some(UnchangedReason::Hash);
+    }
+
+    if mode == SkipUnchanged::Timestamp && previous_timestamp == Some(timestamp) {
+        return Some(UnchangedReason::Timestamp);
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Utc, TimeZone};
+    use crate::source::unchanged::{check, sha256, SkipUnchanged, UnchangedReason};
+
+    #[test]
+    fn should_only_skip_in_the_configured_mode() {
+        let a = vec![sha256(b"a")];
+        let b = vec![sha256(b"b")];
+        let ts = Utc.timestamp(100, 0);
+
+        assert_eq!(check(SkipUnchanged::Off, &a, &a, ts, Some(ts)), None);
+        assert_eq!(check(SkipUnchanged::Hash, &a, &a, ts, Some(ts)), Some(UnchangedReason::Hash));
+        assert_eq!(check(SkipUnchanged::Hash, &a, &b, ts, Some(ts)), None);
+        assert_eq!(check(SkipUnchanged::Timestamp, &a, &b, ts, Some(ts)), Some(UnchangedReason::Timestamp));
+        assert_eq!(check(SkipUnchanged::Timestamp, &a, &b, ts, Some(Utc.timestamp(90, 0))), None);
+
+        // Nothing archived yet
+        assert_eq!(check(SkipUnchanged::Timestamp, &a, &[], ts, None), None);
+    }
+
+    #[test]
+    fn every_response_should_be_compared() {
+        let previous = vec![sha256(b"global"), sha256(b"page 1")];
+        let current = vec![sha256(b"global"), sha256(b"page 1 moved")];
+
+        assert_eq!(check(SkipUnchanged::Hash, &current, &previous, Utc.timestamp(100, 0), None), None);
+        assert_eq!(check(SkipUnchanged::Hash, &current[..1], &previous, Utc.timestamp(100, 0), None), None);
+    }
+}
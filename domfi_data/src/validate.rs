@@ -0,0 +1,340 @@
+//! Plausibility checks of a normalized snapshot before it is committed. Snapshots that
+//! fail them are quarantined instead of being loaded into `coin_dominance`.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::fmt;
+use std::str::FromStr;
+
+use bigdecimal::BigDecimal;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+
+use crate::pg::models::CoinDominanceEntry;
+
+/// Percentage points the dominance of a complete snapshot may be off from 100%
+pub const DEFAULT_SUM_TOLERANCE: &str = "1";
+
+/// Percentage points the dominance of a coin may move between two snapshots
+pub const DEFAULT_MAX_JUMP: &str = "5";
+
+/// Snapshots older than this are not compared against
+pub const DEFAULT_MAX_JUMP_WINDOW_SECONDS: i64 = 60 * 60;
+
+pub const DEFAULT_REQUIRED_COINS: &[&str] = &["bitcoin"];
+
+#[derive(Clone, Debug)]
+pub struct ValidationRules {
+    /// Checks the dominance adds up to 100%, give or take the tolerance in percentage points.
+    /// Snapshots of sources that only cover part of the market are only checked not to exceed it.
+    pub sum_tolerance: Option<BigDecimal>,
+    /// Largest change of a coin's dominance in percentage points since the previous snapshot
+    pub max_jump: Option<BigDecimal>,
+    pub max_jump_window: Duration,
+    pub required_coins: Vec<String>,
+    /// Rejects snapshots older than the previous one
+    pub monotonic: bool,
+}
+
+impl Default for ValidationRules {
+    fn default() -> Self {
+        ValidationRules {
+            sum_tolerance: Some(BigDecimal::from_str(DEFAULT_SUM_TOLERANCE).unwrap()),
+            max_jump: Some(BigDecimal::from_str(DEFAULT_MAX_JUMP).unwrap()),
+            max_jump_window: Duration::seconds(DEFAULT_MAX_JUMP_WINDOW_SECONDS),
+            required_coins: DEFAULT_REQUIRED_COINS.iter().map(|x| (*x).to_owned()).collect(),
+            monotonic: true,
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Debug)]
+pub enum Violation {
+    Empty,
+
+    OutOfRange {
+        coin_id: String,
+        field: &'static str,
+        value: BigDecimal,
+    },
+
+    SumOutOfTolerance {
+        sum: BigDecimal,
+    },
+
+    MissingCoin {
+        coin_id: String,
+    },
+
+    Jump {
+        coin_id: String,
+        previous: BigDecimal,
+        current: BigDecimal,
+    },
+
+    NotMonotonic {
+        previous: DateTime<Utc>,
+        current: DateTime<Utc>,
+    },
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Empty =>
+                write!(f, "snapshot has no rows"),
+            Violation::OutOfRange { coin_id, field, value } =>
+                write!(f, "coin '{}': {} {} is out of range", coin_id, field, value),
+            Violation::SumOutOfTolerance { sum } =>
+                write!(f, "dominance adds up to {}%", sum),
+            Violation::MissingCoin { coin_id } =>
+                write!(f, "coin '{}' is missing", coin_id),
+            Violation::Jump { coin_id, previous, current } =>
+                write!(f, "coin '{}': dominance jumped from {} to {}", coin_id, previous, current),
+            Violation::NotMonotonic { previous, current } =>
+                write!(f, "timestamp {} is before the previous snapshot at {}", current.timestamp(), previous.timestamp()),
+        }
+    }
+}
+
+/// Earlier snapshot of an agent a new one is compared against
+#[derive(Debug)]
+pub struct PreviousSnapshot {
+    pub timestamp: DateTime<Utc>,
+    pub dominance: HashMap<String, BigDecimal>,
+}
+
+impl PreviousSnapshot {
+    /// Returns `None` if there are no entries
+    pub fn from_entries(entries: &[CoinDominanceEntry]) -> Option<PreviousSnapshot> {
+        let timestamp = entries.iter().map(|x| *x.timestamp).max()?;
+        Some(PreviousSnapshot {
+            timestamp,
+            dominance: entries.iter()
+                .map(|x| (x.id.to_string(), x.dominance_percentage.clone().into_owned()))
+                .collect(),
+        })
+    }
+}
+
+/// Checks the entries of a snapshot against the rules. `complete` tells whether the
+/// source covers the whole market, i.e. whether the dominance should add up to 100%.
+///
+/// `previous` is the latest committed snapshot and `observed` the latest one validated,
+/// quarantined or not. A coin only jumped if it moved too far from both, so that a spike
+/// does not get the snapshot after it quarantined, and a genuine move is accepted once
+/// it is seen twice instead of quarantining every snapshot until the window passes.
+pub fn validate(
+    entries: &[CoinDominanceEntry],
+    complete: bool,
+    previous: Option<&PreviousSnapshot>,
+    observed: Option<&PreviousSnapshot>,
+    rules: &ValidationRules,
+) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    if entries.is_empty() {
+        violations.push(Violation::Empty);
+        return violations;
+    }
+
+    let zero = BigDecimal::from(0);
+    let hundred = BigDecimal::from(100);
+    for x in entries {
+        if *x.market_cap_usd < zero {
+            violations.push(Violation::OutOfRange {
+                coin_id: x.id.to_string(),
+                field: "market_cap_usd",
+                value: x.market_cap_usd.clone().into_owned(),
+            });
+        }
+        if *x.dominance_percentage < zero || *x.dominance_percentage > hundred {
+            violations.push(Violation::OutOfRange {
+                coin_id: x.id.to_string(),
+                field: "dominance_percentage",
+                value: x.dominance_percentage.clone().into_owned(),
+            });
+        }
+    }
+
+    if let Some(tolerance) = &rules.sum_tolerance {
+        let sum = entries.iter()
+            .fold(BigDecimal::from(0), |acc, x| acc + x.dominance_percentage.as_ref());
+        let too_high = sum > &hundred + tolerance;
+        let too_low = complete && sum < &hundred - tolerance;
+        if too_high || too_low {
+            violations.push(Violation::SumOutOfTolerance { sum });
+        }
+    }
+
+    let coins: HashSet<&str> = entries.iter().map(|x| x.id.as_ref()).collect();
+    for coin_id in &rules.required_coins {
+        if !coins.contains(coin_id.as_str()) {
+            violations.push(Violation::MissingCoin { coin_id: coin_id.clone() });
+        }
+    }
+
+    let previous = match previous {
+        None => return violations,
+        Some(x) => x,
+    };
+
+    let timestamp = entries.iter().map(|x| *x.timestamp).max().unwrap();
+    if rules.monotonic && timestamp < previous.timestamp {
+        violations.push(Violation::NotMonotonic {
+            previous: previous.timestamp,
+            current: timestamp,
+        });
+    }
+
+    if let Some(max_jump) = &rules.max_jump {
+        let baselines: Vec<&PreviousSnapshot> = std::iter::once(previous)
+            .chain(observed)
+            .filter(|x| timestamp - x.timestamp <= rules.max_jump_window)
+            .collect();
+
+        for x in entries {
+            // The "others" bucket moves whenever a coin enters or leaves the top list
+            if x.id.is_empty() {
+                continue;
+            }
+            let olds: Vec<&BigDecimal> = baselines.iter()
+                .filter_map(|b| b.dominance.get(x.id.as_ref()))
+                .collect();
+            let jumped = !olds.is_empty()
+                && olds.iter().all(|old| (x.dominance_percentage.as_ref() - *old).abs() > *max_jump);
+            if jumped {
+                violations.push(Violation::Jump {
+                    coin_id: x.id.to_string(),
+                    previous: olds[0].clone(),
+                    current: x.dominance_percentage.clone().into_owned(),
+                });
+            }
+        }
+    }
+
+    violations
+}
+
+/// Latest snapshot of the agent in `coin_dominance`
+pub async fn find_previous(agent: &str, pool: &PgPool) -> Result<Option<PreviousSnapshot>, sqlx::Error> {
+    let rows = sqlx::query!(r#"
+        select
+            timestamp_utc,
+            coin_id,
+            market_dominance_percentage
+        from
+            coin_dominance
+        where
+            agent = $1
+            and timestamp_utc = (
+                select max(timestamp_utc) from coin_dominance where agent = $1
+            )
+        "#,
+        agent)
+        .fetch_all(pool)
+        .await?;
+
+    let timestamp = match rows.first() {
+        None => return Ok(None),
+        Some(x) => DateTime::from_utc(x.timestamp_utc, Utc),
+    };
+
+    Ok(Some(PreviousSnapshot {
+        timestamp,
+        dominance: rows.into_iter()
+            .map(|r| (r.coin_id, r.market_dominance_percentage))
+            .collect(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use bigdecimal::BigDecimal;
+    use chrono::{Utc, TimeZone};
+    use crate::pg::models::CoinDominanceEntry;
+    use crate::validate::{validate, PreviousSnapshot, ValidationRules, Violation};
+
+    fn decimal(x: &str) -> BigDecimal {
+        BigDecimal::from_str(x).unwrap()
+    }
+
+    fn entry(id: &'static str, cap: &str, dominance: &str, ts: i64) -> CoinDominanceEntry<'static> {
+        CoinDominanceEntry {
+            name: Cow::Borrowed(id),
+            id: Cow::Borrowed(id),
+            market_cap_usd: Cow::Owned(decimal(cap)),
+            dominance_percentage: Cow::Owned(decimal(dominance)),
+            timestamp: Cow::Owned(Utc.timestamp(ts, 0)),
+        }
+    }
+
+    fn previous(ts: i64, bitcoin: &str) -> PreviousSnapshot {
+        let mut dominance = HashMap::new();
+        dominance.insert("bitcoin".to_owned(), decimal(bitcoin));
+        dominance.insert("".to_owned(), decimal("20"));
+        PreviousSnapshot { timestamp: Utc.timestamp(ts, 0), dominance }
+    }
+
+    #[test]
+    fn valid_snapshot_should_pass() {
+        let entries = vec![entry("bitcoin", "600", "60.4", 100), entry("", "400", "39.1", 100)];
+
+        assert_eq!(validate(&entries, true, Some(&previous(90, "60")), None, &ValidationRules::default()), vec![]);
+    }
+
+    #[test]
+    fn sum_should_only_be_bounded_above_for_partial_sources() {
+        let rules = ValidationRules::default();
+        let partial = vec![entry("bitcoin", "600", "60", 100)];
+        let excess = vec![entry("bitcoin", "600", "60", 100), entry("ethereum", "600", "42", 100)];
+
+        assert_eq!(validate(&partial, false, None, None, &rules), vec![]);
+        assert_eq!(validate(&partial, true, None, None, &rules), vec![Violation::SumOutOfTolerance { sum: decimal("60") }]);
+        assert_eq!(validate(&excess, false, None, None, &rules), vec![Violation::SumOutOfTolerance { sum: decimal("102") }]);
+    }
+
+    #[test]
+    fn invalid_snapshot_should_report_every_violation() {
+        let entries = vec![entry("ethereum", "-1", "101", 80), entry("", "0", "0", 80)];
+        let violations = validate(&entries, false, Some(&previous(90, "60")), None, &ValidationRules::default());
+
+        assert_eq!(violations, vec![
+            Violation::OutOfRange { coin_id: "ethereum".into(), field: "market_cap_usd", value: decimal("-1") },
+            Violation::OutOfRange { coin_id: "ethereum".into(), field: "dominance_percentage", value: decimal("101") },
+            Violation::MissingCoin { coin_id: "bitcoin".into() },
+            Violation::NotMonotonic { previous: Utc.timestamp(90, 0), current: Utc.timestamp(80, 0) },
+        ]);
+    }
+
+    #[test]
+    fn jump_should_only_be_checked_within_window() {
+        let rules = ValidationRules::default();
+        let entries = vec![entry("bitcoin", "600", "66", 4000), entry("", "400", "34", 4000)];
+
+        assert_eq!(validate(&entries, true, Some(&previous(3000, "60")), None, &rules), vec![
+            Violation::Jump { coin_id: "bitcoin".into(), previous: decimal("60"), current: decimal("66") },
+        ]);
+        assert_eq!(validate(&entries, true, Some(&previous(0, "60")), None, &rules), vec![]);
+        assert_eq!(validate(&[], true, None, None, &rules), vec![Violation::Empty]);
+    }
+
+    #[test]
+    fn sustained_jump_should_be_accepted_once_seen_twice() {
+        let rules = ValidationRules::default();
+        let committed = previous(1000, "60");
+        let first = vec![entry("bitcoin", "600", "70", 1060), entry("", "300", "30", 1060)];
+        let second = vec![entry("bitcoin", "600", "70.5", 1120), entry("", "300", "29.5", 1120)];
+        let back = vec![entry("bitcoin", "600", "60.5", 1120), entry("", "400", "39.5", 1120)];
+
+        assert_eq!(validate(&first, true, Some(&committed), None, &rules), vec![
+            Violation::Jump { coin_id: "bitcoin".into(), previous: decimal("60"), current: decimal("70") },
+        ]);
+
+        let observed = PreviousSnapshot::from_entries(&first).unwrap();
+        assert_eq!(validate(&second, true, Some(&committed), Some(&observed), &rules), vec![]);
+        assert_eq!(validate(&back, true, Some(&committed), Some(&observed), &rules), vec![]);
+    }
+}
@@ -0,0 +1,367 @@
+//! Integrity checks of the archived blobs against their stored hash and the
+//! `coin_dominance` rows that were loaded from them.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fmt;
+
+use bigdecimal::BigDecimal;
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use sha2::{Sha256, Digest};
+use snafu::{Snafu, ResultExt};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::coingecko::SourceSchema;
+use crate::pg::models::CoinDominanceEntry;
+use crate::pg::ops::provenance;
+
+#[derive(Snafu, Debug)]
+pub enum VerifyError {
+    #[snafu(display("Failed to access database: {}", source))]
+    DbError {
+        source: sqlx::Error,
+    },
+}
+
+#[derive(Eq, PartialEq, Debug)]
+pub enum Finding {
+    HashMismatch {
+        object_id: i64,
+        stored: Vec<u8>,
+        computed: Vec<u8>,
+    },
+
+    Unparsable {
+        object_id: i64,
+        reason: String,
+    },
+
+    RowMismatch {
+        object_id: i64,
+        provenance_uuid: Uuid,
+        coin_id: String,
+        field: &'static str,
+        stored: String,
+        blob: String,
+    },
+
+    RowNotInBlob {
+        object_id: i64,
+        provenance_uuid: Uuid,
+        coin_id: String,
+        timestamp: DateTime<Utc>,
+    },
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Finding::HashMismatch { object_id, stored, computed } =>
+                write!(f, "obj #{}: sha256 mismatch: stored {}, computed {}",
+                    object_id, hex::encode(stored), hex::encode(computed)),
+
+            Finding::Unparsable { object_id, reason } =>
+                write!(f, "obj #{}: unparsable blob: {}", object_id, reason),
+
+            Finding::RowMismatch { object_id, provenance_uuid, coin_id, field, stored, blob } =>
+                write!(f, "obj #{}: [{}] coin '{}': {} is {} but blob has {}",
+                    object_id, provenance_uuid, coin_id, field, stored, blob),
+
+            Finding::RowNotInBlob { object_id, provenance_uuid, coin_id, timestamp } =>
+                write!(f, "obj #{}: [{}] coin '{}' at {} is stored but missing from blob",
+                    object_id, provenance_uuid, coin_id, timestamp.timestamp()),
+        }
+    }
+}
+
+/// A `coin_dominance` row as loaded from a blob
+#[derive(Debug)]
+pub struct StoredRow {
+    pub provenance_uuid: Uuid,
+    pub timestamp_utc: DateTime<Utc>,
+    pub coin_id: String,
+    pub coin_name: String,
+    pub market_cap_usd: BigDecimal,
+    pub dominance_percentage: BigDecimal,
+}
+
+#[derive(Default, Debug)]
+pub struct VerifySummary {
+    pub blobs: usize,
+    pub rows: usize,
+    pub findings: usize,
+}
+
+/// Recomputes the hash of the blob and re-parses it with the schema of its source.
+/// The derived entries are returned whenever parsing succeeds, even if the hash
+/// does not match.
+pub fn check_blob(object_id: i64, sha256: &[u8], data: &[u8], url: Option<&str>, children: &[Vec<u8>])
+    -> (Vec<Finding>, Option<Vec<CoinDominanceEntry<'static>>>)
+{
+    let mut findings = Vec::new();
+
+    let computed = Sha256::digest(data);
+    if computed.as_slice() != sha256 {
+        findings.push(Finding::HashMismatch {
+            object_id,
+            stored: sha256.to_vec(),
+            computed: computed.to_vec(),
+        });
+    }
+
+    let entries = match SourceSchema::detect_and_parse(url, data, children) {
+        Ok((_, x)) => Some(x),
+        Err(reason) => {
+            findings.push(Finding::Unparsable { object_id, reason });
+            None
+        }
+    };
+
+    (findings, entries)
+}
+
+/// Compares the stored rows of a blob with the entries derived from the blob. Rows are
+/// matched by coin and timestamp, and entries without a row are not a finding, as loaders
+/// may only load part of a blob.
+pub fn compare_rows(object_id: i64, entries: &[CoinDominanceEntry], rows: &[StoredRow]) -> Vec<Finding> {
+    let coins: HashMap<(&str, DateTime<Utc>), _> = entries.iter()
+        .map(|x| ((x.id.as_ref(), *x.timestamp), x))
+        .collect();
+
+    let mut findings = Vec::new();
+    for row in rows {
+        let coin = match coins.get(&(row.coin_id.as_str(), row.timestamp_utc)) {
+            None => {
+                findings.push(Finding::RowNotInBlob {
+                    object_id,
+                    provenance_uuid: row.provenance_uuid,
+                    coin_id: row.coin_id.clone(),
+                    timestamp: row.timestamp_utc,
+                });
+                continue;
+            },
+            Some(x) => x,
+        };
+
+        let mut mismatch = |field: &'static str, stored: String, blob: String| {
+            findings.push(Finding::RowMismatch {
+                object_id,
+                provenance_uuid: row.provenance_uuid,
+                coin_id: row.coin_id.clone(),
+                field,
+                stored,
+                blob,
+            });
+        };
+
+        if row.coin_name != coin.name {
+            mismatch("coin_name", row.coin_name.clone(), coin.name.to_string());
+        }
+        if row.market_cap_usd != *coin.market_cap_usd {
+            mismatch("market_cap_usd", row.market_cap_usd.to_string(), coin.market_cap_usd.to_string());
+        }
+        if row.dominance_percentage != *coin.dominance_percentage {
+            mismatch("dominance_percentage", row.dominance_percentage.to_string(), coin.dominance_percentage.to_string());
+        }
+    }
+
+    findings
+}
+
+/// Stored `coin_dominance` rows loaded from the blob, ordered by coin
+pub(crate) async fn find_rows(object_id: i64, pool: &PgPool) -> Result<Vec<StoredRow>, VerifyError> {
+    let rows = sqlx::query!(r#"
+        select
+            provenance_uuid,
+            timestamp_utc,
+            coin_id,
+            coin_name,
+            market_cap_usd,
+            market_dominance_percentage
+        from
+            coin_dominance
+        where
+            object_id = $1
+        order by
+            coin_id
+        "#,
+        object_id)
+        .fetch_all(pool)
+        .await
+        .context(DbError)?;
+
+    Ok(rows.into_iter()
+        .map(|r| StoredRow {
+            provenance_uuid: r.provenance_uuid,
+            timestamp_utc: DateTime::from_utc(r.timestamp_utc, Utc),
+            coin_id: r.coin_id,
+            coin_name: r.coin_name,
+            market_cap_usd: r.market_cap_usd,
+            dominance_percentage: r.market_dominance_percentage,
+        })
+        .collect())
+}
+
+struct BlobRow {
+    id: i64,
+    sha256: Vec<u8>,
+    data: Vec<u8>,
+    provenance_uuid: Option<Uuid>,
+    url: Option<String>,
+}
+
+/// Streams every blob of `object_storage` in id order, reporting each finding
+/// through `on_finding` as soon as it is found. Blobs are parsed according to their
+/// first provenance, which is also the one their rows were loaded from.
+pub async fn verify_all<F>(pool: &PgPool, mut on_finding: F) -> Result<VerifySummary, VerifyError>
+    where F: FnMut(&Finding)
+{
+    let mut summary = VerifySummary::default();
+    let mut blobs = sqlx::query_as!(BlobRow, r#"
+        select
+            obj.id,
+            obj.sha256,
+            obj.data,
+            p.uuid as "provenance_uuid?",
+            p.request_metadata->>'url' as url
+        from
+            object_storage as obj
+            left join lateral (
+                select uuid, request_metadata
+                from provenance
+                where object_id = obj.id
+                order by timestamp_utc, uuid
+                limit 1
+            ) as p on true
+        order by
+            obj.id
+        "#)
+        .fetch(pool);
+
+    while let Some(blob) = blobs.try_next().await.context(DbError)? {
+        summary.blobs += 1;
+
+        let children = match &blob.provenance_uuid {
+            None => Vec::new(),
+            Some(x) => provenance::find_children_data(x, pool).await.context(DbError)?,
+        };
+
+        let (mut findings, entries) = check_blob(blob.id, &blob.sha256, &blob.data, blob.url.as_deref(), &children);
+        if let Some(entries) = entries {
+            let rows = find_rows(blob.id, pool).await?;
+            summary.rows += rows.len();
+            findings.extend(compare_rows(blob.id, &entries, &rows));
+        }
+
+        summary.findings += findings.len();
+        findings.iter().for_each(&mut on_finding);
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use bigdecimal::BigDecimal;
+    use chrono::{Utc, TimeZone};
+    use sha2::{Sha256, Digest};
+    use uuid::Uuid;
+    use crate::verify::{check_blob, compare_rows, Finding, StoredRow};
+
+    const BLOB: &[u8] = br#"{"data":[
+        {"name":"BTC","id":"bitcoin","market_cap_usd":432393586286.0436,"dominance_percentage":63.05285004474413},
+        {"name":"ETH","id":"ethereum","market_cap_usd":72654329412.53447,"dominance_percentage":10.594688493386614}
+        ],"timestamp":1608195600}"#;
+
+    fn row(coin_id: &str, coin_name: &str, dominance: &str) -> StoredRow {
+        StoredRow {
+            provenance_uuid: Uuid::nil(),
+            timestamp_utc: Utc.timestamp(1608195600, 0),
+            coin_id: coin_id.into(),
+            coin_name: coin_name.into(),
+            market_cap_usd: BigDecimal::from_str(match coin_id {
+                "bitcoin" => "432393586286.0436",
+                _ => "72654329412.53447",
+            }).unwrap(),
+            dominance_percentage: BigDecimal::from_str(dominance).unwrap(),
+        }
+    }
+
+    #[test]
+    fn blob_should_pass_with_matching_hash() {
+        let hash = Sha256::digest(BLOB);
+        let (findings, entries) = check_blob(1, &hash, BLOB, None, &[]);
+
+        assert!(findings.is_empty());
+        assert_eq!(entries.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn blob_should_report_hash_mismatch_and_unparsable() {
+        let (findings, entries) = check_blob(1, &[0u8; 32], b"<html>", None, &[]);
+
+        assert!(entries.is_none());
+        assert!(matches!(findings[0], Finding::HashMismatch { .. }));
+        assert!(matches!(findings[1], Finding::Unparsable { .. }));
+    }
+
+    #[test]
+    fn rows_should_report_disagreeing_values_exactly() {
+        let (_, entries) = check_blob(1, &[], BLOB, None, &[]);
+        let entries = entries.unwrap();
+
+        let rows = vec![
+            row("bitcoin", "BTC", "63.05285004474413"),
+            row("ethereum", "ETH", "10.59468849338661"),
+            row("litecoin", "LTC", "1"),
+        ];
+
+        let findings = compare_rows(1, &entries, &rows);
+        assert_eq!(findings, vec![
+            Finding::RowMismatch {
+                object_id: 1,
+                provenance_uuid: Uuid::nil(),
+                coin_id: "ethereum".into(),
+                field: "dominance_percentage",
+                stored: "10.59468849338661".into(),
+                blob: "10.594688493386614".into(),
+            },
+            Finding::RowNotInBlob {
+                object_id: 1,
+                provenance_uuid: Uuid::nil(),
+                coin_id: "litecoin".into(),
+                timestamp: Utc.timestamp(1608195600, 0),
+            },
+        ]);
+    }
+
+    #[test]
+    fn rows_should_be_matched_by_coin_and_timestamp() {
+        let blob = br#"{"series_data_array":[
+            {"name":"BTC","data":[[1608195600000,63.1],[1608195700000,63.2]]}
+            ]}"#;
+        let (_, entries) = check_blob(1, &[], blob, None, &[]);
+        let entries = entries.unwrap();
+
+        let mut second = row("bitcoin", "BTC", "63.2");
+        second.timestamp_utc = Utc.timestamp(1608195700, 0);
+        second.market_cap_usd = BigDecimal::from(0);
+
+        let mut unknown = row("bitcoin", "BTC", "63.2");
+        unknown.timestamp_utc = Utc.timestamp(1608195800, 0);
+        unknown.market_cap_usd = BigDecimal::from(0);
+
+        let findings = compare_rows(1, &entries, &[second, unknown]);
+        assert_eq!(findings, vec![
+            Finding::RowNotInBlob {
+                object_id: 1,
+                provenance_uuid: Uuid::nil(),
+                coin_id: "bitcoin".into(),
+                timestamp: Utc.timestamp(1608195800, 0),
+            },
+        ]);
+    }
+}
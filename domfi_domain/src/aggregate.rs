@@ -0,0 +1,441 @@
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use bigdecimal::BigDecimal;
+use chrono::{DateTime, Duration, Utc};
+use snafu::Snafu;
+use serde::{Serialize, Serializer, Deserialize, Deserializer, de};
+
+/// Decimal places kept from divisions that do not terminate. `BigDecimal::round` only handles
+/// values that fit into an `i128`, so results must stay well below 38 significant digits.
+pub const AVERAGE_SCALE: i64 = 18;
+
+/// Median of the values. For an even number of values, the mean of the two
+/// middle values is returned so that no input is favoured.
+pub fn median(values: &[BigDecimal]) -> Option<BigDecimal> {
+    if values.is_empty() {
+        return None;
+    }
+
+    let mut sorted: Vec<&BigDecimal> = values.iter().collect();
+    sorted.sort();
+
+    let mid = sorted.len() / 2;
+    if sorted.len() % 2 == 1 {
+        Some(sorted[mid].clone())
+    } else {
+        Some((sorted[mid - 1] + sorted[mid]) / BigDecimal::from(2))
+    }
+}
+
+/// How a sample is weighted when the next sample arrives later than the maximum gap
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum StaleGapMode {
+    /// Weight the sample until the next one, however long the gap
+    Carry,
+    /// Weight the sample for at most the maximum gap; the rest of the gap is not counted
+    Cap,
+    /// Do not weight the sample at all
+    Exclude,
+}
+
+impl Display for StaleGapMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            StaleGapMode::Carry => f.write_str("carry"),
+            StaleGapMode::Cap => f.write_str("cap"),
+            StaleGapMode::Exclude => f.write_str("exclude"),
+        }
+    }
+}
+
+#[derive(Snafu, Debug)]
+pub enum StaleGapModeParseError {
+    #[snafu(display("Invalid stale gap mode specified: '{}'. Expected one of 'carry', 'cap', 'exclude'", input))]
+    InvalidFormat {
+        input: String,
+    }
+}
+
+impl FromStr for StaleGapMode {
+    type Err = StaleGapModeParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "carry" => Ok(StaleGapMode::Carry),
+            "cap" => Ok(StaleGapMode::Cap),
+            "exclude" => Ok(StaleGapMode::Exclude),
+            _ => InvalidFormat { input: s.to_owned() }.fail()
+        }
+    }
+}
+
+impl Serialize for StaleGapMode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for StaleGapMode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de>
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Twap {
+    pub value: BigDecimal,
+    /// Total duration the value was weighted over. Less than the range if the
+    /// series starts late or stale gaps were capped or excluded.
+    pub covered: Duration,
+    /// Number of samples that contributed to the value
+    pub samples: usize,
+}
+
+/// Time-weighted average over a series of timestamped values. Each sample is in
+/// effect from its own timestamp until the next sample.
+#[derive(Copy, Clone, Debug)]
+pub struct TwapCalculator {
+    stale_gap_mode: StaleGapMode,
+    max_gap: Duration,
+}
+
+impl TwapCalculator {
+    pub fn new(stale_gap_mode: StaleGapMode, max_gap: Duration) -> Self {
+        TwapCalculator { stale_gap_mode, max_gap }
+    }
+
+    /// Weights every sample by the time it was in effect without checking for gaps
+    pub fn carry() -> Self {
+        TwapCalculator::new(StaleGapMode::Carry, Duration::zero())
+    }
+
+    pub fn stale_gap_mode(&self) -> StaleGapMode { self.stale_gap_mode }
+    pub fn max_gap(&self) -> Duration { self.max_gap }
+
+    /// Computes the average over `[from, to)`. Samples must be sorted by timestamp; a sample
+    /// before `from` is in effect at the start of the range. The last sample is in effect
+    /// until `to`. Returns `None` if no sample was weighted.
+    ///
+    /// The result is truncated to [`AVERAGE_SCALE`] decimal places.
+    pub fn calculate(
+        &self,
+        samples: &[(DateTime<Utc>, BigDecimal)],
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Option<Twap> {
+        let mut total = BigDecimal::from(0);
+        let mut covered = 0i64;
+        let mut count = 0usize;
+
+        for (i, (ts, value)) in samples.iter().enumerate() {
+            let next = samples.get(i + 1).map(|(next, _)| *next).unwrap_or(to);
+            let mut until = next;
+
+            if next - *ts > self.max_gap {
+                match self.stale_gap_mode {
+                    StaleGapMode::Carry => (),
+                    StaleGapMode::Cap => until = *ts + self.max_gap,
+                    StaleGapMode::Exclude => continue,
+                }
+            }
+
+            let start = (*ts).max(from);
+            let end = until.min(to);
+            if end <= start {
+                continue;
+            }
+
+            // The same whole microseconds make up the numerator and the divisor
+            let weight = match (end - start).num_microseconds() {
+                Some(x) if x > 0 => x,
+                _ => continue,
+            };
+            total += value * BigDecimal::from(weight);
+            covered = covered.saturating_add(weight);
+            count += 1;
+        }
+
+        if count == 0 {
+            return None;
+        }
+
+        let value = (total / BigDecimal::from(covered))
+            .with_scale(AVERAGE_SCALE);
+
+        Some(Twap {
+            value,
+            covered: Duration::microseconds(covered),
+            samples: count,
+        })
+    }
+}
+
+/// Rules under which the latest values of several independent sources agree on a price
+#[derive(Clone, Debug)]
+pub struct ConsensusRules {
+    /// Values older than this at the time of evaluation do not contribute
+    pub max_staleness: Duration,
+    /// Largest deviation from the median a contributing value may have, in percent of the median
+    pub max_deviation_percent: BigDecimal,
+    /// Least number of contributing values
+    pub min_sources: usize,
+}
+
+/// The median of the fresh values and how far each value deviates from it
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Consensus {
+    pub median: Option<BigDecimal>,
+    /// Deviation of each input from the median in percent of the median, in input order.
+    /// `None` if the input is stale and did not contribute.
+    pub deviations: Vec<Option<BigDecimal>>,
+}
+
+impl Consensus {
+    pub fn contributors(&self) -> usize {
+        self.deviations.iter().filter(|x| x.is_some()).count()
+    }
+
+    pub fn max_deviation(&self) -> Option<&BigDecimal> {
+        self.deviations.iter().flatten().max()
+    }
+}
+
+#[derive(Snafu, Clone, Eq, PartialEq, Debug)]
+pub enum ConsensusError {
+    #[snafu(display("Only {} sources are fresh, but at least {} are required", fresh, required))]
+    NotEnoughSources {
+        fresh: usize,
+        required: usize,
+    },
+
+    #[snafu(display("Sources deviate by up to {}% from the median, but at most {}% are allowed", deviation_percent, max_deviation_percent))]
+    Disagreement {
+        deviation_percent: BigDecimal,
+        max_deviation_percent: BigDecimal,
+    },
+}
+
+impl ConsensusRules {
+    /// Takes the median of the inputs that are fresh at `at`. Deviations are truncated to
+    /// [`AVERAGE_SCALE`] decimal places. A zero median deviates 0% from zero and 100% from
+    /// anything else.
+    pub fn evaluate(&self, inputs: &[(DateTime<Utc>, BigDecimal)], at: DateTime<Utc>) -> Consensus {
+        let fresh: Vec<BigDecimal> = inputs.iter()
+            .filter(|(ts, _)| at - *ts <= self.max_staleness)
+            .map(|(_, value)| value.clone())
+            .collect();
+
+        let median = median(&fresh);
+        let deviations = inputs.iter()
+            .map(|(ts, value)| {
+                let median = median.as_ref()?;
+                if at - *ts > self.max_staleness {
+                    return None;
+                }
+
+                let zero = BigDecimal::from(0);
+                let difference = (value - median).abs();
+                Some(if *median == zero {
+                    if difference == zero { zero } else { BigDecimal::from(100) }
+                } else {
+                    (difference * BigDecimal::from(100) / median.abs()).with_scale(AVERAGE_SCALE)
+                })
+            })
+            .collect();
+
+        Consensus { median, deviations }
+    }
+
+    /// Returns the median if enough sources contributed and all of them agree
+    pub fn check(&self, consensus: &Consensus) -> Result<BigDecimal, ConsensusError> {
+        let fresh = consensus.contributors();
+        let median = match &consensus.median {
+            Some(x) if fresh >= self.min_sources => x,
+            _ => return NotEnoughSources { fresh, required: self.min_sources }.fail(),
+        };
+
+        if let Some(deviation) = consensus.max_deviation() {
+            if *deviation > self.max_deviation_percent {
+                return Disagreement {
+                    deviation_percent: deviation.clone(),
+                    max_deviation_percent: self.max_deviation_percent.clone(),
+                }.fail();
+            }
+        }
+
+        Ok(median.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use bigdecimal::BigDecimal;
+    use chrono::{Utc, TimeZone, DateTime, Duration};
+    use crate::aggregate::{median, TwapCalculator, StaleGapMode, ConsensusRules, ConsensusError};
+
+    fn decimal(x: impl AsRef<str>) -> BigDecimal {
+        BigDecimal::from_str(x.as_ref()).unwrap()
+    }
+
+    fn decimals(xs: &[&str]) -> Vec<BigDecimal> {
+        xs.iter().map(decimal).collect()
+    }
+
+    fn ts(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp(secs, 0)
+    }
+
+    fn series() -> Vec<(DateTime<Utc>, BigDecimal)> {
+        vec![
+            (ts(0), decimal("60")),
+            (ts(30), decimal("62")),
+            (ts(40), decimal("70")),
+        ]
+    }
+
+    #[test]
+    fn median_of_odd_count_should_be_middle_value() {
+        assert_eq!(median(&decimals(&["64.2", "61.9", "63.5"])), Some(decimal("63.5")));
+    }
+
+    #[test]
+    fn median_of_even_count_should_be_mean_of_middle_values() {
+        assert_eq!(median(&decimals(&["64.2", "61.9", "63.5", "62.0"])), Some(decimal("62.75")));
+        assert_eq!(median(&[]), None);
+    }
+
+    #[test]
+    fn twap_should_weight_by_duration() {
+        let twap = TwapCalculator::carry().calculate(&series(), ts(0), ts(50)).unwrap();
+
+        // 60 for 30s, 62 for 10s, 70 for 10s
+        assert_eq!(twap.value, decimal("62.4"));
+        assert_eq!(twap.covered, Duration::seconds(50));
+        assert_eq!(twap.samples, 3);
+    }
+
+    #[test]
+    fn twap_should_clip_samples_to_range() {
+        let calc = TwapCalculator::carry();
+
+        // 60 for 10s from the sample before the range, 62 for 10s, 70 for 10s
+        assert_eq!(calc.calculate(&series(), ts(20), ts(50)).unwrap().value, decimal("64"));
+
+        // Last sample is in effect until `to`
+        assert_eq!(calc.calculate(&series(), ts(100), ts(200)).unwrap().value, decimal("70"));
+
+        assert_eq!(calc.calculate(&series(), ts(-20), ts(0)), None);
+        assert_eq!(calc.calculate(&[], ts(0), ts(50)), None);
+    }
+
+    #[test]
+    fn twap_should_truncate_non_terminating_averages() {
+        let samples = vec![(ts(0), decimal("1")), (ts(1), decimal("2"))];
+        let twap = TwapCalculator::carry().calculate(&samples, ts(0), ts(3)).unwrap();
+
+        assert_eq!(twap.value, decimal("1.666666666666666666"));
+    }
+
+    #[test]
+    fn twap_should_weight_sub_millisecond_spacing() {
+        let samples = vec![
+            (Utc.timestamp(0, 0), decimal("1")),
+            (Utc.timestamp(0, 300_000), decimal("2")),
+        ];
+        let twap = TwapCalculator::carry().calculate(&samples, ts(0), Utc.timestamp(0, 400_000)).unwrap();
+
+        // 1 for 300µs, 2 for 100µs
+        assert_eq!(twap.value, decimal("1.25"));
+        assert_eq!(twap.covered, Duration::microseconds(400));
+        assert_eq!(twap.samples, 2);
+
+        // Less than a microsecond carries no weight
+        assert_eq!(TwapCalculator::carry().calculate(&samples, ts(0), Utc.timestamp(0, 500)), None);
+    }
+
+    #[test]
+    fn twap_should_cap_stale_gaps() {
+        let calc = TwapCalculator::new(StaleGapMode::Cap, Duration::seconds(10));
+        let twap = calc.calculate(&series(), ts(0), ts(50)).unwrap();
+
+        // 60 for 10s of its 30s gap, 62 for 10s, 70 for 10s
+        assert_eq!(twap.value, decimal("64"));
+        assert_eq!(twap.covered, Duration::seconds(30));
+        assert_eq!(twap.samples, 3);
+    }
+
+    #[test]
+    fn twap_should_exclude_stale_gaps() {
+        let calc = TwapCalculator::new(StaleGapMode::Exclude, Duration::seconds(10));
+        let twap = calc.calculate(&series(), ts(0), ts(50)).unwrap();
+
+        // 62 for 10s, 70 for 10s
+        assert_eq!(twap.value, decimal("66"));
+        assert_eq!(twap.covered, Duration::seconds(20));
+        assert_eq!(twap.samples, 2);
+
+        assert_eq!(calc.calculate(&series()[..1], ts(0), ts(50)), None);
+    }
+
+    #[test]
+    fn stale_gap_mode_should_parse() {
+        assert_eq!(StaleGapMode::from_str("CAP").unwrap(), StaleGapMode::Cap);
+        assert_eq!(StaleGapMode::from_str(&StaleGapMode::Exclude.to_string()).unwrap(), StaleGapMode::Exclude);
+        assert!(StaleGapMode::from_str("hold").is_err());
+    }
+
+    fn rules() -> ConsensusRules {
+        ConsensusRules {
+            max_staleness: Duration::seconds(60),
+            max_deviation_percent: decimal("1"),
+            min_sources: 2,
+        }
+    }
+
+    #[test]
+    fn consensus_should_take_median_of_fresh_values() {
+        let inputs = vec![
+            (ts(100), decimal("60.0")),
+            (ts(90), decimal("60.3")),
+            (ts(10), decimal("80")),
+            (ts(95), decimal("60.6")),
+        ];
+        let consensus = rules().evaluate(&inputs, ts(100));
+
+        assert_eq!(consensus.median, Some(decimal("60.3")));
+        assert_eq!(consensus.deviations, vec![
+            Some(decimal("0.497512437810945273")),
+            Some(decimal("0")),
+            None,
+            Some(decimal("0.497512437810945273")),
+        ]);
+        assert_eq!(consensus.contributors(), 3);
+        assert_eq!(rules().check(&consensus), Ok(decimal("60.3")));
+    }
+
+    #[test]
+    fn consensus_should_fail_on_disagreement() {
+        let inputs = vec![(ts(100), decimal("60")), (ts(100), decimal("62"))];
+        let consensus = rules().evaluate(&inputs, ts(100));
+
+        assert_eq!(rules().check(&consensus), Err(ConsensusError::Disagreement {
+            deviation_percent: decimal("1.639344262295081967"),
+            max_deviation_percent: decimal("1"),
+        }));
+    }
+
+    #[test]
+    fn consensus_should_require_enough_fresh_sources() {
+        let inputs = vec![(ts(100), decimal("60")), (ts(0), decimal("60"))];
+        let consensus = rules().evaluate(&inputs, ts(100));
+
+        assert_eq!(rules().check(&consensus), Err(ConsensusError::NotEnoughSources { fresh: 1, required: 2 }));
+        assert_eq!(rules().check(&rules().evaluate(&[], ts(100))), Err(ConsensusError::NotEnoughSources { fresh: 0, required: 2 }));
+    }
+}
@@ -0,0 +1,138 @@
+use bigdecimal::BigDecimal;
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Keypair, PublicKey, Signature, Signer, Verifier, SecretKey};
+use snafu::{Snafu, ResultExt};
+use uuid::Uuid;
+
+/// Prefix of every signed message, so that a signature over a price can never be
+/// mistaken for a signature over anything else produced with the same key
+pub const ATTESTATION_FORMAT: &str = "domfi-price-attestation-v1";
+
+#[derive(Snafu, Debug)]
+pub enum AttestationError {
+    #[snafu(display("Invalid key: {}", source))]
+    InvalidKey {
+        source: ed25519_dalek::SignatureError,
+    },
+
+    #[snafu(display("Invalid signature: {}", source))]
+    InvalidSignature {
+        source: ed25519_dalek::SignatureError,
+    },
+
+    #[snafu(display("Invalid hex encoding: {}", source))]
+    InvalidHex {
+        source: hex::FromHexError,
+    },
+
+    #[snafu(display("Signature does not match the attested price"))]
+    SignatureMismatch,
+}
+
+/// The facts a price signature vouches for
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct PriceAttestation<'a> {
+    pub ticker_id: &'a str,
+    pub price: &'a BigDecimal,
+    pub timestamp: DateTime<Utc>,
+    pub provenance_uuid: Uuid,
+}
+
+impl<'a> PriceAttestation<'a> {
+    pub fn new(ticker_id: &'a str, price: &'a BigDecimal, timestamp: DateTime<Utc>, provenance_uuid: Uuid) -> Self {
+        PriceAttestation { ticker_id, price, timestamp, provenance_uuid }
+    }
+
+    /// Newline separated fields in a fixed order: format, ticker id, price exactly as
+    /// serialized in responses, unix timestamp in seconds and hyphenated provenance UUID.
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        format!("{}\n{}\n{}\n{}\n{}",
+            ATTESTATION_FORMAT,
+            self.ticker_id,
+            self.price,
+            self.timestamp.timestamp(),
+            self.provenance_uuid.to_hyphenated())
+            .into_bytes()
+    }
+
+    pub fn sign(&self, keypair: &Keypair) -> Signature {
+        keypair.sign(&self.canonical_bytes())
+    }
+
+    pub fn verify(&self, public_key: &PublicKey, signature: &Signature) -> Result<(), AttestationError> {
+        public_key.verify(&self.canonical_bytes(), signature)
+            .map_err(|_| AttestationError::SignatureMismatch)
+    }
+
+    /// Verifies a hex encoded signature against a hex encoded public key as served by the API
+    pub fn verify_hex(&self, public_key_hex: &str, signature_hex: &str) -> Result<(), AttestationError> {
+        let public_key = hex::decode(public_key_hex).context(InvalidHex)?;
+        let public_key = PublicKey::from_bytes(&public_key).context(InvalidKey)?;
+
+        let signature = hex::decode(signature_hex).context(InvalidHex)?;
+        let signature = Signature::from_bytes(&signature).context(InvalidSignature)?;
+
+        self.verify(&public_key, &signature)
+    }
+}
+
+/// Builds the signing key pair from a hex encoded 32 byte ed25519 secret key
+pub fn keypair_from_hex(secret_hex: &str) -> Result<Keypair, AttestationError> {
+    let secret = hex::decode(secret_hex.trim()).context(InvalidHex)?;
+    let secret = SecretKey::from_bytes(&secret).context(InvalidKey)?;
+    let public = PublicKey::from(&secret);
+    Ok(Keypair { secret, public })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use bigdecimal::BigDecimal;
+    use chrono::{Utc, TimeZone};
+    use uuid::Uuid;
+    use crate::attestation::{PriceAttestation, keypair_from_hex};
+
+    const SECRET: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+
+    fn uuid() -> Uuid {
+        Uuid::from_str("d6c651dd-cd97-183b-2e40-bc464231c962").unwrap()
+    }
+
+    #[test]
+    fn canonical_bytes_should_be_stable() {
+        let price = BigDecimal::from_str("39.70").unwrap();
+        let attestation = PriceAttestation::new("bitcoin^altdom", &price, Utc.timestamp(1608195600, 0), uuid());
+
+        assert_eq!(
+            String::from_utf8(attestation.canonical_bytes()).unwrap(),
+            "domfi-price-attestation-v1\nbitcoin^altdom\n39.70\n1608195600\nd6c651dd-cd97-183b-2e40-bc464231c962");
+    }
+
+    #[test]
+    fn signature_should_verify() {
+        let keypair = keypair_from_hex(SECRET).unwrap();
+        let price = BigDecimal::from_str("60.30").unwrap();
+        let attestation = PriceAttestation::new("bitcoin^dom", &price, Utc.timestamp(1608195600, 0), uuid());
+
+        let signature = attestation.sign(&keypair);
+        let public_key_hex = hex::encode(keypair.public.as_bytes());
+        let signature_hex = hex::encode(signature.to_bytes().as_ref());
+
+        assert!(attestation.verify(&keypair.public, &signature).is_ok());
+        assert!(attestation.verify_hex(&public_key_hex, &signature_hex).is_ok());
+    }
+
+    #[test]
+    fn signature_should_not_verify_other_price() {
+        let keypair = keypair_from_hex(SECRET).unwrap();
+        let price = BigDecimal::from_str("60.30").unwrap();
+        let other_price = BigDecimal::from_str("60.31").unwrap();
+        let attestation = PriceAttestation::new("bitcoin^dom", &price, Utc.timestamp(1608195600, 0), uuid());
+        let tampered = PriceAttestation::new("bitcoin^dom", &other_price, Utc.timestamp(1608195600, 0), uuid());
+
+        let signature = attestation.sign(&keypair);
+
+        assert!(tampered.verify(&keypair.public, &signature).is_err());
+        assert!(keypair_from_hex("00").is_err());
+    }
+}
@@ -3,6 +3,8 @@ extern crate lazy_static;
 
 pub mod models;
 pub mod ext;
+pub mod aggregate;
+pub mod attestation;
 
 use bigdecimal::BigDecimal;
 
@@ -119,6 +119,12 @@ pub mod defaults {
         pub static ref BSVDOM: FinancialAssetWithMetadata<FinancialDominanceAsset> =
             dom(BSV.clone());
 
+        pub static ref SOLDOM: FinancialAssetWithMetadata<FinancialDominanceAsset> =
+            dom(SOL.clone());
+
+        pub static ref ADADOM: FinancialAssetWithMetadata<FinancialDominanceAsset> =
+            dom(ADA.clone());
+
         pub static ref CANONICAL_DEFAULT_ASSETS: HashMap<&'static str, FinancialAssetWithMetadata<FinancialDominanceAsset>> = {
             let mut x = HashMap::new();
             x.insert("btcdom", (&*BTCDOM).clone());
@@ -133,6 +139,8 @@ pub mod defaults {
             x.insert("linkdom", (&*LINKDOM).clone());
             x.insert("bchdom", (&*BCHDOM).clone());
             x.insert("bsvdom", (&*BSVDOM).clone());
+            x.insert("soldom", (&*SOLDOM).clone());
+            x.insert("adadom", (&*ADADOM).clone());
             x
         };
 
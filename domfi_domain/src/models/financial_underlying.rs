@@ -70,5 +70,11 @@ pub mod defaults {
 
         pub static ref BSV: FinancialUnderlying =
             FinancialSymbol::new("bitcoin-cash-sv", "BSV").into();
+
+        pub static ref SOL: FinancialUnderlying =
+            FinancialSymbol::new("solana", "SOL").into();
+
+        pub static ref ADA: FinancialUnderlying =
+            FinancialSymbol::new("cardano", "ADA").into();
     }
 }
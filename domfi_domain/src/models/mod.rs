@@ -29,6 +29,9 @@ pub use financial_dominance_asset::FinancialDominanceAsset;
 mod financial_asset_value;
 pub use financial_asset_value::FinancialAssetValue;
 
+mod ohlc;
+pub use ohlc::Ohlc;
+
 pub mod financial_assets {
     pub use super::financial_dominance_asset::defaults::*;
     pub use super::financial_underlying::defaults::*;
@@ -0,0 +1,65 @@
+use bigdecimal::BigDecimal;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct Ohlc {
+    pub open: BigDecimal,
+    pub high: BigDecimal,
+    pub low: BigDecimal,
+    pub close: BigDecimal,
+}
+
+impl Ohlc {
+    pub fn new(open: BigDecimal, high: BigDecimal, low: BigDecimal, close: BigDecimal) -> Ohlc {
+        Ohlc { open, high, low, close }
+    }
+
+    /// Applies a monotonic transformation to every price of the candle.
+    ///
+    /// A decreasing transformation (e.g. ALTDOM = 100 - DOM) turns the highest
+    /// input into the lowest output, so `high` and `low` are re-ordered afterwards.
+    pub fn map_values<F>(&self, f: F) -> Ohlc
+        where F: Fn(&BigDecimal) -> BigDecimal
+    {
+        let a = f(&self.high);
+        let b = f(&self.low);
+        let (high, low) = if a >= b { (a, b) } else { (b, a) };
+
+        Ohlc {
+            open: f(&self.open),
+            high,
+            low,
+            close: f(&self.close),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use bigdecimal::BigDecimal;
+    use crate::models::{Ohlc, FinancialAssetValueOf};
+    use crate::models::financial_assets::{BTCDOM, ALTDOM};
+
+    fn decimal(x: impl AsRef<str>) -> BigDecimal {
+        BigDecimal::from_str(x.as_ref()).unwrap()
+    }
+
+    fn candle(o: &str, h: &str, l: &str, c: &str) -> Ohlc {
+        Ohlc::new(decimal(o), decimal(h), decimal(l), decimal(c))
+    }
+
+    #[test]
+    fn dom_candle_should_round_in_place() {
+        let x = candle("60.123", "62.555", "59.001", "61.999");
+        let actual = x.map_values(|v| BTCDOM.value_of(v));
+        assert_eq!(actual, candle("60.12", "62.56", "59.00", "62.00"));
+    }
+
+    #[test]
+    fn altdom_candle_should_swap_high_and_low() {
+        let x = candle("60.123", "62.555", "59.001", "61.999");
+        let actual = x.map_values(|v| ALTDOM.value_of(v));
+        assert_eq!(actual, candle("39.88", "41.00", "37.44", "38.00"));
+    }
+}
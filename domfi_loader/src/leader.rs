@@ -0,0 +1,176 @@
+use std::env;
+use std::error::Error;
+use std::fs;
+
+use log::{debug, info, warn, error};
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+
+use domfi_data::pg::leader::LeaderLock;
+use domfi_util::ConfigContext;
+use crate::sources::duration_or;
+use crate::util::AtomicCancellation;
+
+/// Advisory lock shared by the loader replicas, `domfi_ld` in ASCII
+pub const DEFAULT_LEADER_LOCK_KEY: i64 = 0x646f_6d66_695f_6c64;
+
+/// Time after the last heartbeat of the leader until a follower takes over
+pub const DEFAULT_LEADER_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct LeaderConfig {
+    pub database_url: String,
+    pub lock_key: i64,
+    /// Identity of this replica in `loader_leader`
+    pub instance: String,
+    pub timeout: Duration,
+}
+
+fn default_instance() -> String {
+    let host = env::var("HOSTNAME").ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| "unknown".into());
+
+    format!("{}:{}", host, std::process::id())
+}
+
+/// Reads `DOMFI_LOADER_LEADER_ELECTION`, and if enabled `DOMFI_LOADER_LEADER_LOCK_KEY`,
+/// `DOMFI_LOADER_LEADER_TIMEOUT` and `DOMFI_LOADER_INSTANCE_NAME`, which defaults to
+/// `<hostname>:<pid>`. Returns `None` if leader election is disabled.
+pub fn leader_config_from_env(database_url: &str) -> Result<Option<LeaderConfig>, Box<dyn Error>> {
+    let config = ConfigContext::new("DOMFI_LOADER");
+
+    let enabled = match config.var("LEADER_ELECTION") {
+        Err(_) => false,
+        Ok(s) => matches!(s.trim().to_ascii_lowercase().as_str(), "true" | "t" | "yes" | "y" | "1" | "on"),
+    };
+
+    if !enabled {
+        return Ok(None);
+    }
+
+    let lock_key = match config.var("LEADER_LOCK_KEY") {
+        Err(_) => DEFAULT_LEADER_LOCK_KEY,
+        Ok(s) => s.trim().parse::<i64>()
+            .map_err(|e| format!("{} is not a 64-bit integer: {}", config.name_of("LEADER_LOCK_KEY"), e))?,
+    };
+
+    let timeout = duration_or(&config, "LEADER_TIMEOUT", DEFAULT_LEADER_TIMEOUT);
+    if timeout < Duration::from_secs(1) {
+        return Err(format!("{} must be at least 1 s", config.name_of("LEADER_TIMEOUT")).into());
+    }
+
+    let instance = config.var("INSTANCE_NAME").ok()
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(default_instance);
+
+    Ok(Some(LeaderConfig {
+        database_url: database_url.to_owned(),
+        lock_key,
+        instance,
+        timeout,
+    }))
+}
+
+/// Competes for leadership until stopped. Whenever this replica is elected, `start` is called
+/// with a cancellation that is cancelled once it is stopped or loses leadership.
+///
+/// The leader heartbeats three times per timeout and steps down if a heartbeat fails. Followers
+/// try to take the lock as often, and end the session of a leader whose heartbeat is older
+/// than the timeout so that Postgres releases its lock.
+pub async fn run_elected<F>(config: &LeaderConfig, stop: &AtomicCancellation, mut start: F)
+    where F: FnMut(&AtomicCancellation) -> Vec<JoinHandle<()>>
+{
+    let interval = config.timeout / 3;
+    let mut idle: Option<LeaderLock> = None;
+
+    while stop.can_continue() {
+        let mut lock = match idle.take() {
+            Some(x) => x,
+            None => match LeaderLock::connect(&config.database_url, config.lock_key, config.instance.clone()).await {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("Failed to connect for leader election. Retrying in {:#?}. Cause: {}", interval, e);
+                    stop.sleep(interval).await;
+                    continue;
+                }
+            },
+        };
+
+        match lock.try_acquire().await {
+            Err(e) => {
+                error!("Failed to acquire leader lock. Retrying in {:#?}. Cause: {}", interval, e);
+                stop.sleep(interval).await;
+                continue;
+            },
+            Ok(false) => {
+                match lock.terminate_stale_leader(config.timeout).await {
+                    Err(e) => {
+                        error!("Failed to check leader heartbeat. Retrying in {:#?}. Cause: {}", interval, e);
+                        stop.sleep(interval).await;
+                        continue;
+                    },
+                    Ok(Some(leader)) =>
+                        warn!("Leader '{}' stopped heartbeating, ended its session to take over", leader),
+                    Ok(None) =>
+                        debug!("Following, another replica holds leader lock {}", config.lock_key),
+                }
+                idle = Some(lock);
+                stop.sleep(interval).await;
+                continue;
+            },
+            Ok(true) => (),
+        }
+
+        info!("Elected leader as '{}', starting sources", config.instance);
+        let term = AtomicCancellation::new();
+        let tasks = start(&term);
+
+        let healthy = lead(&mut lock, interval, stop).await;
+
+        term.cancel();
+        for result in futures::future::join_all(tasks).await {
+            if let Err(e) = result {
+                error!("Source task failed: {}", e);
+            }
+        }
+
+        if healthy {
+            if let Err(e) = lock.release().await {
+                warn!("Failed to release leader lock: {}", e);
+            }
+            info!("Released leadership");
+        } else {
+            // Ending the session releases the lock, if the server still knows about it
+            warn!("Lost leadership, sources stopped");
+        }
+    }
+}
+
+/// Heartbeats until stopped, returning true, or until a heartbeat fails, returning false
+async fn lead(lock: &mut LeaderLock, interval: Duration, stop: &AtomicCancellation) -> bool {
+    loop {
+        stop.sleep(interval).await;
+        if stop.is_cancelled() {
+            return true;
+        }
+
+        match tokio::time::timeout(interval, lock.heartbeat()).await {
+            Ok(Ok(true)) => (),
+            Ok(Ok(false)) => {
+                error!("No longer recorded as leader, stepping down");
+                return false;
+            },
+            Ok(Err(e)) => {
+                error!("Failed to heartbeat, stepping down. Cause: {}", e);
+                return false;
+            },
+            Err(_) => {
+                error!("Heartbeat timed out after {:#?}, stepping down", interval);
+                return false;
+            },
+        }
+    }
+}
@@ -1,20 +1,24 @@
-mod coingecko;
+mod leader;
+mod sources;
 mod util;
 
 use std::env;
 use std::error::Error;
-use std::borrow::Cow;
 
-use log::{info, warn, error};
-use leaky_bucket::LeakyBuckets;
+use log::{debug, info, warn, error};
+use leaky_bucket::{LeakyBuckets, LeakyBucket};
+use tokio::task::JoinHandle;
 use tokio::time::Duration;
-use reqwest::Url;
 use sqlx::PgPool;
+use chrono::{DateTime, Utc};
 
 use domfi_util::init_logging;
-use domfi_data::pg;
-use domfi_data::pg::models::CoinDominanceEntry;
-use crate::util::{UrlCacheBuster, AtomicCancellation};
+use domfi_data::pg::ops::failed_fetch;
+use domfi_data::source::{self, DataSource, IngestResult, SourceError};
+use domfi_data::validate::PreviousSnapshot;
+use crate::leader::{leader_config_from_env, run_elected, LeaderConfig};
+use crate::sources::{sources_from_env, SourceConfig};
+use crate::util::AtomicCancellation;
 
 const DEFAULT_LOG_FILTERS: &'static str = "info,domfi_loader=debug";
 
@@ -30,7 +34,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config = get_config().await?;
     let db_pool = PgPool::connect(&config.database_url).await?;
 
-    info!("Rate limit interval set to 1 req/{:#?}", config.rate_limit_interval);
+    let sources = sources_from_env(config.rate_limit_interval)?;
 
     //
 
@@ -42,14 +46,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let coordinator = buckets.coordinate()?;
     tokio::spawn(async move { coordinator.await.expect("Failed to start rate limiter coordinator") });
 
-    let rate_limiter = buckets.rate_limiter()
-        .max(5)
-        .tokens(1)
-        .refill_amount(1)
-        .refill_interval(config.rate_limit_interval)
-        .build()
-        .expect("Failed to build request rate limiter");
-
     let stop_signal_source = AtomicCancellation::new();
 
     let h = stop_signal_source.clone();
@@ -60,87 +56,171 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     })
     .expect("Error setting Ctrl-C handler");
 
-    let stop_handle = stop_signal_source.clone();
-    let mut url_gen = config.url_gen();
+    let start = |stop: &AtomicCancellation| start_sources(&sources, &buckets, &http, &db_pool, stop);
+    match &config.leader {
+        Some(leader) => {
+            info!("Leader election enabled, competing for lock {} as '{}'", leader.lock_key, leader.instance);
+            run_elected(leader, &stop_signal_source, start).await;
+        },
+        None => {
+            for result in futures::future::join_all(start(&stop_signal_source)).await {
+                if let Err(e) = result {
+                    error!("Source task failed: {}", e);
+                }
+            }
+        },
+    }
+
+    info!("Quitting");
+    Ok(())
+}
+
+/// Spawns a task polling each source until `stop` is cancelled
+fn start_sources(
+    sources: &[SourceConfig],
+    buckets: &LeakyBuckets,
+    http: &reqwest::Client,
+    db_pool: &PgPool,
+    stop: &AtomicCancellation,
+) -> Vec<JoinHandle<()>> {
+    let mut tasks = Vec::new();
+    for x in sources {
+        let rate_limiter = buckets.rate_limiter()
+            .max(5)
+            .tokens(1)
+            .refill_amount(1)
+            .refill_interval(x.rate_limit_interval)
+            .build()
+            .expect("Failed to build request rate limiter");
+
+        tasks.push(tokio::spawn(run_source(
+            x.clone(),
+            rate_limiter,
+            http.clone(),
+            db_pool.clone(),
+            stop.clone())));
+    }
+    tasks
+}
+
+async fn run_source(
+    config: SourceConfig,
+    rate_limiter: LeakyBucket,
+    http: reqwest::Client,
+    db_pool: PgPool,
+    stop_handle: AtomicCancellation)
+{
+    let retry = config.retry;
+    let name = config.source.name().to_owned();
+    let mut breaker = retry.circuit_breaker();
+    // Set when the last snapshot failed with a `Retry-After` that outlasts the retries
+    let mut retry_after_until: Option<DateTime<Utc>> = None;
+    // Latest validated snapshot, committed or quarantined
+    let mut observed: Option<PreviousSnapshot> = None;
     while stop_handle.can_continue() {
-        rate_limiter.acquire_one().await?;
-
-        let url = url_gen.next();
-        let result =
-            pg::ops::provenance::insert_from_json_url::<coingecko::CoinDominanceResponse, _, _>(
-                &config.agent_name,
-                url,
-                &http,
-                &db_pool
-            ).await;
-
-        let ctx = match result {
-            Err(err) => {
-                error!("Failed to insert provenance! Cause: {}", err);
-                continue;
-            },
-            Ok(x) => x,
-        };
+        if let Err(err) = rate_limiter.acquire_one().await {
+            error!("[{}] Rate limiter failed, stopping source! Cause: {}", name, err);
+            break;
+        }
 
-        let dataset_timestamp = ctx.json.timestamp;
-        let rows: Vec<_> = ctx.json.data.into_iter()
-            .map(|r| {
-                CoinDominanceEntry {
-                    name: Cow::Owned(r.name),
-                    id: Cow::Owned(r.id),
-                    market_cap_usd: Cow::Owned(r.market_cap_usd),
-                    dominance_percentage: Cow::Owned(r.dominance_percentage),
-                    timestamp: Cow::Borrowed(&dataset_timestamp)
+        let now = Utc::now();
+        let wait = retry_after_until
+            .and_then(|x| (x - now).to_std().ok())
+            .unwrap_or_default()
+            .max(breaker.wait_time(now));
+        if wait > Duration::from_secs(0) {
+            stop_handle.sleep(wait).await;
+            if stop_handle.is_cancelled() {
+                break;
+            }
+        }
+
+        match ingest_with_retries(&config, &mut observed, &http, &db_pool, &stop_handle).await {
+            // The source is up, it just sent implausible data
+            Err(err @ SourceError::Quarantined { .. }) => {
+                warn!("[{}] {}", name, err);
+            },
+            Err(err) => {
+                error!("[{}] Failed to load snapshot! Cause: {}", name, err);
+                retry_after_until = err.retry_after()
+                    .and_then(|x| chrono::Duration::from_std(retry.retry_after(Some(x))).ok())
+                    .map(|x| Utc::now() + x);
+                if breaker.record_failure(Utc::now()) {
+                    warn!("[{}] {} consecutive snapshots failed, polling at most every {:#?} until the source recovers",
+                          name, breaker.consecutive_failures(), retry.breaker_interval);
                 }
-            })
-            .collect();
-
-        let result =
-            pg::ops::coin_dominance_entry::insert(
-                &config.agent_name,
-                &ctx.provenance,
-                rows.as_slice(),
-                &db_pool,
-            ).await;
-
-        let provenance_uuid = &ctx.provenance.uuid;
-        match result {
-            Err(e) => {
-                error!("[{}] Failed to insert coin dominance! Cause: {}", provenance_uuid, e);
-                continue;
             },
-            Ok(_) => {
-                info!("[{}] Committed snapshot at {}", provenance_uuid, &ctx.imported_at);
+            Ok(x) => {
+                if breaker.is_open() {
+                    info!("[{}] Source recovered, resuming regular polling", name);
+                }
+                breaker.record_success();
+                match x {
+                    IngestResult::Loaded(x) =>
+                        info!("[{}] [{}] Committed snapshot of {} rows at {}", name, x.provenance.uuid, x.rows, &x.imported_at),
+                    IngestResult::Unchanged(x) =>
+                        debug!("[{}] [{}] Snapshot at {} unchanged: {}", name, x.provenance_uuid, &x.observed_at, x.reason),
+                }
             }
         }
     }
 
-    info!("Quitting");
-    Ok(())
+    info!("[{}] Stopped", name);
 }
 
-struct Config {
-    agent_name: String,
-    database_url: String,
-    url: Url,
-    rate_limit_interval: Duration,
+/// Ingests one snapshot, retrying transient failures with backoff. Every failed
+/// attempt is recorded in `failed_fetch`.
+async fn ingest_with_retries(
+    config: &SourceConfig,
+    observed: &mut Option<PreviousSnapshot>,
+    http: &reqwest::Client,
+    db_pool: &PgPool,
+    stop_handle: &AtomicCancellation,
+) -> Result<IngestResult, SourceError> {
+    let source = config.source.as_ref();
+    let retry = &config.retry;
+    let mut attempt = 0;
+    loop {
+        let err = match source::ingest(source, &config.validation, observed, config.skip_unchanged, http, db_pool).await {
+            Ok(x) => return Ok(x),
+            Err(e) => e,
+        };
+
+        record_failure(source, attempt, &err, db_pool).await;
+
+        if !err.is_retryable() || attempt >= retry.max_retries {
+            return Err(err);
+        }
+
+        let delay = retry.retry_delay(attempt, err.retry_after());
+        warn!("[{}] Attempt {} failed, retrying in {:#?}. Cause: {}", source.name(), attempt + 1, delay, err);
+
+        stop_handle.sleep(delay).await;
+        if stop_handle.is_cancelled() {
+            return Err(err);
+        }
+        attempt += 1;
+    }
 }
 
-impl Config {
-    fn url_gen(&self) -> UrlCacheBuster {
-        UrlCacheBuster::new(&self.url)
+/// Records the failed attempt, with one record per rejected response so each body is kept
+async fn record_failure(source: &dyn DataSource, attempt: u32, err: &SourceError, db_pool: &PgPool) {
+    for failure in err.failed_fetches(source.agent_name(), attempt, Utc::now()) {
+        if let Err(e) = failed_fetch::insert(&failure, db_pool).await {
+            warn!("[{}] Failed to record failed fetch: {}", source.name(), e);
+        }
     }
 }
 
-async fn get_config() -> Result<Config, Box<dyn Error>> {
-    let agent_name = env::var("DOMFI_LOADER_AGENT_NAME").unwrap_or("loader_rust".into());
-    info!("Agent name: '{}'", agent_name);
+struct Config {
+    database_url: String,
+    rate_limit_interval: Duration,
+    leader: Option<LeaderConfig>,
+}
 
+async fn get_config() -> Result<Config, Box<dyn Error>> {
     let database_url = env::var("DOMFI_LOADER_DATABASE_URL").expect("DOMFI_LOADER_DATABASE_URL missing or unset '.env' file");
 
-    let url_raw = env::var("DOMFI_LOADER_URL").expect("DOMFI_LOADER_URL missing or unset in '.env' file");
-    let url = Url::parse(url_raw.as_str()).expect("DOMFI_LOADER_URL has invalid URL format");
-
     let rate_limit_interval_default = Duration::from_millis(1250);
     let rate_limit_interval = env::var("DOMFI_LOADER_INTERVAL").ok()
         .and_then(|s| match parse_duration::parse(&s) {
@@ -153,11 +233,11 @@ async fn get_config() -> Result<Config, Box<dyn Error>> {
         })
         .unwrap_or(rate_limit_interval_default);
 
+    let leader = leader_config_from_env(&database_url)?;
+
     Ok(Config {
-        agent_name,
         database_url,
-        url,
         rate_limit_interval,
+        leader,
     })
 }
-
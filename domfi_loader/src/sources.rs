@@ -0,0 +1,192 @@
+use std::env;
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use bigdecimal::BigDecimal;
+
+use log::{info, warn};
+use reqwest::Url;
+use tokio::time::Duration;
+
+use domfi_data::source::DataSource;
+use domfi_data::source::retry::{RetryPolicy, Backoff};
+use domfi_data::source::unchanged::{SkipUnchanged, DEFAULT_SKIP_UNCHANGED};
+use domfi_data::validate::ValidationRules;
+use domfi_data::source::coingecko::{CoinGeckoDominanceSource, CoinGeckoMarketsSource, COINGECKO_COIN_DOMINANCE_URL, COINGECKO_API_URL};
+use domfi_util::ConfigContext;
+
+const DEFAULT_SOURCES: &str = "coingecko";
+
+#[derive(Clone)]
+pub struct SourceConfig {
+    pub source: Arc<dyn DataSource>,
+    pub rate_limit_interval: Duration,
+    pub retry: RetryPolicy,
+    pub validation: ValidationRules,
+    pub skip_unchanged: SkipUnchanged,
+}
+
+/// Parses a duration such as `4500 ms`, warning and falling back to the default if it is invalid
+pub(crate) fn duration_or(config: &ConfigContext, key: &str, default: Duration) -> Duration {
+    match config.var(key) {
+        Err(_) => default,
+        Ok(s) => match parse_duration::parse(&s) {
+            Ok(x) => x,
+            Err(err) => {
+                warn!("Failed to parse '{}'. Using default value instead of '{:#?}'. Cause: {}",
+                      config.name_of(key), default, err);
+                default
+            }
+        }
+    }
+}
+
+fn number_or(config: &ConfigContext, key: &str, default: u32) -> u32 {
+    match config.var(key) {
+        Err(_) => default,
+        Ok(s) => match s.trim().parse::<u32>() {
+            Ok(x) => x,
+            Err(err) => {
+                warn!("Failed to parse '{}'. Using default value instead of '{}'. Cause: {}",
+                      config.name_of(key), default, err);
+                default
+            }
+        }
+    }
+}
+
+/// Parses a number of percentage points, where `off` disables the rule
+fn percentage_points_or(config: &ConfigContext, key: &str, default: Option<BigDecimal>) -> Option<BigDecimal> {
+    match config.var(key) {
+        Err(_) => default,
+        Ok(s) if s.trim().eq_ignore_ascii_case("off") => None,
+        Ok(s) => match BigDecimal::from_str(s.trim()) {
+            Ok(x) if x >= BigDecimal::from(0) => Some(x),
+            _ => {
+                warn!("Failed to parse '{}'. Expected percentage points or 'off'. Using default value instead of '{:?}'",
+                      config.name_of(key), default);
+                default
+            }
+        }
+    }
+}
+
+/// Reads `_VALIDATE_SUM_TOLERANCE`, `_VALIDATE_MAX_JUMP`, `_VALIDATE_MAX_JUMP_WINDOW`,
+/// the comma separated `_VALIDATE_REQUIRED_COINS` and `_VALIDATE_MONOTONIC`
+fn validation_rules(config: &ConfigContext) -> ValidationRules {
+    let default = ValidationRules::default();
+    let max_jump_window = duration_or(config, "VALIDATE_MAX_JUMP_WINDOW", default.max_jump_window.to_std().unwrap_or_default());
+
+    ValidationRules {
+        sum_tolerance: percentage_points_or(config, "VALIDATE_SUM_TOLERANCE", default.sum_tolerance),
+        max_jump: percentage_points_or(config, "VALIDATE_MAX_JUMP", default.max_jump),
+        max_jump_window: chrono::Duration::from_std(max_jump_window).unwrap_or(default.max_jump_window),
+        required_coins: match config.var("VALIDATE_REQUIRED_COINS") {
+            Err(_) => default.required_coins,
+            Ok(s) => s.split(',').map(|x| x.trim().to_owned()).filter(|x| !x.is_empty()).collect(),
+        },
+        monotonic: match config.var("VALIDATE_MONOTONIC") {
+            Err(_) => default.monotonic,
+            Ok(s) => !matches!(s.trim().to_ascii_lowercase().as_str(), "false" | "f" | "no" | "n" | "0" | "off"),
+        },
+    }
+}
+
+/// Reads `_SKIP_UNCHANGED`, one of `off`, `hash` or `timestamp`
+fn skip_unchanged(config: &ConfigContext) -> SkipUnchanged {
+    let default = DEFAULT_SKIP_UNCHANGED;
+    match config.var("SKIP_UNCHANGED") {
+        Err(_) => default,
+        Ok(s) => match s.parse::<SkipUnchanged>() {
+            Ok(x) => x,
+            Err(err) => {
+                warn!("Failed to parse '{}'. Using default value instead of '{:?}'. Cause: {}",
+                      config.name_of("SKIP_UNCHANGED"), default, err);
+                default
+            }
+        }
+    }
+}
+
+/// Reads `_MAX_RETRIES`, `_BACKOFF_BASE`, `_BACKOFF_MAX`, `_RETRY_AFTER_MAX`, `_BREAKER_THRESHOLD`
+/// and `_BREAKER_INTERVAL`
+fn retry_policy(config: &ConfigContext) -> RetryPolicy {
+    let default = RetryPolicy::default();
+    RetryPolicy {
+        max_retries: number_or(config, "MAX_RETRIES", default.max_retries),
+        backoff: Backoff {
+            base: duration_or(config, "BACKOFF_BASE", default.backoff.base),
+            max: duration_or(config, "BACKOFF_MAX", default.backoff.max),
+        },
+        retry_after_max: duration_or(config, "RETRY_AFTER_MAX", default.retry_after_max),
+        breaker_threshold: number_or(config, "BREAKER_THRESHOLD", default.breaker_threshold),
+        breaker_interval: duration_or(config, "BREAKER_INTERVAL", default.breaker_interval),
+    }
+}
+
+/// Reads `DOMFI_LOADER_SOURCES`, a comma separated list of source names, and the
+/// `DOMFI_LOADER_<NAME>_URL`, `_AGENT_NAME` and `_INTERVAL` of each source.
+/// `coingecko_markets` additionally reads `_PAGES`, the number of 250 coin pages to fetch.
+/// How failed snapshots are retried, how snapshots are validated and whether unchanged
+/// snapshots are skipped is configured per source, see [`retry_policy`], [`validation_rules`]
+/// and [`skip_unchanged`].
+/// The unprefixed `DOMFI_LOADER_URL` and `DOMFI_LOADER_AGENT_NAME` still configure
+/// the `coingecko` source, and `DOMFI_LOADER_INTERVAL` is the default interval of all.
+pub fn sources_from_env(default_interval: Duration) -> Result<Vec<SourceConfig>, Box<dyn Error>> {
+    let names = env::var("DOMFI_LOADER_SOURCES").unwrap_or_else(|_| DEFAULT_SOURCES.into());
+
+    let mut sources = Vec::new();
+    for name in names.split(',').map(|x| x.trim().to_lowercase()).filter(|x| !x.is_empty()) {
+        if sources.iter().any(|x: &SourceConfig| x.source.name() == name) {
+            return Err(format!("Source '{}' is listed more than once in DOMFI_LOADER_SOURCES", name).into());
+        }
+
+        let config = ConfigContext::new(format!("DOMFI_LOADER_{}", name.to_uppercase()));
+        let is_legacy = name == "coingecko";
+        let var = |key: &str| -> Option<String> {
+            config.var(key).ok()
+                .or_else(|| if is_legacy { env::var(format!("DOMFI_LOADER_{}", key)).ok() } else { None })
+        };
+
+        let agent_name = var("AGENT_NAME")
+            .unwrap_or_else(|| if is_legacy { "loader_rust".into() } else { format!("loader_{}", name) });
+
+        let rate_limit_interval = duration_or(&config, "INTERVAL", default_interval);
+        let retry = retry_policy(&config);
+        let validation = validation_rules(&config);
+        let skip_unchanged = skip_unchanged(&config);
+
+        let source: Arc<dyn DataSource> = match name.as_str() {
+            "coingecko" => {
+                let url_raw = var("URL").unwrap_or_else(|| COINGECKO_COIN_DOMINANCE_URL.into());
+                let url = Url::parse(&url_raw)
+                    .map_err(|e| format!("{} has invalid URL format: {}", config.name_of("URL"), e))?;
+                Arc::new(CoinGeckoDominanceSource::new(name.clone(), agent_name, url))
+            },
+            "coingecko_markets" => {
+                let url_raw = var("URL").unwrap_or_else(|| COINGECKO_API_URL.into());
+                let url = Url::parse(&url_raw)
+                    .map_err(|e| format!("{} has invalid URL format: {}", config.name_of("URL"), e))?;
+                let pages = match var("PAGES") {
+                    None => 1,
+                    Some(x) => x.parse::<u32>()
+                        .map_err(|e| format!("{} is not a number of pages: {}", config.name_of("PAGES"), e))?,
+                };
+                Arc::new(CoinGeckoMarketsSource::new(name.clone(), agent_name, &url, pages)?)
+            },
+            _ => return Err(format!("Unknown source '{}' in DOMFI_LOADER_SOURCES", name).into()),
+        };
+
+        info!("Source '{}': agent name '{}', rate limit interval 1 req/{:#?}, {:?}, {:?}, skip unchanged: {:?}",
+            source.name(), source.agent_name(), rate_limit_interval, retry, validation, skip_unchanged);
+
+        sources.push(SourceConfig { source, rate_limit_interval, retry, validation, skip_unchanged });
+    }
+
+    if sources.is_empty() {
+        return Err("DOMFI_LOADER_SOURCES does not list any source".into());
+    }
+
+    Ok(sources)
+}
@@ -1,29 +1,9 @@
-use rand::rngs::ThreadRng;
-use rand::RngCore;
-use reqwest::Url;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-pub struct UrlCacheBuster<'a> {
-    rng: ThreadRng,
-    url: &'a Url,
-}
-
-impl<'a> UrlCacheBuster<'a> {
-    pub fn new(url: &'a Url) -> UrlCacheBuster<'a> {
-        UrlCacheBuster { rng: rand::thread_rng(), url }
-    }
-
-    pub fn next(&mut self) -> Url {
-        let num = self.rng.next_u64();
-
-        self.url.clone()
-            .query_pairs_mut()
-            .append_pair("_", num.to_string().as_str())
-            .finish()
-            .to_owned()
-    }
-}
+/// Longest time a cancelled sleep keeps sleeping
+const SLEEP_POLL_INTERVAL: Duration = Duration::from_millis(250);
 
 #[derive(Clone)]
 pub struct AtomicCancellation {
@@ -51,4 +31,21 @@ impl AtomicCancellation {
     pub fn cancel(&self) {
         (*self.value).swap(true, Ordering::SeqCst);
     }
+
+    /// Sleeps for the duration, returning early once cancelled. A duration too long to
+    /// represent sleeps until cancelled.
+    pub async fn sleep(&self, duration: Duration) {
+        let until = Instant::now().checked_add(duration);
+        while self.can_continue() {
+            let mut wait = SLEEP_POLL_INTERVAL;
+            if let Some(until) = until {
+                let now = Instant::now();
+                if now >= until {
+                    break;
+                }
+                wait = wait.min(until - now);
+            }
+            tokio::time::delay_for(wait).await;
+        }
+    }
 }
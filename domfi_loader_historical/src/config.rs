@@ -1,9 +1,46 @@
-use log::info;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use log::{info, warn};
 use domfi_util::{ConfigError, ConfigContext};
+use domfi_data::pg::ops::coin_dominance_entry::DEFAULT_BATCH_SIZE;
+
+pub const DEFAULT_COINS_LIST_URL: &str = "https://api.coingecko.com/api/v3/coins/list";
+pub const DEFAULT_UNMAPPED: UnmappedSeries = UnmappedSeries::Skip;
+
+/// What to do with series that the coin map has no id for
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum UnmappedSeries {
+    /// Load the mapped series and report the others
+    Skip,
+    /// Abort before loading anything
+    Fail,
+    /// Map the series by symbol through the CoinGecko coins list, skipping the ambiguous ones
+    Auto,
+}
+
+impl FromStr for UnmappedSeries {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "skip" => Ok(UnmappedSeries::Skip),
+            "fail" => Ok(UnmappedSeries::Fail),
+            "auto" => Ok(UnmappedSeries::Auto),
+            x => Err(format!("expected 'skip', 'fail' or 'auto' but got '{}'", x)),
+        }
+    }
+}
 
 pub struct Config {
     pub agent_name: String,
     pub postgres_url: String,
+    /// Rows inserted per statement
+    pub batch_size: usize,
+    /// JSON file of coin ids by series name, replacing the built-in map
+    pub coin_map: Option<PathBuf>,
+    pub unmapped: UnmappedSeries,
+    pub coins_list_url: String,
 }
 
 pub async fn config_with_prefix(prefix: &str) -> Result<Config, ConfigError> {
@@ -14,8 +51,42 @@ pub async fn config_with_prefix(prefix: &str) -> Result<Config, ConfigError> {
 
     let postgres_url = config.var("POSTGRES_URL")?;
 
+    let batch_size = match config.var("BATCH_SIZE") {
+        Err(_) => DEFAULT_BATCH_SIZE,
+        Ok(s) => match s.trim().parse::<usize>() {
+            Ok(x) if x > 0 => x,
+            _ => {
+                warn!("Invalid '{}'. Using default value instead of '{}'", config.name_of("BATCH_SIZE"), DEFAULT_BATCH_SIZE);
+                DEFAULT_BATCH_SIZE
+            }
+        }
+    };
+
+    let coin_map = config.var("COIN_MAP").ok()
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty())
+        .map(PathBuf::from);
+
+    let unmapped = match config.var("UNMAPPED") {
+        Err(_) => DEFAULT_UNMAPPED,
+        Ok(s) => match s.parse::<UnmappedSeries>() {
+            Ok(x) => x,
+            Err(err) => {
+                warn!("Invalid '{}'. Using default value instead of '{:?}'. Cause: {}",
+                      config.name_of("UNMAPPED"), DEFAULT_UNMAPPED, err);
+                DEFAULT_UNMAPPED
+            }
+        }
+    };
+
+    let coins_list_url = config.var("COINS_LIST_URL").unwrap_or(DEFAULT_COINS_LIST_URL.into());
+
     Ok(Config {
         agent_name,
         postgres_url,
+        batch_size,
+        coin_map,
+        unmapped,
+        coins_list_url,
     })
 }
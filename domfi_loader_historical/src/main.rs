@@ -1,45 +1,46 @@
 mod config;
-mod coingecko;
 
-#[macro_use]
-extern crate lazy_static;
-
-use std::collections::HashMap;
 use std::error::Error;
+use std::fs;
+use bytes::Bytes;
+use chrono::{DateTime, NaiveDateTime, Utc};
 use sqlx::PgPool;
-use log::{info, error};
+use structopt::StructOpt;
+use log::{info, warn, error};
 
 use domfi_util::init_logging;
 use domfi_data::pg;
-use crate::config::config_with_prefix;
-use crate::coingecko::MarketDominanceData;
-use bigdecimal::{BigDecimal, Zero};
-use domfi_data::pg::models::{CoinDominanceEntry};
-use std::borrow::Cow;
+use domfi_data::pg::ops::coin_dominance_entry::OnConflict;
+use domfi_data::pg::ops::historical_checkpoint;
+use domfi_data::pg::ops::provenance::FetchIntoProvenanceOutput;
+use domfi_data::retention;
+use domfi_data::coingecko::{CoinListEntry, CoinMap, MarketDominanceData};
+use crate::config::{config_with_prefix, Config, UnmappedSeries};
 
 const DEFAULT_LOG_FILTERS: &'static str = "info,domfi_loader_historical=debug,sqlx=warn";
 
-lazy_static! {
-    static ref KNOWN_COINS_BY_SYMBOL: HashMap<&'static str, &'static str> = {
-        let mut m = HashMap::new();
-        m.insert("Others", "others-coingecko-global");
-        m.insert("XLM"   , "stellar");
-        m.insert("XMR"   , "monero");
-        m.insert("NEO"   , "neo");
-        m.insert("EOS"   , "eos");
-
-        m.insert("BSV"   , "bitcoin-cash-sv");
-        m.insert("LINK"  , "chainlink");
-        m.insert("BNB"   , "binancecoin");
-        m.insert("BCH"   , "bitcoin-cash");
-        m.insert("DOT"   , "polkadot");
-        m.insert("LTC"   , "litecoin");
-        m.insert("XRP"   , "ripple");
-        m.insert("USDT"  , "tether");
-        m.insert("ETH"   , "ethereum");
-        m.insert("BTC"   , "bitcoin");
-        m
-    };
+#[derive(StructOpt, Debug)]
+#[structopt(name = "domfi_loader_historical", about = "Backfills coin dominance from the CoinGecko market dominance chart")]
+struct Args {
+    /// Only load points at or after this unix timestamp in seconds
+    #[structopt(long, parse(try_from_str = parse_timestamp))]
+    from: Option<DateTime<Utc>>,
+
+    /// Only load points before this unix timestamp in seconds
+    #[structopt(long, parse(try_from_str = parse_timestamp))]
+    to: Option<DateTime<Utc>>,
+
+    /// Fetch the chart again instead of resuming an unfinished backfill of the same range,
+    /// marking that one abandoned
+    #[structopt(long)]
+    fresh: bool,
+}
+
+fn parse_timestamp(s: &str) -> Result<DateTime<Utc>, String> {
+    let ts = s.parse::<i64>().map_err(|e| e.to_string())?;
+    NaiveDateTime::from_timestamp_opt(ts, 0)
+        .map(|x| DateTime::from_utc(x, Utc))
+        .ok_or_else(|| format!("timestamp {} is out of range", ts))
 }
 
 #[tokio::main]
@@ -51,52 +52,158 @@ async fn main() -> Result<(), Box<dyn Error>> {
         error!("Failed to load .env file: {}", err);
     }
 
+    let args = Args::from_args();
+    let from = args.from;
+    let to = args.to;
+
     let config = config_with_prefix("DOMFI_LOADER_HIST").await?;
+
+    let coin_map = match &config.coin_map {
+        None => None,
+        Some(path) => {
+            let data = fs::read(path)
+                .map_err(|e| format!("Failed to read coin map '{}': {}", path.display(), e))?;
+            let coins = CoinMap::from_json(&data)
+                .map_err(|e| format!("Failed to parse coin map '{}': {}", path.display(), e))?;
+            info!("Loaded {} coins from '{}'", coins.len(), path.display());
+            Some((coins, data))
+        }
+    };
+
     let db_pool = PgPool::connect(&config.postgres_url).await?;
+    let http = reqwest::Client::new();
 
-    // Fetch and insert into provenance
-    let url = "https://www.coingecko.com/global_charts/market_dominance_data?locale=en";
-    let fetch =
-        pg::ops::provenance::insert_from_json_url_with_client::<MarketDominanceData, _, _>(
+    let resumed = if args.fresh {
+        let abandoned = historical_checkpoint::abandon(&config.agent_name, from, to, &db_pool).await?;
+        if abandoned > 0 {
+            info!("Abandoned {} unfinished backfills of the same range", abandoned);
+        }
+        None
+    } else {
+        historical_checkpoint::find_incomplete(&config.agent_name, from, to, &db_pool).await?
+    };
+
+    let (checkpoint, provenance, market, coins, resume_from) = match resumed {
+        Some(c) => {
+            info!("Resuming backfill #{} of {} from {:?}, {} rows already inserted",
+                c.id, c.provenance, c.resume_from, c.inserted);
+
+            // Mapped as when the chart was fetched, whatever the coin map is now
+            let market = serde_json::from_slice::<MarketDominanceData>(&c.data)?;
+            let children = pg::ops::provenance::find_children_data(&c.provenance.uuid, &db_pool).await?;
+            let coins = CoinMap::from_children(&market, &children)?;
+
+            (c.id, c.provenance, market, coins, c.resume_from)
+        },
+        None => {
+            let (fetch, coins) = fetch_chart(&config, coin_map, &http, &db_pool).await?;
+            let id = historical_checkpoint::create(&config.agent_name, &fetch.provenance, from, to, &db_pool).await?;
+            (id, fetch.provenance, fetch.json, coins, None)
+        },
+    };
+
+    let unmapped = market.unmapped_series(&coins);
+
+    // Points at the checkpoint itself may only be partly inserted and are skipped below.
+    // Retention downsampled every point before its latest cutoff, which must not come back.
+    let cutoff = retention::latest_cutoff(&db_pool).await?;
+    if let Some(cutoff) = cutoff {
+        info!("Skipping points before the retention cutoff at {}", cutoff);
+    }
+    let start = resume_from.or(from).max(cutoff);
+    let loaded = pg::ops::coin_dominance_entry::find_points(&config.agent_name, start, to, &db_pool).await?;
+
+    let x = historical_checkpoint::pending_entries(market.into_entries_with(&coins), start, to, &loaded);
+
+    info!("Got {} rows to insert, {} points in range already loaded", x.len(), loaded.len());
+
+    let mut i = 0usize;
+    for batch in x.chunks(config.batch_size) {
+        let mut tx = db_pool.begin().await?;
+        let inserted = pg::ops::coin_dominance_entry::insert_batch(
             &config.agent_name,
-            url,
-            &db_pool)
+            &provenance,
+            batch,
+            OnConflict::Ignore,
+            &mut tx)
             .await?;
 
-    let x: Vec<_> =
-        fetch.json.series_data_array.into_iter()
-            .filter_map(|x| {
-                let coin_id = (&*KNOWN_COINS_BY_SYMBOL).get(x.name.as_str());
-                coin_id.map(|id| (id, x))
-            })
-            .flat_map(|(coin_id, series)| {
-                let coin_name = series.name.clone();
-                series.data.into_iter()
-                    .flat_map(move |row| {
-                        let coin_name = coin_name.clone();
-                        let timestamp = row.0;
-                        let value_opt = row.1;
-                        value_opt.map(|dom_perc| CoinDominanceEntry {
-                            name: Cow::Owned(coin_name),
-                            id: Cow::Borrowed(coin_id),
-                            market_cap_usd: Cow::Owned(BigDecimal::zero()),
-                            dominance_percentage: Cow::Owned(dom_perc),
-                            timestamp: Cow::Owned(timestamp),
-                        })
-                    })
-            })
-            .collect();
-
-    info!("Got {} rows", x.len());
-
-    pg::ops::coin_dominance_entry::insert(
-        &config.agent_name,
-        &fetch.provenance,
-        x.as_slice(),
-        &db_pool)
-        .await?;
+        let last = *batch[batch.len() - 1].timestamp;
+        historical_checkpoint::advance(checkpoint, last, inserted, &mut tx).await?;
+        tx.commit().await?;
+
+        i += batch.len();
+        info!("INSERT {}/{} rows ({:.2}%)", i, x.len(), (i as f64) / (x.len() as f64) * 100f64);
+    }
+
+    historical_checkpoint::complete(checkpoint, &db_pool).await?;
+
+    if !unmapped.is_empty() {
+        warn!("Skipped {} unmapped series: {}", unmapped.len(), unmapped.join(", "));
+    }
 
     info!("Done.");
 
     Ok(())
 }
+
+/// Fetches the chart into provenance along with the coin map, handling the series without a
+/// coin as configured. The chart is only stored once it is known to be loaded.
+async fn fetch_chart(
+    config: &Config,
+    coin_map: Option<(CoinMap, Vec<u8>)>,
+    http: &reqwest::Client,
+    db_pool: &PgPool,
+) -> Result<(FetchIntoProvenanceOutput<MarketDominanceData>, CoinMap), Box<dyn Error>> {
+    let url = "https://www.coingecko.com/global_charts/market_dominance_data?locale=en";
+    let fetched =
+        pg::ops::provenance::fetch_json_url::<MarketDominanceData, _, _>(
+            &config.agent_name,
+            url,
+            http,
+            db_pool)
+            .await?;
+
+    let (mut coins, coin_map_data) = match coin_map {
+        None => (CoinMap::default(), None),
+        Some((coins, data)) => (coins, Some(data)),
+    };
+
+    let unmapped = fetched.json.unmapped_series(&coins);
+    if !unmapped.is_empty() && config.unmapped == UnmappedSeries::Fail {
+        return Err(format!("{} series have no coin in the coin map: {}",
+            unmapped.len(), unmapped.join(", ")).into());
+    }
+
+    let fetch = pg::ops::provenance::insert_fetched(&config.agent_name, fetched, None, db_pool).await?;
+
+    // Stored alongside the historical data so that replay maps the series alike
+    if let Some(data) = coin_map_data {
+        pg::ops::provenance::insert(
+            &fetch.imported_at,
+            &config.agent_name,
+            &Bytes::from(data),
+            Some("application/json".to_owned()),
+            &None,
+            &None,
+            Some(fetch.provenance.uuid),
+            db_pool)
+            .await?;
+    }
+
+    if !unmapped.is_empty() && config.unmapped == UnmappedSeries::Auto {
+        let list = pg::ops::provenance::insert_from_json_url::<Vec<CoinListEntry>, _, _>(
+            &config.agent_name,
+            config.coins_list_url.as_str(),
+            http,
+            Some(fetch.provenance.uuid),
+            db_pool)
+            .await?;
+
+        for symbol in coins.auto_map(&unmapped, &list.json) {
+            info!("Mapped series '{}' to coin '{}'", symbol, coins.get(&symbol).unwrap_or_default());
+        }
+    }
+
+    Ok((fetch, coins))
+}
@@ -0,0 +1,24 @@
+-- Daily Merkle commitments over the `object_storage.sha256` of every provenance.
+-- Leaves are stored alongside the root so inclusion proofs remain available even
+-- after the provenance or its blob has been pruned.
+
+create table if not exists merkle_day_commitment (
+    day date primary key,
+    leaf_count integer not null check (leaf_count > 0),
+    root_sha256 bytea not null check (length(root_sha256) = 32 /* bytes */),
+    created_at_utc timestamp not null default (now() at time zone 'utc')
+);
+
+create table if not exists merkle_day_leaf (
+    day date not null references merkle_day_commitment(day),
+    leaf_index integer not null check (leaf_index >= 0),
+    provenance_uuid uuid not null unique,
+    sha256 bytea not null check (length(sha256) = 32 /* bytes */),
+    primary key (day, leaf_index)
+);
+
+grant select on table merkle_day_commitment to domfi_coingecko_ro;
+grant select on table merkle_day_leaf to domfi_coingecko_ro;
+
+grant select, insert on table merkle_day_commitment to domfi_coingecko_loader;
+grant select, insert on table merkle_day_leaf to domfi_coingecko_loader;
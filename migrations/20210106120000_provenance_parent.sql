@@ -0,0 +1,6 @@
+-- Sources that fetch several responses per snapshot archive each of them as a
+-- provenance. The rows are attached to the first, and the others point back to it.
+
+alter table provenance add column if not exists parent_uuid uuid references provenance(uuid);
+
+create index if not exists provenance_parent_uuid_idx on provenance(parent_uuid);
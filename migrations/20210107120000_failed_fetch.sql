@@ -0,0 +1,19 @@
+-- Failed attempts of the loader to fetch a snapshot, so that gaps in `coin_dominance`
+-- can be explained. Attempts are numbered from 0 within each snapshot.
+
+create table if not exists failed_fetch (
+    id bigint primary key generated always as identity,
+    timestamp_utc timestamp not null,
+    agent text not null,
+    url text,
+    attempt integer not null check (attempt >= 0),
+    http_status smallint,
+    retry_after_ms bigint check (retry_after_ms >= 0),
+    error text not null
+);
+
+create index if not exists failed_fetch_timestamp_utc_idx on failed_fetch(timestamp_utc);
+
+grant select on table failed_fetch to domfi_coingecko_ro;
+
+grant select, insert on table failed_fetch to domfi_coingecko_loader;
@@ -0,0 +1,7 @@
+-- Responses that were rejected, i.e. non-2xx statuses and bodies that failed to parse,
+-- are kept on their `failed_fetch` record instead of being archived as a provenance.
+
+alter table failed_fetch add column if not exists data bytea;
+alter table failed_fetch add column if not exists mime text;
+alter table failed_fetch add column if not exists request_metadata jsonb;
+alter table failed_fetch add column if not exists response_metadata jsonb;
@@ -0,0 +1,22 @@
+-- Snapshots which parsed but failed validation, e.g. dominance not adding up to 100%.
+-- They are kept here with their responses instead of being archived as a provenance.
+
+create table if not exists quarantined_snapshot (
+    id bigint primary key generated always as identity,
+    timestamp_utc timestamp not null,
+    agent text not null,
+    url text not null,
+    data bytea not null,
+    mime text,
+    request_metadata jsonb,
+    response_metadata jsonb,
+    -- Bodies of the further responses of the snapshot, in the order they were fetched
+    children bytea[] not null default '{}',
+    violations text[] not null check (cardinality(violations) > 0)
+);
+
+create index if not exists quarantined_snapshot_timestamp_utc_idx on quarantined_snapshot(timestamp_utc);
+
+grant select on table quarantined_snapshot to domfi_coingecko_ro;
+
+grant select, insert on table quarantined_snapshot to domfi_coingecko_loader;
@@ -0,0 +1,24 @@
+-- Polls which returned a snapshot unchanged from the agent's latest provenance. Instead of
+-- archiving it again as a provenance, the loader only records that it saw it again.
+
+create table if not exists snapshot_observation (
+    id bigint primary key generated always as identity,
+    timestamp_utc timestamp not null,
+    agent text not null,
+    -- Latest provenance of the agent, which the snapshot was unchanged from
+    provenance_uuid uuid not null references provenance(uuid),
+    -- How the snapshot was found unchanged, `hash` or `timestamp`
+    reason text not null check (reason in ('hash', 'timestamp')),
+    -- Hash of the snapshot's first response, which differs from the provenance's for `timestamp`
+    sha256 bytea not null check (length(sha256) = 32)
+);
+
+create index if not exists snapshot_observation_provenance_uuid_idx on snapshot_observation(provenance_uuid);
+create index if not exists snapshot_observation_timestamp_utc_idx on snapshot_observation(timestamp_utc);
+
+-- Finding the latest provenance of an agent on every poll
+create index if not exists provenance_agent_timestamp_utc_idx on provenance(agent, timestamp_utc desc);
+
+grant select on table snapshot_observation to domfi_coingecko_ro;
+
+grant select, insert on table snapshot_observation to domfi_coingecko_loader;
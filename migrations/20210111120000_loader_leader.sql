@@ -0,0 +1,15 @@
+-- Leader of the loader replicas sharing an advisory lock. Only the leader polls the sources;
+-- it heartbeats here while it holds the lock so that the followers can tell it is alive.
+
+create table if not exists loader_leader (
+    lock_key bigint primary key,
+    instance text not null,
+    -- Backend holding the advisory lock, terminated by a follower once the heartbeat is stale
+    backend_pid integer not null,
+    elected_at timestamp not null,
+    heartbeat_at timestamp not null
+);
+
+grant select on table loader_leader to domfi_coingecko_ro;
+
+grant select, insert, update, delete on table loader_leader to domfi_coingecko_loader;
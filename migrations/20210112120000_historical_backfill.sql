@@ -0,0 +1,32 @@
+-- Historical charts hold many points per coin, so rows are unique per object, coin and
+-- timestamp rather than per object and coin. Live snapshots only have a single timestamp.
+
+alter table coin_dominance drop constraint if exists coin_dominance_object_id_coin_id_key;
+alter table coin_dominance add constraint coin_dominance_object_id_coin_id_timestamp_utc_key
+    unique (object_id, coin_id, timestamp_utc);
+
+-- Finding the points an agent already loaded
+create index if not exists coin_dominance_agent_coin_id_timestamp_utc_idx on coin_dominance(agent, coin_id, timestamp_utc);
+
+-- Progress of a historical backfill, so that an interrupted run resumes loading the chart it
+-- already fetched instead of starting over
+create table if not exists historical_checkpoint (
+    id bigint primary key generated always as identity,
+    agent text not null,
+    provenance_uuid uuid not null references provenance(uuid),
+    -- Range of the points loaded, either end unbounded if null
+    from_utc timestamp,
+    to_utc timestamp,
+    -- Every point before this timestamp has been loaded
+    resume_from_utc timestamp,
+    inserted bigint not null default 0,
+    started_at timestamp not null default (now() at time zone 'utc'),
+    updated_at timestamp not null default (now() at time zone 'utc'),
+    completed_at timestamp
+);
+
+create index if not exists historical_checkpoint_agent_idx on historical_checkpoint(agent) where completed_at is null;
+
+grant select on table historical_checkpoint to domfi_coingecko_ro;
+
+grant select, insert, update on table historical_checkpoint to domfi_coingecko_loader;
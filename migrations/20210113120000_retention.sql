@@ -0,0 +1,26 @@
+-- Retention downsamples old `coin_dominance` rows and prunes the provenances and blobs
+-- left without rows. `domfi_admin retention` runs as the maintenance role, the only one
+-- allowed to delete from the archive.
+
+-- Finding provenances without rows, and the rows referencing a deleted provenance
+create index if not exists coin_dominance_provenance_uuid_object_id_idx on coin_dominance(provenance_uuid, object_id);
+
+-- Finding blobs without provenance
+create index if not exists provenance_object_id_idx on provenance(object_id);
+
+DO $$
+BEGIN
+    CREATE ROLE domfi_coingecko_maintenance LOGIN PASSWORD 'development_only';
+
+EXCEPTION
+    WHEN DUPLICATE_OBJECT THEN
+        RAISE NOTICE 'CREATE ROLE already exists. Ignoring.';
+END
+$$;
+
+grant select, delete on table coin_dominance to domfi_coingecko_maintenance;
+grant select, delete on table provenance to domfi_coingecko_maintenance;
+grant select, delete on table object_storage to domfi_coingecko_maintenance;
+grant select, delete on table snapshot_observation to domfi_coingecko_maintenance;
+grant select on table historical_checkpoint to domfi_coingecko_maintenance;
+grant select on table merkle_day_leaf to domfi_coingecko_maintenance;
@@ -0,0 +1,15 @@
+-- Retention runs, so that replay does not re-insert the rows they deleted. Every row before
+-- the latest cutoff has been downsampled.
+create table if not exists retention_run (
+    id bigint primary key generated always as identity,
+    cutoff_utc timestamp not null,
+    resolution text not null,
+    rows_deleted bigint not null,
+    applied_at timestamp not null default (now() at time zone 'utc')
+);
+
+grant select on table retention_run to domfi_coingecko_ro;
+
+grant select on table retention_run to domfi_coingecko_loader;
+
+grant select, insert on table retention_run to domfi_coingecko_maintenance;
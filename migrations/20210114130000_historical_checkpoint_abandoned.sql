@@ -0,0 +1,6 @@
+-- Backfills restarted with `--fresh` are marked abandoned instead of staying unfinished
+alter table historical_checkpoint add column if not exists abandoned_at timestamp;
+
+drop index if exists historical_checkpoint_agent_idx;
+create index if not exists historical_checkpoint_agent_idx on historical_checkpoint(agent)
+    where completed_at is null and abandoned_at is null;
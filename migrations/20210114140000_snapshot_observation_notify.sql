@@ -0,0 +1,13 @@
+-- Notifies `db_notify` of unchanged snapshots too, so that listeners can tell an agent which
+-- only records observations (e.g. while CoinGecko serves a cached body) is still polling.
+
+drop trigger if exists snapshot_observation_notify on snapshot_observation;
+
+create trigger snapshot_observation_notify after insert on snapshot_observation
+    for each row execute procedure notify_trigger(
+        'id',
+        'timestamp_utc',
+        'agent',
+        'provenance_uuid',
+        'reason'
+    );